@@ -0,0 +1,281 @@
+//! Throughput benchmarks for the proxy path and the storage backends.
+//!
+//! The proxy benchmarks start the `relay` binary against a synthetic origin
+//! served from this process, then send it requests over keep-alive
+//! connections: repeats of one path for cache hits, and a new path each time
+//! for misses. The origin can be tuned through the environment:
+//!
+//! - `RELAY_BENCH_LATENCY_MS`: delay before each origin response (default 0)
+//! - `RELAY_BENCH_BODY_SIZE`: response body size in bytes (default 4096)
+//!
+//! Run with `cargo bench`, or `cargo bench -- proxy/hit` for one benchmark.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http1::SendRequest;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use relay::storage::{MemoryStorage, MokaStorage};
+use relay::{CachedResponse, Storage};
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+/// Connections used by the concurrent benchmarks
+const CONNECTIONS: u64 = 16;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Serves `body`, cacheable for an hour, on every path after `latency`.
+async fn start_origin(latency: Duration, body: Bytes) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let body = body.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |_req| {
+                    let body = body.clone();
+                    async move {
+                        if !latency.is_zero() {
+                            tokio::time::sleep(latency).await;
+                        }
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .header("Cache-Control", "max-age=3600")
+                                .body(Full::new(body))
+                                .unwrap(),
+                        )
+                    }
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+/// A `relay` process proxying to the synthetic origin, stopped on drop.
+struct Relay {
+    child: Child,
+    config_path: PathBuf,
+    addr: SocketAddr,
+}
+
+impl Relay {
+    fn start(origin: SocketAddr) -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let config_path =
+            std::env::temp_dir().join(format!("relay-bench-{}.toml", std::process::id()));
+        std::fs::write(
+            &config_path,
+            format!(
+                "[server]\nhost = \"127.0.0.1\"\nport = {port}\n\n\
+                 [upstream]\nurl = \"http://{origin}\"\n\n\
+                 [logging]\nenabled = false\n"
+            ),
+        )
+        .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_relay"))
+            .arg("--config")
+            .arg(&config_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start relay");
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "relay did not start listening");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        Self {
+            child,
+            config_path,
+            addr,
+        }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config_path);
+    }
+}
+
+async fn connect(addr: SocketAddr) -> SendRequest<Empty<Bytes>> {
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    sender
+}
+
+async fn get(sender: &mut SendRequest<Empty<Bytes>>, path: &str) {
+    sender.ready().await.unwrap();
+    let res = sender
+        .send_request(
+            Request::get(path)
+                .header("Host", "bench")
+                .body(Empty::new())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(res.status().is_success(), "relay returned {}", res.status());
+    res.into_body().collect().await.unwrap();
+}
+
+/// Sends `iters` requests spread over `connections` connections, with
+/// `path` choosing the path of each request, and returns the time taken.
+async fn run(
+    addr: SocketAddr,
+    iters: u64,
+    connections: u64,
+    path: impl Fn() -> String + Send + Sync + 'static,
+) -> Duration {
+    let path = Arc::new(path);
+    let mut senders = Vec::new();
+    for _ in 0..connections {
+        senders.push(connect(addr).await);
+    }
+    let start = Instant::now();
+    let tasks: Vec<_> = senders
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut sender)| {
+            let path = Arc::clone(&path);
+            let count = iters / connections + u64::from((index as u64) < iters % connections);
+            tokio::spawn(async move {
+                for _ in 0..count {
+                    get(&mut sender, &path()).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn proxy(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let latency = Duration::from_millis(env_or("RELAY_BENCH_LATENCY_MS", 0));
+    let body = Bytes::from(vec![b'x'; env_or("RELAY_BENCH_BODY_SIZE", 4096)]);
+    let origin = rt.block_on(start_origin(latency, body));
+    let relay = Relay::start(origin);
+    let addr = relay.addr;
+
+    // Shared by every miss benchmark, so no path is requested twice
+    let next = Arc::new(AtomicU64::new(0));
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Elements(1));
+    for connections in [1, CONNECTIONS] {
+        group.bench_with_input(
+            BenchmarkId::new("hit", connections),
+            &connections,
+            |b, &connections| {
+                rt.block_on(run(addr, 1, 1, || "/hit".to_string()));
+                b.to_async(&rt)
+                    .iter_custom(|iters| run(addr, iters, connections, || "/hit".to_string()));
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("miss", connections),
+            &connections,
+            |b, &connections| {
+                b.to_async(&rt).iter_custom(|iters| {
+                    let next = Arc::clone(&next);
+                    run(addr, iters, connections, move || {
+                        format!("/miss/{}", next.fetch_add(1, Ordering::Relaxed))
+                    })
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn response(size: usize) -> CachedResponse {
+    CachedResponse {
+        status: hyper::StatusCode::OK,
+        headers: hyper::HeaderMap::new(),
+        body: Bytes::from(vec![b'x'; size]),
+        cached_at: SystemTime::now(),
+        ttl: Duration::from_secs(3600),
+        jitter_seed: 0.5,
+        fetch_duration: Duration::ZERO,
+    }
+}
+
+fn storage(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let backends: [(&str, Arc<dyn Storage>); 2] = [
+        ("memory", Arc::new(MemoryStorage::new())),
+        ("moka", Arc::new(MokaStorage::new(1 << 30))),
+    ];
+    let keys = 10_000;
+    let mut group = c.benchmark_group("storage");
+    group.throughput(Throughput::Elements(1));
+    for (name, storage) in backends {
+        rt.block_on(async {
+            for i in 0..keys {
+                storage
+                    .set(
+                        format!("/key/{i}"),
+                        response(4096),
+                        Duration::from_secs(3600),
+                    )
+                    .await;
+            }
+        });
+        let next = AtomicU64::new(0);
+        group.bench_function(BenchmarkId::new("get", name), |b| {
+            b.to_async(&rt).iter(|| {
+                let key = format!("/key/{}", next.fetch_add(1, Ordering::Relaxed) % keys);
+                let storage = Arc::clone(&storage);
+                async move { storage.get(&key).await }
+            });
+        });
+        let value = response(4096);
+        group.bench_function(BenchmarkId::new("set", name), |b| {
+            b.to_async(&rt).iter(|| {
+                let key = format!("/key/{}", next.fetch_add(1, Ordering::Relaxed) % keys);
+                let storage = Arc::clone(&storage);
+                let value = value.clone();
+                async move { storage.set(key, value, Duration::from_secs(3600)).await }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, proxy, storage);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+use globset::{Glob, GlobMatcher};
+use hyper::header::{HeaderMap, HeaderName};
+use ipnet::IpNet;
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::auth::{constant_time_eq, secret};
+use crate::config::{AccessConfig, DebugConfig};
+
+/// Allow and deny lists of networks and countries. A deny match always
+/// wins; a non-empty allow list admits only the addresses, or countries, it
+/// contains.
+struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+}
+
+impl AccessList {
+    fn permits(&self, ip: IpAddr, country: Option<&str>) -> bool {
+        let listed = |countries: &[String]| {
+            country.is_some_and(|country| countries.iter().any(|listed| listed == country))
+        };
+        if contains(&self.deny, ip) || listed(&self.deny_countries) {
+            return false;
+        }
+        (self.allow.is_empty() || contains(&self.allow, ip))
+            && (self.allow_countries.is_empty() || listed(&self.allow_countries))
+    }
+}
+
+/// How specific a route pattern is: the length of its literal prefix, then
+/// the number of literal characters in all.
+pub(crate) fn specificity(pattern: &str) -> (usize, usize) {
+    let is_wildcard = |c: char| matches!(c, '*' | '?' | '[' | ']' | '{' | '}');
+    let prefix = pattern.find(is_wildcard).unwrap_or(pattern.len());
+    let literal = pattern.chars().filter(|&c| !is_wildcard(c)).count();
+    (prefix, literal)
+}
+
+/// Decides which client addresses may use relay, globally and per route.
+pub struct AccessControl {
+    global: AccessList,
+    routes: Vec<(GlobMatcher, AccessList)>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let global = AccessList {
+            allow: parse_networks(&config.allow)?,
+            deny: parse_networks(&config.deny)?,
+            allow_countries: parse_countries(&config.allow_countries),
+            deny_countries: parse_countries(&config.deny_countries),
+        };
+        let mut routes = Vec::new();
+        for (pattern, rule) in &config.routes {
+            // A route's lists replace the global ones; unset lists are inherited
+            let list = AccessList {
+                allow: match &rule.allow {
+                    Some(allow) => parse_networks(allow)?,
+                    None => global.allow.clone(),
+                },
+                deny: match &rule.deny {
+                    Some(deny) => parse_networks(deny)?,
+                    None => global.deny.clone(),
+                },
+                allow_countries: match &rule.allow_countries {
+                    Some(allow) => parse_countries(allow),
+                    None => global.allow_countries.clone(),
+                },
+                deny_countries: match &rule.deny_countries {
+                    Some(deny) => parse_countries(deny),
+                    None => global.deny_countries.clone(),
+                },
+            };
+            routes.push((
+                pattern.as_str(),
+                Glob::new(pattern)?.compile_matcher(),
+                list,
+            ));
+        }
+        // Routes are checked most specific first, whatever order the config
+        // map yields them in
+        routes.sort_by(|(a, ..), (b, ..)| specificity(b).cmp(&specificity(a)).then(a.cmp(b)));
+        let routes = routes
+            .into_iter()
+            .map(|(_, matcher, list)| (matcher, list))
+            .collect();
+
+        Ok(Self {
+            global,
+            routes,
+            trusted_proxies: parse_networks(&config.trusted_proxies)?,
+        })
+    }
+
+    /// Whether a client at `ip`, in `country` when known, may request
+    /// `path`, under the most specific route matching it.
+    pub fn permits(&self, ip: IpAddr, country: Option<&str>, path: &str) -> bool {
+        self.routes
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(path))
+            .map_or(&self.global, |(_, list)| list)
+            .permits(ip, country)
+    }
+
+    /// The address of the client behind any trusted proxies. `X-Forwarded-For`
+    /// is only believed when the peer is a trusted proxy, and is read from the
+    /// right, skipping entries added by further trusted proxies.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !contains(&self.trusted_proxies, peer) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|entry| entry.trim().parse().ok())
+            .collect();
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !contains(&self.trusted_proxies, **ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+/// Decides which clients get debug headers: those asking from a
+/// `debug.allow` network, or from anywhere with the `debug.token`.
+pub struct DebugAccess {
+    always: bool,
+    header: HeaderName,
+    allow: Vec<IpNet>,
+    token: Option<String>,
+}
+
+impl DebugAccess {
+    pub fn new(config: &DebugConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self {
+            always: config.enabled,
+            header: HeaderName::from_bytes(config.header.as_bytes())
+                .map_err(|_| format!("Invalid debug header name: {}", config.header))?,
+            allow: parse_networks(&config.allow)?,
+            token: secret(&config.token, &config.token_env)?,
+        })
+    }
+
+    /// Whether the response to a client at `ip` sending `headers` gets
+    /// debug headers.
+    pub fn permits(&self, ip: IpAddr, headers: &HeaderMap) -> bool {
+        let requested = headers
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok());
+        if let (Some(token), Some(requested)) = (&self.token, requested) {
+            if constant_time_eq(requested.trim(), token) {
+                return true;
+            }
+        }
+        (self.always || requested.is_some()) && contains(&self.allow, ip)
+    }
+}
+
+fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(&ip))
+}
+
+/// Country codes compare in upper case, as the GeoIP database has them.
+fn parse_countries(entries: &[String]) -> Vec<String> {
+    entries
+        .iter()
+        .map(|entry| entry.trim().to_ascii_uppercase())
+        .collect()
+}
+
+/// Accepts CIDR blocks as well as single addresses.
+fn parse_networks(entries: &[String]) -> Result<Vec<IpNet>, Box<dyn Error + Send + Sync>> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid address or CIDR block: {entry}").into())
+        })
+        .collect()
+}
@@ -0,0 +1,406 @@
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::cache_key::generate_cache_key;
+use crate::cluster::ClusterEvent;
+use crate::config::{CacheRule, CompiledRule};
+use crate::dashboard::PAGE;
+use crate::handlers::{full, AppState, Body};
+use crate::logger::AUDIT;
+
+type AdminResult = Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Who made an admin request, as the audit log records it.
+struct Actor {
+    /// "token", the basic-auth username, or "anonymous" without admin auth
+    principal: String,
+    client_ip: IpAddr,
+}
+
+/// Routes requests under the admin path prefix.
+pub async fn handle_admin(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<AppState>,
+    client_ip: IpAddr,
+) -> AdminResult {
+    let actor = Actor {
+        principal: state
+            .admin_auth
+            .as_ref()
+            .and_then(|auth| auth.principal(req.headers()))
+            .unwrap_or_else(|| "anonymous".to_string()),
+        client_ip,
+    };
+    let route = req
+        .uri()
+        .path()
+        .strip_prefix(state.admin_config.path.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    match (req.method(), route.as_str()) {
+        (&Method::GET, "" | "/" | "/stats") if state.dashboard.is_some() => {
+            dashboard(&route, &state)
+        }
+        (&Method::POST, "/purge") => purge(&req, &state, &actor).await,
+        (&Method::GET, "/namespace") => json_response(
+            StatusCode::OK,
+            json!({ "namespace": *state.namespace.read().unwrap() }),
+        ),
+        (&Method::POST, "/namespace") => rotate_namespace(&req, &state, &actor).await,
+        (&Method::GET, "/rules") => list_rules(&state),
+        (&Method::POST | &Method::PUT, "/rules") => save_rule(req, &state, &actor).await,
+        (&Method::DELETE, "/rules") => delete_rule(&req, &state, &actor),
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    }
+}
+
+/// Serves the dashboard page at the admin path, redirecting there from the
+/// path without a trailing slash so the page's relative links resolve, and
+/// the stats it polls.
+fn dashboard(route: &str, state: &AppState) -> AdminResult {
+    match route {
+        "" => Ok(Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header("Location", format!("{}/", state.admin_config.path))
+            .body(full(Bytes::new()))?),
+        "/" => Ok(Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(full(Bytes::from_static(PAGE.as_bytes())))?),
+        _ => {
+            let dashboard = state.dashboard.as_ref().ok_or("dashboard disabled")?;
+            json_response(StatusCode::OK, dashboard.stats(&state.balancer))
+        }
+    }
+}
+
+/// Purges `key` from local storage. A hard purge deletes the entry; a soft
+/// purge marks it stale so the next request revalidates while stale-if-error
+/// can still fall back to it if the origin is down.
+pub async fn apply_purge(
+    state: &AppState,
+    key: &str,
+    soft: bool,
+    stale_if_error: Duration,
+) -> bool {
+    // The next response decides afresh whether the key is cacheable
+    if let Some(hit_for_pass) = &state.hit_for_pass {
+        hit_for_pass.invalidate(key);
+    }
+    if !soft {
+        return state.cache.delete(key).await;
+    }
+    match state.cache.get(key).await {
+        Some(mut entry) => {
+            // Freshness ends now; the stale-if-error window starts from here
+            entry.ttl = entry.age();
+            state
+                .cache
+                .set(key.to_string(), entry, stale_if_error)
+                .await;
+            true
+        }
+        None => false,
+    }
+}
+
+fn query_params(req: &Request<hyper::body::Incoming>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn json_response(status: StatusCode, body: Value) -> AdminResult {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(full(Bytes::from(body.to_string())))?)
+}
+
+/// Purges the entry for `path` on this instance and, when clustering is
+/// enabled, on every other instance.
+async fn purge(
+    req: &Request<hyper::body::Incoming>,
+    state: &AppState,
+    actor: &Actor,
+) -> AdminResult {
+    let params = query_params(req);
+    let Some(path) = params.get("path") else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "missing path parameter" }),
+        );
+    };
+    let Ok(uri) = path.parse::<hyper::Uri>() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "invalid path" }));
+    };
+    let mode = params.get("mode").map(String::as_str).unwrap_or("hard");
+
+    let cache_config = &state.cache_config;
+    let matched_rule = cache_config.find_rule_with_pattern(uri.path());
+    let rule = matched_rule.as_ref().map(|matched| &matched.rule);
+    let key = state.storage_key(generate_cache_key(
+        &uri,
+        &HeaderMap::new(),
+        &cache_config.key,
+        rule,
+    ));
+
+    let soft = match mode {
+        "hard" => false,
+        "soft" => true,
+        _ => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "mode must be \"hard\" or \"soft\"" }),
+            );
+        }
+    };
+    let stale_if_error = rule
+        .and_then(|r| r.stale)
+        .unwrap_or(cache_config.stale_if_error);
+
+    let purged = apply_purge(state, &key, soft, stale_if_error).await;
+
+    if let Some(cluster) = &state.cluster {
+        cluster
+            .publish(ClusterEvent::Purge {
+                key: key.clone(),
+                soft,
+                stale_if_error_ms: stale_if_error.as_millis() as u64,
+            })
+            .await;
+    }
+
+    info!(
+        target: AUDIT,
+        action = "purge",
+        actor = %actor.principal,
+        client_ip = %actor.client_ip,
+        key = %key,
+        mode,
+        purged,
+        "Cache PURGE ({mode}): {key} - purged: {purged}"
+    );
+    json_response(
+        StatusCode::OK,
+        json!({ "key": key, "mode": mode, "purged": purged }),
+    )
+}
+
+/// Switches all requests to a new namespace, which invalidates every entry
+/// stored under the previous one. Without a `value` parameter a new
+/// namespace is derived from the current time.
+async fn rotate_namespace(
+    req: &Request<hyper::body::Incoming>,
+    state: &AppState,
+    actor: &Actor,
+) -> AdminResult {
+    let namespace = match query_params(req).remove("value") {
+        Some(value) => value,
+        None => format!(
+            "{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        ),
+    };
+
+    let previous = std::mem::replace(&mut *state.namespace.write().unwrap(), namespace.clone());
+    info!(
+        target: AUDIT,
+        action = "namespace",
+        actor = %actor.principal,
+        client_ip = %actor.client_ip,
+        namespace = %namespace,
+        previous = %previous,
+        "Cache namespace rotated: {previous:?} -> {namespace:?}"
+    );
+
+    if let Some(cluster) = &state.cluster {
+        cluster
+            .publish(ClusterEvent::Namespace {
+                namespace: namespace.clone(),
+            })
+            .await;
+    }
+    json_response(
+        StatusCode::OK,
+        json!({ "namespace": namespace, "previous": previous }),
+    )
+}
+
+/// Largest rule definition accepted
+const MAX_RULE_SIZE: usize = 64 * 1024;
+
+fn list_rules(state: &AppState) -> AdminResult {
+    let rules: Vec<Value> = state
+        .cache_config
+        .compiled_rules
+        .list()
+        .iter()
+        .map(|compiled| rule_json(compiled))
+        .collect();
+    json_response(StatusCode::OK, json!({ "rules": rules }))
+}
+
+/// Adds (POST) or replaces (PUT) the rule for the `pattern` parameter with
+/// the rule in the JSON body, written like a `[cache.rules]` entry. The rule
+/// applies to the next request; nothing cached under the old one changes.
+async fn save_rule(
+    req: Request<hyper::body::Incoming>,
+    state: &AppState,
+    actor: &Actor,
+) -> AdminResult {
+    let Some(pattern) = query_params(&req).remove("pattern") else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "missing pattern parameter" }),
+        );
+    };
+    let adding = req.method() == Method::POST;
+    let body = match Limited::new(req.into_body(), MAX_RULE_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+    let compiled = match serde_json::from_slice::<CacheRule>(&body)
+        .map_err(|e| e.into())
+        .and_then(|rule| {
+            // Otherwise the admin API could expose any directory relay can read
+            if rule.serve_static.is_some() {
+                return Err("serve_static can only be set in the config file".into());
+            }
+            CompiledRule::new(&pattern, rule)
+        }) {
+        Ok(compiled) => compiled,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+
+    let rules = &state.cache_config.compiled_rules;
+    let exists = rules.get(&pattern).is_some();
+    if adding && exists {
+        return json_response(
+            StatusCode::CONFLICT,
+            json!({ "error": "rule exists; use PUT to replace it" }),
+        );
+    }
+    if !adding && !exists {
+        return json_response(StatusCode::NOT_FOUND, json!({ "error": "no such rule" }));
+    }
+    let response = rule_json(&compiled);
+    rules.insert(compiled);
+    info!(
+        target: AUDIT,
+        action = if adding { "rule_add" } else { "rule_update" },
+        actor = %actor.principal,
+        client_ip = %actor.client_ip,
+        pattern = %pattern,
+        rule = %response,
+        "Cache rule {}: {pattern}",
+        if adding { "added" } else { "updated" }
+    );
+    let status = if adding {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    json_response(status, response)
+}
+
+fn delete_rule(
+    req: &Request<hyper::body::Incoming>,
+    state: &AppState,
+    actor: &Actor,
+) -> AdminResult {
+    let Some(pattern) = query_params(req).remove("pattern") else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "missing pattern parameter" }),
+        );
+    };
+    if !state.cache_config.compiled_rules.remove(&pattern) {
+        return json_response(StatusCode::NOT_FOUND, json!({ "error": "no such rule" }));
+    }
+    info!(
+        target: AUDIT,
+        action = "rule_delete",
+        actor = %actor.principal,
+        client_ip = %actor.client_ip,
+        pattern = %pattern,
+        "Cache rule deleted: {pattern}"
+    );
+    json_response(
+        StatusCode::OK,
+        json!({ "pattern": pattern, "deleted": true }),
+    )
+}
+
+/// A rule as JSON, in the form `save_rule` accepts.
+fn rule_json(compiled: &CompiledRule) -> Value {
+    let rule = &compiled.rule;
+    let status_ttl = rule.status_ttl.as_ref().map(|status_ttl| {
+        status_ttl
+            .iter()
+            .map(|(status, ttl)| (status.clone(), Value::from(format_duration(*ttl))))
+            .collect::<serde_json::Map<_, _>>()
+    });
+    let mut json = json!({
+        "pattern": compiled.pattern,
+        "ttl": rule.ttl.map(format_duration),
+        "stale": rule.stale.map(format_duration),
+        "bypass": rule.bypass,
+        "cache_statuses": rule.cache_statuses,
+        "status_ttl": status_ttl,
+        "content_types": rule.content_types,
+        "exclude_content_types": rule.exclude_content_types,
+        "bypass_cookies": rule.bypass_cookies,
+        "bypass_query": rule.bypass_query,
+        "refresh_interval": rule.refresh_interval.map(format_duration),
+        "cache_set_cookie": rule.cache_set_cookie,
+        "vary_headers": rule.vary_headers,
+        "vary_cookies": rule.vary_cookies,
+        "languages": rule.languages,
+        "device_class": rule.device_class,
+        "bot_ttl": rule.bot_ttl.map(format_duration),
+        "serve_static": rule.serve_static,
+        "spa_fallback": rule.spa_fallback,
+        "spa_fallback_on": rule.spa_fallback_on,
+        "spa_fallback_ttl": rule.spa_fallback_ttl.map(format_duration),
+    });
+    // Unset options are left out, as they would be in the config file
+    if let Some(fields) = json.as_object_mut() {
+        fields.retain(|_, value| !value.is_null());
+    }
+    json
+}
+
+/// `duration` in the largest unit the config accepts that keeps it exact,
+/// such as "90s" or "2h".
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_nanos() != 0 {
+        return format!("{}ms", duration.as_millis());
+    }
+    let secs = duration.as_secs();
+    match secs {
+        0 => "0s".to_string(),
+        _ if secs.is_multiple_of(86400) => format!("{}d", secs / 86400),
+        _ if secs.is_multiple_of(3600) => format!("{}h", secs / 3600),
+        _ if secs.is_multiple_of(60) => format!("{}m", secs / 60),
+        _ => format!("{secs}s"),
+    }
+}
@@ -0,0 +1,99 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use std::error::Error;
+
+use crate::config::AuthConfig;
+
+/// Credentials protecting an internal endpoint such as `/metrics` or the
+/// admin API. Either a bearer token or basic-auth credentials are accepted.
+pub struct EndpointAuth {
+    token: Option<String>,
+    /// Expected value of a basic `Authorization` header, precomputed
+    basic: Option<String>,
+    username: Option<String>,
+}
+
+impl EndpointAuth {
+    /// Resolves secrets given inline or through environment variables.
+    /// Returns `None` when no credentials are configured.
+    pub fn new(config: Option<&AuthConfig>) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let Some(config) = config else {
+            return Ok(None);
+        };
+        let token = secret(&config.token, &config.token_env)?;
+        let password = secret(&config.password, &config.password_env)?;
+        let basic = match (&config.username, password) {
+            (Some(username), Some(password)) => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{username}:{password}"))
+            )),
+            (None, None) => None,
+            _ => return Err("Basic auth needs both a username and a password".into()),
+        };
+        if token.is_none() && basic.is_none() {
+            return Err("Auth configured without a token or username and password".into());
+        }
+        Ok(Some(Self {
+            token,
+            basic,
+            username: config.username.clone(),
+        }))
+    }
+
+    pub fn authorize(&self, headers: &HeaderMap) -> bool {
+        self.principal(headers).is_some()
+    }
+
+    /// Who the credentials in `headers` belong to: "token" for the bearer
+    /// token, or the basic-auth username. `None` when they match neither.
+    pub fn principal(&self, headers: &HeaderMap) -> Option<String> {
+        let provided = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+        let token_ok = self.token.as_ref().is_some_and(|token| {
+            provided
+                .strip_prefix("Bearer ")
+                .is_some_and(|provided| constant_time_eq(provided.trim(), token))
+        });
+        if token_ok {
+            return Some("token".to_string());
+        }
+        let basic_ok = self
+            .basic
+            .as_ref()
+            .is_some_and(|basic| constant_time_eq(provided, basic));
+        basic_ok.then(|| self.username.clone().unwrap_or_default())
+    }
+
+    /// The `WWW-Authenticate` challenge sent with a 401.
+    pub fn challenge(&self) -> &'static str {
+        if self.basic.is_some() {
+            "Basic realm=\"relay\""
+        } else {
+            "Bearer"
+        }
+    }
+}
+
+/// A secret given inline, or else read from the named environment variable.
+pub fn secret(
+    value: &Option<String>,
+    env: &Option<String>,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    match (value, env) {
+        (Some(value), _) => Ok(Some(value.clone())),
+        (None, Some(name)) => std::env::var(name)
+            .map(Some)
+            .map_err(|_| format!("Environment variable {name} is not set").into()),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Compares without stopping at the first difference, so response timing
+/// doesn't reveal how much of a guessed secret was right.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
@@ -0,0 +1,315 @@
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::Uri;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::cache_key::cookies;
+use crate::config::{OutlierDetectionConfig, UpstreamConfig};
+use crate::metrics::UPSTREAM_EJECTIONS;
+use crate::upstream::parse_url;
+
+/// Set on a request whose client is tied to one upstream server, so every
+/// fetch made for it goes there. Unlike an `UpstreamOverride`, it doesn't
+/// change the cache key: all servers are expected to serve the same content.
+#[derive(Clone, Debug)]
+pub struct PinnedUpstream(pub String);
+
+enum Policy {
+    RoundRobin,
+    /// Each path and query always goes to the same server
+    Hash,
+}
+
+enum Affinity {
+    None,
+    /// Relay names the chosen server in a cookie it sets on the client
+    Cookie(String),
+    /// The client's address is hashed onto a server
+    ClientIp,
+}
+
+struct Server {
+    url: String,
+    /// `url` parsed once, so requests don't parse it again
+    uri: Uri,
+    /// Opaque, stable name for the server, used as the affinity cookie value
+    id: String,
+    health: Mutex<Health>,
+}
+
+/// What relay has seen of a server in the current period, for outlier
+/// detection.
+#[derive(Default)]
+struct Health {
+    period_start: Option<Instant>,
+    requests: u32,
+    errors: u32,
+    latency: Duration,
+    ejected_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Picks the upstream server for each request when several are configured.
+pub struct Balancer {
+    servers: Vec<Server>,
+    next: AtomicUsize,
+    policy: Policy,
+    affinity: Affinity,
+    outlier_detection: Option<OutlierDetectionConfig>,
+}
+
+/// The server a client is pinned to, and the cookie to set when the client
+/// doesn't carry it yet.
+pub struct Pin<'a> {
+    pub url: &'a str,
+    pub set_cookie: Option<HeaderValue>,
+}
+
+impl Balancer {
+    pub fn new(config: &UpstreamConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let servers = config
+            .urls
+            .iter()
+            .map(|url| {
+                let Some(uri) = parse_url(url) else {
+                    return Err(format!("Invalid upstream URL: {url}").into());
+                };
+                Ok(Server {
+                    url: url.clone(),
+                    uri,
+                    id: hash(url.as_bytes())[..8]
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect(),
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+        let policy = match config.balance.as_str() {
+            "round_robin" => Policy::RoundRobin,
+            "hash" => Policy::Hash,
+            other => return Err(format!("Unsupported upstream balance policy: {other}").into()),
+        };
+        let affinity = match config.affinity.as_deref() {
+            None => Affinity::None,
+            Some("cookie") => Affinity::Cookie(config.affinity_cookie.clone()),
+            Some("client_ip") => Affinity::ClientIp,
+            Some(other) => return Err(format!("Unsupported upstream affinity: {other}").into()),
+        };
+        Ok(Self {
+            servers,
+            next: AtomicUsize::new(0),
+            policy,
+            affinity,
+            outlier_detection: config.outlier_detection.clone(),
+        })
+    }
+
+    /// The server for a request to `uri` without affinity, chosen by the
+    /// balance policy.
+    pub fn pick(&self, uri: &Uri) -> &str {
+        &self.choose(uri).url
+    }
+
+    /// The parsed URL of the server at `url`, if it is in the pool.
+    pub fn uri(&self, url: &str) -> Option<&Uri> {
+        self.servers
+            .iter()
+            .find(|server| server.url == url)
+            .map(|server| &server.uri)
+    }
+
+    /// An available server other than `except` for a second attempt at a
+    /// request, if there is one.
+    pub fn pick_other(&self, except: &str) -> Option<&str> {
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.servers.len())
+            .map(|offset| &self.servers[(start + offset) % self.servers.len()])
+            .find(|server| server.url != except && self.is_available(server, now))
+            .map(|server| server.url.as_str())
+    }
+
+    fn choose(&self, uri: &Uri) -> &Server {
+        match self.policy {
+            Policy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let now = Instant::now();
+                (0..self.servers.len())
+                    .map(|offset| &self.servers[(start + offset) % self.servers.len()])
+                    .find(|server| self.is_available(server, now))
+                    .unwrap_or(&self.servers[start % self.servers.len()])
+            }
+            Policy::Hash => self.rendezvous(uri.path_and_query().map_or("/", |pq| pq.as_str())),
+        }
+    }
+
+    /// The available server `key` hashes to. Removing a server only moves
+    /// the keys that were on it, and adding one only takes its share from
+    /// the others.
+    fn rendezvous(&self, key: &str) -> &Server {
+        let score = |server: &&Server| {
+            let digest = hash(format!("{}\n{key}", server.url).as_bytes());
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        };
+        let now = Instant::now();
+        self.servers
+            .iter()
+            .filter(|server| self.is_available(server, now))
+            .max_by_key(score)
+            .or_else(|| self.servers.iter().max_by_key(score))
+            .expect("upstream has at least one server")
+    }
+
+    /// Whether `server` may take a request: it isn't ejected, and if it was
+    /// recently, it gets a share of requests that grows over the ramp-up.
+    fn is_available(&self, server: &Server, now: Instant) -> bool {
+        let Some(outlier_detection) = &self.outlier_detection else {
+            return true;
+        };
+        let Some(until) = server.health.lock().unwrap().ejected_until else {
+            return true;
+        };
+        if now < until {
+            return false;
+        }
+        let returned = now - until;
+        returned >= outlier_detection.ramp_up
+            || rand::random::<f64>()
+                < returned.as_secs_f64() / outlier_detection.ramp_up.as_secs_f64()
+    }
+
+    /// Counts a request to the server at `url`, ejecting the server when
+    /// its error rate or mean latency over the period crosses a threshold.
+    /// Requests to URLs outside the pool are ignored.
+    pub fn record(&self, url: &str, failed: bool, latency: Duration) {
+        let Some(outlier_detection) = &self.outlier_detection else {
+            return;
+        };
+        let Some(server) = self.servers.iter().find(|server| server.url == url) else {
+            return;
+        };
+        let now = Instant::now();
+        let reason = {
+            let mut health = server.health.lock().unwrap();
+            if health.is_ejected(now) {
+                return;
+            }
+            if health
+                .period_start
+                .is_none_or(|start| now - start >= outlier_detection.interval)
+            {
+                *health = Health {
+                    period_start: Some(now),
+                    ejected_until: health.ejected_until,
+                    ..Health::default()
+                };
+            }
+            health.requests += 1;
+            health.errors += u32::from(failed);
+            health.latency += latency;
+            if health.requests < outlier_detection.min_requests {
+                return;
+            }
+            let error_rate = f64::from(health.errors) / f64::from(health.requests);
+            let mean_latency = health.latency / health.requests;
+            if error_rate > outlier_detection.max_error_rate {
+                format!("error rate {:.0}%", error_rate * 100.0)
+            } else if outlier_detection
+                .max_latency
+                .is_some_and(|max| mean_latency > max)
+            {
+                format!("mean latency {}ms", mean_latency.as_millis())
+            } else {
+                return;
+            }
+        };
+
+        // Keep enough servers in rotation to carry the traffic
+        let ejected = self
+            .servers
+            .iter()
+            .filter(|server| server.health.lock().unwrap().is_ejected(now))
+            .count();
+        if (ejected + 1) as f64 > outlier_detection.max_ejected * self.servers.len() as f64 {
+            return;
+        }
+        *server.health.lock().unwrap() = Health {
+            ejected_until: Some(now + outlier_detection.ejection_time),
+            ..Health::default()
+        };
+        UPSTREAM_EJECTIONS.inc();
+        warn!(
+            "Upstream ejected for {:?}: {url} - {reason}",
+            outlier_detection.ejection_time
+        );
+    }
+
+    /// Every server's URL and, while it's ejected, how long until it returns.
+    pub fn servers(&self) -> Vec<(&str, Option<Duration>)> {
+        let now = Instant::now();
+        self.servers
+            .iter()
+            .map(|server| {
+                let ejected_until = server.health.lock().unwrap().ejected_until;
+                let ejected_for = ejected_until
+                    .filter(|until| now < *until)
+                    .map(|until| until - now);
+                (server.url.as_str(), ejected_for)
+            })
+            .collect()
+    }
+
+    /// The server a client sticks to when affinity is configured and there
+    /// is more than one server to choose from.
+    pub fn pin(&self, uri: &Uri, headers: &HeaderMap, client_ip: IpAddr) -> Option<Pin<'_>> {
+        if self.servers.len() < 2 {
+            return None;
+        }
+        match &self.affinity {
+            Affinity::None => None,
+            Affinity::Cookie(name) => {
+                let current = cookies(headers)
+                    .find(|(cookie, _)| cookie == name)
+                    .and_then(|(_, id)| self.servers.iter().find(|server| server.id == id));
+                if let Some(server) =
+                    current.filter(|server| self.is_available(server, Instant::now()))
+                {
+                    return Some(Pin {
+                        url: &server.url,
+                        set_cookie: None,
+                    });
+                }
+                // New clients, and those whose server was removed or
+                // ejected, are assigned one like any other request
+                let server = self.choose(uri);
+                let cookie = format!("{name}={}; Path=/; HttpOnly", server.id);
+                Some(Pin {
+                    url: &server.url,
+                    set_cookie: HeaderValue::from_str(&cookie).ok(),
+                })
+            }
+            Affinity::ClientIp => {
+                let server = self.rendezvous(&client_ip.to_string());
+                Some(Pin {
+                    url: &server.url,
+                    set_cookie: None,
+                })
+            }
+        }
+    }
+}
+
+fn hash(input: &[u8]) -> [u8; 32] {
+    Sha256::digest(input).into()
+}
@@ -0,0 +1,74 @@
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A response body read as far as a size limit allows.
+pub enum Buffered<B> {
+    /// The whole body, which stayed within the limit.
+    Complete(Bytes),
+    /// A body that went over the limit, still to be sent from the start.
+    Partial(Prefixed<B>),
+}
+
+/// Reads `body` until it ends or more than `limit` bytes have arrived.
+/// Trailers are dropped, as they are for any stored response.
+pub async fn buffer<B>(mut body: B, limit: u64) -> Result<Buffered<B>, B::Error>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    let mut chunks = VecDeque::new();
+    let mut len = 0;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            len += data.len() as u64;
+            chunks.push_back(data);
+            if len > limit {
+                return Ok(Buffered::Partial(Prefixed { chunks, rest: body }));
+            }
+        }
+    }
+    Ok(Buffered::Complete(Bytes::from(
+        chunks.into_iter().collect::<Vec<_>>().concat(),
+    )))
+}
+
+/// Sends the chunks already read from a body, then the rest of it.
+pub struct Prefixed<B> {
+    chunks: VecDeque<Bytes>,
+    rest: B,
+}
+
+impl<B> Body for Prefixed<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        match self.chunks.pop_front() {
+            Some(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.chunks.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered: u64 = self.chunks.iter().map(|data| data.len() as u64).sum();
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + buffered);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
@@ -1,18 +1,242 @@
 use hyper::body::Bytes;
-use std::time::{Duration, Instant};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, DATE, EXPIRES, LAST_MODIFIED,
+};
+use hyper::http::response::Builder;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Headers that describe a single connection rather than the response itself,
+/// so they are never stored or replayed from the cache.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+/// Responses that signal a missing resource or an origin failure.
+pub fn is_negative_status(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND || status == StatusCode::GONE || status.is_server_error()
+}
+
+/// Freshness guessed from how long ago the response last changed: 10% of the
+/// time since its `Last-Modified`, capped at `max` (RFC 9111 §4.2.2). `None`
+/// when the origin gave an explicit lifetime or no usable `Last-Modified`.
+pub fn heuristic_ttl(headers: &HeaderMap, max: Duration) -> Option<Duration> {
+    let explicit = headers.contains_key(EXPIRES)
+        || headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| {
+                let name = directive.split('=').next().unwrap_or(directive).trim();
+                name.eq_ignore_ascii_case("max-age") || name.eq_ignore_ascii_case("s-maxage")
+            });
+    if explicit {
+        return None;
+    }
+    let date = |name| {
+        let value = headers.get(name)?.to_str().ok()?;
+        httpdate::parse_http_date(value).ok()
+    };
+    let last_modified = date(LAST_MODIFIED)?;
+    let now = date(DATE).unwrap_or_else(SystemTime::now);
+    let unchanged_for = now.duration_since(last_modified).ok()?;
+    Some((unchanged_for / 10).min(max))
+}
+
+/// The `Cache-Control` directives a client sent that limit which cached
+/// entries it will take.
+#[derive(Debug, Default)]
+pub struct ClientCacheControl {
+    /// The client wants a response fresh from the upstream
+    pub no_cache: bool,
+    /// The oldest entry, in seconds, the client accepts
+    pub max_age: Option<u64>,
+}
+
+impl ClientCacheControl {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("no-cache") {
+                directives.no_cache = true;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                if let Ok(max_age) = value.trim().trim_matches('"').parse() {
+                    directives.max_age = Some(max_age);
+                }
+            }
+        }
+        directives
+    }
+
+    /// Whether the client will take `cached` rather than a fresh response.
+    pub fn accepts(&self, cached: &CachedResponse) -> bool {
+        !self.no_cache
+            && self
+                .max_age
+                .is_none_or(|max_age| cached.current_age() <= max_age)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
     pub body: Bytes,
-    pub cached_at: Instant,
+    pub cached_at: SystemTime,
+    /// How long the entry is fresh for, decided when it was stored.
+    pub ttl: Duration,
+    /// Random value in [0, 1) fixed per entry, so TTL jitter is stable
+    /// across requests for the same entry.
+    pub jitter_seed: f64,
+    /// How long the origin took to produce the response.
+    pub fetch_duration: Duration,
+}
+
+/// On-the-wire representation used by backends that store entries as bytes.
+#[derive(Serialize, Deserialize)]
+struct SerializedResponse {
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    cached_at_millis: u64,
+    ttl_millis: u64,
+    jitter_seed: f64,
+    fetch_duration_millis: u64,
 }
 
 impl CachedResponse {
-    pub fn is_stale(&self, ttl: Duration) -> bool {
-        self.cached_at.elapsed() > ttl
+    /// Captures an upstream response, dropping hop-by-hop headers.
+    pub fn new(status: StatusCode, headers: &HeaderMap, body: Bytes, ttl: Duration) -> Self {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| !is_hop_by_hop(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        Self {
+            status,
+            headers,
+            body,
+            cached_at: SystemTime::now(),
+            ttl,
+            jitter_seed: rand::random(),
+            fetch_duration: Duration::ZERO,
+        }
+    }
+
+    /// Time since the entry was stored. Clock skew that would put `cached_at`
+    /// in the future is treated as a zero age.
+    pub fn age(&self) -> Duration {
+        self.cached_at.elapsed().unwrap_or_default()
+    }
+
+    /// `jitter` is the fraction of the TTL (0.0-1.0) by which freshness may
+    /// be shortened, so entries stored together don't all expire together.
+    pub fn is_stale(&self, jitter: f64) -> bool {
+        self.age() > self.fresh_lifetime(jitter)
+    }
+
+    fn fresh_lifetime(&self, jitter: f64) -> Duration {
+        let jitter = jitter.clamp(0.0, 1.0) * self.jitter_seed;
+        self.ttl.mul_f64(1.0 - jitter)
+    }
+
+    /// Probabilistic early expiration (XFetch, Vattani et al. 2015): the closer
+    /// the entry is to expiring and the slower the origin was to produce it,
+    /// the more likely a request is to trigger a refresh. `beta` above 1.0
+    /// favours earlier refreshes.
+    pub fn should_refresh_early(&self, jitter: f64, beta: f64) -> bool {
+        let delta = self.fetch_duration.as_secs_f64();
+        // ln of a value in (0, 1] is <= 0, so the gap is non-negative
+        let gap = -delta * beta * (1.0 - rand::random::<f64>()).ln();
+        self.age().as_secs_f64() + gap >= self.fresh_lifetime(jitter).as_secs_f64()
+    }
+
+    pub fn is_servable_if_error(&self, stale_if_error: Duration) -> bool {
+        self.age() < self.ttl + stale_if_error
+    }
+
+    /// Age reported to clients: any `Age` the origin sent plus the time the
+    /// entry has spent in the cache, in whole seconds (RFC 9111 §5.1).
+    pub fn current_age(&self) -> u64 {
+        let upstream_age = self
+            .headers
+            .get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        upstream_age.saturating_add(self.age().as_secs())
+    }
+
+    /// Starts a response carrying the stored status and headers, with `Age`
+    /// recomputed for the time of serving.
+    pub fn response_builder(&self) -> Builder {
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in self.headers.iter().filter(|(name, _)| *name != AGE) {
+            builder = builder.header(name, value);
+        }
+        builder.header(AGE, self.current_age())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        let serialized = SerializedResponse {
+            status: self.status.as_u16(),
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: self.body.to_vec(),
+            cached_at_millis: self
+                .cached_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            ttl_millis: self.ttl.as_millis() as u64,
+            jitter_seed: self.jitter_seed,
+            fetch_duration_millis: self.fetch_duration.as_millis() as u64,
+        };
+        bincode::serialize(&serialized)
     }
 
-    pub fn is_servable_if_error(&self, ttl: Duration, stale_if_error: Duration) -> bool {
-        self.cached_at.elapsed() < ttl + stale_if_error
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        let serialized: SerializedResponse = bincode::deserialize(bytes)?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in serialized.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_bytes(&value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        Ok(Self {
+            status: StatusCode::from_u16(serialized.status).unwrap_or(StatusCode::OK),
+            headers,
+            body: Bytes::from(serialized.body),
+            cached_at: UNIX_EPOCH + Duration::from_millis(serialized.cached_at_millis),
+            ttl: Duration::from_millis(serialized.ttl_millis),
+            jitter_seed: serialized.jitter_seed,
+            fetch_duration: Duration::from_millis(serialized.fetch_duration_millis),
+        })
     }
 }
@@ -0,0 +1,257 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, COOKIE};
+use hyper::Uri;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+use crate::config::{CacheKeyConfig, CacheRule};
+use crate::device::{DeviceClass, DEVICE_CLASS_HEADER};
+
+/// Matches a name against a pattern that is either exact or ends in `*`
+/// for a prefix match, e.g. "utm_*".
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+pub fn sha256_hex(input: &str) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(input.as_bytes()) {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Iterates over the `name=value` pairs of every `Cookie` header.
+pub fn cookies(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some((name.trim(), value.trim()))
+        })
+}
+
+fn normalize_path(path: &str, config: &CacheKeyConfig) -> String {
+    let mut path = if config.lowercase_path {
+        path.to_lowercase()
+    } else {
+        path.to_string()
+    };
+    if config.ignore_trailing_slash {
+        while path.len() > 1 && path.ends_with('/') {
+            path.pop();
+        }
+    }
+    path
+}
+
+fn normalize_query(query: &str, path: &str, config: &CacheKeyConfig) -> String {
+    let signed_params = config
+        .signed_params
+        .as_ref()
+        .filter(|(routes, _)| routes.is_match(path))
+        .map(|(_, params)| params.as_slice())
+        .unwrap_or_default();
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or(param);
+            !config
+                .strip_params
+                .iter()
+                .any(|pattern| name_matches(pattern, name))
+                && !signed_params.iter().any(|signed| signed == name)
+        })
+        .collect();
+    if config.sort_query {
+        params.sort_unstable();
+    }
+    params.join("&")
+}
+
+/// Builds the storage key for a request: the normalized path and query,
+/// followed by any request headers and cookies configured, globally or by
+/// the matching `rule`, to vary the key.
+pub fn generate_cache_key(
+    uri: &Uri,
+    headers: &HeaderMap,
+    config: &CacheKeyConfig,
+    rule: Option<&CacheRule>,
+) -> String {
+    let mut key = normalize_path(uri.path(), config);
+
+    if let Some(query) = uri.query() {
+        let query = normalize_query(query, uri.path(), config);
+        if !query.is_empty() {
+            key.push('?');
+            key.push_str(&query);
+        }
+    }
+
+    for name in key_headers(config, rule) {
+        let value = headers
+            .get(name.as_str())
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        key.push_str("|h:");
+        key.extend(name.chars().map(|c| c.to_ascii_lowercase()));
+        key.push('=');
+        key.push_str(value);
+    }
+
+    if let Some(languages) = rule.and_then(|r| r.languages.as_deref()) {
+        let _ = write!(key, "|lang:{}", negotiate_language(headers, languages));
+    }
+
+    if varies_by_device(config, rule) {
+        let _ = write!(key, "|dev:{}", DeviceClass::from_headers(headers).as_str());
+    }
+
+    for name in key_cookies(config, rule) {
+        let value = cookies(headers)
+            .find(|(cookie, _)| cookie == name)
+            .map(|(_, value)| value)
+            .unwrap_or("");
+        let _ = write!(key, "|c:{name}={value}");
+    }
+
+    match config.max_length {
+        Some(max_length) if key.len() > max_length => hash_key(&key, config.hash_prefix_length),
+        _ => key,
+    }
+}
+
+/// Request headers that vary the key: `cache.key.include_headers`, then the
+/// rule's `vary_headers` not already among them.
+fn key_headers<'a>(
+    config: &'a CacheKeyConfig,
+    rule: Option<&'a CacheRule>,
+) -> impl Iterator<Item = &'a String> {
+    let rule_headers = rule
+        .and_then(|r| r.vary_headers.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|name| {
+            !config
+                .include_headers
+                .iter()
+                .any(|included| included.eq_ignore_ascii_case(name))
+        });
+    config.include_headers.iter().chain(rule_headers)
+}
+
+fn varies_by_device(config: &CacheKeyConfig, rule: Option<&CacheRule>) -> bool {
+    rule.and_then(|r| r.device_class)
+        .unwrap_or(config.device_class)
+}
+
+/// Picks the supported locale the client prefers from `Accept-Language`,
+/// going by quality. A tag matches a locale exactly or by primary language,
+/// so "fr-CA" picks "fr" and "en" picks "en-US"; with no match, or no
+/// header, the first supported locale is used.
+fn negotiate_language<'a>(headers: &HeaderMap, supported: &'a [String]) -> &'a str {
+    let mut preferences: Vec<(&str, f32)> = headers
+        .get_all(ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally preferred tags keep the client's order
+    preferences.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let primary = |tag: &str| {
+        tag.split(['-', '_'])
+            .next()
+            .unwrap_or(tag)
+            .to_ascii_lowercase()
+    };
+    preferences
+        .iter()
+        .find_map(|(tag, _)| {
+            supported
+                .iter()
+                .find(|locale| locale.eq_ignore_ascii_case(tag))
+                .or_else(|| {
+                    supported
+                        .iter()
+                        .find(|locale| primary(locale) == primary(tag))
+                })
+        })
+        .unwrap_or(&supported[0])
+}
+
+/// Cookies that vary the key: `cache.key.include_cookies`, then the rule's
+/// `vary_cookies` not already among them.
+fn key_cookies<'a>(
+    config: &'a CacheKeyConfig,
+    rule: Option<&'a CacheRule>,
+) -> impl Iterator<Item = &'a String> {
+    let rule_cookies = rule
+        .and_then(|r| r.vary_cookies.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|name| !config.include_cookies.contains(name));
+    config.include_cookies.iter().chain(rule_cookies)
+}
+
+/// The request headers that vary the key, and a `Cookie` header holding
+/// just the cookies that do, for the upstream to produce the variant the
+/// key stands for.
+pub fn varied_headers(
+    headers: &HeaderMap,
+    config: &CacheKeyConfig,
+    rule: Option<&CacheRule>,
+) -> HeaderMap {
+    let mut varied = HeaderMap::new();
+    for name in key_headers(config, rule) {
+        if let (Ok(name), Some(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            headers.get(name.as_str()),
+        ) {
+            varied.insert(name, value.clone());
+        }
+    }
+    if let Some(languages) = rule.and_then(|r| r.languages.as_deref()) {
+        if let Ok(language) = HeaderValue::from_str(negotiate_language(headers, languages)) {
+            varied.insert(ACCEPT_LANGUAGE, language);
+        }
+    }
+    if varies_by_device(config, rule) {
+        let device_class = DeviceClass::from_headers(headers).as_str();
+        varied.insert(DEVICE_CLASS_HEADER, HeaderValue::from_static(device_class));
+    }
+    let cookie = key_cookies(config, rule)
+        .filter_map(|name| cookies(headers).find(|(cookie, _)| cookie == name))
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+        if !cookie.is_empty() {
+            varied.insert(COOKIE, cookie);
+        }
+    }
+    varied
+}
+
+/// Replaces a long key with a readable prefix followed by its SHA-256 digest.
+fn hash_key(key: &str, prefix_length: usize) -> String {
+    let mut end = prefix_length.min(key.len());
+    while !key.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}#sha256:{}", &key[..end], sha256_hex(key))
+}
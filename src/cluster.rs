@@ -0,0 +1,125 @@
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::admin::apply_purge;
+use crate::config::ClusterConfig;
+use crate::handlers::AppState;
+
+/// Invalidation events shared between relay instances.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Purge {
+        key: String,
+        soft: bool,
+        stale_if_error_ms: u64,
+    },
+    Namespace {
+        namespace: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// Identifies the publishing instance so it can ignore its own events
+    instance: String,
+    #[serde(flatten)]
+    event: ClusterEvent,
+}
+
+/// Publishes and receives invalidation events over a Redis pub/sub channel,
+/// so purges and namespace rotations apply to every replica.
+pub struct Cluster {
+    client: redis::Client,
+    publisher: redis::aio::ConnectionManager,
+    channel: String,
+    instance: String,
+}
+
+impl Cluster {
+    pub async fn connect(config: &ClusterConfig) -> Result<Self, redis::RedisError> {
+        let client = redis::Client::open(config.redis_url.as_str())?;
+        let publisher = redis::aio::ConnectionManager::new(client.clone()).await?;
+        Ok(Self {
+            client,
+            publisher,
+            channel: config.channel.clone(),
+            instance: format!("{:016x}", rand::random::<u64>()),
+        })
+    }
+
+    pub async fn publish(&self, event: ClusterEvent) {
+        let envelope = Envelope {
+            instance: self.instance.clone(),
+            event,
+        };
+        let Ok(payload) = serde_json::to_string(&envelope) else {
+            return;
+        };
+        let mut conn = self.publisher.clone();
+        let result: Result<(), redis::RedisError> = redis::cmd("PUBLISH")
+            .arg(&self.channel)
+            .arg(payload)
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            warn!("Failed to publish cluster event: {e}");
+        }
+    }
+}
+
+/// Listens for events from other instances and applies them locally,
+/// reconnecting if the subscription drops.
+pub fn spawn_subscriber(state: Arc<AppState>) {
+    tokio::task::spawn(async move {
+        let Some(cluster) = &state.cluster else {
+            return;
+        };
+        loop {
+            if let Err(e) = subscribe(&state, cluster).await {
+                warn!("Cluster subscription failed: {e}");
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn subscribe(state: &AppState, cluster: &Cluster) -> Result<(), redis::RedisError> {
+    let mut pubsub = cluster.client.get_async_pubsub().await?;
+    pubsub.subscribe(&cluster.channel).await?;
+    info!("Subscribed to cluster channel: {}", cluster.channel);
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Ok(payload) = message.get_payload::<String>() else {
+            continue;
+        };
+        let Ok(envelope) = serde_json::from_str::<Envelope>(&payload) else {
+            warn!("Ignoring malformed cluster event: {payload}");
+            continue;
+        };
+        if envelope.instance == cluster.instance {
+            continue;
+        }
+
+        match envelope.event {
+            ClusterEvent::Purge {
+                key,
+                soft,
+                stale_if_error_ms,
+            } => {
+                let purged =
+                    apply_purge(state, &key, soft, Duration::from_millis(stale_if_error_ms)).await;
+                info!("Cache PURGE (cluster): {key} - purged: {purged}");
+            }
+            ClusterEvent::Namespace { namespace } => {
+                info!("Cache namespace rotated (cluster): {namespace:?}");
+                *state.namespace.write().unwrap() = namespace;
+            }
+        }
+    }
+    Ok(())
+}
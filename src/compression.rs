@@ -0,0 +1,316 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG, VARY};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::time::UNIX_EPOCH;
+use tracing::warn;
+
+use crate::cache::CachedResponse;
+use crate::config::CompressionConfig;
+
+/// Bodies larger than this are compressed and decoded on the blocking pool,
+/// so one large body doesn't hold up the other connections on its worker.
+const BLOCKING_THRESHOLD: usize = 64 * 1024;
+
+/// Runs `work` on a body of `len` bytes, off the async workers when the body
+/// is large.
+async fn run<T, F>(len: usize, work: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    if len <= BLOCKING_THRESHOLD {
+        return work();
+    }
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Levels favour speed, since bodies are compressed while a client waits.
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    writer.write_all(body)?;
+                }
+                Ok(out)
+            }
+            Encoding::Zstd => zstd::encode_all(body, 3),
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// A cache entry's body compressed for one client.
+pub struct Compressed {
+    pub encoding: Encoding,
+    pub body: Bytes,
+}
+
+/// Compresses cached bodies on the way out. The cache always holds the
+/// uncompressed body; compressed variants are kept in a separate size-bounded
+/// cache so each entry is compressed at most once per encoding.
+pub struct Compression {
+    enabled: bool,
+    algorithms: Vec<Encoding>,
+    min_size: usize,
+    content_types: Vec<String>,
+    variants: moka::sync::Cache<String, Bytes>,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let algorithms = config
+            .algorithms
+            .iter()
+            .map(|name| {
+                Encoding::parse(name)
+                    .ok_or_else(|| format!("Unknown compression algorithm: {name}"))
+            })
+            .collect::<Result<_, _>>()?;
+        let variants = moka::sync::Cache::builder()
+            .max_capacity(config.variant_cache_size)
+            .weigher(|key: &String, body: &Bytes| {
+                (key.len() + body.len()).try_into().unwrap_or(u32::MAX)
+            })
+            .build();
+
+        Ok(Self {
+            enabled: config.enabled,
+            algorithms,
+            min_size: config.min_size,
+            content_types: config
+                .content_types
+                .iter()
+                .map(|content_type| content_type.to_ascii_lowercase())
+                .collect(),
+            variants,
+        })
+    }
+
+    /// Whether the entry is sent compressed to clients that accept it, which
+    /// makes its representation vary by `Accept-Encoding`.
+    pub fn applies_to(&self, cached: &CachedResponse) -> bool {
+        self.enabled
+            && cached.body.len() >= self.min_size
+            && !cached.headers.contains_key(CONTENT_ENCODING)
+            && cached
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|content_type| self.is_compressible_type(content_type))
+    }
+
+    /// Compresses the entry with the best encoding the client accepts, reusing
+    /// an earlier result for the same stored entry.
+    pub async fn compress(
+        &self,
+        cache_key: &str,
+        cached: &CachedResponse,
+        accept_encoding: Option<&str>,
+    ) -> Option<Compressed> {
+        if !self.applies_to(cached) {
+            return None;
+        }
+        let encoding = negotiate(accept_encoding?, &self.algorithms)?;
+
+        // Keyed by store time too, so a refreshed entry is compressed again
+        let stored_at = cached
+            .cached_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let variant_key = format!("{cache_key}|{stored_at}|{}", encoding.as_str());
+        if let Some(body) = self.variants.get(&variant_key) {
+            return Some(Compressed { encoding, body });
+        }
+
+        let body = cached.body.clone();
+        match run(body.len(), move || encoding.encode(&body)).await {
+            Ok(body) => {
+                let body = Bytes::from(body);
+                self.variants.insert(variant_key, body.clone());
+                Some(Compressed { encoding, body })
+            }
+            Err(err) => {
+                warn!("Compression failed: {cache_key} - {err}");
+                None
+            }
+        }
+    }
+
+    fn is_compressible_type(&self, content_type: &str) -> bool {
+        content_type_matches(&self.content_types, content_type)
+    }
+}
+
+/// Picks the encoding in `offered` with the highest q-value in the client's
+/// `Accept-Encoding`, preferring earlier encodings on ties.
+pub fn negotiate(accept_encoding: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect();
+    let quality = |encoding: Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| name == encoding.as_str())
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in offered {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a `Content-Type` is one of `allowed`, lowercase types in which
+/// entries ending in `/*` match a whole type, e.g. `text/*`.
+pub fn content_type_matches(allowed: &[String], content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    allowed
+        .iter()
+        .any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => essence.starts_with(prefix),
+            None => essence == *allowed,
+        })
+}
+
+/// Marks a compressible response as varying by `Accept-Encoding` and, when a
+/// compressed body is sent, labels it and weakens its ETag, since the bytes
+/// no longer match the stored representation.
+pub fn add_headers(headers: &mut HeaderMap, compressed: Option<&Compressed>) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| {
+            let token = token.trim();
+            token == "*" || token.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    let Some(compressed) = compressed else {
+        return;
+    };
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(compressed.encoding.as_str()),
+    );
+    weaken_etag(headers);
+}
+
+/// Turns a strong ETag into a weak one for a body whose bytes differ from the
+/// representation the ETag was issued for.
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    let weak_etag = headers
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{etag}")).ok());
+    if let Some(weak_etag) = weak_etag {
+        headers.insert(ETAG, weak_etag);
+    }
+}
+
+/// Undoes a `Content-Encoding` so the cache only holds bodies every client
+/// can read. Codings listed one after another are removed in reverse order;
+/// anything other than gzip, deflate, br, zstd and identity is an error, as
+/// is a body that decodes to more than `limit` bytes.
+pub async fn decode(content_encoding: &str, body: Bytes, limit: u64) -> std::io::Result<Vec<u8>> {
+    let content_encoding = content_encoding.to_string();
+    run(body.len(), move || {
+        decode_all(&content_encoding, &body, limit)
+    })
+    .await
+}
+
+fn decode_all(content_encoding: &str, body: &[u8], limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut decoded = body.to_vec();
+    for coding in content_encoding.rsplit(',') {
+        decoded = match coding.trim().to_ascii_lowercase().as_str() {
+            "identity" | "" => decoded,
+            "gzip" | "x-gzip" => read_all(flate2::read::MultiGzDecoder::new(&decoded[..]), limit)?,
+            // Meant to be zlib-wrapped, but some servers send raw deflate
+            "deflate" => read_all(flate2::read::ZlibDecoder::new(&decoded[..]), limit)
+                .or_else(|_| read_all(flate2::read::DeflateDecoder::new(&decoded[..]), limit))?,
+            "br" => read_all(brotli::Decompressor::new(&decoded[..], 4096), limit)?,
+            "zstd" => read_all(zstd::stream::read::Decoder::new(&decoded[..])?, limit)?,
+            other => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("unsupported content encoding: {other}"),
+                ))
+            }
+        };
+    }
+    Ok(decoded)
+}
+
+/// Reads at most `limit` bytes, so a small compressed body can't expand
+/// without bound.
+fn read_all(reader: impl Read, limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    reader.take(limit.saturating_add(1)).read_to_end(&mut out)?;
+    if out.len() as u64 > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("decoded body exceeds {limit} bytes"),
+        ));
+    }
+    Ok(out)
+}
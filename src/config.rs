@@ -1,8 +1,17 @@
 use globset::{Glob, GlobSet, GlobSetBuilder};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, InvalidHeaderValue, CONTENT_SECURITY_POLICY,
+    REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+use crate::access::specificity;
+use crate::error::{BoxError, RelayError};
+use crate::grpc::is_grpc_content_type;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
@@ -14,112 +23,1658 @@ pub struct Config {
     #[serde(default)]
     pub cache: CacheConfig,
     #[serde(default)]
-    pub storage: StorageConfig,
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub debug: DebugConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub forward_proxy: ForwardProxyConfig,
+    #[serde(default)]
+    pub redirects: RedirectConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    pub geoip: Option<GeoIpConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub signed_urls: Option<SignedUrlConfig>,
+    pub quotas: Option<QuotaConfig>,
+    pub cluster: Option<ClusterConfig>,
+    /// WebAssembly filters, run in order on matching requests
+    #[serde(default)]
+    pub wasm_filters: Vec<WasmFilterConfig>,
+    pub lua: Option<LuaConfig>,
+    /// Body rewrites for text responses, applied in order as they are served
+    #[serde(default)]
+    pub transforms: Vec<TransformConfig>,
+    /// Shadow upstreams sent copies of live requests
+    #[serde(default)]
+    pub mirrors: Vec<MirrorConfig>,
+    /// Canary upstreams taking a share of requests on matching routes
+    #[serde(default)]
+    pub splits: Vec<SplitConfig>,
+    /// Cross-origin policies; the first whose routes match a path applies
+    #[serde(default)]
+    pub cors: Vec<CorsConfig>,
+    /// Bodies for relay-generated errors, keyed by status code
+    #[serde(default)]
+    pub error_pages: HashMap<String, ErrorPageConfig>,
+}
+
+/// A custom body for one status code, given inline or read from a file.
+#[derive(Debug, Deserialize)]
+pub struct ErrorPageConfig {
+    pub body: Option<String>,
+    pub file: Option<String>,
+    /// Inferred from the file extension or inline body when unset
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServerConfig {
+    /// Address of the listener; `listeners` configures several instead
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub port: u16,
+    /// Serve HTTP/2 alongside HTTP/1.1
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Experimental: also serve HTTP/3 over QUIC on the same port (UDP).
+    /// Requires `tls` and a build with the `http3` feature.
+    #[serde(default)]
+    pub http3: bool,
+    /// Requests handled at once before new ones are answered with 503
+    pub max_concurrent_requests: Option<usize>,
+    /// Let other sockets, in this or other processes, listen on the same
+    /// port (SO_REUSEPORT), with the kernel spreading connections over them
+    #[serde(default)]
+    pub reuseport: bool,
+    /// Sockets, each with its own accept loop, per TCP listener; more than
+    /// one requires `reuseport`
+    #[serde(default = "default_acceptors")]
+    pub acceptors: usize,
+    /// Connections the kernel queues until relay accepts them
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+    /// Send small writes right away instead of coalescing them (TCP_NODELAY)
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Idle time after which TCP keepalive probes check client connections
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Threads serving connections; defaults to one per CPU core
+    pub worker_threads: Option<usize>,
+    /// Upper bound on threads for blocking work; defaults to Tokio's 512
+    pub max_blocking_threads: Option<usize>,
+    /// Serve connections on a single-threaded runtime per worker thread
+    /// instead of one work-stealing runtime
+    #[serde(default)]
+    pub runtime_sharding: bool,
+    pub tls: Option<TlsConfig>,
+    /// Expect a PROXY protocol header on every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Addresses and sockets to accept connections on. Filled from `host`,
+    /// `port`, `tls` and `proxy_protocol` when unset.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// A TCP address or Unix domain socket relay accepts connections on.
+#[derive(Debug, Deserialize)]
+pub struct ListenerConfig {
+    /// TCP address, e.g. "0.0.0.0:443"
+    pub address: Option<String>,
+    /// Path of a Unix domain socket
+    pub path: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// Expect a PROXY protocol header (v1 or v2) from a load balancer on
+    /// every connection, and take the client address from it
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Serve `/metrics` and the admin API on this listener
+    #[serde(default = "default_listener_admin")]
+    pub admin: bool,
+}
+
+fn default_listener_admin() -> bool {
+    true
+}
+
+fn default_acceptors() -> usize {
+    1
+}
+
+fn default_backlog() -> u32 {
+    1024
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+/// Certificate and private key, both PEM encoded, for serving HTTPS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// PEM bundle of CAs client certificates must chain to. When set, every
+    /// client has to present a valid certificate.
+    pub client_ca: Option<String>,
+    /// Header carrying the verified client certificate's subject upstream
+    pub client_cert_header: Option<String>,
+}
+
+fn default_http_version() -> String {
+    "1.1".to_string()
+}
+
+/// Thresholds for ejecting an upstream server based on the requests relay
+/// sends it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutlierDetectionConfig {
+    /// Period over which each server's requests are counted
+    #[serde(
+        default = "default_outlier_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Requests needed in a period before a server can be judged
+    #[serde(default = "default_outlier_min_requests")]
+    pub min_requests: u32,
+    /// Share of failed and 5xx responses that ejects a server, e.g. "50%"
+    #[serde(
+        default = "default_outlier_error_rate",
+        deserialize_with = "deserialize_percentage"
+    )]
+    pub max_error_rate: f64,
+    /// Mean response time that ejects a server
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_latency: Option<Duration>,
+    /// How long an ejected server is kept out of rotation
+    #[serde(
+        default = "default_outlier_ejection_time",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub ejection_time: Duration,
+    /// How long a returning server takes to get back its full share
+    #[serde(
+        default = "default_outlier_ejection_time",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub ramp_up: Duration,
+    /// Most servers that may be ejected at once, e.g. "50%"
+    #[serde(
+        default = "default_outlier_max_ejected",
+        deserialize_with = "deserialize_percentage"
+    )]
+    pub max_ejected: f64,
+}
+
+/// When a cache-miss fetch is slow enough to send a second request.
+#[derive(Debug, Deserialize)]
+pub struct HedgingConfig {
+    /// Upstream response time percentile after which a request is hedged,
+    /// e.g. "95%"
+    #[serde(
+        default = "default_hedging_percentile",
+        deserialize_with = "deserialize_percentage"
+    )]
+    pub percentile: f64,
+    /// Requests are never hedged sooner than this
+    #[serde(
+        default = "default_hedging_min_delay",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub min_delay: Duration,
+}
+
+fn default_hedging_percentile() -> f64 {
+    0.95
+}
+
+fn default_hedging_min_delay() -> Duration {
+    Duration::from_millis(10)
+}
+
+fn default_outlier_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_outlier_min_requests() -> u32 {
+    5
+}
+
+fn default_outlier_error_rate() -> f64 {
+    0.5
+}
+
+fn default_outlier_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_outlier_max_ejected() -> f64 {
+    0.5
+}
+
+fn default_balance() -> String {
+    "round_robin".to_string()
+}
+
+fn default_affinity_cookie() -> String {
+    "relay_upstream".to_string()
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamConfig {
+    /// The upstream server; `urls` lists several to balance between instead
+    #[serde(default)]
+    pub url: String,
+    /// Servers requests are balanced across. Filled from `url` when unset.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// How requests are spread over `urls`: "round_robin" (default) or
+    /// "hash", which sends each path to the same server
+    #[serde(default = "default_balance")]
+    pub balance: String,
+    /// Keep each client on one server: "cookie" or "client_ip"
+    pub affinity: Option<String>,
+    /// Cookie naming a client's server when `affinity = "cookie"`
+    #[serde(default = "default_affinity_cookie")]
+    pub affinity_cookie: String,
+    /// Take servers that fail or slow down out of rotation for a while
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// Send a second request for slow cache misses
+    pub hedging: Option<HedgingConfig>,
+    /// "1.1" (default) or "2" for HTTP/2: h2c for http:// URLs, negotiated
+    /// through ALPN for https://
+    #[serde(default = "default_http_version")]
+    pub http_version: String,
+    /// Body sent with relay's 502/504 responses when the upstream fails;
+    /// the status text when unset
+    pub error_body: Option<String>,
+    /// Settings for `https://` upstream URLs
+    pub tls: Option<UpstreamTlsConfig>,
+    /// Send a PROXY protocol header, "v1" or "v2", on each connection
+    pub proxy_protocol: Option<String>,
+    /// Headers added to every request sent to the upstream, keyed by name,
+    /// such as an API key clients never see
+    #[serde(default)]
+    pub headers: HashMap<String, UpstreamHeaderConfig>,
+}
+
+/// An upstream request header's value, given inline, or read from an
+/// environment variable or a file at startup.
+#[derive(Debug, Deserialize)]
+pub struct UpstreamHeaderConfig {
+    pub value: Option<String>,
+    pub value_env: Option<String>,
+    /// Trailing whitespace, such as a final newline, is dropped
+    pub value_file: Option<String>,
+    /// Put before the value, e.g. "Bearer "
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Caching of the DNS answers used to connect to upstream servers.
+#[derive(Debug, Deserialize)]
+pub struct DnsConfig {
+    /// Answers are kept at least this long, even with a lower TTL
+    #[serde(
+        default = "default_dns_min_ttl",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub min_ttl: Duration,
+    /// Answers are looked up again after this long, even with a higher TTL
+    #[serde(
+        default = "default_dns_max_ttl",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_ttl: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            min_ttl: default_dns_min_ttl(),
+            max_ttl: default_dns_max_ttl(),
+        }
+    }
+}
+
+fn default_dns_min_ttl() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_dns_max_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of CAs trusted for the upstream instead of the bundled roots
+    pub ca: Option<String>,
+    /// Client certificate and key presented to the upstream (mutual TLS)
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct PrometheusConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Credentials required to scrape `/metrics`
+    pub auth: Option<AuthConfig>,
+}
+
+/// A bearer token and/or basic-auth credentials. Secrets can be read from
+/// environment variables instead of being written into the config file.
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoggingConfig {
+    /// Access log; diagnostics are logged either way
+    #[serde(default = "default_logging_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_log_format")]
+    pub format: String,
+    /// Level filter, optionally per module ("info,relay::cluster=debug");
+    /// `RUST_LOG` takes precedence when set
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Log each request's cache decision (HIT, MISS, BYPASS, ...)
+    #[serde(default)]
+    pub cache_decisions: bool,
+    /// Where the access log goes: "stdout" or "file"; "syslog" or
+    /// "journald" send all output there instead
+    #[serde(default = "default_log_output")]
+    pub output: String,
+    /// Syslog socket path, or host:port to send over UDP
+    #[serde(default = "default_syslog_address")]
+    pub syslog_address: String,
+    #[serde(default = "default_syslog_facility")]
+    pub syslog_facility: String,
+    /// Access log file when `output` is "file"
+    pub path: Option<String>,
+    #[serde(
+        default = "default_log_rotation",
+        deserialize_with = "deserialize_log_rotation"
+    )]
+    pub rotation: LogRotation,
+    /// Access log files kept, including the one being written
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+    /// File admin operations are also appended to, as JSON lines
+    pub audit_path: Option<String>,
+    /// Access log fields, in any order; leaving one out drops it from each line
+    #[serde(default = "default_log_fields")]
+    pub fields: Vec<String>,
+    /// Request headers whose values are added to each access log line
+    #[serde(default)]
+    pub request_headers: Vec<String>,
+    /// Response headers whose values are added to each access log line
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Log 1 in this many successful requests; errors are always logged
+    #[serde(default = "default_log_sample_rate")]
+    pub sample_rate: u64,
+}
+
+/// Every access log field, in the order they're written
+pub const LOG_FIELDS: &[&str] = &[
+    "method",
+    "path",
+    "status",
+    "duration_ms",
+    "cache_status",
+    "remote_addr",
+    "bytes_sent",
+    "country",
+];
+
+/// When the access log file is rotated: on a schedule, or once it reaches a
+/// size in bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Size(u64),
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_logging_enabled(),
+            format: default_log_format(),
+            level: default_log_level(),
+            cache_decisions: false,
+            output: default_log_output(),
+            syslog_address: default_syslog_address(),
+            syslog_facility: default_syslog_facility(),
+            path: None,
+            rotation: default_log_rotation(),
+            max_files: default_log_max_files(),
+            audit_path: None,
+            fields: default_log_fields(),
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            sample_rate: default_log_sample_rate(),
+        }
+    }
+}
+
+fn default_logging_enabled() -> bool {
+    true
+}
+
+fn default_log_format() -> String {
+    "json".to_string()
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_output() -> String {
+    "stdout".to_string()
+}
+
+fn default_syslog_address() -> String {
+    "/dev/log".to_string()
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".to_string()
+}
+
+/// Syslog facility names, in order of their numeric codes
+pub const SYSLOG_FACILITIES: &[&str] = &[
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+fn default_log_rotation() -> LogRotation {
+    LogRotation::Daily
+}
+
+fn default_log_max_files() -> usize {
+    7
+}
+
+fn default_log_fields() -> Vec<String> {
+    LOG_FIELDS.iter().map(|field| field.to_string()).collect()
+}
+
+fn default_log_sample_rate() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheRule {
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub ttl: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub stale: Option<Duration>,
+    #[serde(default)]
+    pub bypass: Option<bool>,
+    /// Upstream status codes that may be stored; `cache.cacheable_statuses` when unset
+    #[serde(default)]
+    pub cache_statuses: Option<Vec<u16>>,
+    /// TTL per status code ("404") or status class ("2xx")
+    #[serde(default, deserialize_with = "deserialize_optional_duration_map")]
+    pub status_ttl: Option<HashMap<String, Duration>>,
+    /// Overrides `cache.content_types` for matching paths
+    #[serde(default)]
+    pub content_types: Option<Vec<String>>,
+    /// Overrides `cache.exclude_content_types` for matching paths
+    #[serde(default)]
+    pub exclude_content_types: Option<Vec<String>>,
+    /// Skip the cache when any of these cookies is present; a trailing `*` matches a prefix
+    #[serde(default)]
+    pub bypass_cookies: Option<Vec<String>>,
+    /// Skip the cache when any of these query parameters is present
+    #[serde(default)]
+    pub bypass_query: Option<Vec<String>>,
+    /// Re-fetch matching paths on this schedule, independent of traffic
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub refresh_interval: Option<Duration>,
+    /// Store responses that set cookies, without the `Set-Cookie` headers
+    #[serde(default)]
+    pub cache_set_cookie: Option<bool>,
+    /// Request headers whose values become part of the key for matching
+    /// paths, on top of `cache.key.include_headers`
+    #[serde(default)]
+    pub vary_headers: Option<Vec<String>>,
+    /// Cookies whose values become part of the key for matching paths, on
+    /// top of `cache.key.include_cookies`
+    #[serde(default)]
+    pub vary_cookies: Option<Vec<String>>,
+    /// Locales the origin supports; `Accept-Language` is narrowed to the best
+    /// of them, the first being the fallback, which then varies the key
+    #[serde(default)]
+    pub languages: Option<Vec<String>>,
+    /// Overrides `cache.key.device_class` for matching paths
+    #[serde(default)]
+    pub device_class: Option<bool>,
+    /// How long entries count as fresh for crawlers, usually longer than
+    /// `ttl` to spare the origin
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub bot_ttl: Option<Duration>,
+    /// Directory whose files answer matching paths in place of the upstream
+    #[serde(default)]
+    pub serve_static: Option<String>,
+    /// Path answering matching requests that would otherwise 404, such as
+    /// the "/index.html" shell of a single-page app
+    #[serde(default)]
+    pub spa_fallback: Option<String>,
+    /// When to use `spa_fallback`: "not_found" (default) for 404s, or
+    /// "non_file" for every path without a file extension
+    #[serde(default)]
+    pub spa_fallback_on: Option<String>,
+    /// TTL for the `spa_fallback` response, in place of `ttl`
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub spa_fallback_ttl: Option<Duration>,
+}
+
+impl CacheRule {
+    /// Looks up a TTL for `status`, preferring an exact code over its class.
+    pub fn ttl_for_status(&self, status: u16) -> Option<Duration> {
+        let status_ttl = self.status_ttl.as_ref()?;
+        status_ttl
+            .get(&status.to_string())
+            .or_else(|| status_ttl.get(&format!("{}xx", status / 100)))
+            .copied()
+    }
+}
+
+/// Matches a media type against a pattern such as "image/*" or
+/// "application/json", ignoring parameters like charset.
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(main_type) => essence
+            .split_once('/')
+            .is_some_and(|(essence_main, _)| essence_main == main_type),
+        None => essence == pattern,
+    }
+}
+
+fn is_valid_status_key(key: &str) -> bool {
+    let bytes = key.as_bytes();
+    bytes.len() == 3
+        && (b'1'..=b'5').contains(&bytes[0])
+        && ((bytes[1].is_ascii_digit() && bytes[2].is_ascii_digit())
+            || key[1..].eq_ignore_ascii_case("xx"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_ttl", deserialize_with = "deserialize_duration")]
+    pub default_ttl: Duration,
+    #[serde(
+        default = "default_stale_if_error",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub stale_if_error: Duration,
+    /// Upstream statuses treated like a failed request, so a stale entry is
+    /// served in their place when one is available
+    #[serde(default = "default_stale_if_error_statuses")]
+    pub stale_if_error_statuses: Vec<u16>,
+    /// Upstream statuses stored by default; rules may override with `cache_statuses`
+    #[serde(default = "default_cacheable_statuses")]
+    pub cacheable_statuses: Vec<u16>,
+    /// TTL for 404, 410 and 5xx responses. Falls back to the regular TTL when unset.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub negative_ttl: Option<Duration>,
+    /// How long a key whose response couldn't be stored is passed straight
+    /// to the upstream, streamed, before relay tries caching it again
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub hit_for_pass_ttl: Option<Duration>,
+    /// Fraction by which an entry's freshness may randomly be shortened, e.g. "10%"
+    #[serde(default, deserialize_with = "deserialize_percentage")]
+    pub ttl_jitter: f64,
+    /// Prefix for every storage key; changing it invalidates the whole cache
+    #[serde(default)]
+    pub namespace: String,
+    /// Refresh popular entries in the background shortly before they expire
+    #[serde(default)]
+    pub early_refresh: bool,
+    /// Higher values make early refreshes happen sooner
+    #[serde(default = "default_early_refresh_beta")]
+    pub early_refresh_beta: f64,
+    /// Only responses with one of these content types are stored
+    #[serde(default)]
+    pub content_types: Option<Vec<String>>,
+    /// Responses with one of these content types are never stored
+    #[serde(default)]
+    pub exclude_content_types: Option<Vec<String>>,
+    /// Responses with one of these content types are streamed to the client
+    /// as they arrive instead of being buffered, and never stored
+    #[serde(default = "default_stream_content_types")]
+    pub stream_content_types: Vec<String>,
+    /// Largest response body stored, after any `Content-Encoding` is
+    /// decoded, e.g. "64MB"; larger responses are passed through
+    #[serde(
+        default = "default_max_object_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub max_object_size: u64,
+    /// Never store responses with a gRPC content type
+    #[serde(default = "default_exempt_grpc")]
+    pub exempt_grpc: bool,
+    /// Keep fetching a miss after its client disconnects so the response
+    /// still reaches the cache; when false the upstream request is cancelled
+    #[serde(default = "default_continue_on_disconnect")]
+    pub continue_on_disconnect: bool,
+    /// Most requested paths that glob rules with a `refresh_interval` keep
+    /// warm at once
+    #[serde(default = "default_refresh_ahead_max_keys")]
+    pub refresh_ahead_max_keys: usize,
+    /// Upstream response header carrying a TTL for that response, e.g.
+    /// "300" or "5m"; an empty name turns it off
+    #[serde(default = "default_ttl_header")]
+    pub ttl_header: String,
+    /// Upstream response header that keeps a response out of the cache; an
+    /// empty name turns it off
+    #[serde(default = "default_no_cache_header")]
+    pub no_cache_header: String,
+    /// Without a rule TTL, derive the TTL from `Last-Modified` instead of
+    /// using `default_ttl`
+    #[serde(default)]
+    pub heuristic_freshness: bool,
+    /// Longest TTL `heuristic_freshness` may give
+    #[serde(
+        default = "default_heuristic_max_ttl",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub heuristic_max_ttl: Duration,
+    /// Ignore `Cache-Control: no-cache` and `max-age` from clients, so they
+    /// can't force requests through to the upstream
+    #[serde(default)]
+    pub ignore_client_cache_control: bool,
+    #[serde(default)]
+    pub key: CacheKeyConfig,
+    pub warmup: Option<WarmupConfig>,
+    #[serde(default)]
+    pub rules: Option<HashMap<String, CacheRule>>,
+    /// `rules` compiled, and changed from then on through the admin API
+    #[serde(skip)]
+    pub compiled_rules: CacheRules,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            default_ttl: default_ttl(),
+            stale_if_error: default_stale_if_error(),
+            stale_if_error_statuses: default_stale_if_error_statuses(),
+            cacheable_statuses: default_cacheable_statuses(),
+            negative_ttl: None,
+            hit_for_pass_ttl: None,
+            ttl_jitter: 0.0,
+            namespace: String::new(),
+            early_refresh: false,
+            early_refresh_beta: default_early_refresh_beta(),
+            content_types: None,
+            exclude_content_types: None,
+            stream_content_types: default_stream_content_types(),
+            max_object_size: default_max_object_size(),
+            exempt_grpc: default_exempt_grpc(),
+            continue_on_disconnect: default_continue_on_disconnect(),
+            refresh_ahead_max_keys: default_refresh_ahead_max_keys(),
+            ttl_header: default_ttl_header(),
+            no_cache_header: default_no_cache_header(),
+            heuristic_freshness: false,
+            heuristic_max_ttl: default_heuristic_max_ttl(),
+            ignore_client_cache_control: false,
+            key: CacheKeyConfig::default(),
+            warmup: None,
+            rules: None,
+            compiled_rules: CacheRules::default(),
+        }
+    }
+}
+
+/// Paths fetched ahead of traffic to pre-populate the cache.
+#[derive(Debug, Deserialize)]
+pub struct WarmupConfig {
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Sitemap whose `<loc>` entries are warmed: a path on the upstream or
+    /// an absolute http:// URL
+    pub sitemap: Option<String>,
+    /// Repeat the warmup on this schedule; runs once at startup when unset
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub interval: Option<Duration>,
+    /// Maximum number of warmup fetches in flight at once
+    #[serde(default = "default_warmup_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_warmup_concurrency() -> usize {
+    4
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheKeyConfig {
+    /// Sort query parameters so `?a=1&b=2` and `?b=2&a=1` share an entry
+    #[serde(default)]
+    pub sort_query: bool,
+    /// Query parameters dropped from the key; a trailing `*` matches a prefix
+    #[serde(default)]
+    pub strip_params: Vec<String>,
+    #[serde(default)]
+    pub lowercase_path: bool,
+    #[serde(default)]
+    pub ignore_trailing_slash: bool,
+    /// Request headers whose values become part of the key
+    #[serde(default)]
+    pub include_headers: Vec<String>,
+    /// Cookies whose values become part of the key
+    #[serde(default)]
+    pub include_cookies: Vec<String>,
+    /// Vary the key on the device class (mobile, tablet, desktop or bot)
+    /// guessed from the `User-Agent`, which is sent upstream as `X-Device-Class`
+    #[serde(default)]
+    pub device_class: bool,
+    /// Keys longer than this are replaced by a prefix and a SHA-256 digest
+    #[serde(default)]
+    pub max_length: Option<usize>,
+    /// Characters of the original key kept in front of the digest
+    #[serde(default = "default_hash_prefix_length")]
+    pub hash_prefix_length: usize,
+    /// The `[signed_urls]` routes and their signature and expiry params,
+    /// dropped from keys for those routes only
+    #[serde(skip)]
+    pub signed_params: Option<(GlobSet, [String; 2])>,
+}
+
+impl Default for CacheKeyConfig {
+    fn default() -> Self {
+        Self {
+            sort_query: false,
+            strip_params: Vec::new(),
+            lowercase_path: false,
+            ignore_trailing_slash: false,
+            include_headers: Vec::new(),
+            include_cookies: Vec::new(),
+            device_class: false,
+            max_length: None,
+            hash_prefix_length: default_hash_prefix_length(),
+            signed_params: None,
+        }
+    }
+}
+
+fn default_hash_prefix_length() -> usize {
+    64
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default = "default_backend")]
+    pub backend: String,
+    pub redis: Option<RedisConfig>,
+    pub disk: Option<DiskConfig>,
+    pub tiered: Option<TieredConfig>,
+    pub moka: Option<MokaConfig>,
+    pub snapshot: Option<SnapshotConfig>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: default_backend(),
+            redis: None,
+            disk: None,
+            tiered: None,
+            moka: None,
+            snapshot: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedisConfig {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiskConfig {
+    pub path: String,
+    /// Total size of bodies and metadata kept on disk, e.g. "10GB"; the
+    /// entries closest to expiring are evicted past it. Unbounded when unset
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    pub max_size: Option<u64>,
+    /// How often expired entries are deleted from the directory
+    #[serde(
+        default = "default_disk_sweep_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub sweep_interval: Duration,
+}
+
+fn default_disk_sweep_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Path prefix the admin API is served under
+    #[serde(default = "default_admin_path")]
+    pub path: String,
+    /// Credentials required for every admin request
+    pub auth: Option<AuthConfig>,
+    /// Status page at the admin path itself
+    #[serde(default = "default_admin_dashboard")]
+    pub dashboard: bool,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_admin_path(),
+            auth: None,
+            dashboard: default_admin_dashboard(),
+        }
+    }
+}
+
+fn default_admin_path() -> String {
+    "/_relay".to_string()
+}
+
+fn default_admin_dashboard() -> bool {
+    true
+}
+
+/// Client address allow and deny lists, as addresses or CIDR blocks.
+#[derive(Debug, Deserialize, Default)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` header is trusted to name the client
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// ISO country codes admitted, looked up with `[geoip]`; empty admits all
+    #[serde(default)]
+    pub allow_countries: Vec<String>,
+    /// ISO country codes refused, looked up with `[geoip]`
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
+    /// Per-route lists, keyed by glob pattern, replacing the global ones
+    #[serde(default)]
+    pub routes: HashMap<String, AccessRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccessRule {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub allow_countries: Option<Vec<String>>,
+    pub deny_countries: Option<Vec<String>>,
+}
+
+/// Headers relay adds to the responses it serves.
+#[derive(Debug, Deserialize, Default)]
+pub struct HeadersConfig {
+    pub security: Option<SecurityHeadersConfig>,
+}
+
+/// Common security headers, each added to responses that don't already
+/// carry it. An empty value leaves that header out.
+#[derive(Debug, Deserialize)]
+pub struct SecurityHeadersConfig {
+    #[serde(default = "default_strict_transport_security")]
+    pub strict_transport_security: String,
+    #[serde(default = "default_content_type_options")]
+    pub content_type_options: String,
+    #[serde(default = "default_frame_options")]
+    pub frame_options: String,
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: String,
+    /// Left out unless set, since a policy has to fit the site
+    #[serde(default)]
+    pub content_security_policy: String,
+}
+
+fn default_strict_transport_security() -> String {
+    "max-age=31536000; includeSubDomains".to_string()
+}
+
+fn default_content_type_options() -> String {
+    "nosniff".to_string()
+}
+
+fn default_frame_options() -> String {
+    "SAMEORIGIN".to_string()
+}
+
+fn default_referrer_policy() -> String {
+    "strict-origin-when-cross-origin".to_string()
+}
+
+impl SecurityHeadersConfig {
+    /// The configured headers, skipping those left empty.
+    pub fn header_map(&self) -> Result<HeaderMap, InvalidHeaderValue> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (STRICT_TRANSPORT_SECURITY, &self.strict_transport_security),
+            (X_CONTENT_TYPE_OPTIONS, &self.content_type_options),
+            (X_FRAME_OPTIONS, &self.frame_options),
+            (REFERRER_POLICY, &self.referrer_policy),
+            (CONTENT_SECURITY_POLICY, &self.content_security_policy),
+        ] {
+            if !value.is_empty() {
+                headers.insert(name, HeaderValue::from_str(value)?);
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// Country lookups for client addresses from a MaxMind database.
+#[derive(Debug, Deserialize)]
+pub struct GeoIpConfig {
+    /// Path to a GeoIP2 or GeoLite2 Country or City database
+    pub database: String,
+    /// Request header carrying the client's country to the upstream; an
+    /// empty name keeps it from the upstream
+    #[serde(default = "default_geoip_header")]
+    pub header: String,
+}
+
+fn default_geoip_header() -> String {
+    "X-Geo-Country".to_string()
+}
+
+/// Bearer JWT verification for matching routes. Keys come from a JWKS URL,
+/// a shared secret (HMAC) or a PEM public key.
+#[derive(Debug, Deserialize)]
+pub struct JwtConfig {
+    /// Glob patterns of paths that require a valid token
+    pub routes: Vec<String>,
+    pub jwks_url: Option<String>,
+    /// How long fetched keys are used before being fetched again
+    #[serde(
+        default = "default_jwks_refresh",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub jwks_refresh: Duration,
+    pub secret: Option<String>,
+    pub secret_env: Option<String>,
+    /// Path to a PEM-encoded RSA, EC or Ed25519 public key
+    pub public_key: Option<String>,
+    /// Accepted signing algorithms; HS256 with a secret, RS256 otherwise
+    pub algorithms: Option<Vec<String>>,
+    /// Required `iss` claim, when set
+    pub issuer: Option<String>,
+    /// Accepted `aud` values, when set
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Claims sent to the upstream, mapped to the header carrying each
+    #[serde(default)]
+    pub claim_headers: HashMap<String, String>,
+}
+
+fn default_jwks_refresh() -> Duration {
+    Duration::from_secs(600)
+}
+
+/// URLs that must carry a valid HMAC signature and an unexpired expiry
+/// timestamp in their query string.
+#[derive(Debug, Deserialize)]
+pub struct SignedUrlConfig {
+    /// Glob patterns of paths that require a signature
+    pub routes: Vec<String>,
+    pub secret: Option<String>,
+    pub secret_env: Option<String>,
+    #[serde(default = "default_signature_param")]
+    pub signature_param: String,
+    /// Expiry as a Unix timestamp in seconds
+    #[serde(default = "default_expires_param")]
+    pub expires_param: String,
+}
+
+fn default_signature_param() -> String {
+    "signature".to_string()
+}
+
+fn default_expires_param() -> String {
+    "expires".to_string()
+}
+
+/// Per-client request quotas, keyed by API key.
+#[derive(Debug, Deserialize)]
+pub struct QuotaConfig {
+    /// Request header carrying the API key
+    #[serde(default = "default_quota_header")]
+    pub header: String,
+    /// Query parameter checked when the header is absent
+    pub query_param: Option<String>,
+    /// Reject requests without a known key instead of metering them by
+    /// client IP
+    #[serde(default)]
+    pub require_key: bool,
+    /// Requests per minute allowed for each API key
+    pub keys: HashMap<String, u32>,
+    /// Requests per minute allowed for each client IP sending no known key,
+    /// when `require_key` is off
+    #[serde(default = "default_quota_anonymous_limit")]
+    pub anonymous_limit: u32,
+    /// Redis to keep counters in, in place of the storage backend
+    pub redis_url: Option<String>,
+    /// Prefix of counter keys, keeping them apart from cache entries
+    #[serde(default = "default_quota_key_prefix")]
+    pub key_prefix: String,
+}
+
+fn default_quota_header() -> String {
+    "X-API-Key".to_string()
+}
+
+fn default_quota_anonymous_limit() -> u32 {
+    60
+}
+
+fn default_quota_key_prefix() -> String {
+    "relay:quota:".to_string()
+}
+
+/// A WebAssembly module that can rewrite requests and upstream responses on
+/// matching routes.
+#[derive(Debug, Deserialize)]
+pub struct WasmFilterConfig {
+    /// Path to a compiled `.wasm` module, or its `.wat` text form
+    pub module: String,
+    /// Glob patterns of paths the filter runs on
+    pub routes: Vec<String>,
+    /// Instructions a single call may execute before it is stopped
+    #[serde(default = "default_wasm_fuel")]
+    pub fuel: u64,
+    /// Largest linear memory a module instance may grow to, in bytes
+    #[serde(default = "default_wasm_max_memory")]
+    pub max_memory: usize,
+}
+
+/// Rewrites of text response bodies on matching routes.
+#[derive(Debug, Deserialize)]
+pub struct TransformConfig {
+    /// Glob patterns of paths the rewrites apply to
+    pub routes: Vec<String>,
+    /// Content types rewritten; entries ending in `/*` match a whole type
+    #[serde(default = "default_transform_content_types")]
+    pub content_types: Vec<String>,
+    #[serde(default)]
+    pub replace: Vec<ReplaceConfig>,
+    /// Inserted before the closing `</body>` tag
+    pub inject_before_body_end: Option<String>,
+    /// Hosts whose absolute URLs are made relative, e.g. `https://www.example.com/a` to `/a`
+    #[serde(default)]
+    pub relative_hosts: Vec<String>,
+}
+
+/// A substitution in response bodies, of a literal string or, with `regex`,
+/// a pattern whose replacement can refer to groups as `$1`.
+#[derive(Debug, Deserialize)]
+pub struct ReplaceConfig {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub regex: bool,
+}
+
+fn default_transform_content_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+/// A secondary upstream that serves a share of the requests on matching
+/// routes, as in a canary deployment.
+#[derive(Debug, Deserialize)]
+pub struct SplitConfig {
+    /// Label for this split in metrics; the URL when unset
+    pub name: Option<String>,
+    /// Glob patterns of paths that are split
+    pub routes: Vec<String>,
+    pub url: String,
+    /// Share of matching requests sent to `url`, e.g. "10%"
+    #[serde(deserialize_with = "deserialize_percentage")]
+    pub weight: f64,
+    /// Keep clients on one variant by hashing this cookie's value
+    pub sticky_cookie: Option<String>,
+    /// Keep clients on one variant by hashing their address, for requests
+    /// without the sticky cookie
+    #[serde(default)]
+    pub sticky_client_ip: bool,
+}
+
+/// A shadow upstream that receives a copy of requests on matching routes.
+/// Its responses are discarded.
+#[derive(Debug, Deserialize)]
+pub struct MirrorConfig {
+    /// Glob patterns of paths that are mirrored
+    pub routes: Vec<String>,
+    pub url: String,
+    /// Share of matching requests copied, e.g. "10%"
+    #[serde(
+        default = "default_mirror_sample",
+        deserialize_with = "deserialize_percentage"
+    )]
+    pub sample: f64,
+    /// How long a mirrored request may take before it is abandoned
+    #[serde(
+        default = "default_mirror_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub timeout: Duration,
+    /// Mirrored requests in flight at once; further copies are skipped
+    #[serde(default = "default_mirror_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+fn default_mirror_sample() -> f64 {
+    1.0
+}
+
+fn default_mirror_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_mirror_max_in_flight() -> usize {
+    100
+}
+
+/// Cross-origin access to matching routes, answered by relay.
+#[derive(Debug, Deserialize)]
+pub struct CorsConfig {
+    /// Glob patterns of paths the policy covers
+    pub routes: Vec<String>,
+    /// Origins allowed, e.g. "https://app.example.com"; "*" allows any
+    pub allow_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allow_methods: Vec<String>,
+    /// Request headers allowed; "*" allows whatever a preflight asks for
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Response headers scripts may read beyond the CORS-safelisted ones
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// Whether requests may carry cookies and credentials
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may reuse a preflight answer
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_age: Option<Duration>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST"].map(String::from).to_vec()
+}
+
+/// A Lua script whose functions can compute cache keys, rewrite request
+/// headers and pick the upstream per request.
+#[derive(Debug, Deserialize)]
+pub struct LuaConfig {
+    pub script: String,
+    /// How long one call into the script may run before it is stopped
+    #[serde(
+        default = "default_lua_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub timeout: Duration,
+}
+
+fn default_lua_timeout() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_wasm_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_wasm_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+/// Bounds on what a single client can make relay hold on to.
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfig {
+    /// Largest request body accepted, in bytes
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: u64,
+    /// Most headers accepted on one request
+    #[serde(default = "default_max_headers")]
+    pub max_headers: usize,
+    /// Largest request line plus headers, in bytes (at least 8192)
+    #[serde(default = "default_max_header_size")]
+    pub max_header_size: usize,
+    /// Time a client gets to send the complete request head
+    #[serde(
+        default = "default_header_read_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub header_read_timeout: Duration,
+    /// Connections with no traffic and no request in flight are closed
+    #[serde(
+        default = "default_idle_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub idle_timeout: Duration,
+    /// Connections whose client stops reading a response are closed
+    #[serde(
+        default = "default_write_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub write_timeout: Duration,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: default_max_body_size(),
+            max_headers: default_max_headers(),
+            max_header_size: default_max_header_size(),
+            header_read_timeout: default_header_read_timeout(),
+            idle_timeout: default_idle_timeout(),
+            write_timeout: default_write_timeout(),
+        }
+    }
+}
+
+fn default_max_body_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_headers() -> usize {
+    100
+}
+
+fn default_max_header_size() -> usize {
+    64 * 1024
+}
+
+fn default_header_read_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_write_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// On-the-fly compression of cached responses, negotiated per client.
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Encodings offered, in order of preference: "br", "zstd", "gzip"
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<String>,
+    /// Bodies smaller than this many bytes are sent uncompressed
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: usize,
+    /// Content types to compress; `type/*` matches a whole type
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<String>,
+    /// Memory, in bytes, for compressed variants of cached bodies
+    #[serde(default = "default_variant_cache_size")]
+    pub variant_cache_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithms: default_compression_algorithms(),
+            min_size: default_compression_min_size(),
+            content_types: default_compression_content_types(),
+            variant_cache_size: default_variant_cache_size(),
+        }
+    }
+}
+
+fn default_compression_algorithms() -> Vec<String> {
+    vec!["br".to_string(), "zstd".to_string(), "gzip".to_string()]
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+fn default_compression_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .iter()
+    .map(|content_type| content_type.to_string())
+    .collect()
+}
+
+fn default_variant_cache_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Lets clients use relay as an egress proxy: absolute-form requests go to
+/// the host they name instead of the upstream, and `CONNECT` opens tunnels.
+#[derive(Debug, Deserialize)]
+pub struct ForwardProxyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Ports `CONNECT` may tunnel to
+    #[serde(default = "default_connect_ports")]
+    pub connect_ports: Vec<u16>,
+}
+
+impl Default for ForwardProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            connect_ports: default_connect_ports(),
+        }
+    }
+}
+
+fn default_connect_ports() -> Vec<u16> {
+    vec![443]
 }
 
+/// Handling of upstream redirects, so clients are never sent to the
+/// upstream's internal address.
 #[derive(Debug, Deserialize)]
-pub struct ServerConfig {
-    pub host: String,
-    pub port: u16,
+pub struct RedirectConfig {
+    /// Rewrite `Location` headers pointing at the upstream to relay's own
+    /// address
+    #[serde(default = "default_rewrite_location")]
+    pub rewrite_location: bool,
+    /// Scheme and host clients reach relay at, such as
+    /// `https://www.example.com`; taken from the listener and the request's
+    /// `Host` header when unset
+    pub public_url: Option<String>,
+    /// Redirects to the upstream itself followed on cacheable GETs before
+    /// the response is cached; 0 passes them to the client
+    #[serde(default)]
+    pub follow: u32,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            rewrite_location: default_rewrite_location(),
+            public_url: None,
+            follow: 0,
+        }
+    }
+}
+
+fn default_rewrite_location() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize)]
-pub struct UpstreamConfig {
-    pub url: String,
+pub struct ClusterConfig {
+    pub redis_url: String,
+    #[serde(default = "default_cluster_channel")]
+    pub channel: String,
 }
 
-#[derive(Debug, Deserialize, Default)]
-pub struct PrometheusConfig {
-    #[serde(default)]
-    pub enabled: bool,
+fn default_cluster_channel() -> String {
+    "relay:invalidate".to_string()
 }
 
 #[derive(Debug, Deserialize)]
-pub struct LoggingConfig {
-    #[serde(default = "default_logging_enabled")]
+pub struct DebugConfig {
+    /// Always include debug headers, regardless of the request
+    #[serde(default)]
     pub enabled: bool,
-    #[serde(default = "default_log_format")]
-    pub format: String,
+    /// Request header that turns on debug headers for a single request
+    #[serde(default = "default_debug_header")]
+    pub header: String,
+    /// Client networks that may see debug headers
+    #[serde(default = "default_debug_allow")]
+    pub allow: Vec<String>,
+    /// Sent as the debug header's value, shows debug headers to a client
+    /// from anywhere
+    pub token: Option<String>,
+    pub token_env: Option<String>,
 }
 
-impl Default for LoggingConfig {
+impl Default for DebugConfig {
     fn default() -> Self {
         Self {
-            enabled: default_logging_enabled(),
-            format: default_log_format(),
+            enabled: false,
+            header: default_debug_header(),
+            allow: default_debug_allow(),
+            token: None,
+            token_env: None,
         }
     }
 }
 
-fn default_logging_enabled() -> bool {
-    true
+fn default_debug_header() -> String {
+    "X-Relay-Debug".to_string()
 }
 
-fn default_log_format() -> String {
-    "json".to_string()
+fn default_debug_allow() -> Vec<String> {
+    vec!["127.0.0.1".to_string(), "::1".to_string()]
 }
 
 #[derive(Debug, Deserialize, Clone)]
-pub struct CacheRule {
-    #[serde(default, deserialize_with = "deserialize_optional_duration")]
-    pub ttl: Option<Duration>,
-    #[serde(default, deserialize_with = "deserialize_optional_duration")]
-    pub stale: Option<Duration>,
+pub struct RateLimitRule {
     #[serde(default)]
-    pub bypass: Option<bool>,
+    pub rate: Option<u32>,
+    #[serde(default)]
+    pub burst: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct CacheConfig {
-    #[serde(default = "default_ttl", deserialize_with = "deserialize_duration")]
-    pub default_ttl: Duration,
-    #[serde(
-        default = "default_stale_if_error",
-        deserialize_with = "deserialize_duration"
-    )]
-    pub stale_if_error: Duration,
+pub struct RateLimitConfig {
     #[serde(default)]
-    pub rules: Option<HashMap<String, CacheRule>>,
+    pub enabled: bool,
+    /// Sustained requests per second allowed for each client IP
+    #[serde(default = "default_rate_limit_rate")]
+    pub rate: u32,
+    /// Maximum number of requests a client can make in a single burst
+    #[serde(default = "default_rate_limit_burst")]
+    pub burst: u32,
+    #[serde(default)]
+    pub routes: Option<HashMap<String, RateLimitRule>>,
+    /// Limits for crawlers in place of the ones above; they apply even when
+    /// `enabled` is false
+    #[serde(default)]
+    pub bots: Option<RateLimitRule>,
     #[serde(skip)]
-    pub compiled_rules: Option<Vec<(GlobSet, CacheRule)>>,
+    pub compiled_routes: Option<Vec<(GlobSet, RateLimitRule)>>,
 }
 
-impl Default for CacheConfig {
+impl Default for RateLimitConfig {
     fn default() -> Self {
         Self {
-            default_ttl: default_ttl(),
-            stale_if_error: default_stale_if_error(),
-            rules: None,
-            compiled_rules: None,
+            enabled: false,
+            rate: default_rate_limit_rate(),
+            burst: default_rate_limit_burst(),
+            routes: None,
+            bots: None,
+            compiled_routes: None,
         }
     }
 }
 
+fn default_rate_limit_rate() -> u32 {
+    10
+}
+
+fn default_rate_limit_burst() -> u32 {
+    20
+}
+
 #[derive(Debug, Deserialize)]
-pub struct StorageConfig {
-    #[serde(default = "default_backend")]
-    pub backend: String,
-    pub redis: Option<RedisConfig>,
+pub struct TieredConfig {
+    /// Backend used as the second tier: "redis" or "disk"
+    pub l2: String,
+    #[serde(default = "default_l1_max_entries")]
+    pub l1_max_entries: usize,
+    #[serde(default = "default_l1_ttl", deserialize_with = "deserialize_duration")]
+    pub l1_ttl: Duration,
 }
 
-impl Default for StorageConfig {
+#[derive(Debug, Deserialize)]
+pub struct MokaConfig {
+    /// Maximum total weight of cached entries, e.g. "256MB"
+    #[serde(
+        default = "default_moka_max_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub max_size: u64,
+}
+
+impl Default for MokaConfig {
     fn default() -> Self {
         Self {
-            backend: default_backend(),
-            redis: None,
+            max_size: default_moka_max_size(),
         }
     }
 }
 
+/// Periodic snapshots of the memory backend, reloaded on startup.
 #[derive(Debug, Deserialize)]
-pub struct RedisConfig {
-    pub url: String,
+pub struct SnapshotConfig {
+    pub path: String,
+    #[serde(
+        default = "default_snapshot_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+}
+
+fn default_snapshot_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_moka_max_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_l1_max_entries() -> usize {
+    1000
+}
+
+fn default_l1_ttl() -> Duration {
+    Duration::from_secs(60)
 }
 
 fn default_backend() -> String {
     "memory".to_string()
 }
 
+fn default_stream_content_types() -> Vec<String> {
+    vec!["text/event-stream".to_string()]
+}
+
+fn default_max_object_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_continue_on_disconnect() -> bool {
+    true
+}
+
+fn default_refresh_ahead_max_keys() -> usize {
+    10_000
+}
+
+fn default_heuristic_max_ttl() -> Duration {
+    Duration::from_secs(86400)
+}
+
+fn default_ttl_header() -> String {
+    "X-Relay-TTL".to_string()
+}
+
+fn default_no_cache_header() -> String {
+    "X-Relay-No-Cache".to_string()
+}
+
+fn default_exempt_grpc() -> bool {
+    true
+}
+
+fn default_cacheable_statuses() -> Vec<u16> {
+    vec![200, 203, 301, 404]
+}
+
+fn default_stale_if_error_statuses() -> Vec<u16> {
+    vec![500, 501, 502, 503, 504]
+}
+
+fn default_early_refresh_beta() -> f64 {
+    1.0
+}
+
 fn default_ttl() -> Duration {
     Duration::from_secs(300) // 5 minutes
 }
@@ -149,12 +1704,37 @@ where
     }
 }
 
+fn deserialize_optional_duration_map<'de, D>(
+    deserializer: D,
+) -> Result<Option<HashMap<String, Duration>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let map: Option<HashMap<String, String>> = Option::deserialize(deserializer)?;
+    match map {
+        Some(map) => map
+            .into_iter()
+            .map(|(key, value)| parse_duration(&value).map(|duration| (key, duration)))
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
 fn parse_duration(s: &str) -> Result<Duration, String> {
     let s = s.trim();
     if s.is_empty() {
         return Err("Duration string is empty".to_string());
     }
 
+    if let Some(millis) = s.strip_suffix("ms") {
+        let value: u64 = millis
+            .parse()
+            .map_err(|_| format!("Invalid number: {millis}"))?;
+        return Ok(Duration::from_millis(value));
+    }
+
     let (value_str, unit) = s.split_at(s.len() - 1);
     let last_char = s.chars().last().unwrap();
 
@@ -179,26 +1759,321 @@ fn parse_duration(s: &str) -> Result<Duration, String> {
     Ok(Duration::from_secs(value * multiplier))
 }
 
+fn deserialize_percentage<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_percentage(&s).map_err(serde::de::Error::custom)
+}
+
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let num_str = s
+        .strip_suffix('%')
+        .ok_or_else(|| format!("Percentage must end with %: {s}"))?;
+    let value: f64 = num_str
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid number: {num_str}"))?;
+    if !(0.0..=100.0).contains(&value) {
+        return Err(format!("Percentage must be between 0% and 100%: {s}"));
+    }
+    Ok(value / 100.0)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_size(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    s.map(|s| parse_size(&s))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_log_rotation<'de, D>(deserializer: D) -> Result<LogRotation, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let rotation = match s.as_str() {
+        "never" => LogRotation::Never,
+        "minutely" => LogRotation::Minutely,
+        "hourly" => LogRotation::Hourly,
+        "daily" => LogRotation::Daily,
+        "weekly" => LogRotation::Weekly,
+        size => match parse_size(size) {
+            Ok(0) => return Err(serde::de::Error::custom("Rotation size must be above 0")),
+            Ok(bytes) => LogRotation::Size(bytes),
+            Err(_) => {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid rotation: {size} (expected never, minutely, hourly, daily, weekly or a size like \"100MB\")"
+                )))
+            }
+        },
+    };
+    Ok(rotation)
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("Size string is empty".to_string());
+    }
+
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num_str, unit_str) = s.split_at(split);
+
+    let value: u64 = num_str
+        .parse()
+        .map_err(|_| format!("Invalid number: {num_str}"))?;
+
+    let multiplier = match unit_str.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid size unit: {unit_str}")),
+    };
+
+    Ok(value * multiplier)
+}
+
+/// A cache rule with its path pattern compiled.
+#[derive(Debug)]
+pub struct CompiledRule {
+    pub pattern: String,
+    globset: GlobSet,
+    pub rule: CacheRule,
+}
+
+impl CompiledRule {
+    pub fn new(pattern: &str, rule: CacheRule) -> Result<Self, BoxError> {
+        if let Some(status_ttl) = &rule.status_ttl {
+            if let Some(key) = status_ttl.keys().find(|key| !is_valid_status_key(key)) {
+                return Err(format!(
+                    "Invalid status_ttl key \"{key}\" in rule \"{pattern}\": expected a status code like \"404\" or a class like \"4xx\""
+                )
+                .into());
+            }
+        }
+        for name in rule.vary_headers.iter().flatten() {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                format!("Invalid vary_headers entry \"{name}\" in rule \"{pattern}\"")
+            })?;
+        }
+        if rule.languages.as_ref().is_some_and(Vec::is_empty) {
+            return Err(format!("languages in rule \"{pattern}\" must not be empty").into());
+        }
+        if let Some(fallback) = &rule.spa_fallback {
+            if !fallback.starts_with('/') || fallback.parse::<hyper::Uri>().is_err() {
+                return Err(format!(
+                    "Invalid spa_fallback \"{fallback}\" in rule \"{pattern}\": expected a path like \"/index.html\""
+                )
+                .into());
+            }
+        }
+        match rule.spa_fallback_on.as_deref() {
+            None | Some("not_found") | Some("non_file") => {}
+            Some(other) => {
+                return Err(format!(
+                    "Invalid spa_fallback_on \"{other}\" in rule \"{pattern}\": expected \"not_found\" or \"non_file\""
+                )
+                .into())
+            }
+        }
+        let mut builder = GlobSetBuilder::new();
+        builder.add(Glob::new(pattern)?);
+        Ok(Self {
+            pattern: pattern.to_string(),
+            globset: builder.build()?,
+            rule,
+        })
+    }
+}
+
+/// The cache rules in effect. Each change swaps in a complete rule, so a
+/// request sees a rule either before or after it changed, never halfway.
+#[derive(Debug, Default)]
+pub struct CacheRules {
+    rules: RwLock<Vec<Arc<CompiledRule>>>,
+}
+
+impl CacheRules {
+    /// The first rule matching `path`.
+    pub fn find(&self, path: &str) -> Option<Arc<CompiledRule>> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|compiled| compiled.globset.is_match(path))
+            .cloned()
+    }
+
+    pub fn get(&self, pattern: &str) -> Option<Arc<CompiledRule>> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|compiled| compiled.pattern == pattern)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<CompiledRule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Adds `compiled`, replacing the rule with the same pattern in place.
+    /// Returns whether a rule was replaced.
+    pub fn insert(&self, compiled: CompiledRule) -> bool {
+        let mut rules = self.rules.write().unwrap();
+        let compiled = Arc::new(compiled);
+        match rules
+            .iter_mut()
+            .find(|rule| rule.pattern == compiled.pattern)
+        {
+            Some(existing) => {
+                *existing = compiled;
+                true
+            }
+            None => {
+                rules.push(compiled);
+                false
+            }
+        }
+    }
+
+    /// Removes the rule for `pattern`, returning whether there was one.
+    pub fn remove(&self, pattern: &str) -> bool {
+        let mut rules = self.rules.write().unwrap();
+        let before = rules.len();
+        rules.retain(|rule| rule.pattern != pattern);
+        rules.len() < before
+    }
+}
+
 impl CacheConfig {
-    pub fn compile_rules(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(rules) = &self.rules {
+    pub fn compile_rules(&mut self) -> Result<(), BoxError> {
+        for (pattern, rule) in self.rules.iter().flatten() {
+            self.compiled_rules
+                .insert(CompiledRule::new(pattern, rule.clone())?);
+        }
+        Ok(())
+    }
+
+    /// Decides from the upstream status whether a response may be stored. A
+    /// rule's `cache_statuses` replaces the global list, and a rule with a
+    /// `status_ttl` entry for the status opts it in. 206 is never stored.
+    pub fn is_cacheable_status(&self, rule: Option<&CacheRule>, status: u16) -> bool {
+        // A partial response is only a slice of the object; storing it would
+        // serve the slice to every later request
+        if status == 206 {
+            return false;
+        }
+        if let Some(statuses) = rule.and_then(|r| r.cache_statuses.as_ref()) {
+            return statuses.contains(&status);
+        }
+        rule.is_some_and(|r| r.ttl_for_status(status).is_some())
+            || self.cacheable_statuses.contains(&status)
+    }
+
+    /// Removes `ttl_header` and `no_cache_header` from an upstream response,
+    /// since they're meant for relay rather than clients, and returns the TTL
+    /// they ask for: zero for `no_cache_header`, `None` when neither is set
+    /// or the TTL isn't a duration.
+    pub fn take_ttl_override(&self, headers: &mut HeaderMap) -> Option<Duration> {
+        let no_cache = headers.remove(self.no_cache_header.as_str()).is_some();
+        let ttl = headers.remove(self.ttl_header.as_str());
+        if no_cache {
+            return Some(Duration::ZERO);
+        }
+        parse_duration(ttl?.to_str().ok()?).ok()
+    }
+
+    pub fn is_streaming_content_type(&self, content_type: Option<&str>) -> bool {
+        content_type.is_some_and(|content_type| {
+            self.stream_content_types
+                .iter()
+                .any(|pattern| content_type_matches(pattern, content_type))
+        })
+    }
+
+    /// Decides from the upstream `Content-Type` whether a response may be
+    /// stored. Rule-level lists replace the global ones.
+    pub fn is_cacheable_content_type(
+        &self,
+        rule: Option<&CacheRule>,
+        content_type: Option<&str>,
+    ) -> bool {
+        let allow = rule
+            .and_then(|r| r.content_types.as_ref())
+            .or(self.content_types.as_ref());
+        let exclude = rule
+            .and_then(|r| r.exclude_content_types.as_ref())
+            .or(self.exclude_content_types.as_ref());
+
+        let Some(content_type) = content_type else {
+            return allow.is_none();
+        };
+        if self.exempt_grpc && is_grpc_content_type(content_type) {
+            return false;
+        }
+        if let Some(exclude) = exclude {
+            if exclude
+                .iter()
+                .any(|pattern| content_type_matches(pattern, content_type))
+            {
+                return false;
+            }
+        }
+        allow.is_none_or(|allow| {
+            allow
+                .iter()
+                .any(|pattern| content_type_matches(pattern, content_type))
+        })
+    }
+
+    /// Returns the first rule matching `path` along with its glob pattern.
+    pub fn find_rule_with_pattern(&self, path: &str) -> Option<Arc<CompiledRule>> {
+        self.compiled_rules.find(path)
+    }
+}
+
+impl RateLimitConfig {
+    pub fn compile_routes(&mut self) -> Result<(), BoxError> {
+        if let Some(routes) = &self.routes {
+            let mut patterns: Vec<_> = routes.iter().collect();
+            // Routes are checked most specific first, whatever order the
+            // config map yields them in
+            patterns.sort_by(|(a, _), (b, _)| specificity(b).cmp(&specificity(a)).then(a.cmp(b)));
             let mut compiled = Vec::new();
-            for (pattern, rule) in rules {
+            for (pattern, rule) in patterns {
                 let mut builder = GlobSetBuilder::new();
                 builder.add(Glob::new(pattern)?);
                 let globset = builder.build()?;
                 compiled.push((globset, rule.clone()));
             }
-            self.compiled_rules = Some(compiled);
+            self.compiled_routes = Some(compiled);
         }
         Ok(())
     }
 
-    pub fn find_rule(&self, path: &str) -> Option<&CacheRule> {
-        if let Some(compiled) = &self.compiled_rules {
-            for (globset, rule) in compiled {
+    /// Returns the index of the most specific route override matching
+    /// `path`, if any.
+    pub fn find_route(&self, path: &str) -> Option<(usize, &RateLimitRule)> {
+        if let Some(compiled) = &self.compiled_routes {
+            for (index, (globset, rule)) in compiled.iter().enumerate() {
                 if globset.is_match(path) {
-                    return Some(rule);
+                    return Some((index, rule));
                 }
             }
         }
@@ -206,9 +2081,149 @@ impl CacheConfig {
     }
 }
 
-pub fn load_config(path: &str) -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
-    let config_str = std::fs::read_to_string(path)?;
-    let mut config: Config = toml::from_str(&config_str)?;
+pub fn load_config(path: &str) -> Result<Config, RelayError> {
+    let config_str = std::fs::read_to_string(path)
+        .map_err(|e| RelayError::config(format!("Failed to read {path}: {e}")))?;
+    parse_config(&config_str)
+}
+
+/// Parses and validates a config given as TOML, as `load_config` does for
+/// a file.
+pub fn parse_config(config_str: &str) -> Result<Config, RelayError> {
+    parse(config_str).map_err(RelayError::Config)
+}
+
+fn parse(config_str: &str) -> Result<Config, BoxError> {
+    let mut config: Config = toml::from_str(config_str)?;
+    let upstream = &mut config.upstream;
+    match (upstream.url.is_empty(), upstream.urls.is_empty()) {
+        (false, true) => upstream.urls.push(upstream.url.clone()),
+        (true, false) => upstream.url = upstream.urls[0].clone(),
+        (true, true) => return Err("upstream.url or upstream.urls must be set".into()),
+        (false, false) => return Err("set only one of upstream.url and upstream.urls".into()),
+    }
+    let server = &mut config.server;
+    if server.listeners.is_empty() {
+        if server.host.is_empty() || server.port == 0 {
+            return Err("server.host and server.port, or server.listeners, must be set".into());
+        }
+        server.listeners.push(ListenerConfig {
+            address: Some(format!("{}:{}", server.host, server.port)),
+            path: None,
+            tls: server.tls.clone(),
+            proxy_protocol: server.proxy_protocol,
+            admin: true,
+        });
+    } else if !server.host.is_empty()
+        || server.port != 0
+        || server.tls.is_some()
+        || server.proxy_protocol
+    {
+        return Err(
+            "server.listeners replaces server.host, server.port, server.tls and server.proxy_protocol"
+                .into(),
+        );
+    }
+    if server.acceptors == 0 {
+        return Err("server.acceptors must be at least 1".into());
+    }
+    if server.worker_threads == Some(0) {
+        return Err("server.worker_threads must be at least 1".into());
+    }
+    if server.max_blocking_threads == Some(0) {
+        return Err("server.max_blocking_threads must be at least 1".into());
+    }
+    if server.acceptors > 1 && !server.reuseport {
+        return Err("server.acceptors above 1 requires server.reuseport".into());
+    }
+    for listener in &server.listeners {
+        if listener.address.is_some() == listener.path.is_some() {
+            return Err("each of server.listeners needs either an address or a path".into());
+        }
+    }
     config.cache.compile_rules()?;
+    config.rate_limit.compile_routes()?;
+    if let Some(signed_urls) = &config.signed_urls {
+        // Every signed link to an asset shares one cache entry
+        let mut routes = GlobSetBuilder::new();
+        for pattern in &signed_urls.routes {
+            routes.add(Glob::new(pattern)?);
+        }
+        config.cache.key.signed_params = Some((
+            routes.build()?,
+            [
+                signed_urls.signature_param.clone(),
+                signed_urls.expires_param.clone(),
+            ],
+        ));
+    }
+    for name in [&config.cache.ttl_header, &config.cache.no_cache_header] {
+        if !name.is_empty() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name in cache config: {name}"))?;
+        }
+    }
+    if let Some(security) = &config.headers.security {
+        security
+            .header_map()
+            .map_err(|_| "Invalid header value in headers.security")?;
+    }
+    let access = &config.access;
+    let countries_used = !access.allow_countries.is_empty()
+        || !access.deny_countries.is_empty()
+        || access
+            .routes
+            .values()
+            .any(|rule| rule.allow_countries.is_some() || rule.deny_countries.is_some());
+    if countries_used && config.geoip.is_none() {
+        return Err("access country lists need a [geoip] database".into());
+    }
+    if let Some(geoip) = &config.geoip {
+        if !geoip.header.is_empty() {
+            HeaderName::from_bytes(geoip.header.as_bytes())
+                .map_err(|_| format!("Invalid geoip.header: {}", geoip.header))?;
+        }
+    }
+    if config.limits.max_header_size < 8192 {
+        return Err("limits.max_header_size must be at least 8192 bytes".into());
+    }
+    let logging = &config.logging;
+    match logging.output.as_str() {
+        "stdout" => {}
+        "file" if logging.path.is_none() => {
+            return Err("logging.path must be set when logging.output is \"file\"".into())
+        }
+        "file" | "journald" => {}
+        "syslog" if !SYSLOG_FACILITIES.contains(&logging.syslog_facility.as_str()) => {
+            return Err(format!(
+                "Invalid logging.syslog_facility: {}",
+                logging.syslog_facility
+            )
+            .into())
+        }
+        "syslog" => {}
+        other => return Err(format!("Invalid logging.output: {other}").into()),
+    }
+    if logging.max_files == 0 {
+        return Err("logging.max_files must be at least 1".into());
+    }
+    if let Some(field) = logging
+        .fields
+        .iter()
+        .find(|field| !LOG_FIELDS.contains(&field.as_str()))
+    {
+        return Err(format!("Unknown logging.fields entry: {field}").into());
+    }
+    for name in logging
+        .request_headers
+        .iter()
+        .chain(&logging.response_headers)
+    {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name in logging: {name}"))?;
+    }
+    if logging.sample_rate == 0 {
+        return Err("logging.sample_rate must be at least 1".into());
+    }
     Ok(config)
 }
@@ -0,0 +1,168 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::Method;
+use std::error::Error;
+
+use crate::config::CorsConfig;
+
+struct Policy {
+    routes: GlobSet,
+    /// None admits any origin
+    origins: Option<Vec<String>>,
+    methods: HeaderValue,
+    /// None echoes whatever the preflight asks for
+    headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+impl Policy {
+    /// The `Access-Control-Allow-Origin` value for a request's `Origin`, or
+    /// None when the origin isn't allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        match &self.origins {
+            // A wildcard can't be combined with credentials, so the origin
+            // is echoed instead
+            None if !self.credentials => Some(HeaderValue::from_static("*")),
+            None => Some(origin.clone()),
+            Some(origins) => {
+                let origin_str = origin.to_str().ok()?;
+                origins
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+                    .then(|| origin.clone())
+            }
+        }
+    }
+
+    fn add_origin_headers(&self, origin: HeaderValue, headers: &mut HeaderMap) {
+        if origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+/// Cross-origin resource sharing on configured routes. Relay answers
+/// preflights itself and sets the CORS headers on every response, cached or
+/// not, in place of any the upstream sent.
+pub struct Cors {
+    policies: Vec<Policy>,
+}
+
+impl Cors {
+    pub fn new(configs: &[CorsConfig]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let policies = configs
+            .iter()
+            .map(|config| {
+                let mut routes = GlobSetBuilder::new();
+                for pattern in &config.routes {
+                    routes.add(Glob::new(pattern)?);
+                }
+                let any_origin = config.allow_origins.iter().any(|origin| origin == "*");
+                let any_header = config.allow_headers.iter().any(|name| name == "*");
+                let list = |values: &[String]| HeaderValue::from_str(&values.join(", "));
+                Ok(Policy {
+                    routes: routes.build()?,
+                    origins: (!any_origin).then(|| config.allow_origins.clone()),
+                    methods: list(&config.allow_methods)?,
+                    headers: (!any_header)
+                        .then(|| list(&config.allow_headers))
+                        .transpose()?,
+                    expose_headers: (!config.expose_headers.is_empty())
+                        .then(|| list(&config.expose_headers))
+                        .transpose()?,
+                    credentials: config.allow_credentials,
+                    max_age: config
+                        .max_age
+                        .map(|max_age| HeaderValue::from(max_age.as_secs())),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+        Ok(Self { policies })
+    }
+
+    fn policy(&self, path: &str) -> Option<&Policy> {
+        self.policies
+            .iter()
+            .find(|policy| policy.routes.is_match(path))
+    }
+
+    /// Whether a request is a CORS preflight on a configured route, which
+    /// relay answers rather than forwarding.
+    pub fn is_preflight(&self, method: &Method, path: &str, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(ORIGIN)
+            && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+            && self.policy(path).is_some()
+    }
+
+    /// Headers answering a preflight, or None when its origin isn't allowed.
+    pub fn preflight(&self, path: &str, headers: &HeaderMap) -> Option<HeaderMap> {
+        let policy = self.policy(path)?;
+        let origin = policy.allow_origin(headers.get(ORIGIN)?)?;
+        let mut response = HeaderMap::new();
+        policy.add_origin_headers(origin, &mut response);
+        response.insert(ACCESS_CONTROL_ALLOW_METHODS, policy.methods.clone());
+        let allow_headers = match &policy.headers {
+            Some(allowed) => Some(allowed.clone()),
+            None => headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(allow_headers) = allow_headers.filter(|value| !value.is_empty()) {
+            response.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            if policy.headers.is_none() {
+                response.append(
+                    VARY,
+                    HeaderValue::from_static("Access-Control-Request-Headers"),
+                );
+            }
+        }
+        if let Some(max_age) = &policy.max_age {
+            response.insert(ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+        Some(response)
+    }
+
+    /// The CORS headers for the response to a request on `path`; None when
+    /// no policy covers the path, leaving the response as it is.
+    pub fn response_headers(&self, path: &str, headers: &HeaderMap) -> Option<HeaderMap> {
+        let policy = self.policy(path)?;
+        let mut response = HeaderMap::new();
+        if let Some(origin) = headers
+            .get(ORIGIN)
+            .and_then(|origin| policy.allow_origin(origin))
+        {
+            policy.add_origin_headers(origin, &mut response);
+            if let Some(expose_headers) = &policy.expose_headers {
+                response.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers.clone());
+            }
+        }
+        Some(response)
+    }
+}
+
+/// Replaces a response's CORS headers with relay's, appending to `Vary`.
+pub fn apply(response: &mut HeaderMap, cors: HeaderMap) {
+    for name in [
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+    ] {
+        response.remove(name);
+    }
+    for (name, value) in cors.iter() {
+        response.append(name, value.clone());
+    }
+}
@@ -0,0 +1,183 @@
+use hyper::{Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::balancer::Balancer;
+use crate::handlers::Body;
+
+/// The dashboard page, which polls `stats` next to it
+pub const PAGE: &str = include_str!("dashboard.html");
+
+/// Keys listed on the dashboard
+const TOP_KEYS: usize = 10;
+/// Keys counted at most; beyond this, counts are halved and the rarest dropped
+const TRACKED_KEYS: usize = 1000;
+/// Errors kept for the dashboard
+const RECENT_ERRORS: usize = 20;
+
+/// What the admin dashboard shows, gathered as responses go out: requests
+/// by cache status, the paths served from cache most often, and the latest
+/// errors.
+pub struct Dashboard {
+    started: Instant,
+    hits: AtomicU64,
+    stale: AtomicU64,
+    misses: AtomicU64,
+    bypasses: AtomicU64,
+    top_keys: Mutex<HashMap<String, u64>>,
+    errors: Mutex<VecDeque<RecentError>>,
+}
+
+struct RecentError {
+    at: SystemTime,
+    path: String,
+    status: u16,
+    kind: &'static str,
+    message: String,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            hits: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypasses: AtomicU64::new(0),
+            top_keys: Mutex::new(HashMap::new()),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Counts a response to `path` by its `X-Cache` status, and keeps it as
+    /// a recent error when the upstream answered with a 5xx.
+    pub fn record(&self, path: &str, response: &Response<Body>) {
+        let cache_status = response
+            .headers()
+            .get("X-Cache")
+            .and_then(|value| value.to_str().ok());
+        match cache_status {
+            Some("HIT") => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.count_hit(path);
+            }
+            Some("STALE") => {
+                self.stale.fetch_add(1, Ordering::Relaxed);
+                self.count_hit(path);
+            }
+            Some("MISS") => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                self.bypasses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let status = response.status();
+        if status.is_server_error() {
+            let reason = status.canonical_reason().unwrap_or_default();
+            self.record_error(path, status, "upstream_response", reason.to_string());
+        }
+    }
+
+    /// Keeps an error relay answered `path` with.
+    pub fn record_error(
+        &self,
+        path: &str,
+        status: StatusCode,
+        kind: &'static str,
+        message: String,
+    ) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            at: SystemTime::now(),
+            path: path.to_string(),
+            status: status.as_u16(),
+            kind,
+            message,
+        });
+    }
+
+    fn count_hit(&self, path: &str) {
+        let mut top_keys = self.top_keys.lock().unwrap();
+        if let Some(hits) = top_keys.get_mut(path) {
+            *hits += 1;
+            return;
+        }
+        // Halving keeps recent popularity ahead of old, and frees room
+        if top_keys.len() >= TRACKED_KEYS {
+            top_keys.retain(|_, hits| {
+                *hits /= 2;
+                *hits > 0
+            });
+        }
+        top_keys.insert(path.to_string(), 1);
+    }
+
+    /// Everything the dashboard page displays, as JSON.
+    pub fn stats(&self, balancer: &Balancer) -> Value {
+        let mut top_keys: Vec<_> = self
+            .top_keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, hits)| (path.clone(), *hits))
+            .collect();
+        top_keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_keys.truncate(TOP_KEYS);
+
+        let errors: Vec<Value> = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|error| {
+                json!({
+                    "at_ms": error
+                        .at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    "path": error.path,
+                    "status": error.status,
+                    "kind": error.kind,
+                    "message": error.message,
+                })
+            })
+            .collect();
+
+        let upstreams: Vec<Value> = balancer
+            .servers()
+            .into_iter()
+            .map(|(url, ejected_for)| {
+                json!({
+                    "url": url,
+                    "healthy": ejected_for.is_none(),
+                    "ejected_for_secs": ejected_for.map(|remaining| remaining.as_secs()),
+                })
+            })
+            .collect();
+
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "requests": {
+                "hit": self.hits.load(Ordering::Relaxed),
+                "stale": self.stale.load(Ordering::Relaxed),
+                "miss": self.misses.load(Ordering::Relaxed),
+                "bypass": self.bypasses.load(Ordering::Relaxed),
+            },
+            "top_keys": top_keys
+                .into_iter()
+                .map(|(path, hits)| json!({ "path": path, "hits": hits }))
+                .collect::<Vec<_>>(),
+            "upstreams": upstreams,
+            "errors": errors,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use hyper::header::{HeaderMap, USER_AGENT};
+
+/// Header carrying the device class to the upstream
+pub const DEVICE_CLASS_HEADER: &str = "x-device-class";
+
+/// Markers of crawlers, monitors and command-line clients, lowercased
+const BOT_MARKERS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "yandex",
+    "ia_archiver",
+    "mediapartners-google",
+    "facebookexternalhit",
+    "whatsapp",
+    "feedfetcher",
+    "lighthouse",
+    "headless",
+    "scrapy",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "okhttp",
+    "axios/",
+    "node-fetch",
+    "libwww-perl",
+    "java/",
+];
+
+const TABLET_MARKERS: &[&str] = &["ipad", "tablet", "kindle", "silk/", "playbook"];
+
+const MOBILE_MARKERS: &[&str] = &[
+    "mobi",
+    "iphone",
+    "ipod",
+    "android",
+    "windows phone",
+    "blackberry",
+    "opera mini",
+];
+
+/// The kind of device a request comes from, guessed from its `User-Agent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Mobile,
+    Tablet,
+    Desktop,
+    Bot,
+}
+
+impl DeviceClass {
+    /// Classifies a request; one without a `User-Agent` counts as a bot,
+    /// since browsers always send one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        match headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(user_agent) => Self::from_user_agent(user_agent),
+            None => DeviceClass::Bot,
+        }
+    }
+
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+        let has = |markers: &[&str]| markers.iter().any(|marker| user_agent.contains(marker));
+        if has(BOT_MARKERS) {
+            DeviceClass::Bot
+        } else if has(TABLET_MARKERS)
+            // Android tablets leave "Mobile" out of their user agent
+            || (user_agent.contains("android") && !user_agent.contains("mobile"))
+        {
+            DeviceClass::Tablet
+        } else if has(MOBILE_MARKERS) {
+            DeviceClass::Mobile
+        } else {
+            DeviceClass::Desktop
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Tablet => "tablet",
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Bot => "bot",
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use hickory_resolver::TokioResolver;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::DnsConfig;
+
+static RESOLVER: OnceLock<Dns> = OnceLock::new();
+
+/// Addresses are re-resolved in the background this long before they expire,
+/// so requests never wait on DNS for a host relay keeps resolving
+const REFRESH_AHEAD: Duration = Duration::from_secs(1);
+
+struct Entry {
+    addrs: Arc<[IpAddr]>,
+    valid_until: Instant,
+}
+
+/// Asynchronous DNS resolution for upstream connections. Successive
+/// connections to a host rotate through all of its A and AAAA records.
+/// Answers for the configured upstream hosts are kept here for their TTL,
+/// bounded by the configured minimum and maximum, and outlive failed
+/// lookups; any other host, such as one a forward-proxy client names, is
+/// left to the resolver's own bounded cache.
+struct Dns {
+    resolver: TokioResolver,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    /// Hosts whose answers are kept in `entries`
+    upstream_hosts: Mutex<HashSet<String>>,
+    entries: Mutex<HashMap<String, Entry>>,
+    next: AtomicUsize,
+}
+
+impl Dns {
+    /// Looks `host` up, caching the answer for upstream hosts. On failure,
+    /// their previous answer is kept, and returned, for as long as the
+    /// lookup keeps failing.
+    async fn lookup(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        match self.resolver.lookup_ip(host).await {
+            Ok(lookup) => {
+                let addrs: Arc<[IpAddr]> = lookup.iter().collect();
+                if addrs.is_empty() {
+                    return Err(io::Error::other(format!("no addresses found for {host}")));
+                }
+                if !self.is_upstream_host(host) {
+                    return Ok(addrs);
+                }
+                let now = Instant::now();
+                let ttl = lookup
+                    .valid_until()
+                    .saturating_duration_since(now)
+                    .clamp(self.min_ttl, self.max_ttl);
+                self.entries.lock().unwrap().insert(
+                    host.to_string(),
+                    Entry {
+                        addrs: Arc::clone(&addrs),
+                        valid_until: now + ttl,
+                    },
+                );
+                Ok(addrs)
+            }
+            Err(err) => match self.entries.lock().unwrap().get(host) {
+                Some(entry) => {
+                    warn!("DNS lookup failed for {host}, using previous addresses: {err}");
+                    Ok(Arc::clone(&entry.addrs))
+                }
+                None => Err(io::Error::other(format!(
+                    "DNS lookup failed for {host}: {err}"
+                ))),
+            },
+        }
+    }
+
+    fn is_upstream_host(&self, host: &str) -> bool {
+        self.upstream_hosts.lock().unwrap().contains(host)
+    }
+
+    async fn resolve(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        let cached = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(host)
+            .filter(|entry| Instant::now() < entry.valid_until)
+            .map(|entry| Arc::clone(&entry.addrs));
+        match cached {
+            Some(addrs) => Ok(addrs),
+            None => self.lookup(host).await,
+        }
+    }
+}
+
+/// Sets up the resolver from the system configuration (`/etc/resolv.conf`
+/// and `/etc/hosts`). Only the first call has an effect.
+pub fn init(config: &DnsConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if RESOLVER.get().is_some() {
+        return Ok(());
+    }
+    let mut builder = TokioResolver::builder_tokio()?;
+    let options = builder.options_mut();
+    options.positive_min_ttl = Some(config.min_ttl);
+    options.positive_max_ttl = Some(config.max_ttl);
+    let _ = RESOLVER.set(Dns {
+        resolver: builder.build()?,
+        min_ttl: config.min_ttl,
+        max_ttl: config.max_ttl,
+        upstream_hosts: Mutex::new(HashSet::new()),
+        entries: Mutex::new(HashMap::new()),
+        next: AtomicUsize::new(0),
+    });
+    Ok(())
+}
+
+/// The addresses to try for `host` and `port`, in order. The order rotates
+/// from one call to the next so connections spread over every address.
+/// Hosts that are IP literals are returned as they are.
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_matches(['[', ']']);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let Some(dns) = RESOLVER.get() else {
+        // Not initialized when connecting outside a running relay
+        return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+    };
+    let addrs = dns.resolve(host).await?;
+    let start = dns.next.fetch_add(1, Ordering::Relaxed);
+    Ok((0..addrs.len())
+        .map(|offset| SocketAddr::new(addrs[(start + offset) % addrs.len()], port))
+        .collect())
+}
+
+/// Keeps the addresses of the upstream `hosts` resolved, looking each up
+/// again shortly before its cached answer expires.
+pub fn spawn_refresh(hosts: Vec<String>) {
+    let Some(dns) = RESOLVER.get() else {
+        return;
+    };
+    let hosts: Vec<String> = hosts
+        .into_iter()
+        .map(|host| host.trim_matches(['[', ']']).to_string())
+        .filter(|host| host.parse::<IpAddr>().is_err())
+        .collect();
+    dns.upstream_hosts
+        .lock()
+        .unwrap()
+        .extend(hosts.iter().cloned());
+    if hosts.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            for host in &hosts {
+                let due = dns
+                    .entries
+                    .lock()
+                    .unwrap()
+                    .get(host)
+                    .is_none_or(|entry| entry.valid_until <= Instant::now() + REFRESH_AHEAD);
+                if due {
+                    if let Err(err) = dns.lookup(host).await {
+                        warn!("{err}");
+                    }
+                }
+            }
+            let next_expiry = {
+                let entries = dns.entries.lock().unwrap();
+                hosts
+                    .iter()
+                    .filter_map(|host| entries.get(host))
+                    .map(|entry| entry.valid_until)
+                    .min()
+            };
+            let wait = next_expiry
+                .map_or(dns.min_ttl, |expiry| {
+                    expiry.saturating_duration_since(Instant::now() + REFRESH_AHEAD)
+                })
+                .max(REFRESH_AHEAD);
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
@@ -0,0 +1,151 @@
+use hyper::header::InvalidHeaderValue;
+use hyper::http::uri::InvalidUriParts;
+use hyper::StatusCode;
+use std::error::Error;
+use std::io;
+
+use crate::limits::is_timeout;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Why relay couldn't do what was asked of it. Each kind maps to the status
+/// sent to the client in its place and to a metric label.
+#[derive(Debug, thiserror::Error)]
+pub enum RelayError {
+    /// The configuration couldn't be read or is invalid
+    #[error("{0}")]
+    Config(#[source] BoxError),
+    /// No connection to the upstream could be opened
+    #[error("failed to connect to upstream: {0}")]
+    UpstreamConnect(#[source] BoxError),
+    /// The upstream didn't respond in time
+    #[error("upstream timed out: {0}")]
+    UpstreamTimeout(#[source] BoxError),
+    /// The upstream was reached but the exchange with it failed
+    #[error("upstream request failed: {0}")]
+    UpstreamProtocol(#[source] BoxError),
+    /// The upstream answered with a status treated as a failure
+    #[error("upstream returned {0}")]
+    UpstreamStatus(StatusCode),
+    #[error("storage error: {0}")]
+    Storage(#[source] BoxError),
+    #[error("TLS error: {0}")]
+    Tls(#[source] BoxError),
+    #[error("{0}")]
+    Internal(#[source] BoxError),
+}
+
+impl RelayError {
+    pub fn config(error: impl Into<BoxError>) -> Self {
+        Self::Config(error.into())
+    }
+
+    /// A failure to connect, or a timeout when the attempt timed out.
+    pub fn connect(error: impl Into<BoxError>) -> Self {
+        let error = error.into();
+        if is_timeout(error.as_ref()) {
+            Self::UpstreamTimeout(error)
+        } else {
+            Self::UpstreamConnect(error)
+        }
+    }
+
+    /// A failure on an open upstream connection, or a timeout when the
+    /// exchange timed out.
+    pub fn protocol(error: impl Into<BoxError>) -> Self {
+        let error = error.into();
+        if is_timeout(error.as_ref()) {
+            Self::UpstreamTimeout(error)
+        } else {
+            Self::UpstreamProtocol(error)
+        }
+    }
+
+    pub fn internal(error: impl Into<BoxError>) -> Self {
+        Self::Internal(error.into())
+    }
+
+    pub fn storage(error: impl Into<BoxError>) -> Self {
+        Self::Storage(error.into())
+    }
+
+    pub fn tls(error: impl Into<BoxError>) -> Self {
+        Self::Tls(error.into())
+    }
+
+    /// Status of the response sent in place of the one that failed.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::UpstreamConnect(_)
+            | Self::UpstreamProtocol(_)
+            | Self::UpstreamStatus(_)
+            | Self::Tls(_) => StatusCode::BAD_GATEWAY,
+            Self::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Config(_) | Self::Storage(_) | Self::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Value of the `kind` label on error metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Config(_) => "config",
+            Self::UpstreamConnect(_) => "upstream_connect",
+            Self::UpstreamTimeout(_) => "upstream_timeout",
+            Self::UpstreamProtocol(_) => "upstream_protocol",
+            Self::UpstreamStatus(_) => "upstream_status",
+            Self::Storage(_) => "storage",
+            Self::Tls(_) => "tls",
+            Self::Internal(_) => "internal",
+        }
+    }
+}
+
+/// Recovers a `RelayError` passed along as a boxed error, classifying any
+/// other error by its type.
+impl From<BoxError> for RelayError {
+    fn from(error: BoxError) -> Self {
+        let error = match error.downcast::<RelayError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        if is_timeout(error.as_ref()) {
+            Self::UpstreamTimeout(error)
+        } else if error.is::<hyper::Error>() {
+            Self::UpstreamProtocol(error)
+        } else {
+            Self::Internal(error)
+        }
+    }
+}
+
+impl From<hyper::Error> for RelayError {
+    fn from(error: hyper::Error) -> Self {
+        Self::protocol(error)
+    }
+}
+
+impl From<hyper::http::Error> for RelayError {
+    fn from(error: hyper::http::Error) -> Self {
+        Self::Internal(error.into())
+    }
+}
+
+impl From<io::Error> for RelayError {
+    fn from(error: io::Error) -> Self {
+        Self::Internal(error.into())
+    }
+}
+
+impl From<InvalidHeaderValue> for RelayError {
+    fn from(error: InvalidHeaderValue) -> Self {
+        Self::Internal(error.into())
+    }
+}
+
+impl From<InvalidUriParts> for RelayError {
+    fn from(error: InvalidUriParts) -> Self {
+        Self::Internal(error.into())
+    }
+}
@@ -0,0 +1,99 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::http::response::Builder;
+use hyper::{Response, StatusCode};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::config::ErrorPageConfig;
+use crate::handlers::{full, Body};
+
+struct ErrorPage {
+    body: Bytes,
+    content_type: HeaderValue,
+}
+
+/// Custom bodies for responses relay generates itself, such as 502 when the
+/// upstream is down or 429 when a client is rate limited.
+pub struct ErrorPages {
+    pages: HashMap<StatusCode, ErrorPage>,
+}
+
+impl ErrorPages {
+    /// Resolves the configured pages, reading any files once at startup.
+    pub fn load(
+        config: &HashMap<String, ErrorPageConfig>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut pages = HashMap::new();
+        for (status, page) in config {
+            let status = status
+                .parse::<u16>()
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .ok_or_else(|| format!("Invalid error page status: {status}"))?;
+
+            let (body, guessed_type) = match (&page.body, &page.file) {
+                (Some(body), None) => (body.clone().into_bytes(), sniff_content_type(body)),
+                (None, Some(file)) => {
+                    let body = std::fs::read(file)
+                        .map_err(|e| format!("Failed to read error page {file}: {e}"))?;
+                    (body, content_type_for_file(Path::new(file)))
+                }
+                _ => {
+                    return Err(
+                        format!("Error page {status} must set exactly one of body or file").into(),
+                    )
+                }
+            };
+            let content_type = match &page.content_type {
+                Some(content_type) => HeaderValue::from_str(content_type)?,
+                None => HeaderValue::from_static(guessed_type),
+            };
+
+            pages.insert(
+                status,
+                ErrorPage {
+                    body: Bytes::from(body),
+                    content_type,
+                },
+            );
+        }
+        Ok(Self { pages })
+    }
+
+    /// Finishes `builder` as a `status` response with the configured page, or
+    /// with `default_body` as plain text when none is configured.
+    pub fn response(
+        &self,
+        builder: Builder,
+        status: StatusCode,
+        default_body: &str,
+    ) -> Result<Response<Body>, hyper::http::Error> {
+        let builder = builder.status(status);
+        match self.pages.get(&status) {
+            Some(page) => builder
+                .header(CONTENT_TYPE, page.content_type.clone())
+                .body(full(page.body.clone())),
+            None => builder
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(full(Bytes::from(default_body.to_string()))),
+        }
+    }
+}
+
+fn content_type_for_file(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+fn sniff_content_type(body: &str) -> &'static str {
+    match body.trim_start().chars().next() {
+        Some('<') => "text/html; charset=utf-8",
+        Some('{') | Some('[') => "application/json",
+        _ => "text/plain; charset=utf-8",
+    }
+}
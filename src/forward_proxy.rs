@@ -0,0 +1,119 @@
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use hyper_util::rt::TokioIo;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use crate::error::RelayError;
+use crate::geoip::client_country;
+use crate::handlers::{full, AppState, Body};
+use crate::logger::{AccessLogEntry, CacheStatus};
+
+/// True for `CONNECT` and for HTTP/1 requests with an absolute-form URI
+/// (`GET http://host/path`), which clients only send to a forward proxy.
+/// HTTP/2 and HTTP/3 requests always carry an authority, so they never count.
+pub fn is_forward_request(req: &Request<Incoming>) -> bool {
+    req.method() == Method::CONNECT
+        || (req.version() < Version::HTTP_2 && req.uri().scheme_str() == Some("http"))
+}
+
+/// Reduces a request URI to its path and query, so only forward-proxy
+/// requests keep an authority past this point.
+pub fn to_origin_form(req: &mut Request<Incoming>) {
+    if req.uri().authority().is_none() {
+        return;
+    }
+    if let Some(path_and_query) = req.uri().path_and_query().cloned() {
+        *req.uri_mut() = Uri::from(path_and_query);
+    }
+}
+
+/// Answers `CONNECT host:port` by opening a TCP connection to the target
+/// and, after replying `200`, tunneling bytes both ways. The traffic inside,
+/// normally TLS, is never inspected or cached.
+pub async fn proxy_connect(
+    mut req: Request<Incoming>,
+    state: &AppState,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let authority = req
+        .uri()
+        .authority()
+        .ok_or("CONNECT request has no host:port")?
+        .clone();
+
+    let port = authority.port_u16().unwrap_or(443);
+    let status = if state.forward_proxy.connect_ports.contains(&port) {
+        StatusCode::OK
+    } else {
+        StatusCode::FORBIDDEN
+    };
+    if status == StatusCode::FORBIDDEN {
+        log_connect(state, &req, authority.as_str(), status, start, remote_addr);
+        debug!("CONNECT refused: {authority} (port {port} not allowed)");
+        return Ok(state
+            .error_pages
+            .response(Response::builder(), status, "Forbidden")?);
+    }
+
+    // Logged once the outcome is known, with the status the client gets
+    let mut upstream = match TcpStream::connect((authority.host(), port)).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            let err = RelayError::connect(err);
+            log_connect(
+                state,
+                &req,
+                authority.as_str(),
+                err.status(),
+                start,
+                remote_addr,
+            );
+            return Err(err.into());
+        }
+    };
+    log_connect(state, &req, authority.as_str(), status, start, remote_addr);
+    let target = authority.to_string();
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(client) => {
+                let mut client = TokioIo::new(client);
+                if let Err(err) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("CONNECT tunnel closed with error: {target} - {err}");
+                }
+            }
+            Err(err) => warn!("CONNECT upgrade failed: {target} - {err}"),
+        }
+    });
+
+    debug!("CONNECT: {authority}");
+    Ok(Response::builder().status(status).body(full(""))?)
+}
+
+fn log_connect(
+    state: &AppState,
+    req: &Request<Incoming>,
+    authority: &str,
+    status: StatusCode,
+    start: Instant,
+    remote_addr: SocketAddr,
+) {
+    if state.access_log.enabled() {
+        state.access_log.log(AccessLogEntry {
+            method: Method::CONNECT,
+            path: authority.to_string(),
+            status: status.as_u16(),
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            cache_status: CacheStatus::Bypass,
+            remote_addr,
+            bytes_sent: 0,
+            country: client_country(req.extensions()),
+            request_headers: state.access_log.request_headers(req.headers()),
+            response_headers: None,
+        });
+    }
+}
@@ -0,0 +1,63 @@
+use hyper::header::HeaderName;
+use hyper::http::Extensions;
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+use crate::config::GeoIpConfig;
+use crate::error::BoxError;
+
+/// The client's country code, attached to a request once looked up
+#[derive(Clone)]
+pub struct ClientCountry(pub String);
+
+/// The part of a Country or City database record relay reads.
+#[derive(Deserialize)]
+struct Record<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+/// Looks up the country of client addresses in a MaxMind database, read
+/// into memory at startup.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    header: Option<HeaderName>,
+}
+
+impl GeoIp {
+    pub fn new(config: &GeoIpConfig) -> Result<Self, BoxError> {
+        let reader = Reader::open_readfile(&config.database)
+            .map_err(|e| format!("Failed to open GeoIP database {}: {e}", config.database))?;
+        let header = if config.header.is_empty() {
+            None
+        } else {
+            Some(HeaderName::from_bytes(config.header.as_bytes())?)
+        };
+        Ok(Self { reader, header })
+    }
+
+    /// The ISO 3166-1 code of the country `ip` is in, when the database
+    /// knows it.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: Record = self.reader.lookup(ip).ok()??;
+        Some(record.country?.iso_code?.to_string())
+    }
+
+    /// The request header the country is sent upstream in, if any
+    pub fn header(&self) -> Option<&HeaderName> {
+        self.header.as_ref()
+    }
+}
+
+/// The country `handle_request` looked up for a request, if any.
+pub fn client_country(extensions: &Extensions) -> Option<String> {
+    extensions
+        .get::<ClientCountry>()
+        .map(|ClientCountry(country)| country.clone())
+}
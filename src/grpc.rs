@@ -0,0 +1,122 @@
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::header::{CONTENT_TYPE, TE};
+use hyper::{Request, Response, StatusCode};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+use crate::cache::is_hop_by_hop;
+use crate::geoip::client_country;
+use crate::handlers::{upstream_uri, AppState, Body};
+use crate::logger::{AccessLogEntry, CacheStatus};
+use crate::upstream::Http2Upstream;
+
+/// True for `application/grpc` and its variants such as `application/grpc+proto`
+/// and `application/grpc-web`.
+pub fn is_grpc_content_type(content_type: &str) -> bool {
+    content_type
+        .trim_start()
+        .to_ascii_lowercase()
+        .starts_with("application/grpc")
+}
+
+pub fn is_grpc_request(req: &Request<Incoming>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_grpc_content_type)
+}
+
+/// Proxies a gRPC call to the upstream over HTTP/2 (prior knowledge, h2c),
+/// streaming the request and response bodies and passing trailers, which
+/// carry `grpc-status`, through untouched.
+pub async fn proxy_grpc(
+    req: Request<Incoming>,
+    state: &AppState,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let upstream_url = state.upstream_for(&req).to_string();
+    let base_url = state.upstream_base(&upstream_url)?;
+
+    let (parts, body) = req.into_parts();
+    let uri = upstream_uri(&base_url, &parts.uri)?;
+
+    // `te: trailers` is hop-by-hop, but gRPC servers require it
+    let mut builder = Request::builder().method(parts.method.clone()).uri(uri);
+    for (name, value) in parts
+        .headers
+        .iter()
+        .filter(|(name, _)| *name == TE || !is_hop_by_hop(name))
+    {
+        builder = builder.header(name, value);
+    }
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(state.upstream_request_headers.clone());
+    }
+    // Streamed bodies without a Content-Length are cut off at the limit. The
+    // upstream only sees its stream reset, so whether that's why the call
+    // failed is noted on the way
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let body = Limited::new(body, state.limits.max_body_size as usize).map_err({
+        let exceeded = Arc::clone(&exceeded);
+        move |e| {
+            if e.is::<LengthLimitError>() {
+                exceeded.store(true, Ordering::Relaxed);
+            }
+            e
+        }
+    });
+    let upstream_req = builder.body(body.boxed())?;
+    let sent = match state.upstream_h2.get(&upstream_url) {
+        Some(upstream_h2) => upstream_h2.send(upstream_req).await,
+        // Without a shared HTTP/2 connection, each call opens its own
+        None => {
+            Http2Upstream::new(&upstream_url, &state.upstream_tls)?
+                .send(upstream_req)
+                .await
+        }
+    };
+    let res = match sent {
+        Err(_) if exceeded.load(Ordering::Relaxed) => {
+            debug!("Request body too large: {}", parts.uri.path());
+            return Ok(state.error_pages.response(
+                Response::builder(),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Payload Too Large",
+            )?);
+        }
+        sent => sent?,
+    };
+
+    let (res_parts, res_body) = res.into_parts();
+    debug!("gRPC {}: {}", res_parts.status, parts.uri.path());
+    if state.access_log.enabled() {
+        state.access_log.log(AccessLogEntry {
+            method: parts.method.clone(),
+            path: parts.uri.path().to_string(),
+            status: res_parts.status.as_u16(),
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            cache_status: CacheStatus::Bypass,
+            remote_addr,
+            bytes_sent: 0,
+            country: client_country(&parts.extensions),
+            request_headers: state.access_log.request_headers(&parts.headers),
+            response_headers: state.access_log.response_headers(&res_parts.headers),
+        });
+    }
+
+    let mut response = Response::builder().status(res_parts.status);
+    for (name, value) in res_parts
+        .headers
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+    {
+        response = response.header(name, value);
+    }
+    Ok(response.body(res_body.map_err(Into::into).boxed())?)
+}
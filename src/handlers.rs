@@ -1,329 +1,2019 @@
+use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::Bytes;
-use hyper::{Request, Response};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, CACHE_CONTROL,
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, ETAG, HOST, LAST_MODIFIED, SET_COOKIE, VARY,
+    WARNING,
+};
+use hyper::http::response::Builder;
+use hyper::http::uri::{Parts, PathAndQuery, Scheme};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use prometheus::{Encoder, TextEncoder};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{debug, warn};
 
-use crate::cache::CachedResponse;
-use crate::config::CacheConfig;
-use crate::logger::{log_access, AccessLogEntry, CacheStatus};
+use crate::access::{AccessControl, DebugAccess};
+use crate::admin::handle_admin;
+use crate::auth::EndpointAuth;
+use crate::balancer::{Balancer, PinnedUpstream};
+use crate::buffer::{buffer, Buffered};
+use crate::cache::{
+    heuristic_ttl, is_hop_by_hop, is_negative_status, CachedResponse, ClientCacheControl,
+};
+use crate::cache_key::{cookies, generate_cache_key, name_matches, varied_headers};
+use crate::cluster::Cluster;
+use crate::compression::{self, Compression};
+use crate::config::{
+    AdminConfig, CacheConfig, CacheRule, CompiledRule, ForwardProxyConfig, LimitsConfig,
+};
+use crate::cors::{self, Cors};
+use crate::dashboard::Dashboard;
+use crate::device::DeviceClass;
+use crate::error::{BoxError, RelayError};
+use crate::error_pages::ErrorPages;
+use crate::forward_proxy::{is_forward_request, proxy_connect, to_origin_form};
+use crate::geoip::{client_country, ClientCountry, GeoIp};
+use crate::grpc::{is_grpc_request, proxy_grpc};
+use crate::hedge::Hedging;
+use crate::jwt::JwtAuth;
+use crate::logger::{AccessLog, AccessLogEntry, CacheStatus, CACHE_DECISIONS};
 use crate::metrics::{
-    CACHE_HITS, CACHE_MISSES, CACHE_SIZE, CACHE_STALE_SERVED, REQUEST_DURATION, UPSTREAM_ERRORS,
+    observe_upstream_response, InFlightRequest, CACHE_HITS, CACHE_MISSES, CACHE_SERVED_BYTES,
+    CACHE_STALE_SERVED, ERRORS, HEDGED_REQUESTS, HEDGE_WINS, LOAD_SHED, ORIGIN_FETCHED_BYTES,
+    QUOTA_EXCEEDED, RATE_LIMITED, REQUESTS, REQUEST_DURATION, SPLIT_DURATION, SPLIT_ERRORS,
+    SPLIT_REQUESTS, UPSTREAM_ERRORS,
 };
+use crate::mirror::Mirrors;
+use crate::plugin::{CacheKeyOverride, Plugin, UpstreamOverride};
+use crate::quota::{QuotaCheck, Quotas};
+use crate::range::{ByteRange, RangeRequest};
+use crate::rate_limit::RateLimiter;
+use crate::redirect::Redirects;
+use crate::refresh;
+use crate::signed_url::SignedUrls;
+use crate::spa::{self, ShellTtl};
+use crate::split::Splits;
+use crate::static_files::{self, StaticFile};
 use crate::storage::Cache;
+use crate::transform::Transforms;
+use crate::upgrade::{is_upgrade_request, proxy_upgrade};
+use crate::upstream::{connect, parse_url, request_scheme, Http2Upstream, CLIENT_ADDR};
+
+/// Response body type for every handler: either a buffered body or an
+/// upstream body streamed through as it arrives.
+pub type Body = BoxBody<Bytes, BoxError>;
+
+/// Wraps a complete, in-memory body.
+pub fn full(bytes: impl Into<Bytes>) -> Body {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// Subject of the certificate a client authenticated with over mTLS, attached
+/// to each request on the connection.
+#[derive(Clone)]
+pub struct ClientSubject(pub String);
+
+/// A concurrency permit, attached to the request it was taken for so a
+/// detached miss fetch can keep it.
+#[derive(Clone)]
+struct RequestPermit {
+    _permit: Arc<OwnedSemaphorePermit>,
+}
+
+/// Set on requests from a listener that doesn't serve `/metrics` or the
+/// admin API; those paths are proxied like any other.
+#[derive(Clone, Copy)]
+pub struct AdminHidden;
+
+/// Shared state handed to every request handler.
+pub struct AppState {
+    pub upstream_url: Arc<String>,
+    pub upstream_error_body: Option<String>,
+    /// Added to every request sent to the upstream, from `upstream.headers`
+    pub upstream_request_headers: HeaderMap,
+    /// Shared connections, by server URL, used when `upstream.http_version`
+    /// is "2"
+    pub upstream_h2: HashMap<String, Http2Upstream>,
+    /// Chooses among the servers in `upstream.urls`
+    pub balancer: Balancer,
+    pub hedging: Option<Hedging>,
+    /// TLS settings for `https://` upstreams
+    pub upstream_tls: Arc<ClientConfig>,
+    pub error_pages: ErrorPages,
+    pub cache: Cache,
+    pub prometheus_enabled: Arc<bool>,
+    pub access_log: Arc<AccessLog>,
+    pub cache_config: Arc<CacheConfig>,
+    pub rate_limiter: RateLimiter,
+    pub debug_access: DebugAccess,
+    pub admin_config: AdminConfig,
+    /// Credentials required for `/metrics`, when configured
+    pub metrics_auth: Option<EndpointAuth>,
+    /// Credentials required for the admin API, when configured
+    pub admin_auth: Option<EndpointAuth>,
+    /// Stats for the admin dashboard, when it's served
+    pub dashboard: Option<Dashboard>,
+    /// Carries the mTLS client certificate subject to the upstream
+    pub client_cert_header: Option<HeaderName>,
+    pub forward_proxy: ForwardProxyConfig,
+    pub redirects: Redirects,
+    pub compression: Compression,
+    pub transforms: Transforms,
+    pub mirrors: Mirrors,
+    pub splits: Splits,
+    pub cors: Cors,
+    /// Added to responses without them, from `[headers.security]`
+    pub security_headers: HeaderMap,
+    pub limits: LimitsConfig,
+    pub access: AccessControl,
+    /// Verifies bearer tokens on routes configured under `[jwt]`
+    pub jwt: Option<JwtAuth>,
+    pub signed_urls: Option<SignedUrls>,
+    pub quotas: Option<Quotas>,
+    /// Looks up client countries when `[geoip]` is configured
+    pub geoip: Option<GeoIp>,
+    /// Bounds concurrent requests when `server.max_concurrent_requests` is set
+    pub concurrency_limit: Option<Arc<Semaphore>>,
+    /// Keys whose last response couldn't be stored, passed to the upstream
+    /// until they expire, when `cache.hit_for_pass_ttl` is set
+    pub hit_for_pass: Option<moka::sync::Cache<String, ()>>,
+    /// Cache keys with a background refresh in flight
+    pub refreshing: Mutex<HashSet<String>>,
+    /// Current cache namespace, initialized from config and rotatable at runtime
+    pub namespace: RwLock<String>,
+    /// Propagates invalidations to other instances when configured
+    pub cluster: Option<Cluster>,
+    /// Cache keys (without namespace) kept warm by rules with a
+    /// `refresh_interval`, mapped to what to fetch
+    pub refresh_ahead: Mutex<HashMap<String, refresh::Tracked>>,
+    /// Hooks registered by an embedding program
+    pub plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl AppState {
+    /// Sends a bodyless request for the path and query of `incoming_uri` to
+    /// the upstream, over the shared HTTP/2 connection when one is configured.
+    /// `headers` are added to the request. `upstream` replaces the configured
+    /// upstream URL when set.
+    pub async fn request_upstream(
+        &self,
+        incoming_uri: &hyper::Uri,
+        method: Method,
+        headers: &HeaderMap,
+        upstream: Option<&str>,
+    ) -> Result<Response<hyper::body::Incoming>, RelayError> {
+        let res = self
+            .send_request(incoming_uri, method, headers, upstream)
+            .await?;
+        if self.plugins.is_empty() {
+            return Ok(res);
+        }
+        let (mut parts, body) = res.into_parts();
+        for plugin in &self.plugins {
+            plugin.on_upstream_response(incoming_uri, &mut parts).await;
+        }
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Fetches a cacheable GET like `request_upstream`, following redirects
+    /// that stay on the same upstream up to `redirects.follow` times.
+    pub async fn fetch_upstream(
+        &self,
+        incoming_uri: &hyper::Uri,
+        headers: &HeaderMap,
+        upstream: Option<&str>,
+    ) -> Result<Response<hyper::body::Incoming>, RelayError> {
+        if self.forward_authority(incoming_uri).is_some() {
+            return self
+                .request_upstream(incoming_uri, Method::GET, headers, upstream)
+                .await;
+        }
+        let mut res = self.hedged_get(incoming_uri, headers, upstream).await?;
+        for _ in 0..self.redirects.follow() {
+            let Some(target) = self
+                .redirects
+                .follow_target(res.status(), res.headers(), upstream)
+            else {
+                break;
+            };
+            res = self.hedged_get(&target, headers, upstream).await?;
+        }
+        Ok(res)
+    }
+
+    /// Sends a GET and, when hedging is configured and no response arrives
+    /// within the hedging delay, a second one to another server if there's
+    /// one available, using whichever answers first. A failure waits for the
+    /// other request.
+    async fn hedged_get(
+        &self,
+        incoming_uri: &hyper::Uri,
+        headers: &HeaderMap,
+        upstream: Option<&str>,
+    ) -> Result<Response<hyper::body::Incoming>, RelayError> {
+        // An override names the one upstream to use, so there's no other
+        // server to hedge to
+        let delay = self.hedging.as_ref().and_then(Hedging::delay);
+        let (Some(delay), None) = (delay, upstream) else {
+            return self
+                .request_upstream(incoming_uri, Method::GET, headers, upstream)
+                .await;
+        };
+        let first = self.balancer.pick(incoming_uri);
+        let primary = self.request_upstream(incoming_uri, Method::GET, headers, Some(first));
+        tokio::pin!(primary);
+        tokio::select! {
+            res = &mut primary => return res,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        // Sending the same request to the same server again wouldn't help
+        let Some(second) = self.balancer.pick_other(first) else {
+            return primary.await;
+        };
+        if *self.prometheus_enabled {
+            HEDGED_REQUESTS.inc();
+        }
+        let hedge = self.request_upstream(incoming_uri, Method::GET, headers, Some(second));
+        tokio::pin!(hedge);
+        tokio::select! {
+            res = &mut primary => match res {
+                Ok(res) => Ok(res),
+                Err(_) => hedge.await,
+            },
+            res = &mut hedge => match res {
+                Ok(res) => {
+                    if *self.prometheus_enabled {
+                        HEDGE_WINS.inc();
+                    }
+                    Ok(res)
+                }
+                Err(_) => primary.await,
+            },
+        }
+    }
+
+    async fn send_request(
+        &self,
+        incoming_uri: &hyper::Uri,
+        method: Method,
+        headers: &HeaderMap,
+        upstream: Option<&str>,
+    ) -> Result<Response<hyper::body::Incoming>, RelayError> {
+        if let Some(authority) = self.forward_authority(incoming_uri) {
+            let mut parts = Parts::default();
+            parts.scheme = Some(Scheme::HTTP);
+            parts.authority = Some(authority.clone());
+            parts.path_and_query = Some(PathAndQuery::from_static("/"));
+            return send_upstream_with_method(
+                &hyper::Uri::from_parts(parts)?,
+                &self.upstream_tls,
+                incoming_uri,
+                method,
+                headers,
+            )
+            .await;
+        }
+        let url = upstream.unwrap_or_else(|| self.balancer.pick(incoming_uri));
+        let base_url = self.upstream_base(url)?;
+        let mut headers = Cow::Borrowed(headers);
+        if !self.upstream_request_headers.is_empty() {
+            headers
+                .to_mut()
+                .extend(self.upstream_request_headers.clone());
+        }
+        let headers = headers.as_ref();
+        let start = Instant::now();
+        let res = match self.upstream_h2.get(url) {
+            Some(upstream_h2) => {
+                let mut req = Request::builder()
+                    .method(method)
+                    .uri(upstream_uri(&base_url, incoming_uri)?)
+                    .body(full(Bytes::new()))?;
+                req.headers_mut().extend(headers.clone());
+                upstream_h2.send(req).await
+            }
+            None => {
+                send_upstream_with_method(
+                    &base_url,
+                    &self.upstream_tls,
+                    incoming_uri,
+                    method,
+                    headers,
+                )
+                .await
+            }
+        };
+        let failed = res
+            .as_ref()
+            .map_or(true, |res| res.status().is_server_error());
+        self.balancer.record(url, failed, start.elapsed());
+        if let Some(hedging) = self.hedging.as_ref().filter(|_| res.is_ok()) {
+            hedging.record(start.elapsed());
+        }
+        res
+    }
+
+    /// The parsed URL of an upstream server. Those in the pool were parsed
+    /// at startup; others, from plugins, are parsed here.
+    pub fn upstream_base(&self, url: &str) -> Result<hyper::Uri, RelayError> {
+        match self.balancer.uri(url) {
+            Some(uri) => Ok(uri.clone()),
+            None => parse_url(url).ok_or_else(|| RelayError::internal("invalid upstream url")),
+        }
+    }
+
+    /// The upstream server for a request relay passes through as-is: the one
+    /// a plugin or affinity chose, or else the balancer's pick.
+    pub fn upstream_for<'a, B>(&'a self, req: &'a Request<B>) -> &'a str {
+        let extensions = req.extensions();
+        if let Some(UpstreamOverride(url)) = extensions.get::<UpstreamOverride>() {
+            return url;
+        }
+        if let Some(PinnedUpstream(url)) = extensions.get::<PinnedUpstream>() {
+            return url;
+        }
+        self.balancer.pick(req.uri())
+    }
+
+    /// Headers relay sends to the upstream on a client's behalf: the claims
+    /// of a verified JWT, the subject of an mTLS client certificate and the
+    /// client's country.
+    pub fn upstream_headers(&self, headers: &HeaderMap) -> HeaderMap {
+        let mut upstream_headers = self
+            .jwt
+            .as_ref()
+            .map(|jwt| jwt.claim_headers(headers))
+            .unwrap_or_default();
+        let geoip_header = self.geoip.as_ref().and_then(GeoIp::header);
+        for name in self.client_cert_header.iter().chain(geoip_header) {
+            if let Some(value) = headers.get(name) {
+                upstream_headers.insert(name.clone(), value.clone());
+            }
+        }
+        upstream_headers
+    }
+
+    /// The host a forward-proxy request goes to instead of the upstream.
+    /// Only forward-proxy requests keep an authority in their URI.
+    pub fn forward_authority<'a>(
+        &self,
+        incoming_uri: &'a hyper::Uri,
+    ) -> Option<&'a hyper::http::uri::Authority> {
+        if !self.forward_proxy.enabled {
+            return None;
+        }
+        incoming_uri.authority()
+    }
+
+    /// Whether every plugin lets `response` be stored under `key`.
+    pub async fn may_store(&self, key: &str, response: &CachedResponse) -> bool {
+        for plugin in &self.plugins {
+            if !plugin.on_cache_store(key, response).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Prefixes a cache key with the current namespace.
+    pub fn storage_key(&self, key: String) -> String {
+        let namespace = self.namespace.read().unwrap();
+        if namespace.is_empty() {
+            key
+        } else {
+            format!("{namespace}:{key}")
+        }
+    }
+}
 
 struct RequestContext {
     prometheus_enabled: Arc<bool>,
-    logging_enabled: Arc<bool>,
+    access_log: Arc<AccessLog>,
     start: Instant,
-    method: String,
+    request_headers: Option<String>,
+    country: Option<String>,
+    method: Method,
     path: String,
     remote_addr: SocketAddr,
+    debug: Option<DebugInfo>,
+}
+
+/// Details about the cache decision, echoed back as response headers when
+/// debugging is requested.
+struct DebugInfo {
+    cache_key: String,
+    rule: String,
+    upstream: String,
+}
+
+impl DebugInfo {
+    fn apply(&self, mut builder: Builder, cached: Option<&CachedResponse>) -> Builder {
+        let mut headers = vec![
+            ("X-Relay-Cache-Key", self.cache_key.clone()),
+            ("X-Relay-Rule", self.rule.clone()),
+            ("X-Relay-Upstream", self.upstream.clone()),
+        ];
+        if let Some(cached) = cached {
+            let age = cached.age();
+            headers.push(("X-Relay-Debug-TTL", cached.ttl.as_secs().to_string()));
+            headers.push(("X-Relay-Age", age.as_secs().to_string()));
+            headers.push((
+                "X-Relay-Fresh-For",
+                cached.ttl.saturating_sub(age).as_secs().to_string(),
+            ));
+        }
+        for (name, value) in headers {
+            // Keys can contain bytes that are not valid in a header value
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                builder = builder.header(name, value);
+            }
+        }
+        builder
+    }
+}
+
+fn with_debug(
+    builder: Builder,
+    debug: Option<&DebugInfo>,
+    cached: Option<&CachedResponse>,
+) -> Builder {
+    match debug {
+        Some(debug) => debug.apply(builder, cached),
+        None => builder,
+    }
 }
 
 pub async fn handle_request(
     req: Request<hyper::body::Incoming>,
-    upstream_url: Arc<String>,
-    cache: Cache,
-    prometheus_enabled: Arc<bool>,
-    logging_enabled: Arc<bool>,
-    cache_config: Arc<CacheConfig>,
+    state: Arc<AppState>,
     remote_addr: SocketAddr,
-) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
-    if req.uri().path() == "/metrics" {
-        if *prometheus_enabled {
+) -> Result<Response<Body>, RelayError> {
+    // Worked out up front so relay's own errors carry CORS headers too,
+    // letting scripts read them
+    let cors = if state.forward_proxy.enabled && is_forward_request(&req) {
+        None
+    } else {
+        state.cors.response_headers(req.uri().path(), req.headers())
+    };
+    let mut response = route_request(req, Arc::clone(&state), remote_addr).await?;
+    if let Some(cors) = cors {
+        cors::apply(response.headers_mut(), cors);
+    }
+    for (name, value) in &state.security_headers {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+    Ok(response)
+}
+
+async fn route_request(
+    mut req: Request<hyper::body::Incoming>,
+    state: Arc<AppState>,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, RelayError> {
+    let forwarded = state.forward_proxy.enabled && is_forward_request(&req);
+    if state.forward_proxy.enabled && !forwarded {
+        to_origin_form(&mut req);
+    }
+
+    for plugin in &state.plugins {
+        if let Some(response) = plugin.on_request(&mut req).await {
+            return Ok(response);
+        }
+    }
+
+    let client_ip = state.access.client_ip(remote_addr.ip(), req.headers());
+    let country = state
+        .geoip
+        .as_ref()
+        .and_then(|geoip| geoip.country(client_ip));
+    if !state
+        .access
+        .permits(client_ip, country.as_deref(), req.uri().path())
+    {
+        debug!("Access denied: {client_ip} {}", req.uri().path());
+        return Ok(state.error_pages.response(
+            Response::builder(),
+            StatusCode::FORBIDDEN,
+            "Forbidden",
+        )?);
+    }
+
+    let admin = !forwarded && req.extensions().get::<AdminHidden>().is_none();
+    if admin && req.uri().path() == "/metrics" {
+        if *state.prometheus_enabled {
+            if let Some(denied) = unauthorized(&state, state.metrics_auth.as_ref(), &req)? {
+                return Ok(denied);
+            }
             return metrics_handler().await;
         } else {
             return Ok(Response::builder()
                 .status(404)
-                .body(Full::new(Bytes::from("Not Found")))?);
+                .body(full(Bytes::from("Not Found")))?);
         }
     }
 
-    call_upstream(req, upstream_url, cache, prometheus_enabled, logging_enabled, cache_config, remote_addr).await
+    if admin && state.admin_config.enabled && req.uri().path().starts_with(&state.admin_config.path)
+    {
+        if let Some(denied) = unauthorized(&state, state.admin_auth.as_ref(), &req)? {
+            return Ok(denied);
+        }
+        return Ok(handle_admin(req, state, client_ip).await?);
+    }
+
+    // Answered before rate limits and authentication, since browsers send
+    // preflights without credentials
+    if !forwarded
+        && state
+            .cors
+            .is_preflight(req.method(), req.uri().path(), req.headers())
+    {
+        let Some(headers) = state.cors.preflight(req.uri().path(), req.headers()) else {
+            debug!("CORS preflight refused: {}", req.uri().path());
+            return Ok(state.error_pages.response(
+                Response::builder(),
+                StatusCode::FORBIDDEN,
+                "Forbidden",
+            )?);
+        };
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(full(Bytes::new()))?;
+        *response.headers_mut() = headers;
+        return Ok(response);
+    }
+
+    if state.rate_limiter.enabled() {
+        let bot = state.rate_limiter.limits_bots()
+            && DeviceClass::from_headers(req.headers()) == DeviceClass::Bot;
+        if let Err(retry_after) = state.rate_limiter.check(client_ip, req.uri().path(), bot) {
+            if *state.prometheus_enabled {
+                RATE_LIMITED.inc();
+            }
+            debug!("Rate limited: {} {}", client_ip, req.uri().path());
+            return Ok(state.error_pages.response(
+                Response::builder()
+                    .header("Retry-After", retry_after.as_secs_f64().ceil().to_string()),
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too Many Requests",
+            )?);
+        }
+    }
+
+    let quota_usage = match &state.quotas {
+        Some(quotas) if !forwarded => {
+            match quotas.check(client_ip, req.headers(), req.uri()).await {
+                QuotaCheck::Allowed(usage) => Some(usage),
+                QuotaCheck::Unknown => {
+                    debug!("Missing or unknown API key: {}", req.uri().path());
+                    return Ok(state.error_pages.response(
+                        Response::builder(),
+                        StatusCode::UNAUTHORIZED,
+                        "Unauthorized",
+                    )?);
+                }
+                QuotaCheck::Exceeded(usage) => {
+                    if *state.prometheus_enabled {
+                        QUOTA_EXCEEDED.inc();
+                    }
+                    debug!("Quota exceeded: {}", req.uri().path());
+                    let mut response = state.error_pages.response(
+                        Response::builder().header("Retry-After", usage.retry_after()),
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too Many Requests",
+                    )?;
+                    usage.add_headers(response.headers_mut());
+                    return Ok(response);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // Shed load rather than queue without bound; the permit is held until
+    // the response head is ready, or by a miss fetch detached from the
+    // client until it's done
+    let permit = match &state.concurrency_limit {
+        Some(semaphore) => match Arc::clone(semaphore).try_acquire_owned() {
+            Ok(permit) => Some(RequestPermit {
+                _permit: Arc::new(permit),
+            }),
+            Err(_) => {
+                if *state.prometheus_enabled {
+                    LOAD_SHED.inc();
+                }
+                debug!("Overloaded, shedding: {}", req.uri().path());
+                return Ok(state.error_pages.response(
+                    Response::builder().header("Retry-After", "1"),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Service Unavailable",
+                )?);
+            }
+        },
+        None => None,
+    };
+    if let Some(permit) = &permit {
+        req.extensions_mut().insert(permit.clone());
+    }
+    let _in_flight = state.prometheus_enabled.then(InFlightRequest::start);
+
+    // Bodies are only read for gRPC calls, which stream them through the same
+    // limit for when there's no Content-Length
+    let body_size = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if body_size.is_some_and(|size| size > state.limits.max_body_size) {
+        debug!("Request body too large: {} {}", client_ip, req.uri().path());
+        return Ok(state.error_pages.response(
+            Response::builder(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Payload Too Large",
+        )?);
+    }
+
+    if let Some(geoip) = &state.geoip {
+        // Only relay's lookup can set the country, never the client itself
+        if let Some(name) = geoip.header() {
+            let value = country
+                .as_deref()
+                .and_then(|country| HeaderValue::from_str(country).ok());
+            let headers = req.headers_mut();
+            headers.remove(name);
+            if let Some(value) = value {
+                headers.insert(name.clone(), value);
+            }
+        }
+        if let Some(country) = country {
+            req.extensions_mut().insert(ClientCountry(country));
+        }
+    }
+
+    if let Some(name) = &state.client_cert_header {
+        // Only a verified certificate can set this, never the client itself
+        let subject = req
+            .extensions()
+            .get::<ClientSubject>()
+            .and_then(|ClientSubject(subject)| HeaderValue::from_str(subject).ok());
+        let headers = req.headers_mut();
+        headers.remove(name);
+        if let Some(subject) = subject {
+            headers.insert(name.clone(), subject);
+        }
+    }
+
+    if let Some(signed_urls) = state.signed_urls.as_ref().filter(|_| !forwarded) {
+        if signed_urls.applies_to(req.uri().path()) {
+            if let Err(reason) = signed_urls.verify(req.uri()) {
+                debug!("Signed URL rejected: {} - {reason}", req.uri().path());
+                return Ok(state.error_pages.response(
+                    Response::builder(),
+                    StatusCode::FORBIDDEN,
+                    "Forbidden",
+                )?);
+            }
+        }
+    }
+
+    if let Some(jwt) = state.jwt.as_ref().filter(|_| !forwarded) {
+        jwt.strip_claim_headers(req.headers_mut());
+        if jwt.applies_to(req.uri().path()) {
+            match jwt.verify(req.headers()).await {
+                Ok(claims) => jwt.insert_claim_headers(req.headers_mut(), &claims),
+                Err(err) => {
+                    debug!("JWT rejected: {} - {err}", req.uri().path());
+                    return Ok(state.error_pages.response(
+                        Response::builder()
+                            .header("WWW-Authenticate", JwtAuth::challenge(req.headers())),
+                        StatusCode::UNAUTHORIZED,
+                        "Unauthorized",
+                    )?);
+                }
+            }
+        }
+    }
+
+    let variant = if forwarded {
+        None
+    } else {
+        state.splits.route(&mut req, client_ip)
+    };
+    let split_start = Instant::now();
+    let mut affinity_cookie = None;
+    if !forwarded && req.extensions().get::<UpstreamOverride>().is_none() {
+        if let Some(pin) = state.balancer.pin(req.uri(), req.headers(), client_ip) {
+            req.extensions_mut()
+                .insert(PinnedUpstream(pin.url.to_string()));
+            affinity_cookie = pin.set_cookie;
+        }
+    }
+
+    // Kept for pointing upstream redirects back at relay once the request
+    // has been handed off
+    let host = req
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| {
+            req.headers()
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(str::to_string);
+    let upstream_override = req
+        .extensions()
+        .get::<UpstreamOverride>()
+        .map(|UpstreamOverride(url)| url.clone());
+    let dashboard_path = state
+        .dashboard
+        .as_ref()
+        .map(|_| req.uri().path().to_string());
+
+    let spa_rule = if forwarded || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        None
+    } else {
+        state
+            .cache_config
+            .find_rule_with_pattern(req.uri().path())
+            .filter(|matched| matched.rule.spa_fallback.is_some())
+    };
+    let spa_rule = match spa_rule {
+        Some(matched) if spa::rewrites_non_files(&matched.rule) => {
+            if !spa::is_file_path(req.uri().path()) {
+                spa::rewrite(&mut req, &matched.rule);
+            }
+            None
+        }
+        spa_rule => spa_rule,
+    };
+
+    let result = if forwarded && req.method() == Method::CONNECT {
+        proxy_connect(req, &state, remote_addr)
+            .await
+            .map_err(RelayError::from)
+    } else if is_grpc_request(&req) {
+        proxy_grpc(req, &state, remote_addr)
+            .await
+            .map_err(RelayError::from)
+    } else if is_upgrade_request(&req) {
+        proxy_upgrade(req, &state, remote_addr)
+            .await
+            .map_err(RelayError::from)
+    } else if let Some(matched) = spa_rule {
+        // Bodies of GET and HEAD requests aren't forwarded, so the request
+        // can be replayed without one
+        let (parts, body) = req.into_parts();
+        let result = fetch(
+            Request::from_parts(parts.clone(), body),
+            &state,
+            remote_addr,
+            false,
+        )
+        .await;
+        match result {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                debug!("SPA fallback: {}", parts.uri.path());
+                let mut req = Request::from_parts(parts, ());
+                spa::rewrite(&mut req, &matched.rule);
+                fetch(req, &state, remote_addr, false).await
+            }
+            result => result,
+        }
+    } else {
+        fetch(req, &state, remote_addr, forwarded).await
+    };
+    if let Some(variant) = variant.as_ref().filter(|_| *state.prometheus_enabled) {
+        let labels = [variant.split, variant.label()];
+        SPLIT_REQUESTS.with_label_values(&labels).inc();
+        SPLIT_DURATION
+            .with_label_values(&labels)
+            .observe(split_start.elapsed().as_secs_f64());
+        if result
+            .as_ref()
+            .map_or(true, |response| response.status().is_server_error())
+        {
+            SPLIT_ERRORS.with_label_values(&labels).inc();
+        }
+    }
+    let mut response = match result {
+        Ok(response) => {
+            if let (Some(dashboard), Some(path)) = (&state.dashboard, &dashboard_path) {
+                dashboard.record(path, &response);
+            }
+            response
+        }
+        Err(e) => {
+            let status = e.status();
+            if *state.prometheus_enabled {
+                ERRORS.with_label_values(&[e.kind()]).inc();
+            }
+            if let (Some(dashboard), Some(path)) = (&state.dashboard, &dashboard_path) {
+                dashboard.record_error(path, status, e.kind(), e.to_string());
+            }
+            warn!("Upstream error, responding {status}: {e}");
+            let default_body = match &state.upstream_error_body {
+                Some(body) => body.as_str(),
+                None => status.canonical_reason().unwrap_or_default(),
+            };
+            state
+                .error_pages
+                .response(Response::builder(), status, default_body)?
+        }
+    };
+    if !forwarded {
+        state.redirects.rewrite(
+            response.headers_mut(),
+            host.as_deref(),
+            upstream_override.as_deref(),
+        );
+    }
+    if let Some(cookie) = affinity_cookie {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    if let Some(usage) = quota_usage {
+        usage.add_headers(response.headers_mut());
+    }
+    Ok(response)
+}
+
+/// Answers a request from a `serve_static` directory, or through the cache
+/// from the upstream.
+async fn fetch<B: Send + Sync + 'static>(
+    req: Request<B>,
+    state: &Arc<AppState>,
+    remote_addr: SocketAddr,
+    forwarded: bool,
+) -> Result<Response<Body>, RelayError> {
+    let static_rule = if forwarded {
+        None
+    } else {
+        state
+            .cache_config
+            .find_rule_with_pattern(req.uri().path())
+            .filter(|matched| matched.rule.serve_static.is_some())
+    };
+
+    if let Some(matched) = static_rule {
+        serve_static(req, state, remote_addr, &matched).await
+    } else {
+        call_upstream(req, Arc::clone(state), remote_addr).await
+    }
+}
+
+/// Answers a request on a `serve_static` route from the rule's directory,
+/// without involving the upstream or the cache.
+async fn serve_static<B>(
+    req: Request<B>,
+    state: &AppState,
+    remote_addr: SocketAddr,
+    matched: &CompiledRule,
+) -> Result<Response<Body>, RelayError> {
+    let start = Instant::now();
+    let path = req.uri().path().to_string();
+    let delivery = Delivery::from_request(&req);
+    let file = match (req.method(), &matched.rule.serve_static) {
+        (&Method::GET | &Method::HEAD, Some(root)) => {
+            match static_files::resolve(Path::new(root), &matched.pattern, &path) {
+                Some(file) => static_files::open(
+                    file,
+                    delivery.accept_encoding.as_deref(),
+                    delivery.range.is_some(),
+                )
+                .await
+                .map_err(RelayError::internal)?,
+                None => None,
+            }
+        }
+        _ => {
+            return Ok(state.error_pages.response(
+                Response::builder().header(ALLOW, "GET, HEAD"),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed",
+            )?);
+        }
+    };
+
+    let response = match file {
+        None => {
+            debug!("Static file not found: {path}");
+            state
+                .error_pages
+                .response(Response::builder(), StatusCode::NOT_FOUND, "Not Found")?
+        }
+        Some(file) => {
+            let mut builder = Response::builder();
+            let ttl = req
+                .extensions()
+                .get::<ShellTtl>()
+                .map(|ShellTtl(ttl)| *ttl)
+                .or(matched.rule.ttl);
+            if let Some(ttl) = ttl {
+                builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl.as_secs()));
+            }
+            if static_files::is_not_modified(req.headers(), file.headers()) {
+                for name in [ETAG, LAST_MODIFIED, VARY] {
+                    if let Some(value) = file.headers().get(&name) {
+                        builder = builder.header(name, value);
+                    }
+                }
+                builder
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(full(Bytes::new()))?
+            } else {
+                for (name, value) in file.headers() {
+                    builder = builder.header(name, value);
+                }
+                match file {
+                    StaticFile::Buffered(file) => {
+                        let key = format!("static:{path}");
+                        cached_body(builder, state, &key, &path, &file, &delivery).await?
+                    }
+                    StaticFile::Streamed { path, headers, len } => {
+                        streamed_file(builder, &path, &headers, len, &delivery).await?
+                    }
+                }
+            }
+        }
+    };
+
+    let bytes_sent = if delivery.head {
+        0
+    } else {
+        response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    };
+    if *state.prometheus_enabled {
+        REQUESTS
+            .with_label_values(&[CacheStatus::Static.as_str()])
+            .inc();
+        REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
+    }
+    let access_log = &state.access_log;
+    if access_log.enabled() {
+        access_log.log(AccessLogEntry {
+            method: req.method().clone(),
+            path,
+            status: response.status().as_u16(),
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            cache_status: CacheStatus::Static,
+            remote_addr,
+            bytes_sent,
+            country: client_country(req.extensions()),
+            request_headers: access_log.request_headers(req.headers()),
+            response_headers: access_log.response_headers(response.headers()),
+        });
+    }
+    Ok(response)
+}
+
+/// Completes a response with a file too large to hold in memory, read from
+/// disk as it is sent, serving only the requested byte range when the client
+/// asked for one.
+async fn streamed_file(
+    builder: Builder,
+    path: &Path,
+    headers: &HeaderMap,
+    len: u64,
+    delivery: &Delivery,
+) -> Result<Response<Body>, RelayError> {
+    let builder = builder.header(ACCEPT_RANGES, "bytes");
+    let (builder, start, end) = match delivery
+        .range
+        .as_ref()
+        .and_then(|range| range.resolve_len(headers, len as usize))
+    {
+        Some(ByteRange::Satisfiable { start, end }) => (
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+            start as u64,
+            end as u64,
+        ),
+        Some(ByteRange::Unsatisfiable) => {
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{len}"))
+                .body(full(Bytes::new()))?);
+        }
+        None => (builder, 0, len - 1),
+    };
+    let builder = builder.header(CONTENT_LENGTH, end - start + 1);
+    let body = if delivery.head {
+        full(Bytes::new())
+    } else {
+        static_files::stream(path, start, end - start + 1)
+            .await
+            .map_err(RelayError::internal)?
+    };
+    Ok(builder.body(body)?)
+}
+
+/// A 401 challenge when `auth` is configured and the request doesn't carry
+/// matching credentials.
+fn unauthorized<B>(
+    state: &AppState,
+    auth: Option<&EndpointAuth>,
+    req: &Request<B>,
+) -> Result<Option<Response<Body>>, RelayError> {
+    let Some(auth) = auth.filter(|auth| !auth.authorize(req.headers())) else {
+        return Ok(None);
+    };
+    debug!("Unauthorized: {}", req.uri().path());
+    Ok(Some(state.error_pages.response(
+        Response::builder().header("WWW-Authenticate", auth.challenge()),
+        StatusCode::UNAUTHORIZED,
+        "Unauthorized",
+    )?))
 }
 
-pub async fn metrics_handler()
--> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn metrics_handler() -> Result<Response<Body>, RelayError> {
     let encoder = TextEncoder::new();
     let metric_families = prometheus::gather();
     let mut buffer = Vec::new();
-    encoder.encode(&metric_families, &mut buffer)?;
+    encoder
+        .encode(&metric_families, &mut buffer)
+        .map_err(RelayError::internal)?;
 
     Ok(Response::builder()
         .header("Content-Type", "text/plain; version=0.0.4")
-        .body(Full::new(Bytes::from(buffer)))?)
+        .body(full(Bytes::from(buffer)))?)
 }
 
-pub fn generate_cache_key(uri: &hyper::Uri) -> String {
-    uri.path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/")
-        .to_string()
-}
-
-pub async fn call_upstream(
-    req: Request<hyper::body::Incoming>,
-    upstream_url: Arc<String>,
-    cache: Cache,
-    prometheus_enabled: Arc<bool>,
-    logging_enabled: Arc<bool>,
-    cache_config: Arc<CacheConfig>,
+pub async fn call_upstream<B>(
+    mut req: Request<B>,
+    state: Arc<AppState>,
     remote_addr: SocketAddr,
-) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<Response<Body>, RelayError> {
+    let upstream_url = Arc::clone(&state.upstream_url);
+    let cache = Arc::clone(&state.cache);
+    let prometheus_enabled = Arc::clone(&state.prometheus_enabled);
+    let access_log = Arc::clone(&state.access_log);
+    let cache_config = Arc::clone(&state.cache_config);
+
     let start = Instant::now();
     let incoming_uri = req.uri().clone();
-    let method = req.method().to_string();
-    let cache_key = generate_cache_key(&incoming_uri);
+    let method = req.method().clone();
+    let delivery = Delivery::from_request(&req);
+    let client_cache_control = if cache_config.ignore_client_cache_control {
+        ClientCacheControl::default()
+    } else {
+        ClientCacheControl::from_headers(req.headers())
+    };
+    let request_headers = access_log.request_headers(req.headers());
+    let country = client_country(req.extensions());
+    let upstream_override = req
+        .extensions()
+        .get::<UpstreamOverride>()
+        .map(|UpstreamOverride(url)| url.clone());
+    let pinned_upstream = req
+        .extensions()
+        .get::<PinnedUpstream>()
+        .map(|PinnedUpstream(url)| url.clone());
     let path = incoming_uri.path().to_string();
 
     // Check if this path has a cache rule
-    let rule = cache_config.find_rule(&path);
+    let matched_rule = cache_config.find_rule_with_pattern(&path);
+    let rule = matched_rule.as_ref().map(|matched| &matched.rule);
+
+    let mut base_key = match req.extensions().get::<CacheKeyOverride>() {
+        Some(CacheKeyOverride(key)) => key.clone(),
+        None => generate_cache_key(&incoming_uri, req.headers(), &cache_config.key, rule),
+    };
+    if let Some(url) = &upstream_override {
+        base_key.push_str("|u:");
+        base_key.push_str(url);
+    }
+    if let Some(authority) = state.forward_authority(&incoming_uri) {
+        // Forward-proxied responses from different hosts must not collide
+        base_key.insert_str(0, authority.as_str());
+    }
+    // Claims passed upstream can change the response, so each combination
+    // of values gets its own entry
+    let mut upstream_headers = state.upstream_headers(req.headers());
+    for (name, value) in &upstream_headers {
+        let _ = write!(base_key, "|h:{name}={}", value.to_str().unwrap_or(""));
+    }
+    // Headers that vary the key are already part of it, and the upstream
+    // needs them to produce the right variant
+    upstream_headers.extend(varied_headers(req.headers(), &cache_config.key, rule));
+
+    // Refresh-ahead only fetches from the configured upstream
+    let refresh_ahead =
+        rule.is_some_and(|r| r.refresh_interval.is_some()) && upstream_override.is_none();
+    let cache_key = if refresh_ahead {
+        state.storage_key(base_key.clone())
+    } else {
+        state.storage_key(std::mem::take(&mut base_key))
+    };
+
+    // Mirrors see every request, whether or not the cache answers it
+    if state.forward_authority(&incoming_uri).is_none() {
+        let method = if req.method() == Method::HEAD {
+            Method::HEAD
+        } else {
+            Method::GET
+        };
+        state.mirrors.send(
+            &incoming_uri,
+            &method,
+            &upstream_headers,
+            &state.upstream_tls,
+        );
+    }
+
+    let client_ip = state.access.client_ip(remote_addr.ip(), req.headers());
+    let debug = state
+        .debug_access
+        .permits(client_ip, req.headers())
+        .then(|| DebugInfo {
+            cache_key: cache_key.clone(),
+            rule: matched_rule
+                .as_ref()
+                .map(|matched| matched.pattern.clone())
+                .unwrap_or_else(|| "default".to_string()),
+            upstream: match (state.forward_authority(&incoming_uri), &upstream_override) {
+                (Some(authority), _) => format!("http://{authority}"),
+                (None, Some(url)) => url.clone(),
+                (None, None) => pinned_upstream
+                    .clone()
+                    .unwrap_or_else(|| upstream_url.to_string()),
+            },
+        });
 
     // If bypass is enabled for this path, skip caching entirely
     if let Some(rule) = rule {
-        if rule.bypass == Some(true) {
-            println!("Cache BYPASS: {cache_key}");
+        if rule.bypass == Some(true) || bypassed_by_request(rule, &req) {
+            debug!(target: CACHE_DECISIONS, "Cache BYPASS: {cache_key}");
             let context = RequestContext {
                 prometheus_enabled,
-                logging_enabled,
+                access_log,
                 start,
+                request_headers,
+                country,
                 method,
                 path,
                 remote_addr,
+                debug,
             };
-            return forward_to_upstream(req, upstream_url, incoming_uri, context).await;
+            return forward_to_upstream(req, &state, incoming_uri, context, CacheStatus::Bypass)
+                .await;
         }
     }
 
-    // Determine TTL to use (rule-specific or default)
-    let ttl = rule.and_then(|r| r.ttl).unwrap_or(cache_config.default_ttl);
+    if state
+        .hit_for_pass
+        .as_ref()
+        .is_some_and(|hit_for_pass| hit_for_pass.contains_key(&cache_key))
+    {
+        debug!(target: CACHE_DECISIONS, "Cache PASS: {cache_key}");
+        let context = RequestContext {
+            prometheus_enabled,
+            access_log,
+            start,
+            request_headers,
+            country,
+            method,
+            path,
+            remote_addr,
+            debug,
+        };
+        return forward_to_upstream(req, &state, incoming_uri, context, CacheStatus::Pass).await;
+    }
+
+    if refresh_ahead {
+        refresh::track(&state, base_key, &incoming_uri, &upstream_headers);
+    }
+
+    // Determine TTL to use (an app shell's, rule-specific or default)
+    let ttl = req
+        .extensions()
+        .get::<ShellTtl>()
+        .map(|ShellTtl(ttl)| *ttl)
+        .or(rule.and_then(|r| r.ttl))
+        .unwrap_or(cache_config.default_ttl);
+
+    // Determine stale duration to use (rule-specific or default)
+    let stale_if_error = rule
+        .and_then(|r| r.stale)
+        .unwrap_or(cache_config.stale_if_error);
+
+    // Crawlers take older entries, sparing the origin
+    let bot_ttl = rule
+        .and_then(|r| r.bot_ttl)
+        .filter(|_| DeviceClass::from_headers(req.headers()) == DeviceClass::Bot);
 
     if let Some(cached_response) = cache.get(&cache_key).await {
-        if !cached_response.is_stale(ttl) {
+        let fresh = !cached_response.is_stale(cache_config.ttl_jitter)
+            || bot_ttl.is_some_and(|bot_ttl| cached_response.age() < bot_ttl);
+        if fresh && client_cache_control.accepts(&cached_response) {
             let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
-            let bytes_sent = cached_response.body.len();
+            let bytes_sent = if delivery.head {
+                0
+            } else {
+                cached_response.body.len()
+            };
 
             if *prometheus_enabled {
                 CACHE_HITS.inc();
+                REQUESTS
+                    .with_label_values(&[CacheStatus::Hit.as_str()])
+                    .inc();
+                CACHE_SERVED_BYTES.inc_by(bytes_sent as u64);
                 REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
             }
 
-            if *logging_enabled {
-                log_access(AccessLogEntry {
+            if access_log.enabled() {
+                access_log.log(AccessLogEntry {
                     method: method.clone(),
                     path: path.clone(),
-                    status: 200,
+                    status: cached_response.status.as_u16(),
                     duration_ms,
                     cache_status: CacheStatus::Hit,
                     remote_addr,
                     bytes_sent,
+                    country: country.clone(),
+                    request_headers: request_headers.clone(),
+                    response_headers: access_log.response_headers(&cached_response.headers),
                 });
             }
 
-            println!("Cache HIT: {cache_key}");
-            return Ok(Response::builder()
-                .header("X-Cache", "HIT")
-                .body(Full::new(cached_response.body.clone()))?);
+            if cache_config.early_refresh
+                && cached_response
+                    .should_refresh_early(cache_config.ttl_jitter, cache_config.early_refresh_beta)
+            {
+                spawn_refresh(
+                    Arc::clone(&state),
+                    cache_key.clone(),
+                    incoming_uri.clone(),
+                    upstream_headers.clone(),
+                    upstream_override.clone(),
+                );
+            }
+
+            debug!(target: CACHE_DECISIONS, "Cache HIT: {cache_key}");
+            let builder = with_debug(
+                cached_response.response_builder(),
+                debug.as_ref(),
+                Some(&cached_response),
+            )
+            .header("X-Cache", "HIT");
+            return Ok(cached_body(
+                builder,
+                &state,
+                &cache_key,
+                &path,
+                &cached_response,
+                &delivery,
+            )
+            .await?);
         }
     }
 
     if *prometheus_enabled {
         CACHE_MISSES.inc();
     }
-    println!("Cache MISS: {cache_key}");
-
-    let base_url = upstream_url.parse::<hyper::Uri>()?;
-
-    let host = base_url.host().expect("uri has no host").to_string();
-    let port = base_url.port_u16().unwrap_or(80);
-
-    let path_and_query = incoming_uri
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/");
-
-    let upstream_uri = format!(
-        "{}://{}{}",
-        base_url.scheme_str().unwrap_or("http"),
-        base_url.authority().expect("uri has no authority"),
-        path_and_query
-    )
-    .parse::<hyper::Uri>()?;
-
-    let address = format!("{host}:{port}");
-
-    let stream = TcpStream::connect(address).await?;
-
-    let io = TokioIo::new(stream);
-
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            println!("Connection failed: {err:?}");
-        }
-    });
+    debug!(target: CACHE_DECISIONS, "Cache MISS: {cache_key}");
 
-    let upstream_req = Request::builder()
-        .uri(upstream_uri)
-        .header(hyper::header::HOST, host)
-        .body(Empty::<Bytes>::new())?;
+    // The permit taken for this request, held until the miss is done
+    let permit = req.extensions_mut().remove::<RequestPermit>();
+    let detach = cache_config.continue_on_disconnect;
+    let miss = async move {
+        let _permit = permit;
+        let rule = matched_rule.as_ref().map(|matched| &matched.rule);
+        let fetch_start = Instant::now();
+        let upstream = state
+            .fetch_upstream(
+                &incoming_uri,
+                &upstream_headers,
+                upstream_override.as_deref().or(pinned_upstream.as_deref()),
+            )
+            .await;
+        let failure = match &upstream {
+            Ok(res)
+                if cache_config
+                    .stale_if_error_statuses
+                    .contains(&res.status().as_u16()) =>
+            {
+                let e = RelayError::UpstreamStatus(res.status());
+                Some((e.kind(), e.to_string()))
+            }
+            Ok(_) => None,
+            Err(e) => Some((e.kind(), e.to_string())),
+        };
 
-    let res = match sender.send_request(upstream_req).await {
-        Ok(r) => r,
-        Err(e) => {
+        if let Some((kind, reason)) = failure {
             if *prometheus_enabled {
-                UPSTREAM_ERRORS.inc();
+                UPSTREAM_ERRORS.with_label_values(&[kind]).inc();
             }
 
-            // Determine stale duration to use (rule-specific or default)
-            let stale_if_error = rule
-                .and_then(|r| r.stale)
-                .unwrap_or(cache_config.stale_if_error);
-
             if let Some(cached_response) = cache.get(&cache_key).await {
-                if cached_response.is_servable_if_error(ttl, stale_if_error) {
+                if cached_response.is_servable_if_error(stale_if_error) {
                     let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
-                    let bytes_sent = cached_response.body.len();
+                    let bytes_sent = if delivery.head {
+                        0
+                    } else {
+                        cached_response.body.len()
+                    };
 
                     if *prometheus_enabled {
                         CACHE_STALE_SERVED.inc();
+                        REQUESTS
+                            .with_label_values(&[CacheStatus::Stale.as_str()])
+                            .inc();
+                        CACHE_SERVED_BYTES.inc_by(bytes_sent as u64);
                         REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
                     }
 
-                    if *logging_enabled {
-                        log_access(AccessLogEntry {
+                    if access_log.enabled() {
+                        access_log.log(AccessLogEntry {
                             method: method.clone(),
                             path: path.clone(),
-                            status: 200,
+                            status: cached_response.status.as_u16(),
                             duration_ms,
                             cache_status: CacheStatus::Stale,
                             remote_addr,
                             bytes_sent,
+                            country: country.clone(),
+                            request_headers: request_headers.clone(),
+                            response_headers: access_log.response_headers(&cached_response.headers),
                         });
                     }
 
-                    println!("Cache STALE (serving due to upstream error): {cache_key} - error: {e}");
-                    return Ok(Response::builder()
-                        .header("X-Cache", "STALE")
-                        .header("X-Cache-Reason", "upstream-error")
-                        .body(Full::new(cached_response.body.clone()))?);
+                    warn!(
+                        target: CACHE_DECISIONS,
+                        "Cache STALE (serving due to upstream error): {cache_key} - error: {reason}"
+                    );
+                    let builder = with_debug(
+                        cached_response.response_builder(),
+                        debug.as_ref(),
+                        Some(&cached_response),
+                    )
+                    .header("X-Cache", "STALE")
+                    .header("X-Cache-Reason", "upstream-error")
+                    .header(WARNING, "110 - \"Response is Stale\"")
+                    .header(WARNING, "111 - \"Revalidation Failed\"");
+                    return Ok(cached_body(
+                        builder,
+                        &state,
+                        &cache_key,
+                        &path,
+                        &cached_response,
+                        &delivery,
+                    )
+                    .await?);
                 }
             }
-
-            if *prometheus_enabled {
-                REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
-            }
-            return Err(Box::new(e));
         }
-    };
 
-    let body_bytes = res.collect().await?.to_bytes();
+        let res = match upstream {
+            Ok(res) => res,
+            Err(e) => {
+                if *prometheus_enabled {
+                    REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
+                }
+                return Err(e);
+            }
+        };
 
-    cache
-        .set(
+        let content_type = res
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let content_length = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        // Streams and bodies too large to store go to the client as they
+        // arrive rather than being held in memory
+        let too_large = content_length.is_some_and(|len| len > cache_config.max_object_size);
+        let captured = if cache_config.is_streaming_content_type(content_type) || too_large {
+            Captured::TooLarge(res.map(|body| body.map_err(Into::into).boxed()))
+        } else {
+            capture_response(&cache_config, rule, res, ttl, fetch_start).await?
+        };
+        let (cached_response, cacheable) = match captured {
+            Captured::Complete(cached_response, cacheable) => (cached_response, cacheable),
+            Captured::TooLarge(res) => {
+                debug!(target: CACHE_DECISIONS, "Cache STREAM: {cache_key}");
+                let context = RequestContext {
+                    prometheus_enabled,
+                    access_log,
+                    start,
+                    request_headers,
+                    country,
+                    method,
+                    path,
+                    remote_addr,
+                    debug,
+                };
+                return Ok(stream_response(
+                    res,
+                    &cache_config,
+                    context,
+                    CacheStatus::Miss,
+                )?);
+            }
+        };
+        store_fetched(
+            &state,
             cache_key.clone(),
-            CachedResponse {
-                body: body_bytes.clone(),
-                cached_at: Instant::now(),
-            },
+            rule,
+            stale_if_error,
+            &cached_response,
+            cacheable,
         )
         .await;
 
-    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
-    let bytes_sent = body_bytes.len();
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let bytes_sent = if delivery.head {
+            0
+        } else {
+            cached_response.body.len()
+        };
 
-    if *prometheus_enabled {
-        CACHE_SIZE.set(cache.size().await as i64);
-        REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
+        if *prometheus_enabled {
+            REQUESTS
+                .with_label_values(&[CacheStatus::Miss.as_str()])
+                .inc();
+            REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
+        }
+
+        if access_log.enabled() {
+            access_log.log(AccessLogEntry {
+                method,
+                path: path.clone(),
+                status: cached_response.status.as_u16(),
+                duration_ms,
+                cache_status: CacheStatus::Miss,
+                remote_addr,
+                bytes_sent,
+                country,
+                request_headers,
+                response_headers: access_log.response_headers(&cached_response.headers),
+            });
+        }
+
+        let builder = with_debug(
+            cached_response.response_builder(),
+            debug.as_ref(),
+            Some(&cached_response),
+        )
+        .header("X-Cache", "MISS");
+        Ok(cached_body(
+            builder,
+            &state,
+            &cache_key,
+            &path,
+            &cached_response,
+            &delivery,
+        )
+        .await?)
+    };
+    if detach {
+        // Detached, so a client hanging up mid-miss doesn't abort the fetch
+        // and the response still reaches the cache
+        tokio::task::spawn(CLIENT_ADDR.scope(remote_addr, miss))
+            .await
+            .map_err(RelayError::internal)?
+    } else {
+        // Dropped along with the client connection, cancelling the fetch
+        miss.await
     }
+}
 
-    if *logging_enabled {
-        log_access(AccessLogEntry {
-            method,
-            path,
-            status: 200,
-            duration_ms,
-            cache_status: CacheStatus::Miss,
-            remote_addr,
-            bytes_sent,
+/// Completes a response with `body`. HEAD responses keep the `Content-Length`
+/// of the full body but carry no payload.
+fn finish(builder: Builder, head: bool, body: Bytes) -> Result<Response<Body>, hyper::http::Error> {
+    if head {
+        builder
+            .header(CONTENT_LENGTH, body.len())
+            .body(full(Bytes::new()))
+    } else {
+        builder.body(full(body))
+    }
+}
+
+/// How the client wants a cache entry delivered, beyond the resource itself.
+struct Delivery {
+    head: bool,
+    range: Option<RangeRequest>,
+    accept_encoding: Option<String>,
+}
+
+impl Delivery {
+    fn from_request<B>(req: &Request<B>) -> Self {
+        Self {
+            head: req.method() == Method::HEAD,
+            range: RangeRequest::from_headers(req.headers()),
+            accept_encoding: req
+                .headers()
+                .get(ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// Completes a response from a cache entry served at `path`, after any body
+/// transforms, serving only the requested byte range when the client asked
+/// for one, or otherwise a compressed body when compression applies and the
+/// client accepts it.
+async fn cached_body(
+    mut builder: Builder,
+    state: &AppState,
+    cache_key: &str,
+    path: &str,
+    cached: &CachedResponse,
+    delivery: &Delivery,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let head = delivery.head;
+    let transformed = state
+        .transforms
+        .apply(path, cached)
+        .map(|body| CachedResponse {
+            body,
+            ..cached.clone()
         });
+    if transformed.is_some() {
+        if let Some(headers) = builder.headers_mut() {
+            compression::weaken_etag(headers);
+        }
+    }
+    let cached = transformed.as_ref().unwrap_or(cached);
+    if cached.status == StatusCode::OK && !cached.headers.contains_key(ACCEPT_RANGES) {
+        builder = builder.header(ACCEPT_RANGES, "bytes");
     }
 
-    Ok(Response::builder()
-        .header("X-Cache", "MISS")
-        .body(Full::new(body_bytes))?)
+    if state.compression.applies_to(cached) {
+        // Ranges always refer to the uncompressed body
+        let compressed = match delivery.range {
+            Some(_) => None,
+            None => {
+                state
+                    .compression
+                    .compress(cache_key, cached, delivery.accept_encoding.as_deref())
+                    .await
+            }
+        };
+        if let Some(headers) = builder.headers_mut() {
+            compression::add_headers(headers, compressed.as_ref());
+        }
+        if let Some(compressed) = compressed {
+            return finish(builder, head, compressed.body);
+        }
+    }
+
+    let len = cached.body.len();
+    match delivery
+        .range
+        .as_ref()
+        .and_then(|range| range.resolve(cached))
+    {
+        Some(ByteRange::Satisfiable { start, end }) => finish(
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+            head,
+            cached.body.slice(start..=end),
+        ),
+        Some(ByteRange::Unsatisfiable) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{len}"))
+            .body(full(Bytes::new())),
+        None => finish(builder, head, cached.body.clone()),
+    }
 }
 
-async fn forward_to_upstream(
-    _req: Request<hyper::body::Incoming>,
-    upstream_url: Arc<String>,
-    incoming_uri: hyper::Uri,
-    context: RequestContext,
-) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
-    let base_url = upstream_url.parse::<hyper::Uri>()?;
-
-    let host = base_url.host().expect("uri has no host").to_string();
-    let port = base_url.port_u16().unwrap_or(80);
-
-    let path_and_query = incoming_uri
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/");
-
-    let upstream_uri = format!(
-        "{}://{}{}",
-        base_url.scheme_str().unwrap_or("http"),
-        base_url.authority().expect("uri has no authority"),
-        path_and_query
-    )
-    .parse::<hyper::Uri>()?;
+/// Opens a connection to the upstream and sends a GET for the request's
+/// path and query.
+pub async fn send_upstream(
+    base_url: &hyper::Uri,
+    tls: &Arc<ClientConfig>,
+    incoming_uri: &hyper::Uri,
+) -> Result<Response<hyper::body::Incoming>, RelayError> {
+    send_upstream_with_method(base_url, tls, incoming_uri, Method::GET, &HeaderMap::new()).await
+}
+
+/// Like `send_upstream`, with the request method given explicitly.
+pub async fn send_upstream_with_method(
+    base_url: &hyper::Uri,
+    tls: &Arc<ClientConfig>,
+    incoming_uri: &hyper::Uri,
+    method: Method,
+    headers: &HeaderMap,
+) -> Result<Response<hyper::body::Incoming>, RelayError> {
+    let host = base_url
+        .host()
+        .ok_or_else(|| RelayError::connect("upstream url has no host"))?;
+    let host = HeaderValue::from_str(host)?;
 
-    let address = format!("{host}:{port}");
-    let stream = TcpStream::connect(address).await?;
-    let io = TokioIo::new(stream);
+    let upstream_uri = upstream_uri(base_url, incoming_uri)?;
+
+    let io = TokioIo::new(connect(base_url, tls).await?);
 
     let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
 
     tokio::task::spawn(async move {
         if let Err(err) = conn.await {
-            println!("Connection failed: {err:?}");
+            debug!("Connection failed: {err:?}");
         }
     });
 
-    let upstream_req = Request::builder()
+    let mut upstream_req = Request::builder()
+        .method(method)
         .uri(upstream_uri)
         .header(hyper::header::HOST, host)
         .body(Empty::<Bytes>::new())?;
+    upstream_req.headers_mut().extend(headers.clone());
 
+    let sent = Instant::now();
     let res = sender.send_request(upstream_req).await?;
-    let body_bytes = res.collect().await?.to_bytes();
+    observe_upstream_response(sent, res.status());
+    Ok(res)
+}
+
+/// Joins the upstream's scheme and authority with the request's path and
+/// query. The parts share their buffers with the URIs they come from, so
+/// nothing is copied.
+pub fn upstream_uri(
+    base_url: &hyper::Uri,
+    incoming_uri: &hyper::Uri,
+) -> Result<hyper::Uri, RelayError> {
+    let mut parts = Parts::default();
+    parts.scheme = Some(match request_scheme(base_url) {
+        "https" => Scheme::HTTPS,
+        _ => Scheme::HTTP,
+    });
+    parts.authority = Some(
+        base_url
+            .authority()
+            .ok_or_else(|| RelayError::internal("upstream url has no authority"))?
+            .clone(),
+    );
+    parts.path_and_query = Some(
+        incoming_uri
+            .path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/")),
+    );
+    Ok(hyper::Uri::from_parts(parts)?)
+}
+
+/// An upstream response read for the cache.
+enum Captured {
+    /// The cache entry, and whether it may be stored.
+    Complete(CachedResponse, bool),
+    /// A response too large to store, to be streamed instead. Any body
+    /// already read is sent ahead of the rest.
+    TooLarge(Response<Body>),
+}
 
-    let duration_ms = context.start.elapsed().as_secs_f64() * 1000.0;
-    let bytes_sent = body_bytes.len();
+/// Reads an upstream response into a cache entry and decides whether it may
+/// be stored, based on its status and content type. Bodies are read up to
+/// `cache.max_object_size`, which a body of unknown length may turn out to
+/// exceed.
+async fn capture_response(
+    cache_config: &CacheConfig,
+    rule: Option<&CacheRule>,
+    res: Response<hyper::body::Incoming>,
+    ttl: Duration,
+    fetch_start: Instant,
+) -> Result<Captured, RelayError> {
+    let (parts, body) = res.into_parts();
+    let body_bytes = match buffer(body, cache_config.max_object_size).await? {
+        Buffered::Complete(body_bytes) => body_bytes,
+        Buffered::Partial(body) => {
+            return Ok(Captured::TooLarge(Response::from_parts(
+                parts,
+                body.map_err(Into::into).boxed(),
+            )))
+        }
+    };
+    ORIGIN_FETCHED_BYTES.inc_by(body_bytes.len() as u64);
+    let (mut cached_response, cacheable) = cache_entry(
+        cache_config,
+        rule,
+        parts.status,
+        parts.headers,
+        body_bytes,
+        ttl,
+    )
+    .await;
+    cached_response.fetch_duration = fetch_start.elapsed();
+    Ok(Captured::Complete(cached_response, cacheable))
+}
+
+/// Turns a complete upstream response into a cache entry and decides
+/// whether it may be stored, based on its status and content type.
+pub(crate) async fn cache_entry(
+    cache_config: &CacheConfig,
+    rule: Option<&CacheRule>,
+    status: StatusCode,
+    mut headers: HeaderMap,
+    mut body_bytes: Bytes,
+    ttl: Duration,
+) -> (CachedResponse, bool) {
+    let content_type = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let mut cacheable = cache_config.is_cacheable_status(rule, status.as_u16())
+        && cache_config.is_cacheable_content_type(rule, content_type);
+    // A cookie meant for one client must never be replayed to others, so
+    // such responses are only stored where a rule asks for it, and even then
+    // without the cookie
+    if headers.contains_key(SET_COOKIE) && rule.is_none_or(|r| r.cache_set_cookie != Some(true)) {
+        cacheable = false;
+    }
+
+    // An encoded body would be replayed to clients that never said they
+    // accept that encoding, so only decoded bodies are stored
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if let Some(content_encoding) = content_encoding {
+        let decoded = compression::decode(
+            &content_encoding,
+            body_bytes.clone(),
+            cache_config.max_object_size,
+        )
+        .await;
+        match decoded {
+            Ok(decoded) => {
+                body_bytes = Bytes::from(decoded);
+                headers.remove(CONTENT_ENCODING);
+                compression::weaken_etag(&mut headers);
+            }
+            Err(e) => {
+                debug!("Not caching {content_encoding}-encoded response: {e}");
+                cacheable = false;
+            }
+        }
+    }
+
+    // A per-status rule TTL wins; otherwise error responses are cached only
+    // briefly so a missing resource being hammered is absorbed without
+    // pinning an outage for the full TTL
+    let ttl = match rule.and_then(|r| r.ttl_for_status(status.as_u16())) {
+        Some(status_ttl) => status_ttl,
+        None if is_negative_status(status) => cache_config.negative_ttl.unwrap_or(ttl),
+        None if cache_config.heuristic_freshness && rule.is_none_or(|r| r.ttl.is_none()) => {
+            heuristic_ttl(&headers, cache_config.heuristic_max_ttl).unwrap_or(ttl)
+        }
+        None => ttl,
+    };
+    // The upstream's say on this one response beats config
+    let ttl = match cache_config.take_ttl_override(&mut headers) {
+        Some(ttl) => {
+            cacheable = cacheable && !ttl.is_zero();
+            ttl
+        }
+        None => ttl,
+    };
+    (
+        CachedResponse::new(status, &headers, body_bytes, ttl),
+        cacheable,
+    )
+}
+
+/// Fetches `uri` from the upstream in the background and stores the result,
+/// unless a refresh for the same key is already running.
+pub fn spawn_refresh(
+    state: Arc<AppState>,
+    cache_key: String,
+    uri: hyper::Uri,
+    headers: HeaderMap,
+    upstream: Option<String>,
+) {
+    if !state.refreshing.lock().unwrap().insert(cache_key.clone()) {
+        return;
+    }
+
+    tokio::task::spawn(async move {
+        match fetch_and_store(&state, &cache_key, &uri, &headers, upstream.as_deref()).await {
+            Ok(true) => debug!(target: CACHE_DECISIONS, "Cache REFRESH: {cache_key}"),
+            Ok(false) => {}
+            Err(e) => warn!("Cache REFRESH failed: {cache_key} - error: {e}"),
+        }
+
+        state.refreshing.lock().unwrap().remove(&cache_key);
+    });
+}
+
+/// Fetches `uri` from the upstream, or `upstream` when set, sending
+/// `headers` along, and stores it under `cache_key` if the response is
+/// cacheable, returning whether it was stored.
+pub async fn fetch_and_store(
+    state: &AppState,
+    cache_key: &str,
+    uri: &hyper::Uri,
+    headers: &HeaderMap,
+    upstream: Option<&str>,
+) -> Result<bool, RelayError> {
+    let cache_config = &state.cache_config;
+    let matched_rule = cache_config.find_rule_with_pattern(uri.path());
+    let rule = matched_rule.as_ref().map(|matched| &matched.rule);
+    let ttl = rule.and_then(|r| r.ttl).unwrap_or(cache_config.default_ttl);
+    let stale_if_error = rule
+        .and_then(|r| r.stale)
+        .unwrap_or(cache_config.stale_if_error);
+
+    let fetch_start = Instant::now();
+    let res = state.fetch_upstream(uri, headers, upstream).await?;
+    // Keep the existing entry rather than replacing it with an error page
+    if cache_config
+        .stale_if_error_statuses
+        .contains(&res.status().as_u16())
+    {
+        return Err(RelayError::UpstreamStatus(res.status()));
+    }
+    let content_type = res
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if cache_config.is_streaming_content_type(content_type) {
+        return Ok(false);
+    }
+    let Captured::Complete(mut cached_response, mut cacheable) =
+        capture_response(cache_config, rule, res, ttl, fetch_start).await?
+    else {
+        return Ok(false);
+    };
+    cached_response.headers.remove(SET_COOKIE);
+    cacheable = cacheable && state.may_store(cache_key, &cached_response).await;
+    if cacheable {
+        let retention = retention(rule, cached_response.ttl, stale_if_error);
+        state
+            .cache
+            .set(cache_key.to_string(), cached_response, retention)
+            .await;
+    }
+    Ok(cacheable)
+}
+
+/// Stores a response fetched for a miss if it may be cached. Otherwise,
+/// with hit-for-pass on, the key is passed straight to the upstream for a
+/// while.
+async fn store_fetched(
+    state: &AppState,
+    cache_key: String,
+    rule: Option<&CacheRule>,
+    stale_if_error: Duration,
+    fetched: &CachedResponse,
+    cacheable: bool,
+) {
+    let mut stored = fetched.clone();
+    stored.headers.remove(SET_COOKIE);
+    if cacheable && state.may_store(&cache_key, &stored).await {
+        let retention = retention(rule, stored.ttl, stale_if_error);
+        state.cache.set(cache_key, stored, retention).await;
+    } else if let Some(hit_for_pass) = &state.hit_for_pass {
+        // An upstream failure says nothing about whether the key is cacheable
+        if !fetched.status.is_server_error() {
+            hit_for_pass.insert(cache_key, ());
+        }
+    }
+}
+
+/// How long a new entry is kept: through its stale-if-error window, and as
+/// long as crawlers may still be served it.
+fn retention(rule: Option<&CacheRule>, ttl: Duration, stale_if_error: Duration) -> Duration {
+    let bot_ttl = rule.and_then(|r| r.bot_ttl).unwrap_or_default();
+    (ttl + stale_if_error).max(bot_ttl)
+}
+
+/// Checks the rule's bypass cookies and query parameters against the request,
+/// so logged-in or explicitly uncached traffic skips the cache.
+fn bypassed_by_request<B>(rule: &CacheRule, req: &Request<B>) -> bool {
+    let cookie_match = rule.bypass_cookies.as_ref().is_some_and(|patterns| {
+        cookies(req.headers())
+            .any(|(name, _)| patterns.iter().any(|pattern| name_matches(pattern, name)))
+    });
+    let query_match = rule.bypass_query.as_ref().is_some_and(|patterns| {
+        req.uri().query().is_some_and(|query| {
+            query.split('&').any(|param| {
+                let name = param.split('=').next().unwrap_or(param);
+                patterns.iter().any(|pattern| name_matches(pattern, name))
+            })
+        })
+    });
+    cookie_match || query_match
+}
+
+async fn forward_to_upstream<B>(
+    req: Request<B>,
+    state: &AppState,
+    incoming_uri: hyper::Uri,
+    context: RequestContext,
+    cache_status: CacheStatus,
+) -> Result<Response<Body>, RelayError> {
+    // Nothing is cached here, so a HEAD can go to the upstream as-is
+    let method = if req.method() == Method::HEAD {
+        Method::HEAD
+    } else {
+        Method::GET
+    };
+    let headers = state.upstream_headers(req.headers());
+    let upstream = state.upstream_for(&req);
+    let res = state
+        .request_upstream(&incoming_uri, method, &headers, Some(upstream))
+        .await?;
+    Ok(stream_response(
+        res,
+        &state.cache_config,
+        context,
+        cache_status,
+    )?)
+}
+
+/// Passes an upstream response to the client as it arrives, without
+/// buffering it.
+fn stream_response<B>(
+    res: Response<B>,
+    cache_config: &CacheConfig,
+    context: RequestContext,
+    cache_status: CacheStatus,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    let (mut parts, body) = res.into_parts();
+    cache_config.take_ttl_override(&mut parts.headers);
+    // The body passes through unchanged, so any length the upstream declared
+    // still holds; for open-ended streams the size isn't known up front
+    let content_length = parts.headers.get(CONTENT_LENGTH);
+    let bytes_sent = content_length
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
 
     if *context.prometheus_enabled {
+        REQUESTS.with_label_values(&[cache_status.as_str()]).inc();
         REQUEST_DURATION.observe(context.start.elapsed().as_secs_f64());
     }
 
-    if *context.logging_enabled {
-        log_access(AccessLogEntry {
+    if context.access_log.enabled() {
+        context.access_log.log(AccessLogEntry {
             method: context.method,
             path: context.path,
-            status: 200,
-            duration_ms,
-            cache_status: CacheStatus::Bypass,
+            status: parts.status.as_u16(),
+            duration_ms: context.start.elapsed().as_secs_f64() * 1000.0,
+            cache_status,
             remote_addr: context.remote_addr,
             bytes_sent,
+            country: context.country,
+            request_headers: context.request_headers,
+            response_headers: context.access_log.response_headers(&parts.headers),
         });
     }
 
-    Ok(Response::builder()
-        .header("X-Cache", "BYPASS")
-        .body(Full::new(body_bytes))?)
+    let mut builder = Response::builder().status(parts.status);
+    for (name, value) in parts
+        .headers
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+    {
+        builder = builder.header(name, value);
+    }
+    if let Some(length) = content_length {
+        builder = builder.header(CONTENT_LENGTH, length);
+    }
+
+    let body = body.map_err(Into::into);
+    let body = if *context.prometheus_enabled {
+        // Streamed bytes are counted as they pass through
+        body.map_frame(|frame| {
+            if let Some(data) = frame.data_ref() {
+                ORIGIN_FETCHED_BYTES.inc_by(data.len() as u64);
+            }
+            frame
+        })
+        .boxed()
+    } else {
+        body.boxed()
+    };
+    with_debug(builder, context.debug.as_ref(), None)
+        .header("X-Cache", cache_status.as_str())
+        .body(body)
 }
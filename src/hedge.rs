@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::HedgingConfig;
+
+/// Upstream response times kept for working out the hedging delay
+const SAMPLES: usize = 1000;
+/// Responses needed before hedging starts, so an early outlier doesn't set
+/// the delay
+const MIN_SAMPLES: usize = 100;
+/// The delay is recomputed after this many new responses
+const RECOMPUTE_EVERY: u64 = 50;
+
+/// Decides when a slow cache-miss fetch gets a second, hedged request: once
+/// it has taken longer than the configured percentile of recent upstream
+/// response times.
+pub struct Hedging {
+    percentile: f64,
+    min_delay: Duration,
+    samples: Mutex<VecDeque<Duration>>,
+    recorded: AtomicU64,
+    /// Current delay in microseconds; 0 until enough responses were seen
+    delay_micros: AtomicU64,
+}
+
+impl Hedging {
+    pub fn new(config: &HedgingConfig) -> Self {
+        Self {
+            percentile: config.percentile,
+            min_delay: config.min_delay,
+            samples: Mutex::new(VecDeque::with_capacity(SAMPLES)),
+            recorded: AtomicU64::new(0),
+            delay_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// How long to wait for a response before hedging, once known.
+    pub fn delay(&self) -> Option<Duration> {
+        match self.delay_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Adds the time an upstream took to send its response head.
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+        let recorded = self.recorded.fetch_add(1, Ordering::Relaxed) + 1;
+        if samples.len() < MIN_SAMPLES || !recorded.is_multiple_of(RECOMPUTE_EVERY) {
+            return;
+        }
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        drop(samples);
+        sorted.sort_unstable();
+        let rank = (self.percentile * sorted.len() as f64).ceil() as usize;
+        let delay = sorted[rank.clamp(1, sorted.len()) - 1].max(self.min_delay);
+        let micros = u64::try_from(delay.as_micros()).unwrap_or(u64::MAX).max(1);
+        self.delay_micros.store(micros, Ordering::Relaxed);
+    }
+}
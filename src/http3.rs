@@ -0,0 +1,146 @@
+use futures_util::stream;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Buf, Bytes, Frame};
+use hyper::client::conn::http2::SendRequest;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::warn;
+
+use crate::config::TlsConfig;
+use crate::handlers::AppState;
+use crate::service::HttpService;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type BridgeBody = BoxBody<Bytes, BoxError>;
+type H3Stream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Binds a QUIC endpoint on the UDP port matching the TCP listener, using the
+/// same certificate and offering the `h3` ALPN protocol.
+pub fn bind(addr: SocketAddr, tls: &TlsConfig) -> Result<quinn::Endpoint, BoxError> {
+    let mut tls_config = crate::tls::load_server_config(tls)?;
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls_config)?;
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(quinn::Endpoint::server(server_config, addr)?)
+}
+
+/// Accepts QUIC connections until the endpoint is closed.
+pub async fn serve(endpoint: quinn::Endpoint, state: Arc<AppState>, service: HttpService) {
+    while let Some(incoming) = endpoint.accept().await {
+        let state = Arc::clone(&state);
+        let service = service.clone();
+        tokio::task::spawn(async move {
+            let remote_addr = incoming.remote_address();
+            if let Err(err) = serve_connection(incoming, state, service).await {
+                warn!("Error serving HTTP/3 connection: {remote_addr} - {err}");
+            }
+        });
+    }
+}
+
+/// Each QUIC connection is paired with an in-memory HTTP/2 connection to the
+/// regular request handler, so HTTP/3 requests go through exactly the same
+/// routing, caching and proxying as requests arriving over TCP.
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    state: Arc<AppState>,
+    service: HttpService,
+) -> Result<(), BoxError> {
+    let conn = incoming.await?;
+    let remote_addr = conn.remote_address();
+    let peer_certs = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let settings = crate::server::ConnectionSettings {
+        http2: true,
+        service,
+        timeouts: false,
+        client_subject: crate::tls::client_subject(peer_certs.as_deref().map(Vec::as_slice)),
+    };
+    tokio::task::spawn(crate::server::serve_connection(
+        server_io,
+        state,
+        remote_addr,
+        settings,
+    ));
+    let (sender, bridge) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client_io))
+            .await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = bridge.await {
+            warn!("HTTP/3 bridge failed: {remote_addr} - {err}");
+        }
+    });
+
+    let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+    loop {
+        let resolver = match h3_conn.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => break,
+            // The client closing the connection normally
+            Err(err) if err.is_h3_no_error() => break,
+            Err(err) => return Err(err.into()),
+        };
+        let sender = sender.clone();
+        tokio::task::spawn(async move {
+            match resolver.resolve_request().await {
+                Ok((req, stream)) => {
+                    let path = req.uri().path().to_string();
+                    if let Err(err) = serve_request(req, stream, sender).await {
+                        warn!("HTTP/3 request failed: {path} - {err}");
+                    }
+                }
+                Err(err) => warn!("HTTP/3 request failed: {remote_addr} - {err}"),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Relays one request over the bridge, streaming both bodies and passing
+/// response trailers through.
+async fn serve_request(
+    req: Request<()>,
+    stream: H3Stream,
+    mut sender: SendRequest<BridgeBody>,
+) -> Result<(), BoxError> {
+    let (mut send, recv) = stream.split();
+
+    let request_body = stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+        let data = match recv.recv_data().await {
+            Ok(Some(mut data)) => data.copy_to_bytes(data.remaining()),
+            Ok(None) => return None,
+            Err(err) => return Some((Err(BoxError::from(err)), None)),
+        };
+        Some((Ok(Frame::data(data)), Some(recv)))
+    });
+    let (parts, ()) = req.into_parts();
+    let req = Request::from_parts(parts, StreamBody::new(request_body).boxed());
+
+    sender.ready().await?;
+    let res = sender.send_request(req).await?;
+    let (parts, mut body) = res.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
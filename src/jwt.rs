@@ -0,0 +1,307 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, HOST};
+use hyper::{Request, Response, Uri};
+use hyper_util::rt::TokioIo;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, AlgorithmFamily, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+use crate::auth::secret;
+use crate::config::JwtConfig;
+use crate::tls;
+
+/// Unknown key ids trigger a refetch, but no more often than this, so
+/// tokens with made-up ids can't be used to hammer the JWKS endpoint.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Claims = Map<String, Value>;
+
+enum Keys {
+    Static(DecodingKey),
+    Jwks(Jwks),
+}
+
+/// Verifies bearer tokens on configured routes and turns selected claims
+/// into headers for the upstream.
+pub struct JwtAuth {
+    routes: GlobSet,
+    keys: Keys,
+    validation: Validation,
+    claim_headers: Vec<(String, HeaderName)>,
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut routes = GlobSetBuilder::new();
+        for pattern in &config.routes {
+            routes.add(Glob::new(pattern)?);
+        }
+
+        let secret = secret(&config.secret, &config.secret_env)?;
+        let algorithms = match &config.algorithms {
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    name.parse::<Algorithm>()
+                        .map_err(|_| format!("Unknown JWT algorithm: {name}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None if secret.is_some() => vec![Algorithm::HS256],
+            None => vec![Algorithm::RS256],
+        };
+        if algorithms.is_empty() {
+            return Err("jwt.algorithms must not be empty".into());
+        }
+
+        let keys = match (&config.jwks_url, secret, &config.public_key) {
+            (Some(url), None, None) => Keys::Jwks(Jwks::new(url, config.jwks_refresh)?),
+            (None, Some(secret), None) => Keys::Static(DecodingKey::from_secret(secret.as_bytes())),
+            (None, None, Some(path)) => {
+                let pem = std::fs::read(path)
+                    .map_err(|e| format!("Failed to read JWT public key {path}: {e}"))?;
+                Keys::Static(match algorithms[0].family() {
+                    AlgorithmFamily::Rsa => DecodingKey::from_rsa_pem(&pem)?,
+                    AlgorithmFamily::Ec => DecodingKey::from_ec_pem(&pem)?,
+                    AlgorithmFamily::Ed => DecodingKey::from_ed_pem(&pem)?,
+                    AlgorithmFamily::Hmac => {
+                        return Err("HMAC algorithms need jwt.secret, not a public key".into())
+                    }
+                })
+            }
+            _ => {
+                return Err(
+                    "jwt needs exactly one of jwks_url, secret/secret_env or public_key".into(),
+                )
+            }
+        };
+        // A static key belongs to one family, and accepting algorithms from
+        // another would let a token pick how its signature is checked
+        if let Keys::Static(key) = &keys {
+            if algorithms.iter().any(|alg| alg.family() != key.family()) {
+                return Err("jwt.algorithms don't match the configured key".into());
+            }
+        }
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&config.audience);
+        }
+
+        let mut claim_headers = config
+            .claim_headers
+            .iter()
+            .map(|(claim, header)| Ok((claim.clone(), HeaderName::try_from(header.as_str())?)))
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+        // A stable order keeps cache keys built from these headers stable
+        claim_headers.sort_by(|(_, a), (_, b)| a.as_str().cmp(b.as_str()));
+
+        Ok(Self {
+            routes: routes.build()?,
+            keys,
+            validation,
+            claim_headers,
+        })
+    }
+
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.routes.is_match(path)
+    }
+
+    /// Checks the request's bearer token, returning its claims.
+    pub async fn verify(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Claims, Box<dyn Error + Send + Sync>> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or("missing bearer token")?;
+        let header = decode_header(token)?;
+        let claims = match &self.keys {
+            Keys::Static(key) => decode::<Claims>(token, key, &self.validation)?,
+            Keys::Jwks(jwks) => {
+                let key = DecodingKey::from_jwk(&jwks.key(header.kid.as_deref()).await?)?;
+                decode::<Claims>(token, &key, &self.validation)?
+            }
+        };
+        Ok(claims.claims)
+    }
+
+    /// The `WWW-Authenticate` challenge for a rejected request.
+    pub fn challenge(headers: &HeaderMap) -> &'static str {
+        if headers.contains_key(AUTHORIZATION) {
+            "Bearer error=\"invalid_token\""
+        } else {
+            "Bearer"
+        }
+    }
+
+    /// Removes claim headers sent by the client, so the upstream only ever
+    /// sees values taken from a verified token.
+    pub fn strip_claim_headers(&self, headers: &mut HeaderMap) {
+        for (_, name) in &self.claim_headers {
+            headers.remove(name);
+        }
+    }
+
+    pub fn insert_claim_headers(&self, headers: &mut HeaderMap, claims: &Claims) {
+        for (claim, name) in &self.claim_headers {
+            let value = match claims.get(claim) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Array(values)) => values
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(value) => value.clone(),
+                        other => other.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Some(Value::Null) | None => continue,
+                Some(other) => other.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name.clone(), value);
+            }
+        }
+    }
+
+    /// The claim headers present on a request, to be passed to the upstream.
+    pub fn claim_headers(&self, headers: &HeaderMap) -> HeaderMap {
+        let mut claim_headers = HeaderMap::new();
+        for (_, name) in &self.claim_headers {
+            if let Some(value) = headers.get(name) {
+                claim_headers.insert(name.clone(), value.clone());
+            }
+        }
+        claim_headers
+    }
+}
+
+/// Keys fetched from a JWKS endpoint, refreshed once they are older than
+/// `refresh` or when a token names a key id that isn't known yet.
+struct Jwks {
+    url: Uri,
+    refresh: Duration,
+    connector: TlsConnector,
+    keys: RwLock<Option<(JwkSet, Instant)>>,
+    /// Held while fetching; records when the last fetch was attempted
+    last_fetch: Mutex<Option<Instant>>,
+}
+
+impl Jwks {
+    fn new(url: &str, refresh: Duration) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let url = url.parse::<Uri>()?;
+        if url.host().is_none() {
+            return Err("jwt.jwks_url has no host".into());
+        }
+        Ok(Self {
+            url,
+            refresh,
+            connector: tls::client_connector(),
+            keys: RwLock::new(None),
+            last_fetch: Mutex::new(None),
+        })
+    }
+
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, Box<dyn Error + Send + Sync>> {
+        if let Some(jwk) = self.cached(kid, self.refresh) {
+            return Ok(jwk);
+        }
+
+        let mut last_fetch = self.last_fetch.lock().await;
+        // Another request may have fetched the keys while this one waited
+        if let Some(jwk) = self.cached(kid, self.refresh) {
+            return Ok(jwk);
+        }
+        if last_fetch.is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL) {
+            *last_fetch = Some(Instant::now());
+            match tokio::time::timeout(JWKS_FETCH_TIMEOUT, self.fetch()).await {
+                Ok(Ok(set)) => *self.keys.write().unwrap() = Some((set, Instant::now())),
+                Ok(Err(err)) => warn!("JWKS fetch failed: {} - {err}", self.url),
+                Err(_) => warn!("JWKS fetch timed out: {}", self.url),
+            }
+        }
+
+        // Previously fetched keys stay usable while the endpoint is failing
+        self.cached(kid, Duration::MAX).ok_or_else(|| match kid {
+            Some(kid) => format!("no JWKS key with kid {kid}").into(),
+            None => "token has no kid and the JWKS has more than one key".into(),
+        })
+    }
+
+    /// A token without a key id can only be checked when the set has a
+    /// single key.
+    fn cached(&self, kid: Option<&str>, max_age: Duration) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        let (set, _) = keys.as_ref().filter(|(_, at)| at.elapsed() < max_age)?;
+        match kid {
+            Some(kid) => set.find(kid).cloned(),
+            None if set.keys.len() == 1 => set.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn fetch(&self) -> Result<JwkSet, Box<dyn Error + Send + Sync>> {
+        let host = self.url.host().unwrap_or_default();
+        let https = self.url.scheme_str() == Some("https");
+        let port = self.url.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let authority = self
+            .url
+            .authority()
+            .map_or(host, |authority| authority.as_str());
+        let req = Request::builder()
+            .uri(self.url.path_and_query().map_or("/", |pq| pq.as_str()))
+            .header(HOST, authority)
+            .header(ACCEPT, "application/json")
+            .body(Empty::<Bytes>::new())?;
+
+        let stream = TcpStream::connect((host, port)).await?;
+        let res = if https {
+            let server_name = ServerName::try_from(host.to_string())?;
+            send(self.connector.connect(server_name, stream).await?, req).await?
+        } else {
+            send(stream, req).await?
+        };
+        if !res.status().is_success() {
+            return Err(format!("JWKS endpoint returned {}", res.status()).into());
+        }
+        let body = res.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+async fn send<S>(
+    stream: S,
+    req: Request<Empty<Bytes>>,
+) -> Result<Response<Incoming>, Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            warn!("JWKS connection failed: {err:?}");
+        }
+    });
+    Ok(sender.send_request(req).await?)
+}
@@ -0,0 +1,107 @@
+//! relay is a caching reverse proxy. The `relay` binary runs it from a
+//! `config.toml`; this library lets other programs embed the same proxy,
+//! optionally with a storage backend of their own.
+//!
+//! A custom backend implements [`Storage`] and is passed to
+//! [`RelayBuilder::storage`]:
+//!
+//! ```no_run
+//! use relay::{async_trait, CachedResponse, RelayBuilder, Storage};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! struct NoStore;
+//!
+//! #[async_trait]
+//! impl Storage for NoStore {
+//!     async fn get(&self, _key: &str) -> Option<CachedResponse> {
+//!         None
+//!     }
+//!     async fn set(&self, _key: String, _value: CachedResponse, _ttl: Duration) {}
+//!     async fn delete(&self, _key: &str) -> bool {
+//!         false
+//!     }
+//!     async fn size(&self) -> usize {
+//!         0
+//!     }
+//!     async fn increment(&self, _key: &str, _ttl: Duration) -> u64 {
+//!         0
+//!     }
+//! }
+//!
+//! # async fn example() -> Result<(), relay::RelayError> {
+//! let config = relay::load_config("config.toml")?;
+//! RelayBuilder::new(config).storage(Arc::new(NoStore)).run().await
+//! # }
+//! ```
+
+mod access;
+mod admin;
+mod auth;
+mod balancer;
+mod buffer;
+mod cache;
+mod cache_key;
+mod cluster;
+mod compression;
+pub mod config;
+mod cors;
+mod dashboard;
+mod device;
+mod dns;
+mod error;
+mod error_pages;
+mod forward_proxy;
+mod geoip;
+mod grpc;
+mod handlers;
+mod hedge;
+#[cfg(feature = "http3")]
+mod http3;
+mod jwt;
+mod limits;
+mod logger;
+#[cfg(feature = "lua")]
+mod lua;
+mod metrics;
+mod mirror;
+mod plugin;
+mod proxy_protocol;
+mod quota;
+mod range;
+mod rate_limit;
+mod redirect;
+mod refresh;
+mod runtime;
+mod server;
+mod service;
+mod signed_url;
+mod spa;
+mod split;
+mod static_files;
+pub mod storage;
+mod systemd;
+#[cfg(feature = "test-support")]
+pub mod testing;
+mod tls;
+mod transform;
+mod upgrade;
+mod upstream;
+mod warmup;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use async_trait::async_trait;
+pub use cache::CachedResponse;
+pub use config::{load_config, parse_config, CacheConfig, CacheRule, Config};
+pub use error::RelayError;
+pub use handlers::Body;
+pub use logger::{init_logging, LogGuard};
+pub use plugin::{CacheKeyOverride, Plugin, UpstreamOverride};
+pub use runtime::build_runtime;
+pub use server::{run, RelayBuilder};
+pub use service::{
+    AccessLogLayer, AccessLogService, CacheLayer, CacheService, ClientAddr, HttpService, RateLimit,
+    RateLimitLayer, RelayService, ResponseHeaders, ResponseHeadersLayer,
+};
+pub use storage::{Cache, Storage};
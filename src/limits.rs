@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// True when anything in the error's source chain is a timeout.
+pub fn is_timeout(error: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        let timed_out = error
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+            || error
+                .downcast_ref::<hyper::Error>()
+                .is_some_and(|e| e.is_timeout())
+            || error.is::<tokio::time::error::Elapsed>();
+        if timed_out {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Counts the requests on one connection that are still waiting for their
+/// response, so a slow upstream doesn't make the client look idle.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn start(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(Arc::clone(&self.0))
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// Marks the request finished when dropped.
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a client connection so it fails once nothing has been read or
+/// written for `idle_timeout` while no request is in flight, or when a write
+/// has been stuck for `write_timeout` because the client stopped reading.
+pub struct TimeoutIo<S> {
+    inner: S,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    idle: Pin<Box<Sleep>>,
+    write: Option<Pin<Box<Sleep>>>,
+    in_flight: InFlight,
+}
+
+impl<S> TimeoutIo<S> {
+    pub fn new(
+        inner: S,
+        idle_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+        in_flight: InFlight,
+    ) -> Self {
+        let idle = Box::pin(sleep(idle_timeout.unwrap_or_default()));
+        Self {
+            inner,
+            idle_timeout,
+            write_timeout,
+            idle,
+            write: None,
+            in_flight,
+        }
+    }
+
+    fn touch(&mut self) {
+        if let Some(idle_timeout) = self.idle_timeout {
+            self.idle.as_mut().reset(Instant::now() + idle_timeout);
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        if self.idle_timeout.is_none() {
+            return Poll::Pending;
+        }
+        while self.idle.as_mut().poll(cx).is_ready() {
+            if self.in_flight.is_idle() {
+                return Poll::Ready(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle timeout",
+                ));
+            }
+            // Still answering a request; look again after another period
+            self.touch();
+        }
+        Poll::Pending
+    }
+
+    fn write_result<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.write = None;
+            self.touch();
+            return poll;
+        }
+        let Some(write_timeout) = self.write_timeout else {
+            return Poll::Pending;
+        };
+        let deadline = self
+            .write
+            .get_or_insert_with(|| Box::pin(sleep(write_timeout)));
+        if deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection write timeout",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.touch();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_idle(cx).map(Err),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.write_result(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.write_result(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.write_result(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
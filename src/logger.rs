@@ -1,8 +1,33 @@
-use std::net::SocketAddr;
-use tracing::info;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::Method;
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, Level, Metadata};
+use tracing_appender::non_blocking::{NonBlockingBuilder, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, Targets};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer, Registry};
 
-use crate::config::LoggingConfig;
+use crate::config::{LogRotation, LoggingConfig, SYSLOG_FACILITIES};
+use crate::error::BoxError;
+
+/// Target of access log events, which `logging.output` can send to a file
+const ACCESS_LOG: &str = "relay::access_log";
+
+/// Target of admin operation events, which `logging.audit_path` also
+/// writes to a file of their own
+pub const AUDIT: &str = "relay::audit";
+
+/// Target of per-request cache decision events, logged at debug level so
+/// `logging.cache_decisions` or a `relay::cache=debug` filter turns them on.
+pub const CACHE_DECISIONS: &str = "relay::cache";
 
 #[derive(Debug, Clone, Copy)]
 pub enum CacheStatus {
@@ -10,6 +35,9 @@ pub enum CacheStatus {
     Miss,
     Bypass,
     Stale,
+    Pass,
+    /// Served from a `serve_static` directory
+    Static,
 }
 
 impl CacheStatus {
@@ -19,59 +47,464 @@ impl CacheStatus {
             CacheStatus::Miss => "MISS",
             CacheStatus::Bypass => "BYPASS",
             CacheStatus::Stale => "STALE",
+            CacheStatus::Pass => "PASS",
+            CacheStatus::Static => "STATIC",
+        }
+    }
+
+    /// The status an `X-Cache` header value names.
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value {
+            "HIT" => Some(CacheStatus::Hit),
+            "MISS" => Some(CacheStatus::Miss),
+            "BYPASS" => Some(CacheStatus::Bypass),
+            "STALE" => Some(CacheStatus::Stale),
+            "PASS" => Some(CacheStatus::Pass),
+            "STATIC" => Some(CacheStatus::Static),
+            _ => None,
         }
     }
 }
 
 pub struct AccessLogEntry {
-    pub method: String,
+    pub method: Method,
     pub path: String,
     pub status: u16,
     pub duration_ms: f64,
     pub cache_status: CacheStatus,
     pub remote_addr: SocketAddr,
     pub bytes_sent: usize,
+    /// The client's country, when `[geoip]` knows it
+    pub country: Option<String>,
+    /// Captured request headers, from [`AccessLog::request_headers`]
+    pub request_headers: Option<String>,
+    /// Captured response headers, from [`AccessLog::response_headers`]
+    pub response_headers: Option<String>,
+}
+
+/// Keeps the access and audit log file writers running; dropping it
+/// flushes lines still buffered, so it's held until relay exits.
+#[must_use]
+pub struct LogGuard {
+    _writers: Vec<WorkerGuard>,
+}
+
+pub fn init_logging(config: &LoggingConfig) -> Result<LogGuard, BoxError> {
+    let mut env_filter = match EnvFilter::try_from_default_env() {
+        Ok(env_filter) => env_filter,
+        Err(_) => EnvFilter::try_new(&config.level)
+            .map_err(|e| format!("Invalid logging.level {:?}: {e}", config.level))?,
+    };
+    if config.cache_decisions {
+        env_filter = env_filter.add_directive(format!("{CACHE_DECISIONS}=debug").parse()?);
+    }
+
+    let main = match config.output.as_str() {
+        "syslog" => {
+            let writer = SyslogWriter::connect(&config.syslog_address, &config.syslog_facility)?;
+            format_layer(&config.format, writer, false)?
+        }
+        "journald" => tracing_journald::layer()
+            .map_err(|e| format!("Cannot connect to journald: {e}"))?
+            .boxed(),
+        _ => format_layer(&config.format, std::io::stdout, true)?,
+    };
+    let access_log_path = config
+        .path
+        .as_deref()
+        .filter(|_| config.enabled && config.output == "file");
+    // With a file of its own, the access log goes only there, whatever the
+    // level filter says; diagnostics stay on stdout
+    let access_log_elsewhere = access_log_path.is_some();
+    let main = main
+        .with_filter(env_filter)
+        .with_filter(filter_fn(move |metadata| {
+            !access_log_elsewhere || metadata.target() != ACCESS_LOG
+        }));
+    let mut layers = vec![main.boxed()];
+    let mut writers = Vec::new();
+
+    if let Some(path) = access_log_path {
+        let (writer, guard) = tracing_appender::non_blocking(open_access_log(
+            Path::new(path),
+            config.rotation,
+            config.max_files,
+        )?);
+        let file = format_layer(&config.format, writer, false)?
+            .with_filter(Targets::new().with_target(ACCESS_LOG, Level::INFO));
+        layers.push(file.boxed());
+        writers.push(guard);
+    }
+
+    if let Some(path) = &config.audit_path {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Cannot open logging.audit_path {path}: {e}"))?;
+        // Admin operations are rare, and none may go unrecorded
+        let (writer, guard) = NonBlockingBuilder::default().lossy(false).finish(file);
+        let audit = format_layer("json", writer, false)?
+            .with_filter(Targets::new().with_target(AUDIT, Level::INFO));
+        layers.push(audit.boxed());
+        writers.push(guard);
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    Ok(LogGuard { _writers: writers })
+}
+
+fn format_layer<W>(
+    format: &str,
+    writer: W,
+    ansi: bool,
+) -> Result<Box<dyn Layer<Registry> + Send + Sync>, BoxError>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        "json" => Ok(layer.json().boxed()),
+        "combined" => Ok(layer.with_target(false).boxed()),
+        _ => Err(format!("Invalid log format: {format}").into()),
+    }
+}
+
+fn open_access_log(
+    path: &Path,
+    rotation: LogRotation,
+    max_files: usize,
+) -> Result<Box<dyn Write + Send>, BoxError> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("Invalid logging.path: {}", path.display()))?;
+    let period = match rotation {
+        LogRotation::Size(max_size) => {
+            return Ok(Box::new(SizeRotatingFile::open(
+                path.to_path_buf(),
+                max_size,
+                max_files,
+            )?));
+        }
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Weekly => Rotation::WEEKLY,
+    };
+    // Dated files are named after the configured one, as in access.log.2024-02-17
+    let appender = RollingFileAppender::builder()
+        .rotation(period)
+        .filename_prefix(file_name.to_string_lossy())
+        .max_log_files(max_files)
+        .build(directory)?;
+    Ok(Box::new(appender))
+}
+
+/// Appends to a file, moving it to `<path>.1` once it would grow past
+/// `max_size`, and older files one number up, deleting the oldest so that
+/// `max_files` are kept.
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl SizeRotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 1 {
+            let _ = fs::remove_file(self.rotated(self.max_files - 1));
+            for index in (1..self.max_files - 1).rev() {
+                let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Each write is one whole log line, so lines are never split
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
 
-pub fn init_logging(config: &LoggingConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if !config.enabled {
-        return Ok(());
+enum SyslogSocket {
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+}
+
+/// Sends each log line as an RFC 5424 syslog message, with the severity
+/// taken from the event's level.
+struct SyslogWriter {
+    socket: SyslogSocket,
+    facility: u8,
+    hostname: String,
+    pid: u32,
+}
+
+impl SyslogWriter {
+    /// Connects to `address`, a Unix socket path such as `/dev/log` or a
+    /// `host:port` to reach over UDP.
+    fn connect(address: &str, facility: &str) -> Result<Self, BoxError> {
+        let facility = SYSLOG_FACILITIES
+            .iter()
+            .position(|name| *name == facility)
+            .ok_or_else(|| format!("Invalid logging.syslog_facility: {facility}"))?;
+        let socket = if address.starts_with('/') {
+            let socket = UnixDatagram::unbound()?;
+            // Fail at startup rather than drop every line later
+            socket
+                .connect(address)
+                .map_err(|e| format!("Cannot connect to syslog at {address}: {e}"))?;
+            SyslogSocket::Unix(socket, PathBuf::from(address))
+        } else {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket
+                .connect(address)
+                .map_err(|e| format!("Cannot reach syslog at {address}: {e}"))?;
+            SyslogSocket::Udp(socket)
+        };
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+        Ok(Self {
+            socket,
+            facility: facility as u8,
+            hostname,
+            pid: std::process::id(),
+        })
+    }
+
+    fn send(&self, severity: u8, message: &[u8]) {
+        let mut packet = format!(
+            "<{}>1 {} {} relay {} - - ",
+            u16::from(self.facility) * 8 + u16::from(severity),
+            rfc3339(SystemTime::now()),
+            self.hostname,
+            self.pid
+        )
+        .into_bytes();
+        packet.extend_from_slice(message.strip_suffix(b"\n").unwrap_or(message));
+        // Logging must never fail a request, so undelivered lines are lost
+        let _ = match &self.socket {
+            SyslogSocket::Unix(socket, path) => socket.send(&packet).or_else(|_| {
+                // The syslog daemon restarted; its socket is a new one
+                let socket = UnixDatagram::unbound()?;
+                socket.send_to(&packet, path)
+            }),
+            SyslogSocket::Udp(socket) => socket.send(&packet),
+        };
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage {
+            writer: self,
+            severity: 6,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        SyslogMessage {
+            writer: self,
+            severity,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// One log line, sent as a single message once it's fully written.
+struct SyslogMessage<'a> {
+    writer: &'a SyslogWriter,
+    severity: u8,
+    buffer: Vec<u8>,
+}
+
+impl Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
     }
 
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-    match config.format.as_str() {
-        "json" => {
-            tracing_subscriber::registry()
-                .with(env_filter)
-                .with(fmt::layer().json())
-                .init();
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.writer.send(self.severity, &self.buffer);
         }
-        "combined" => {
-            tracing_subscriber::registry()
-                .with(env_filter)
-                .with(fmt::layer().with_target(false).with_level(false))
-                .init();
+    }
+}
+
+/// `time` in UTC as RFC 3339 with milliseconds, as syslog timestamps are.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The access log as `[logging]` configures it: which fields each line
+/// has, which headers are captured, and how many requests are sampled.
+pub struct AccessLog {
+    enabled: bool,
+    fields: Vec<String>,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+    sample_rate: u64,
+    successes: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn new(config: &LoggingConfig) -> Self {
+        // Header names were validated with the rest of the config
+        let header_names = |names: &[String]| {
+            names
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect()
+        };
+        Self {
+            enabled: config.enabled,
+            fields: config.fields.clone(),
+            request_headers: header_names(&config.request_headers),
+            response_headers: header_names(&config.response_headers),
+            sample_rate: config.sample_rate.max(1),
+            successes: AtomicU64::new(0),
         }
-        _ => {
-            return Err(format!("Invalid log format: {}", config.format).into());
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The configured request headers present in `headers`, as a JSON
+    /// object, or `None` when none are configured.
+    pub fn request_headers(&self, headers: &HeaderMap) -> Option<String> {
+        capture_headers(&self.request_headers, headers)
+    }
+
+    /// The configured response headers present in `headers`, as a JSON
+    /// object, or `None` when none are configured.
+    pub fn response_headers(&self, headers: &HeaderMap) -> Option<String> {
+        capture_headers(&self.response_headers, headers)
+    }
+
+    pub fn log(&self, entry: AccessLogEntry) {
+        if !self.enabled || !self.sampled(entry.status) {
+            return;
         }
+        let field = |name: &str| self.fields.iter().any(|field| field == name);
+        info!(
+            target: ACCESS_LOG,
+            method = field("method").then(|| display(&entry.method)),
+            path = field("path").then(|| display(&entry.path)),
+            status = field("status").then_some(entry.status),
+            duration_ms = field("duration_ms").then_some(entry.duration_ms),
+            cache_status = field("cache_status").then(|| entry.cache_status.as_str()),
+            remote_addr = field("remote_addr").then(|| display(&entry.remote_addr)),
+            bytes_sent = field("bytes_sent").then_some(entry.bytes_sent),
+            country = entry.country.as_deref().filter(|_| field("country")),
+            request_headers = entry.request_headers.as_deref(),
+            response_headers = entry.response_headers.as_deref(),
+            "access"
+        );
     }
 
-    Ok(())
+    /// Errors are always logged; successful requests 1 in `sample_rate`.
+    fn sampled(&self, status: u16) -> bool {
+        status >= 400
+            || self.sample_rate == 1
+            || self
+                .successes
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample_rate)
+    }
 }
 
-pub fn log_access(entry: AccessLogEntry) {
-    info!(
-        method = %entry.method,
-        path = %entry.path,
-        status = entry.status,
-        duration_ms = entry.duration_ms,
-        cache_status = entry.cache_status.as_str(),
-        remote_addr = %entry.remote_addr,
-        bytes_sent = entry.bytes_sent,
-        "access"
-    );
+fn capture_headers(names: &[HeaderName], headers: &HeaderMap) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    let captured: Map<String, Value> = names
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.into()))
+        })
+        .collect();
+    Some(Value::Object(captured).to_string())
 }
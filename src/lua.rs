@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value, VmState};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::LuaConfig;
+use crate::handlers::{full, Body};
+use crate::plugin::{CacheKeyOverride, Plugin, UpstreamOverride};
+use crate::upstream::parse_url;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Calls stop once this deadline, set before each call, has passed.
+struct Deadline(Instant);
+
+/// Runs the functions a config-referenced Lua script defines, each given the
+/// request as a table of `method`, `path`, `query` and lowercased `headers`:
+///
+/// - `rewrite_headers(req)` returns a table of headers to set, where `false`
+///   removes a header
+/// - `cache_key(req)` returns the cache key to use
+/// - `upstream(req)` returns the URL of the upstream to fetch from
+///
+/// Missing functions, and functions returning `nil`, leave relay's behaviour
+/// unchanged. Scripts get only the string, table and math libraries.
+pub struct LuaHooks {
+    script: String,
+    /// One interpreter per worker thread, so scripts don't serialize requests
+    states: Vec<Mutex<Lua>>,
+    next: AtomicUsize,
+    timeout: Duration,
+}
+
+impl LuaHooks {
+    pub fn new(config: &LuaConfig) -> Result<Self, BoxError> {
+        let source = std::fs::read_to_string(&config.script)
+            .map_err(|e| format!("Failed to read Lua script {}: {e}", config.script))?;
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let states = (0..workers)
+            .map(|_| Ok(Mutex::new(load(&config.script, &source)?)))
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            script: config.script.clone(),
+            states,
+            next: AtomicUsize::new(0),
+            timeout: config.timeout,
+        })
+    }
+
+    fn run(&self, req: &mut Request<Incoming>) -> Result<(), BoxError> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.states.len();
+        let lua = self.states[index].lock().unwrap();
+        lua.set_app_data(Deadline(Instant::now() + self.timeout));
+        let globals = lua.globals();
+
+        if let Some(rewrite_headers) = globals.get::<Option<Function>>("rewrite_headers")? {
+            let changes: Option<Table> = rewrite_headers.call(request_table(&lua, req)?)?;
+            let changes = changes.map_or_else(Vec::new, |changes| {
+                changes.pairs::<String, Value>().collect::<Vec<_>>()
+            });
+            for pair in changes {
+                let (name, value) = pair?;
+                let name = HeaderName::try_from(name.as_str())?;
+                match value {
+                    Value::Boolean(false) => {
+                        req.headers_mut().remove(&name);
+                    }
+                    value => {
+                        let value = lua.coerce_string(value)?.ok_or_else(|| {
+                            format!("rewrite_headers returned a non-string value for {name}")
+                        })?;
+                        let value = HeaderValue::from_bytes(&value.as_bytes())?;
+                        req.headers_mut().insert(name, value);
+                    }
+                }
+            }
+        }
+
+        if let Some(cache_key) = globals.get::<Option<Function>>("cache_key")? {
+            if let Some(key) = cache_key.call::<Option<String>>(request_table(&lua, req)?)? {
+                req.extensions_mut().insert(CacheKeyOverride(key));
+            }
+        }
+
+        if let Some(upstream) = globals.get::<Option<Function>>("upstream")? {
+            if let Some(url) = upstream.call::<Option<String>>(request_table(&lua, req)?)? {
+                if parse_url(&url).is_none() {
+                    return Err(format!("upstream returned an invalid URL: {url}").into());
+                }
+                req.extensions_mut().insert(UpstreamOverride(url));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn load(path: &str, source: &str) -> Result<Lua, BoxError> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(1000),
+        |lua, _debug| match lua.app_data_ref::<Deadline>() {
+            Some(deadline) if Instant::now() > deadline.0 => {
+                Err(mlua::Error::runtime("script timed out"))
+            }
+            _ => Ok(VmState::Continue),
+        },
+    )?;
+    // Running the script body to define its functions gets a generous limit
+    lua.set_app_data(Deadline(Instant::now() + Duration::from_secs(5)));
+    lua.load(source)
+        .set_name(path)
+        .exec()
+        .map_err(|e| format!("Failed to load Lua script {path}: {e}"))?;
+    Ok(lua)
+}
+
+fn request_table(lua: &Lua, req: &Request<Incoming>) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set("method", req.method().as_str())?;
+    table.set("path", req.uri().path())?;
+    table.set("query", req.uri().query())?;
+    let headers = lua.create_table()?;
+    for name in req.headers().keys() {
+        let values: Vec<&str> = req
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        if !values.is_empty() {
+            headers.set(name.as_str(), values.join(", "))?;
+        }
+    }
+    table.set("headers", headers)?;
+    Ok(table)
+}
+
+#[async_trait]
+impl Plugin for LuaHooks {
+    async fn on_request(&self, req: &mut Request<Incoming>) -> Option<Response<Body>> {
+        // Half-applied changes could cache a response under the wrong key,
+        // so a failing script fails the request
+        let err = self.run(req).err()?;
+        warn!("Lua script failed: {} - {err}", self.script);
+        let mut response = Response::new(full(Bytes::from("Internal Server Error")));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        Some(response)
+    }
+}
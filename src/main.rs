@@ -1,110 +1,36 @@
-mod cache;
-mod config;
-mod handlers;
-mod logger;
-mod metrics;
-mod storage;
-
-use std::net::SocketAddr;
-use std::sync::Arc;
-
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
-
-use config::load_config;
-use handlers::handle_request;
-use storage::{Cache, MemoryStorage, RedisStorage};
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let config = load_config("config.toml")?;
-
-    logger::init_logging(&config.logging)?;
-
-    let addr: SocketAddr = format!("{}:{}", config.server.host, config.server.port).parse()?;
-    let upstream_url = Arc::new(config.upstream.url.clone());
-
-    let cache: Cache = match config.storage.backend.as_str() {
-        "redis" => {
-            let redis_config = config
-                .storage
-                .redis
-                .as_ref()
-                .ok_or("Redis backend selected but no redis configuration provided")?;
-            println!("Initializing Redis storage backend: {}", redis_config.url);
-            Arc::new(RedisStorage::new(&redis_config.url).await?)
-        }
-        "memory" => {
-            println!("Initializing in-memory storage backend");
-            Arc::new(MemoryStorage::new())
-        }
-        backend => {
-            return Err(format!("Unknown storage backend: {backend}").into());
+use relay::{build_runtime, init_logging, load_config, run, RelayError};
+use std::process::ExitCode;
+
+/// Usage: `relay [--config <path>]`, reading `config.toml` by default.
+fn main() -> ExitCode {
+    match start() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
         }
-    };
-
-    let prometheus_enabled = Arc::new(config.prometheus.enabled);
-    let logging_enabled = Arc::new(config.logging.enabled);
-    let cache_config = Arc::new(config.cache);
-
-    println!("Server listening on {addr}");
-    println!("Upstream URL: {upstream_url}");
-    println!(
-        "Prometheus metrics: {}",
-        if *prometheus_enabled {
-            "enabled"
-        } else {
-            "disabled"
-        }
-    );
-    let ttl = cache_config.default_ttl;
-    let stale_if_error = cache_config.stale_if_error;
-    println!("Cache config: TTL={ttl:?}, stale-if-error={stale_if_error:?}");
+    }
+}
 
-    if let Some(rules) = &cache_config.rules {
-        println!("Cache rules configured:");
-        for (pattern, rule) in rules {
-            if let Some(true) = rule.bypass {
-                println!("  {pattern} -> BYPASS");
-            } else {
-                println!("  {pattern} -> TTL={:?}, stale={:?}", rule.ttl, rule.stale);
+fn start() -> Result<(), RelayError> {
+    let mut path = "config.toml".to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => {
+                path = args
+                    .next()
+                    .ok_or_else(|| RelayError::config("--config needs a path"))?
             }
+            other => return Err(RelayError::config(format!("Unknown argument: {other}"))),
         }
     }
+    let config = load_config(&path)?;
 
-    let listener = TcpListener::bind(addr).await?;
-
-    loop {
-        let (stream, remote_addr) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        let upstream_url = Arc::clone(&upstream_url);
-        let cache = Arc::clone(&cache);
-        let prometheus_enabled = Arc::clone(&prometheus_enabled);
-        let logging_enabled = Arc::clone(&logging_enabled);
-        let cache_config = Arc::clone(&cache_config);
+    // The runtime is sized from the config, so it can't come from #[tokio::main]
+    build_runtime(&config.server)?.block_on(async {
+        let _log_guard = init_logging(&config.logging).map_err(RelayError::config)?;
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req| {
-                        handle_request(
-                            req,
-                            Arc::clone(&upstream_url),
-                            Arc::clone(&cache),
-                            Arc::clone(&prometheus_enabled),
-                            Arc::clone(&logging_enabled),
-                            Arc::clone(&cache_config),
-                            remote_addr,
-                        )
-                    }),
-                )
-                .await
-            {
-                eprintln!("Error serving connection: {err:?}");
-            }
-        });
-    }
+        run(config).await
+    })
 }
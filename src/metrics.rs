@@ -1,7 +1,12 @@
+use hyper::StatusCode;
 use lazy_static::lazy_static;
 use prometheus::{
-    Histogram, IntCounter, IntGauge, register_histogram, register_int_counter, register_int_gauge,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
 };
+use std::time::{Duration, Instant};
+
+use crate::storage::Cache;
 
 lazy_static! {
     pub static ref CACHE_HITS: IntCounter =
@@ -13,19 +18,163 @@ lazy_static! {
         "Total number of stale cache responses served"
     )
     .unwrap();
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "relay_requests_total",
+        "Total number of proxied requests, by cache status",
+        &["cache_status"]
+    )
+    .unwrap();
+    pub static ref CACHE_SERVED_BYTES: IntCounter = register_int_counter!(
+        "relay_cache_served_bytes_total",
+        "Total number of response body bytes served from cache"
+    )
+    .unwrap();
+    pub static ref ORIGIN_FETCHED_BYTES: IntCounter = register_int_counter!(
+        "relay_origin_fetched_bytes_total",
+        "Total number of response body bytes received from the upstream"
+    )
+    .unwrap();
     pub static ref REQUEST_DURATION: Histogram = register_histogram!(
         "relay_request_duration_seconds",
         "Request duration in seconds",
-        vec![
-            0.001, 0.005, 0.010, 0.025, 0.050, 0.100, 0.250, 0.500, 1.0, 2.5
-        ]
+        vec![0.001, 0.005, 0.010, 0.025, 0.050, 0.100, 0.250, 0.500, 1.0, 2.5]
     )
     .unwrap();
-    pub static ref UPSTREAM_ERRORS: IntCounter = register_int_counter!(
+    pub static ref UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
         "relay_upstream_errors_total",
-        "Total number of upstream request errors"
+        "Total number of upstream request errors, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_CONNECT_DURATION: Histogram = register_histogram!(
+        "relay_upstream_connect_duration_seconds",
+        "Time to open a connection to the upstream, including TLS, in seconds",
+        vec![0.0005, 0.001, 0.005, 0.010, 0.025, 0.050, 0.100, 0.250, 0.500, 1.0, 2.5]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_FIRST_BYTE: Histogram = register_histogram!(
+        "relay_upstream_first_byte_seconds",
+        "Time from sending a request upstream to receiving the response headers, in seconds",
+        vec![0.001, 0.005, 0.010, 0.025, 0.050, 0.100, 0.250, 0.500, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "relay_upstream_responses_total",
+        "Total number of upstream responses, by status class",
+        &["status_class"]
+    )
+    .unwrap();
+    pub static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "relay_errors_total",
+        "Total number of requests answered with an error relay generated, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounter = register_int_counter!(
+        "relay_rate_limited_total",
+        "Total number of requests rejected by the rate limiter"
+    )
+    .unwrap();
+    pub static ref QUOTA_EXCEEDED: IntCounter = register_int_counter!(
+        "relay_quota_exceeded_total",
+        "Total number of requests rejected because an API key's quota was used up"
+    )
+    .unwrap();
+    pub static ref CACHE_EVICTIONS: IntCounter = register_int_counter!(
+        "relay_cache_evictions_total",
+        "Total number of cache entries evicted to stay within the size limit"
     )
     .unwrap();
     pub static ref CACHE_SIZE: IntGauge =
         register_int_gauge!("relay_cache_entries", "Current number of entries in cache").unwrap();
+    pub static ref REQUESTS_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "relay_requests_in_flight",
+        "Number of requests currently being handled"
+    )
+    .unwrap();
+    pub static ref LOAD_SHED: IntCounter = register_int_counter!(
+        "relay_load_shed_total",
+        "Total number of requests rejected because the concurrency limit was reached"
+    )
+    .unwrap();
+    pub static ref UPSTREAM_EJECTIONS: IntCounter = register_int_counter!(
+        "relay_upstream_ejections_total",
+        "Total number of upstream servers taken out of rotation by outlier detection"
+    )
+    .unwrap();
+    pub static ref HEDGED_REQUESTS: IntCounter = register_int_counter!(
+        "relay_hedged_requests_total",
+        "Total number of second requests sent because the first was slow"
+    )
+    .unwrap();
+    pub static ref HEDGE_WINS: IntCounter = register_int_counter!(
+        "relay_hedge_wins_total",
+        "Total number of hedged requests answered before the original"
+    )
+    .unwrap();
+    pub static ref SPLIT_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "relay_split_requests_total",
+        "Total number of requests on split routes, by split and variant",
+        &["split", "variant"]
+    )
+    .unwrap();
+    pub static ref SPLIT_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "relay_split_errors_total",
+        "Total number of upstream failures and 5xx responses on split routes, by split and variant",
+        &["split", "variant"]
+    )
+    .unwrap();
+    pub static ref SPLIT_DURATION: HistogramVec = register_histogram_vec!(
+        "relay_split_request_duration_seconds",
+        "Request duration in seconds on split routes, by split and variant",
+        &["split", "variant"],
+        vec![0.001, 0.005, 0.010, 0.025, 0.050, 0.100, 0.250, 0.500, 1.0, 2.5]
+    )
+    .unwrap();
+}
+
+/// Counts a request in `REQUESTS_IN_FLIGHT` for as long as it is alive.
+pub struct InFlightRequest;
+
+impl InFlightRequest {
+    pub fn start() -> Self {
+        REQUESTS_IN_FLIGHT.inc();
+        InFlightRequest
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+/// Records an upstream response whose headers arrived `sent.elapsed()`
+/// after the request went out.
+pub fn observe_upstream_response(sent: Instant, status: StatusCode) {
+    UPSTREAM_FIRST_BYTE.observe(sent.elapsed().as_secs_f64());
+    let class = match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    };
+    UPSTREAM_RESPONSES.with_label_values(&[class]).inc();
+}
+
+/// How often `CACHE_SIZE` is brought up to date
+const CACHE_SIZE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keeps `CACHE_SIZE` current from a background task, since asking the
+/// backend can mean a lock or a Redis round trip.
+pub fn spawn_cache_size_updates(cache: Cache) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CACHE_SIZE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            CACHE_SIZE.set(cache.size().await as i64);
+        }
+    });
 }
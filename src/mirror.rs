@@ -0,0 +1,92 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use http_body_util::BodyExt;
+use hyper::header::HeaderMap;
+use hyper::{Method, Uri};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls::ClientConfig;
+use tracing::warn;
+
+use crate::config::MirrorConfig;
+use crate::handlers::send_upstream_with_method;
+use crate::upstream::parse_url;
+
+struct Mirror {
+    routes: GlobSet,
+    url: Arc<String>,
+    base_url: Uri,
+    sample: f64,
+    timeout: Duration,
+    in_flight: Arc<Semaphore>,
+}
+
+/// Shadow upstreams that get a fire-and-forget copy of live requests, so a
+/// new backend can be tried against production traffic. Their responses are
+/// read and discarded; they never affect what the client receives.
+pub struct Mirrors {
+    mirrors: Vec<Mirror>,
+}
+
+impl Mirrors {
+    pub fn new(configs: &[MirrorConfig]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mirrors = configs
+            .iter()
+            .map(|config| {
+                let mut routes = GlobSetBuilder::new();
+                for pattern in &config.routes {
+                    routes.add(Glob::new(pattern)?);
+                }
+                let Some(base_url) = parse_url(&config.url) else {
+                    return Err(format!("Invalid mirror URL: {}", config.url).into());
+                };
+                Ok(Mirror {
+                    routes: routes.build()?,
+                    url: Arc::new(config.url.clone()),
+                    base_url,
+                    sample: config.sample,
+                    timeout: config.timeout,
+                    in_flight: Arc::new(Semaphore::new(config.max_in_flight)),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+        Ok(Self { mirrors })
+    }
+
+    /// Copies a request to each mirror whose routes match `uri`, subject to
+    /// its sampling rate, without waiting for the copies to complete.
+    pub fn send(&self, uri: &Uri, method: &Method, headers: &HeaderMap, tls: &Arc<ClientConfig>) {
+        for mirror in &self.mirrors {
+            if !mirror.routes.is_match(uri.path()) || rand::random::<f64>() >= mirror.sample {
+                continue;
+            }
+            // A slow mirror mustn't pile up tasks, so copies beyond the
+            // limit are dropped rather than queued
+            let Ok(permit) = Arc::clone(&mirror.in_flight).try_acquire_owned() else {
+                continue;
+            };
+            let url = Arc::clone(&mirror.url);
+            let base_url = mirror.base_url.clone();
+            let tls = Arc::clone(tls);
+            let uri = uri.clone();
+            let method = method.clone();
+            let headers = headers.clone();
+            let timeout = mirror.timeout;
+            tokio::spawn(async move {
+                let request = async {
+                    let res =
+                        send_upstream_with_method(&base_url, &tls, &uri, method, &headers).await?;
+                    res.into_body().collect().await?;
+                    Ok::<_, Box<dyn Error + Send + Sync>>(())
+                };
+                match tokio::time::timeout(timeout, request).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => warn!("Mirror request failed: {url}{uri} - {err}"),
+                    Err(_) => warn!("Mirror request timed out: {url}{uri}"),
+                }
+                drop(permit);
+            });
+        }
+    }
+}
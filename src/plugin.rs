@@ -0,0 +1,44 @@
+use async_trait::async_trait;
+use hyper::body::Incoming;
+use hyper::http::response::Parts;
+use hyper::{Request, Response, Uri};
+
+use crate::cache::CachedResponse;
+use crate::handlers::Body;
+
+/// Set on a request in `on_request` to use this cache key instead of the one
+/// relay would generate. Keys are still prefixed with the cache namespace.
+#[derive(Clone, Debug)]
+pub struct CacheKeyOverride(pub String);
+
+/// Set on a request in `on_request` to fetch it from this upstream, a URL
+/// such as `http://api-v2:8080`, instead of the configured one. Applies to
+/// cached and bypassed routes; responses are cached apart from those of
+/// other upstreams.
+#[derive(Clone, Debug)]
+pub struct UpstreamOverride(pub String);
+
+/// Hooks into request handling, registered with `RelayBuilder::plugin`.
+/// Every hook has a default that leaves relay's behaviour unchanged, so a
+/// plugin only implements the ones it needs. Plugins run in the order they
+/// were registered.
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    /// Runs on each request before relay's own access checks, routing and
+    /// caching. The request can be changed in place; returning a response
+    /// answers the request with it, and later plugins don't run.
+    async fn on_request(&self, _req: &mut Request<Incoming>) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Runs on each response fetched from the upstream for a cacheable or
+    /// bypassed route, including background refreshes, before it is cached
+    /// or sent on. `uri` is the request's URI.
+    async fn on_upstream_response(&self, _uri: &Uri, _res: &mut Parts) {}
+
+    /// Runs before a response is stored under `key`. Returning false keeps it
+    /// out of the cache; it is still sent to the client.
+    async fn on_cache_store(&self, _key: &str, _response: &CachedResponse) -> bool {
+        true
+    }
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Opening bytes of a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// PROXY protocol version sent to origins.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> Result<Self, String> {
+        match version {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            other => Err(format!("Unsupported PROXY protocol version: {other}")),
+        }
+    }
+}
+
+/// Reads the PROXY protocol header, version 1 or 2, a load balancer sends
+/// ahead of the connection's data, and returns the client address it
+/// carries. `None` means the header names no client, as in health checks
+/// (`LOCAL` and `UNKNOWN`), so the peer's own address applies. Nothing past
+/// the header is read.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY protocol source address"))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let [version_command, family, len_high, len_low] = head;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes([len_high, len_low]))];
+    stream.read_exact(&mut addresses).await?;
+    // LOCAL connections come from the load balancer itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let source = match family {
+        // TCP over IPv4: source and destination addresses, then ports
+        0x11 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into().unwrap();
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        0x21 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into().unwrap();
+            SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )
+        }
+        // UDP, Unix sockets and unspecified families carry no usable address
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// The header announcing a connection from `source` to `destination`, or
+/// one naming no client when `source` is unknown.
+pub fn header(version: Version, source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+    let addresses = source.map(|source| match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (source, destination)
+        }
+        // Both sides must be of one family; IPv4 maps into IPv6
+        _ => (to_v6(source), to_v6(destination)),
+    });
+    match version {
+        Version::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addresses {
+                Some((source, destination)) => {
+                    let (family, mut body) = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                            (0x11, [source.octets(), destination.octets()].concat())
+                        }
+                        (source, destination) => (
+                            0x21,
+                            [to_v6_octets(source), to_v6_octets(destination)].concat(),
+                        ),
+                    };
+                    body.extend(source.port().to_be_bytes());
+                    body.extend(destination.port().to_be_bytes());
+                    header.extend([0x21, family]);
+                    header.extend((body.len() as u16).to_be_bytes());
+                    header.extend(body);
+                }
+                None => header.extend([0x20, 0x00, 0x00, 0x00]),
+            }
+            header
+        }
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(to_v6_octets(addr.ip()).into()), addr.port())
+}
+
+fn to_v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
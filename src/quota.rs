@@ -0,0 +1,112 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::Uri;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache_key::sha256_hex;
+use crate::config::QuotaConfig;
+use crate::storage::Cache;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Where a client stands against its quota in the current minute.
+pub struct QuotaUsage {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the window resets
+    reset: u64,
+}
+
+impl QuotaUsage {
+    pub fn retry_after(&self) -> u64 {
+        self.reset
+    }
+
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("X-RateLimit-Reset", HeaderValue::from(self.reset));
+    }
+}
+
+pub enum QuotaCheck {
+    /// The key is missing or unknown and one is required
+    Unknown,
+    Allowed(QuotaUsage),
+    Exceeded(QuotaUsage),
+}
+
+/// Requests-per-minute quotas per API key, counted in fixed one-minute
+/// windows. Requests without a known key are counted per client IP unless
+/// a key is required. Counters live in `counters`, so instances sharing a
+/// Redis share quotas.
+pub struct Quotas {
+    header: HeaderName,
+    query_param: Option<String>,
+    require_key: bool,
+    keys: HashMap<String, u32>,
+    anonymous_limit: u32,
+    key_prefix: String,
+    counters: Cache,
+}
+
+impl Quotas {
+    pub fn new(
+        config: &QuotaConfig,
+        counters: Cache,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self {
+            header: HeaderName::try_from(config.header.as_str())?,
+            query_param: config.query_param.clone(),
+            require_key: config.require_key,
+            keys: config.keys.clone(),
+            anonymous_limit: config.anonymous_limit,
+            key_prefix: config.key_prefix.clone(),
+            counters,
+        })
+    }
+
+    /// Counts the request from `client_ip` against its key's quota, or the
+    /// client's own when it has no known key.
+    pub async fn check(&self, client_ip: IpAddr, headers: &HeaderMap, uri: &Uri) -> QuotaCheck {
+        let key = self.api_key(headers, uri);
+        // Hashed so API keys never appear in the storage backend
+        let (counter, limit) = match key.and_then(|key| self.keys.get_key_value(key.as_str())) {
+            Some((key, &limit)) => (format!("key:{}", sha256_hex(key)), limit),
+            None if self.require_key => return QuotaCheck::Unknown,
+            None => (format!("ip:{client_ip}"), self.anonymous_limit),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let window = now / WINDOW.as_secs();
+        let counter = format!("{}{counter}:{window}", self.key_prefix);
+        let count = self.counters.increment(&counter, WINDOW).await;
+
+        let usage = QuotaUsage {
+            limit,
+            remaining: limit.saturating_sub(count.try_into().unwrap_or(u32::MAX)),
+            reset: WINDOW.as_secs() - now % WINDOW.as_secs(),
+        };
+        if count > u64::from(limit) {
+            QuotaCheck::Exceeded(usage)
+        } else {
+            QuotaCheck::Allowed(usage)
+        }
+    }
+
+    /// The key from the header, falling back to the query parameter.
+    fn api_key(&self, headers: &HeaderMap, uri: &Uri) -> Option<String> {
+        if let Some(value) = headers.get(&self.header).and_then(|v| v.to_str().ok()) {
+            return Some(value.trim().to_string());
+        }
+        let param = self.query_param.as_deref()?;
+        form_urlencoded::parse(uri.query()?.as_bytes())
+            .find(|(name, _)| name == param)
+            .map(|(_, value)| value.into_owned())
+    }
+}
@@ -0,0 +1,101 @@
+use hyper::header::{HeaderMap, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use hyper::StatusCode;
+
+use crate::cache::CachedResponse;
+
+/// A byte range resolved against a stored body, with inclusive bounds.
+pub enum ByteRange {
+    Satisfiable { start: usize, end: usize },
+    Unsatisfiable,
+}
+
+/// The `Range` and `If-Range` headers of a client request.
+pub struct RangeRequest {
+    range: String,
+    if_range: Option<String>,
+}
+
+impl RangeRequest {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let range = headers.get(RANGE)?.to_str().ok()?.to_string();
+        let if_range = headers
+            .get(IF_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Some(Self { range, if_range })
+    }
+
+    /// Resolves the range against a cached response. `None` means the full
+    /// response should be sent instead: the entry isn't a 200, the `If-Range`
+    /// validator doesn't match, or the range is malformed or has several
+    /// parts, all of which RFC 9110 §14.2 allows a server to ignore.
+    pub fn resolve(&self, cached: &CachedResponse) -> Option<ByteRange> {
+        if cached.status != StatusCode::OK {
+            return None;
+        }
+        self.resolve_len(&cached.headers, cached.body.len())
+    }
+
+    /// Like `resolve`, for a 200 response with `headers` whose body is `len`
+    /// bytes long but not at hand, such as a file streamed from disk.
+    pub fn resolve_len(&self, headers: &HeaderMap, len: usize) -> Option<ByteRange> {
+        if !self.if_range_matches(headers) {
+            return None;
+        }
+
+        let spec = self.range.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+
+        let range = if first.is_empty() {
+            let suffix: usize = last.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                }
+            }
+        } else {
+            let start: usize = first.parse().ok()?;
+            let end = match last {
+                "" => usize::MAX,
+                last => last.parse().ok()?,
+            };
+            if end < start {
+                return None;
+            }
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable {
+                    start,
+                    end: end.min(len - 1),
+                }
+            }
+        };
+        Some(range)
+    }
+
+    /// `If-Range` must equal the stored strong ETag or Last-Modified date
+    /// exactly; a weak ETag never matches.
+    fn if_range_matches(&self, headers: &HeaderMap) -> bool {
+        let Some(if_range) = &self.if_range else {
+            return true;
+        };
+        if if_range.starts_with("W/") {
+            return false;
+        }
+        let header = if if_range.starts_with('"') {
+            ETAG
+        } else {
+            LAST_MODIFIED
+        };
+        headers
+            .get(header)
+            .is_some_and(|value| value.as_bytes() == if_range.as_bytes())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// Buckets that have been idle long enough to refill completely carry no
+/// state worth keeping, so they are pruned once the map grows past this size,
+/// and after that whenever it has doubled since the last prune.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A bucket, filling at the rate and up to the burst of the limits it was
+/// created under.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+            rate,
+            burst,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Whether the bucket would be full by `now`, so dropping it changes
+    /// nothing.
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.burst
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(IpAddr, Limits), TokenBucket>,
+    /// The size the map has to pass before it is next pruned.
+    prune_at: usize,
+}
+
+/// Which limits a bucket counts against: a route's, the global ones, or
+/// those for crawlers.
+#[derive(PartialEq, Eq, Hash)]
+enum Limits {
+    Route(usize),
+    Global,
+    Bots,
+}
+
+/// Token-bucket rate limiter keyed by client IP and matched route.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled || self.config.bots.is_some()
+    }
+
+    /// Whether crawlers have limits of their own.
+    pub fn limits_bots(&self) -> bool {
+        self.config.bots.is_some()
+    }
+
+    /// Consumes a token for `ip` on `path`, from the crawler limits when
+    /// `bot` is set and they are configured. Returns how long the client
+    /// should wait before retrying when the bucket is empty.
+    pub fn check(&self, ip: IpAddr, path: &str, bot: bool) -> Result<(), Duration> {
+        let (limits, rate, burst) = match (&self.config.bots, self.config.find_route(path)) {
+            (Some(bots), _) if bot => (
+                Limits::Bots,
+                bots.rate.unwrap_or(self.config.rate),
+                bots.burst.unwrap_or(self.config.burst),
+            ),
+            _ if !self.config.enabled => return Ok(()),
+            (_, Some((index, rule))) => (
+                Limits::Route(index),
+                rule.rate.unwrap_or(self.config.rate),
+                rule.burst.unwrap_or(self.config.burst),
+            ),
+            (_, None) => (Limits::Global, self.config.rate, self.config.burst),
+        };
+        let rate = f64::from(rate.max(1));
+        let burst = f64::from(burst.max(1));
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { buckets, prune_at } = &mut *buckets;
+        if buckets.len() > *prune_at {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+            *prune_at = (buckets.len() * 2).max(PRUNE_THRESHOLD);
+        }
+
+        let bucket = buckets
+            .entry((ip, limits))
+            .or_insert_with(|| TokenBucket::new(rate, burst));
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
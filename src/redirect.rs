@@ -0,0 +1,147 @@
+use hyper::header::{HeaderMap, HeaderValue, LOCATION};
+use hyper::{StatusCode, Uri};
+use std::error::Error;
+
+use crate::config::RedirectConfig;
+use crate::upstream::parse_url;
+
+/// Scheme, host and port of a URL, compared case-insensitively and with the
+/// scheme's default port filled in.
+#[derive(PartialEq)]
+struct Origin {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    fn of(uri: &Uri) -> Option<Self> {
+        let scheme = uri.scheme_str()?.to_ascii_lowercase();
+        let port = match (uri.port_u16(), scheme.as_str()) {
+            (Some(port), _) => port,
+            (None, "http") => 80,
+            (None, "https") => 443,
+            _ => return None,
+        };
+        Some(Self {
+            host: uri.host()?.to_ascii_lowercase(),
+            scheme,
+            port,
+        })
+    }
+}
+
+/// Keeps upstream redirects from leaking the upstream's address: `Location`
+/// headers pointing at it are rewritten to relay's public address, and
+/// redirects can be followed server-side on cacheable GETs.
+pub struct Redirects {
+    rewrite_location: bool,
+    /// `scheme://host` clients use, when configured
+    public_origin: Option<String>,
+    /// Scheme of relay's listener, paired with the request's host otherwise
+    scheme: &'static str,
+    upstreams: Vec<Origin>,
+    follow: u32,
+}
+
+impl Redirects {
+    pub fn new(
+        config: &RedirectConfig,
+        upstream_urls: &[String],
+        tls: bool,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let public_origin = match &config.public_url {
+            Some(url) => {
+                let uri = url.parse::<Uri>()?;
+                match (uri.scheme_str(), uri.authority()) {
+                    (Some(scheme), Some(authority)) => Some(format!("{scheme}://{authority}")),
+                    _ => {
+                        return Err(
+                            format!("redirects.public_url must be an absolute URL: {url}").into(),
+                        )
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(Self {
+            rewrite_location: config.rewrite_location,
+            public_origin,
+            scheme: if tls { "https" } else { "http" },
+            upstreams: upstream_urls
+                .iter()
+                .filter_map(|url| Origin::of(&parse_url(url)?))
+                .collect(),
+            follow: config.follow,
+        })
+    }
+
+    /// How many redirects to follow for a cacheable GET.
+    pub fn follow(&self) -> u32 {
+        self.follow
+    }
+
+    /// Points a `Location` header at relay when it names an upstream server, or
+    /// `upstream` when the request was sent elsewhere. `host` is the host the
+    /// client addressed.
+    pub fn rewrite(&self, headers: &mut HeaderMap, host: Option<&str>, upstream: Option<&str>) {
+        if !self.rewrite_location {
+            return;
+        }
+        let Some(location) = headers
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Uri>().ok())
+        else {
+            return;
+        };
+        if !self.is_upstream(&location, upstream) {
+            return;
+        }
+        let path = location.path_and_query().map_or("/", |pq| pq.as_str());
+        let rewritten = match (&self.public_origin, host) {
+            (Some(origin), _) => format!("{origin}{path}"),
+            (None, Some(host)) => format!("{}://{host}{path}", self.scheme),
+            (None, None) => path.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&rewritten) {
+            headers.insert(LOCATION, value);
+        }
+    }
+
+    /// Where a redirect response sends the client, when that is a path on
+    /// the same upstream and so safe to fetch in its place.
+    pub fn follow_target(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        upstream: Option<&str>,
+    ) -> Option<Uri> {
+        if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+            return None;
+        }
+        let location = headers.get(LOCATION)?.to_str().ok()?;
+        if location.starts_with('/') && !location.starts_with("//") {
+            return location.parse().ok();
+        }
+        let uri = location.parse::<Uri>().ok()?;
+        if !self.is_upstream(&uri, upstream) {
+            return None;
+        }
+        uri.path_and_query()?.as_str().parse().ok()
+    }
+
+    fn is_upstream(&self, location: &Uri, upstream: Option<&str>) -> bool {
+        let Some(origin) = Origin::of(location) else {
+            return false;
+        };
+        match upstream {
+            Some(url) => url
+                .parse::<Uri>()
+                .ok()
+                .and_then(|uri| Origin::of(&uri))
+                .is_some_and(|upstream| upstream == origin),
+            None => self.upstreams.contains(&origin),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use hyper::header::HeaderMap;
+use hyper::Uri;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::cache_key::generate_cache_key;
+use crate::handlers::{spawn_refresh, AppState};
+
+/// A path kept warm by a rule's refresh-ahead loop.
+pub struct Tracked {
+    uri: Uri,
+    /// Claims, client-certificate fields and varied headers or cookies
+    /// that are part of the key, sent again so the refresh fetches the
+    /// same variant
+    headers: HeaderMap,
+    /// When a client last asked for it; None for literal patterns, which
+    /// are refreshed whether or not anyone does
+    last_requested: Option<Instant>,
+}
+
+/// Registers a requested key to be refreshed by its rule's refresh-ahead
+/// loop, unless `cache.refresh_ahead_max_keys` are already tracked.
+pub fn track(state: &AppState, key: String, uri: &Uri, headers: &HeaderMap) {
+    let mut tracked = state.refresh_ahead.lock().unwrap();
+    if let Some(existing) = tracked.get_mut(&key) {
+        if existing.last_requested.is_some() {
+            existing.last_requested = Some(Instant::now());
+        }
+        return;
+    }
+    if tracked.len() < state.cache_config.refresh_ahead_max_keys {
+        tracked.insert(
+            key,
+            Tracked {
+                uri: uri.clone(),
+                headers: headers.clone(),
+                last_requested: Some(Instant::now()),
+            },
+        );
+    }
+}
+
+/// Starts one loop per rule with a `refresh_interval`. Literal patterns are
+/// refreshed from startup; glob patterns refresh every matching path that has
+/// been requested within the rule's TTL and is still cached.
+pub fn spawn_refresh_ahead(state: &Arc<AppState>) {
+    let Some(rules) = &state.cache_config.rules else {
+        return;
+    };
+
+    for (pattern, rule) in rules {
+        let Some(interval) = rule.refresh_interval else {
+            continue;
+        };
+        info!("Refresh-ahead: {pattern} every {interval:?}");
+
+        if !pattern.contains(['*', '?', '[', '{']) {
+            if let Ok(uri) = pattern.parse::<Uri>() {
+                let key = generate_cache_key(
+                    &uri,
+                    &HeaderMap::new(),
+                    &state.cache_config.key,
+                    Some(rule),
+                );
+                state.refresh_ahead.lock().unwrap().insert(
+                    key,
+                    Tracked {
+                        uri,
+                        headers: HeaderMap::new(),
+                        last_requested: None,
+                    },
+                );
+            }
+        }
+
+        // A path nobody asks for again stops being refreshed once the entry
+        // it was refreshing would have expired on its own
+        let idle = rule
+            .ttl
+            .unwrap_or(state.cache_config.default_ttl)
+            .max(interval);
+        let state = Arc::clone(state);
+        let pattern = pattern.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let due = take_due(&state, &pattern, idle);
+                for (key, uri, headers, requested) in due {
+                    let storage_key = state.storage_key(key.clone());
+                    // Evicted or purged entries aren't brought back
+                    if requested && state.cache.get(&storage_key).await.is_none() {
+                        state.refresh_ahead.lock().unwrap().remove(&key);
+                        continue;
+                    }
+                    spawn_refresh(Arc::clone(&state), storage_key, uri, headers, None);
+                }
+            }
+        });
+    }
+}
+
+/// The tracked keys under `pattern`, dropping those idle for longer than
+/// `idle`, with whether each was added by a request.
+fn take_due(
+    state: &AppState,
+    pattern: &str,
+    idle: Duration,
+) -> Vec<(String, Uri, HeaderMap, bool)> {
+    let mut tracked = state.refresh_ahead.lock().unwrap();
+    let mut due = Vec::new();
+    tracked.retain(|key, entry| {
+        let matches = state
+            .cache_config
+            .find_rule_with_pattern(entry.uri.path())
+            .is_some_and(|matched| matched.pattern == pattern);
+        if !matches {
+            return true;
+        }
+        if entry
+            .last_requested
+            .is_some_and(|requested| requested.elapsed() > idle)
+        {
+            return false;
+        }
+        due.push((
+            key.clone(),
+            entry.uri.clone(),
+            entry.headers.clone(),
+            entry.last_requested.is_some(),
+        ));
+        true
+    });
+    due
+}
@@ -0,0 +1,69 @@
+use std::io;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::oneshot;
+
+use crate::config::ServerConfig;
+
+/// Builds the runtime the `relay` binary runs on: a multi-threaded one
+/// sized by `worker_threads` and `max_blocking_threads`, or, with
+/// `runtime_sharding`, a single-threaded one left with startup and
+/// background work while connections are served by [`Shards`].
+pub fn build_runtime(server: &ServerConfig) -> io::Result<Runtime> {
+    let mut builder = if server.runtime_sharding {
+        Builder::new_current_thread()
+    } else {
+        let mut builder = Builder::new_multi_thread();
+        if let Some(worker_threads) = server.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        builder
+    };
+    if let Some(max_blocking_threads) = server.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+    builder.enable_all().build()
+}
+
+/// Single-threaded runtimes, one per thread, that each accept and serve
+/// their own share of connections. A connection, and everything spawned
+/// while serving it, stays on one thread, trading work stealing for less
+/// cross-thread synchronization.
+pub struct Shards {
+    handles: Vec<Handle>,
+    /// Dropping these stops the runtimes
+    _stop: Vec<oneshot::Sender<()>>,
+}
+
+impl Shards {
+    /// Starts a shard per worker thread, defaulting to one per CPU core.
+    pub fn start(server: &ServerConfig) -> io::Result<Self> {
+        let count = server
+            .worker_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        let mut handles = Vec::with_capacity(count);
+        let mut stop = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut builder = Builder::new_current_thread();
+            if let Some(max_blocking_threads) = server.max_blocking_threads {
+                builder.max_blocking_threads(max_blocking_threads);
+            }
+            let runtime = builder.enable_all().build()?;
+            let (stop_tx, stop_rx) = oneshot::channel::<()>();
+            handles.push(runtime.handle().clone());
+            std::thread::Builder::new()
+                .name(format!("relay-shard-{index}"))
+                .spawn(move || {
+                    let _ = runtime.block_on(stop_rx);
+                })?;
+            stop.push(stop_tx);
+        }
+        Ok(Self {
+            handles,
+            _stop: stop,
+        })
+    }
+
+    pub fn handles(&self) -> &[Handle] {
+        &self.handles
+    }
+}
@@ -0,0 +1,1074 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::header::{HeaderName, HeaderValue, ALT_SVC};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tower::util::{BoxCloneSyncService, MapRequestLayer};
+use tower::{Layer, Service, ServiceExt};
+use tracing::{debug, info, warn};
+
+use crate::access::{AccessControl, DebugAccess};
+use crate::auth::EndpointAuth;
+use crate::balancer::Balancer;
+use crate::cluster::Cluster;
+use crate::compression::Compression;
+use crate::config::{
+    Config, LuaConfig, MokaConfig, SecurityHeadersConfig, ServerConfig, StorageConfig, TlsConfig,
+    WasmFilterConfig,
+};
+use crate::cors::Cors;
+use crate::dashboard::Dashboard;
+use crate::dns;
+use crate::error::{BoxError, RelayError};
+use crate::error_pages::ErrorPages;
+use crate::geoip::GeoIp;
+use crate::handlers::{AdminHidden, AppState, Body, ClientSubject};
+use crate::hedge::Hedging;
+#[cfg(feature = "http3")]
+use crate::http3;
+use crate::jwt::JwtAuth;
+use crate::limits::{InFlight, InFlightGuard, TimeoutIo};
+use crate::logger::AccessLog;
+#[cfg(feature = "lua")]
+use crate::lua;
+use crate::mirror::Mirrors;
+use crate::plugin::Plugin;
+use crate::proxy_protocol;
+use crate::quota::Quotas;
+use crate::rate_limit::RateLimiter;
+use crate::redirect::Redirects;
+use crate::runtime::Shards;
+use crate::service::{ClientAddr, HttpService, RelayService, ResponseHeadersLayer};
+use crate::signed_url::SignedUrls;
+use crate::split::Splits;
+use crate::storage::{Cache, DiskStorage, MemoryStorage, MokaStorage, RedisStorage, TieredStorage};
+use crate::systemd::{self, InheritedSockets};
+use crate::transform::Transforms;
+use crate::upstream::{self, load_headers, Http2Upstream};
+#[cfg(feature = "wasm")]
+use crate::wasm;
+use crate::{cluster, limits, metrics, refresh, tls, warmup};
+
+/// Embeds relay in another program. Takes the same [`Config`] the binary
+/// reads from `config.toml`, and optionally a storage backend to use in
+/// place of the configured one.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), relay::RelayError> {
+/// let config = relay::load_config("config.toml")?;
+/// relay::RelayBuilder::new(config)
+///     .shutdown(async {
+///         let _ = tokio::signal::ctrl_c().await;
+///     })
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct RelayBuilder {
+    config: Config,
+    storage: Option<Cache>,
+    shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    layers: Vec<BoxLayer>,
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+type BoxLayer = Box<dyn FnOnce(HttpService) -> HttpService + Send>;
+
+impl RelayBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            storage: None,
+            shutdown: None,
+            layers: Vec::new(),
+            plugins: Vec::new(),
+        }
+    }
+
+    /// Stores cached responses in `storage`, ignoring `[storage]` in the
+    /// config.
+    pub fn storage(mut self, storage: Cache) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Stops accepting connections once `signal` resolves, instead of on
+    /// Ctrl-C or SIGTERM.
+    pub fn shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Registers a plugin, whose hooks run after those of plugins registered
+    /// before it.
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Wraps the proxy pipeline in a tower layer, such as a timeout or
+    /// concurrency limit from the tower ecosystem or middleware of your own.
+    /// Each layer wraps the ones added before it. Requests carry a
+    /// [`ClientAddr`](crate::ClientAddr) extension.
+    ///
+    /// ```no_run
+    /// # async fn example() -> Result<(), relay::RelayError> {
+    /// use hyper::header::{HeaderValue, SERVER};
+    /// use tower::util::MapResponseLayer;
+    ///
+    /// let config = relay::load_config("config.toml")?;
+    /// relay::RelayBuilder::new(config)
+    ///     .layer(MapResponseLayer::new(|mut res: hyper::Response<relay::Body>| {
+    ///         res.headers_mut().insert(SERVER, HeaderValue::from_static("relay"));
+    ///         res
+    ///     }))
+    ///     .run()
+    ///     .await
+    /// # }
+    /// ```
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<HttpService> + Send + 'static,
+        L::Service: Service<Request<Incoming>, Response = Response<Body>, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <L::Service as Service<Request<Incoming>>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |service| {
+            BoxCloneSyncService::new(layer.layer(service))
+        }));
+        self
+    }
+
+    /// Serves until the shutdown signal, saving the memory backend's
+    /// snapshot, if configured, on the way out.
+    pub async fn run(self) -> Result<(), RelayError> {
+        serve(self).await
+    }
+}
+
+/// Runs relay with `config` until Ctrl-C or SIGTERM.
+pub async fn run(config: Config) -> Result<(), RelayError> {
+    RelayBuilder::new(config).run().await
+}
+
+async fn serve(builder: RelayBuilder) -> Result<(), RelayError> {
+    let RelayBuilder {
+        config,
+        storage,
+        shutdown,
+        layers,
+        mut plugins,
+    } = builder;
+    let upstream_url = Arc::new(config.upstream.url.clone());
+
+    let mut snapshot_storage = None;
+    let cache: Cache = match (
+        storage,
+        config.storage.backend.as_str(),
+        &config.storage.snapshot,
+    ) {
+        (Some(storage), _, _) => {
+            info!("Using embedder-provided storage backend");
+            storage
+        }
+        (None, "tiered", _) => {
+            let tiered_config = config.storage.tiered.as_ref().ok_or_else(|| {
+                RelayError::config("Tiered backend selected but no tiered configuration provided")
+            })?;
+            info!(
+                "Initializing tiered storage backend: L1 memory ({} entries), L2 {}",
+                tiered_config.l1_max_entries, tiered_config.l2
+            );
+            let l2 = build_storage(&tiered_config.l2, &config.storage).await?;
+            Arc::new(TieredStorage::new(
+                l2,
+                tiered_config.l1_max_entries,
+                tiered_config.l1_ttl,
+            ))
+        }
+        (None, "memory", Some(snapshot)) => {
+            info!(
+                "Initializing in-memory storage backend with snapshots to {} every {:?}",
+                snapshot.path, snapshot.interval
+            );
+            let storage = Arc::new(MemoryStorage::new());
+            let path = PathBuf::from(&snapshot.path);
+            match storage.load_snapshot(&path).await {
+                Ok(loaded) => info!("Loaded {loaded} cache entries from snapshot"),
+                Err(e) => warn!("Failed to load cache snapshot {}: {e}", snapshot.path),
+            }
+            storage.spawn_snapshots(path.clone(), snapshot.interval);
+            snapshot_storage = Some((Arc::clone(&storage), path));
+            storage
+        }
+        (None, backend, _) => build_storage(backend, &config.storage).await?,
+    };
+
+    let metrics_auth =
+        EndpointAuth::new(config.prometheus.auth.as_ref()).map_err(RelayError::config)?;
+    let admin_auth = EndpointAuth::new(config.admin.auth.as_ref()).map_err(RelayError::config)?;
+    if config.prometheus.enabled && metrics_auth.is_none() {
+        warn!("/metrics is enabled without authentication");
+    }
+    if config.admin.enabled && admin_auth.is_none() {
+        warn!("admin API is enabled without authentication");
+    }
+
+    let prometheus_enabled = Arc::new(config.prometheus.enabled);
+    if config.prometheus.enabled {
+        metrics::spawn_cache_size_updates(Arc::clone(&cache));
+    }
+    let access_log = Arc::new(AccessLog::new(&config.logging));
+    let cache_config = Arc::new(config.cache);
+
+    if config.upstream.urls.len() > 1 {
+        info!("Upstream URLs: {}", config.upstream.urls.join(", "));
+    } else {
+        info!("Upstream URL: {upstream_url}");
+    }
+    info!(
+        "Prometheus metrics: {}",
+        if *prometheus_enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    let ttl = cache_config.default_ttl;
+    let stale_if_error = cache_config.stale_if_error;
+    info!("Cache config: TTL={ttl:?}, stale-if-error={stale_if_error:?}");
+
+    if let Some(rules) = &cache_config.rules {
+        info!("Cache rules configured:");
+        for (pattern, rule) in rules {
+            if let Some(true) = rule.bypass {
+                info!("  {pattern} -> BYPASS");
+            } else {
+                info!("  {pattern} -> TTL={:?}, stale={:?}", rule.ttl, rule.stale);
+            }
+        }
+    }
+
+    if let Some(jwt) = &config.jwt {
+        info!("JWT verification: {}", jwt.routes.join(", "));
+    }
+
+    let quotas = match &config.quotas {
+        Some(quotas) => {
+            info!("API key quotas: {} keys", quotas.keys.len());
+            let counters: Cache = match &quotas.redis_url {
+                Some(url) => Arc::new(RedisStorage::new(url).await.map_err(RelayError::storage)?),
+                None => Arc::clone(&cache),
+            };
+            Some(Quotas::new(quotas, counters).map_err(RelayError::config)?)
+        }
+        None => None,
+    };
+
+    if let Some(signed_urls) = &config.signed_urls {
+        info!("Signed URLs required: {}", signed_urls.routes.join(", "));
+    }
+
+    if let Some(max) = config.server.max_concurrent_requests {
+        info!("Concurrency limit: {max} requests");
+    }
+
+    if config.rate_limit.enabled {
+        info!(
+            "Rate limiting: rate={}/s, burst={}",
+            config.rate_limit.rate, config.rate_limit.burst
+        );
+    }
+
+    let upstream_tls =
+        tls::upstream_client_config(config.upstream.tls.as_ref()).map_err(RelayError::tls)?;
+    if let Some(version) = &config.upstream.proxy_protocol {
+        info!("Upstream PROXY protocol: {version}");
+        upstream::send_proxy_protocol(
+            proxy_protocol::Version::parse(version).map_err(RelayError::config)?,
+        );
+    }
+    if let Some(cert) = config
+        .upstream
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.cert.as_ref())
+    {
+        info!("Upstream client certificate: {cert}");
+    }
+
+    let upstream_h2 = match config.upstream.http_version.as_str() {
+        "1.1" => HashMap::new(),
+        "2" => {
+            info!("Upstream HTTP version: 2");
+            config
+                .upstream
+                .urls
+                .iter()
+                .map(|url| Ok((url.clone(), Http2Upstream::new(url, &upstream_tls)?)))
+                .collect::<Result<_, BoxError>>()
+                .map_err(RelayError::config)?
+        }
+        version => {
+            return Err(RelayError::config(format!(
+                "Unsupported upstream http_version: {version}"
+            )))
+        }
+    };
+
+    let balancer = Balancer::new(&config.upstream).map_err(RelayError::config)?;
+
+    // Upstream hosts are kept resolved so requests don't wait on DNS
+    dns::init(&config.dns).map_err(RelayError::config)?;
+    let mut upstream_hosts: Vec<String> = config
+        .upstream
+        .urls
+        .iter()
+        .chain(config.splits.iter().map(|split| &split.url))
+        .chain(config.mirrors.iter().map(|mirror| &mirror.url))
+        .filter_map(|url| Some(url.parse::<hyper::Uri>().ok()?.host()?.to_string()))
+        .collect();
+    upstream_hosts.sort();
+    upstream_hosts.dedup();
+    dns::spawn_refresh(upstream_hosts);
+    let namespace = RwLock::new(cache_config.namespace.clone());
+
+    let cluster = match &config.cluster {
+        Some(cluster_config) => {
+            info!("Cluster invalidation enabled: {}", cluster_config.redis_url);
+            Some(
+                Cluster::connect(cluster_config)
+                    .await
+                    .map_err(RelayError::storage)?,
+            )
+        }
+        None => None,
+    };
+
+    let client_cert_header = config
+        .server
+        .listeners
+        .iter()
+        .find_map(|listener| listener.tls.as_ref()?.client_cert_header.as_deref())
+        .map(HeaderName::try_from)
+        .transpose()
+        .map_err(RelayError::config)?;
+
+    // Hooks from the config run before plugins added by an embedder
+    let mut configured = lua_hooks(config.lua.as_ref())?;
+    configured.extend(wasm_filters(&config.wasm_filters)?);
+    plugins.splice(0..0, configured);
+
+    let hit_for_pass = cache_config.hit_for_pass_ttl.map(|ttl| {
+        moka::sync::Cache::builder()
+            .max_capacity(HIT_FOR_PASS_KEYS)
+            .time_to_live(ttl)
+            .build()
+    });
+    let state = Arc::new(AppState {
+        upstream_url,
+        upstream_error_body: config.upstream.error_body,
+        upstream_request_headers: load_headers(&config.upstream.headers)
+            .map_err(RelayError::config)?,
+        upstream_h2,
+        balancer,
+        hedging: config.upstream.hedging.as_ref().map(Hedging::new),
+        upstream_tls: Arc::new(upstream_tls),
+        error_pages: ErrorPages::load(&config.error_pages).map_err(RelayError::config)?,
+        cache,
+        prometheus_enabled,
+        access_log,
+        cache_config,
+        rate_limiter: RateLimiter::new(config.rate_limit),
+        debug_access: DebugAccess::new(&config.debug).map_err(RelayError::config)?,
+        dashboard: (config.admin.enabled && config.admin.dashboard).then(Dashboard::new),
+        admin_config: config.admin,
+        metrics_auth,
+        admin_auth,
+        client_cert_header,
+        forward_proxy: config.forward_proxy,
+        redirects: Redirects::new(
+            &config.redirects,
+            &config.upstream.urls,
+            config
+                .server
+                .listeners
+                .iter()
+                .any(|listener| listener.tls.is_some()),
+        )
+        .map_err(RelayError::config)?,
+        limits: config.limits,
+        access: AccessControl::new(&config.access).map_err(RelayError::config)?,
+        jwt: config
+            .jwt
+            .as_ref()
+            .map(JwtAuth::new)
+            .transpose()
+            .map_err(RelayError::config)?,
+        quotas,
+        geoip: config
+            .geoip
+            .as_ref()
+            .map(GeoIp::new)
+            .transpose()
+            .map_err(RelayError::config)?,
+        signed_urls: config
+            .signed_urls
+            .as_ref()
+            .map(SignedUrls::new)
+            .transpose()
+            .map_err(RelayError::config)?,
+        concurrency_limit: config
+            .server
+            .max_concurrent_requests
+            .map(|limit| Arc::new(Semaphore::new(limit))),
+        compression: Compression::new(&config.compression).map_err(RelayError::config)?,
+        transforms: Transforms::new(&config.transforms).map_err(RelayError::config)?,
+        mirrors: Mirrors::new(&config.mirrors).map_err(RelayError::config)?,
+        splits: Splits::new(&config.splits).map_err(RelayError::config)?,
+        cors: Cors::new(&config.cors).map_err(RelayError::config)?,
+        security_headers: config
+            .headers
+            .security
+            .as_ref()
+            .map(SecurityHeadersConfig::header_map)
+            .transpose()
+            .map_err(RelayError::config)?
+            .unwrap_or_default(),
+        hit_for_pass,
+        refreshing: Mutex::new(HashSet::new()),
+        namespace,
+        cluster,
+        refresh_ahead: Mutex::new(HashMap::new()),
+        plugins,
+    });
+
+    if state.cluster.is_some() {
+        cluster::spawn_subscriber(Arc::clone(&state));
+    }
+
+    if state.cache_config.warmup.is_some() {
+        warmup::spawn_warmup(Arc::clone(&state));
+    }
+
+    refresh::spawn_refresh_ahead(&state);
+
+    let mut service = BoxCloneSyncService::new(RelayService::new(Arc::clone(&state)));
+    for layer in layers {
+        service = layer(service);
+    }
+
+    let mut shutdown = shutdown.unwrap_or_else(|| Box::pin(shutdown_signal()));
+
+    if config.server.http3
+        && !config
+            .server
+            .listeners
+            .iter()
+            .any(|listener| listener.address.is_some() && listener.tls.is_some())
+    {
+        return Err(RelayError::config(
+            "HTTP/3 requires a TCP listener with TLS configured",
+        ));
+    }
+
+    // Sockets passed by systemd are used in place of binding the listeners
+    // with the same address
+    let mut inherited = InheritedSockets::from_env().map_err(RelayError::config)?;
+    let shards = if config.server.runtime_sharding {
+        let shards = Shards::start(&config.server)?;
+        info!(
+            "Serving connections on {} runtime shards",
+            shards.handles().len()
+        );
+        Some(shards)
+    } else {
+        None
+    };
+    // With reuseport, each shard gets sockets of its own
+    let sockets = if config.server.reuseport {
+        config.server.acceptors * shards.as_ref().map_or(1, |shards| shards.handles().len())
+    } else {
+        1
+    };
+    let mut accept_loops = JoinSet::new();
+    let mut socket_paths = Vec::new();
+    for listener_config in &config.server.listeners {
+        let listeners = match (&listener_config.address, &listener_config.path) {
+            (Some(address), _) => {
+                let addr: SocketAddr = address.parse().map_err(RelayError::config)?;
+                match inherited.take_tcp(addr).map_err(RelayError::config)? {
+                    Some(listener) => {
+                        info!("Server listening on {addr} (socket from systemd)");
+                        vec![Listener::Tcp(listener)]
+                    }
+                    None => {
+                        if sockets > 1 {
+                            info!("Server listening on {addr} ({sockets} sockets)");
+                        } else {
+                            info!("Server listening on {addr}");
+                        }
+                        (0..sockets)
+                            .map(|_| Ok(Listener::Tcp(bind_tcp(addr, &config.server)?)))
+                            .collect::<std::io::Result<_>>()?
+                    }
+                }
+            }
+            (None, Some(path)) => match inherited.take_unix(path).map_err(RelayError::config)? {
+                Some(listener) => {
+                    info!("Server listening on unix:{path} (socket from systemd)");
+                    vec![Listener::Unix(listener)]
+                }
+                None => {
+                    // A socket file left behind by a previous run would fail
+                    // the bind
+                    if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                        std::fs::remove_file(path)?;
+                    }
+                    info!("Server listening on unix:{path}");
+                    socket_paths.push(path.clone());
+                    vec![Listener::Unix(UnixListener::bind(path)?)]
+                }
+            },
+            (None, None) => unreachable!("validated when the config was parsed"),
+        };
+
+        let service = if listener_config.admin {
+            service.clone()
+        } else {
+            BoxCloneSyncService::new(
+                MapRequestLayer::new(|mut req: Request<Incoming>| {
+                    req.extensions_mut().insert(AdminHidden);
+                    req
+                })
+                .layer(service.clone()),
+            )
+        };
+
+        let http2 = config.server.http2;
+        let tls_acceptor = match &listener_config.tls {
+            Some(tls_config) => {
+                info!("TLS enabled: {}", tls_config.cert);
+                if let Some(client_ca) = &tls_config.client_ca {
+                    info!("Client certificates required, issued by: {client_ca}");
+                }
+                Some(tls::load_acceptor(tls_config, http2).map_err(RelayError::tls)?)
+            }
+            None => None,
+        };
+
+        // Clients only try HTTP/3 once a TCP response has advertised it
+        let service = match (&listeners[0], &listener_config.tls) {
+            (Listener::Tcp(tcp), Some(tls_config)) if config.server.http3 => {
+                let addr = tcp.local_addr()?;
+                start_http3(addr, tls_config, &state, service.clone())?;
+                let alt_svc = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", addr.port()))?;
+                BoxCloneSyncService::new(
+                    ResponseHeadersLayer::new()
+                        .header(ALT_SVC, alt_svc)
+                        .layer(service),
+                )
+            }
+            _ => service,
+        };
+
+        let settings = ConnectionSettings {
+            http2,
+            service,
+            timeouts: true,
+            client_subject: None,
+        };
+        if listener_config.proxy_protocol {
+            info!("  expecting PROXY protocol headers");
+        }
+        let options = AcceptOptions {
+            proxy_protocol: listener_config.proxy_protocol,
+            tcp_nodelay: config.server.tcp_nodelay,
+            tcp_keepalive: config.server.tcp_keepalive,
+        };
+        let Some(shards) = &shards else {
+            for listener in listeners {
+                accept_loops.spawn(accept_connections(
+                    listener,
+                    options,
+                    tls_acceptor.clone(),
+                    Arc::clone(&state),
+                    settings.clone(),
+                ));
+            }
+            continue;
+        };
+        for (handle, listener) in assign_to_shards(listeners, shards.handles())? {
+            let tls_acceptor = tls_acceptor.clone();
+            let state = Arc::clone(&state);
+            let settings = settings.clone();
+            accept_loops.spawn_on(
+                async move {
+                    // Registering the socket here ties its connections to
+                    // the shard
+                    let listener = listener.into_tokio()?;
+                    accept_connections(listener, options, tls_acceptor, state, settings).await
+                },
+                handle,
+            );
+        }
+    }
+
+    inherited.check_all_taken().map_err(RelayError::config)?;
+    systemd::notify_ready();
+
+    tokio::select! {
+        _ = &mut shutdown => {}
+        Some(result) = accept_loops.join_next() => result.map_err(RelayError::internal)??,
+    }
+    systemd::notify_stopping();
+    accept_loops.abort_all();
+    for path in socket_paths {
+        let _ = std::fs::remove_file(path);
+    }
+
+    info!("Shutting down");
+    if let Some((storage, path)) = snapshot_storage {
+        match storage.save_snapshot(&path).await {
+            Ok(saved) => info!("Saved {saved} cache entries to snapshot"),
+            Err(e) => warn!("Failed to write cache snapshot {}: {e}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn into_std(self) -> std::io::Result<StdListener> {
+        Ok(match self {
+            Listener::Tcp(listener) => StdListener::Tcp(listener.into_std()?),
+            Listener::Unix(listener) => StdListener::Unix(listener.into_std()?),
+        })
+    }
+}
+
+/// A listener not registered with any runtime, on its way to a shard
+enum StdListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl StdListener {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            StdListener::Tcp(listener) => StdListener::Tcp(listener.try_clone()?),
+            StdListener::Unix(listener) => StdListener::Unix(listener.try_clone()?),
+        })
+    }
+
+    /// Registers the socket with the runtime this is called on.
+    fn into_tokio(self) -> std::io::Result<Listener> {
+        Ok(match self {
+            StdListener::Tcp(listener) => Listener::Tcp(TcpListener::from_std(listener)?),
+            StdListener::Unix(listener) => Listener::Unix(UnixListener::from_std(listener)?),
+        })
+    }
+}
+
+/// Spreads a listener's sockets over the shards. With at least one socket
+/// per shard, each socket is accepted on by one shard; otherwise every
+/// shard accepts on every socket.
+fn assign_to_shards(
+    listeners: Vec<Listener>,
+    shards: &[Handle],
+) -> std::io::Result<Vec<(&Handle, StdListener)>> {
+    let listeners = listeners
+        .into_iter()
+        .map(Listener::into_std)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if listeners.len() >= shards.len() {
+        return Ok(listeners
+            .into_iter()
+            .enumerate()
+            .map(|(index, listener)| (&shards[index % shards.len()], listener))
+            .collect());
+    }
+    shards
+        .iter()
+        .flat_map(|shard| {
+            listeners
+                .iter()
+                .map(move |listener| Ok((shard, listener.try_clone()?)))
+        })
+        .collect()
+}
+
+/// Hit-for-pass keys remembered at most
+const HIT_FOR_PASS_KEYS: u64 = 100_000;
+
+/// Address given to clients on a Unix socket, which have none of their own
+const UNIX_CLIENT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Binds a TCP listener with the socket options from `[server]`.
+fn bind_tcp(addr: SocketAddr, server: &ServerConfig) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if server.reuseport {
+        socket.set_reuseport(true)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(server.backlog)
+}
+
+/// How a listener treats the connections it accepts, before HTTP.
+#[derive(Clone, Copy)]
+struct AcceptOptions {
+    proxy_protocol: bool,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+}
+
+/// Accepts connections on `listener` until it fails, serving each on its
+/// own task.
+async fn accept_connections(
+    listener: Listener,
+    options: AcceptOptions,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: Arc<AppState>,
+    settings: ConnectionSettings,
+) -> std::io::Result<()> {
+    loop {
+        let state = Arc::clone(&state);
+        let tls_acceptor = tls_acceptor.clone();
+        let settings = settings.clone();
+        match &listener {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                // Failing to tune one connection is no reason to drop it
+                let _ = stream.set_nodelay(options.tcp_nodelay);
+                if let Some(time) = options.tcp_keepalive {
+                    let _ = SockRef::from(&stream)
+                        .set_tcp_keepalive(&TcpKeepalive::new().with_time(time));
+                }
+                tokio::task::spawn(accept_connection(
+                    stream,
+                    remote_addr,
+                    options.proxy_protocol,
+                    tls_acceptor,
+                    state,
+                    settings,
+                ));
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::task::spawn(accept_connection(
+                    stream,
+                    UNIX_CLIENT_ADDR,
+                    options.proxy_protocol,
+                    tls_acceptor,
+                    state,
+                    settings,
+                ));
+            }
+        }
+    }
+}
+
+/// Reads the PROXY protocol header and completes the TLS handshake, when
+/// the listener expects them, and serves the connection.
+async fn accept_connection<S>(
+    mut stream: S,
+    mut remote_addr: SocketAddr,
+    proxy_protocol: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: Arc<AppState>,
+    mut settings: ConnectionSettings,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if proxy_protocol {
+        let header = tokio::time::timeout(
+            state.limits.header_read_timeout,
+            proxy_protocol::read_header(&mut stream),
+        )
+        .await;
+        match header {
+            Ok(Ok(client)) => remote_addr = client.unwrap_or(remote_addr),
+            Ok(Err(err)) => {
+                debug!("PROXY protocol header rejected: {remote_addr} - {err}");
+                return;
+            }
+            Err(_) => {
+                debug!("PROXY protocol header timed out: {remote_addr}");
+                return;
+            }
+        }
+    }
+    match tls_acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => {
+                settings.client_subject =
+                    tls::client_subject(stream.get_ref().1.peer_certificates());
+                serve_connection(stream, state, remote_addr, settings).await
+            }
+            Err(err) => debug!("TLS handshake failed: {remote_addr} - {err}"),
+        },
+        None => serve_connection(stream, state, remote_addr, settings).await,
+    }
+}
+
+/// How a listener serves the connections it accepts.
+#[derive(Clone)]
+pub(crate) struct ConnectionSettings {
+    pub(crate) http2: bool,
+    /// The proxy pipeline and any layers around it
+    pub(crate) service: HttpService,
+    /// Whether the idle and write timeouts apply; off for in-process
+    /// connections, which stay open between requests by design
+    pub(crate) timeouts: bool,
+    /// Subject of the client's verified certificate, on mTLS connections
+    pub(crate) client_subject: Option<String>,
+}
+
+/// Serves HTTP/1.1 and, when enabled, HTTP/2 on one client connection. The
+/// protocol is picked from the connection preface, so this covers both
+/// ALPN-negotiated HTTP/2 over TLS and cleartext HTTP/2 with prior knowledge.
+pub(crate) async fn serve_connection<S>(
+    stream: S,
+    state: Arc<AppState>,
+    remote_addr: SocketAddr,
+    settings: ConnectionSettings,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let limits = &state.limits;
+    let (idle_timeout, write_timeout) = if settings.timeouts {
+        (Some(limits.idle_timeout), Some(limits.write_timeout))
+    } else {
+        (None, None)
+    };
+    let in_flight = InFlight::default();
+    let io = TokioIo::new(TimeoutIo::new(
+        stream,
+        idle_timeout,
+        write_timeout,
+        in_flight.clone(),
+    ));
+
+    let relay_service = settings.service;
+    let client_subject = settings.client_subject.map(ClientSubject);
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(ClientAddr(remote_addr));
+        if let Some(client_subject) = &client_subject {
+            req.extensions_mut().insert(client_subject.clone());
+        }
+        respond(relay_service.clone(), req, in_flight.start())
+    });
+    // The auto builder can't be restricted to HTTP/1 while serving upgrades
+    let result = if settings.http2 {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(limits.header_read_timeout)
+            .max_headers(limits.max_headers)
+            .max_buf_size(limits.max_header_size);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_header_list_size(u32::try_from(limits.max_header_size).unwrap_or(u32::MAX));
+        builder.serve_connection_with_upgrades(io, service).await
+    } else {
+        http1::Builder::new()
+            .timer(TokioTimer::new())
+            .header_read_timeout(limits.header_read_timeout)
+            .max_headers(limits.max_headers)
+            .max_buf_size(limits.max_header_size)
+            .serve_connection(io, service)
+            .with_upgrades()
+            .await
+            .map_err(Into::into)
+    };
+    match result {
+        // Idle and slow clients being cut off is routine
+        Err(err) if limits::is_timeout(err.as_ref()) => {}
+        Err(err) => debug!("Error serving connection: {err:?}"),
+        Ok(()) => {}
+    }
+}
+
+#[cfg(feature = "http3")]
+fn start_http3(
+    addr: SocketAddr,
+    tls_config: &TlsConfig,
+    state: &Arc<AppState>,
+    service: HttpService,
+) -> Result<(), RelayError> {
+    let endpoint = http3::bind(addr, tls_config).map_err(RelayError::tls)?;
+    info!("HTTP/3 (experimental) listening on udp {addr}");
+    tokio::task::spawn(http3::serve(endpoint, Arc::clone(state), service));
+    Ok(())
+}
+
+#[cfg(not(feature = "http3"))]
+fn start_http3(
+    _addr: SocketAddr,
+    _tls_config: &TlsConfig,
+    _state: &Arc<AppState>,
+    _service: HttpService,
+) -> Result<(), RelayError> {
+    Err(RelayError::config(
+        "server.http3 requires relay to be built with the http3 feature",
+    ))
+}
+
+#[cfg(feature = "lua")]
+fn lua_hooks(config: Option<&LuaConfig>) -> Result<Vec<Box<dyn Plugin>>, RelayError> {
+    let Some(config) = config else {
+        return Ok(Vec::new());
+    };
+    info!("Lua script: {}", config.script);
+    Ok(vec![Box::new(
+        lua::LuaHooks::new(config).map_err(RelayError::config)?,
+    )])
+}
+
+#[cfg(not(feature = "lua"))]
+fn lua_hooks(config: Option<&LuaConfig>) -> Result<Vec<Box<dyn Plugin>>, RelayError> {
+    match config {
+        Some(_) => Err(RelayError::config(
+            "[lua] requires relay to be built with the lua feature",
+        )),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn wasm_filters(configs: &[WasmFilterConfig]) -> Result<Vec<Box<dyn Plugin>>, RelayError> {
+    configs
+        .iter()
+        .map(|config| {
+            info!(
+                "WASM filter: {} on {}",
+                config.module,
+                config.routes.join(", ")
+            );
+            let filter = wasm::WasmFilter::new(config).map_err(RelayError::config)?;
+            Ok(Box::new(filter) as Box<dyn Plugin>)
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "wasm"))]
+fn wasm_filters(configs: &[WasmFilterConfig]) -> Result<Vec<Box<dyn Plugin>>, RelayError> {
+    if configs.is_empty() {
+        Ok(Vec::new())
+    } else {
+        Err(RelayError::config(
+            "wasm_filters require relay to be built with the wasm feature",
+        ))
+    }
+}
+
+async fn respond(
+    service: HttpService,
+    req: Request<Incoming>,
+    _in_flight: InFlightGuard,
+) -> Result<Response<Body>, BoxError> {
+    service.oneshot(req).await
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn build_storage(backend: &str, storage_config: &StorageConfig) -> Result<Cache, RelayError> {
+    let cache: Cache = match backend {
+        "redis" => {
+            let redis_config = storage_config.redis.as_ref().ok_or_else(|| {
+                RelayError::config("Redis backend selected but no redis configuration provided")
+            })?;
+            info!("Initializing Redis storage backend: {}", redis_config.url);
+            let storage = RedisStorage::new(&redis_config.url)
+                .await
+                .map_err(RelayError::storage)?;
+            Arc::new(storage)
+        }
+        "disk" => {
+            let disk_config = storage_config.disk.as_ref().ok_or_else(|| {
+                RelayError::config("Disk backend selected but no disk configuration provided")
+            })?;
+            info!("Initializing disk storage backend: {}", disk_config.path);
+            let storage = DiskStorage::new(&disk_config.path, disk_config.max_size)
+                .await
+                .map_err(RelayError::storage)?;
+            let storage = Arc::new(storage);
+            storage.spawn_sweeper(disk_config.sweep_interval);
+            storage
+        }
+        "memory" => {
+            info!("Initializing in-memory storage backend");
+            Arc::new(MemoryStorage::new())
+        }
+        "moka" => {
+            let max_size = storage_config
+                .moka
+                .as_ref()
+                .map(|moka| moka.max_size)
+                .unwrap_or_else(|| MokaConfig::default().max_size);
+            info!("Initializing moka storage backend: max_size={max_size} bytes");
+            Arc::new(MokaStorage::new(max_size))
+        }
+        backend => {
+            return Err(RelayError::config(format!(
+                "Unknown storage backend: {backend}"
+            )));
+        }
+    };
+    Ok(cache)
+}
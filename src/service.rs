@@ -0,0 +1,417 @@
+use http_body_util::BodyExt;
+use hyper::body::{Body as _, Bytes, Incoming};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, RETRY_AFTER,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower::util::BoxCloneSyncService;
+use tower::{Layer, Service};
+
+use crate::cache_key::generate_cache_key;
+use crate::config::{CacheConfig, LoggingConfig, RateLimitConfig};
+use crate::device::DeviceClass;
+use crate::error::RelayError;
+use crate::handlers::{cache_entry, full, handle_request, AppState, Body};
+use crate::logger::{AccessLog, AccessLogEntry, CacheStatus};
+use crate::rate_limit::RateLimiter;
+use crate::storage::Cache;
+use crate::upstream::CLIENT_ADDR;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// A type-erased HTTP service, the form the proxy pipeline and any layers
+/// around it take once assembled.
+pub type HttpService =
+    BoxCloneSyncService<Request<Incoming>, Response<Body>, Box<dyn Error + Send + Sync>>;
+
+/// Address of the client a request came from, set on every request before it
+/// reaches the service stack.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// The proxy pipeline as a `tower::Service`: access control, rate limits,
+/// authentication, caching and the upstream fetch. Requests need a
+/// [`ClientAddr`] extension.
+#[derive(Clone)]
+pub struct RelayService {
+    state: Arc<AppState>,
+}
+
+impl RelayService {
+    pub(crate) fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+}
+
+impl Service<Request<Incoming>> for RelayService {
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let state = Arc::clone(&self.state);
+        Box::pin(async move {
+            let ClientAddr(remote_addr) = *req
+                .extensions()
+                .get::<ClientAddr>()
+                .ok_or("request has no client address")?;
+            Ok(CLIENT_ADDR
+                .scope(remote_addr, handle_request(req, state, remote_addr))
+                .await?)
+        })
+    }
+}
+
+/// Sets fixed headers on every response, replacing any the inner service
+/// set.
+#[derive(Clone, Default)]
+pub struct ResponseHeadersLayer {
+    headers: HeaderMap,
+}
+
+impl ResponseHeadersLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl<S> Layer<S> for ResponseHeadersLayer {
+    type Service = ResponseHeaders<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseHeaders {
+            inner,
+            headers: Arc::new(self.headers.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseHeaders<S> {
+    inner: S,
+    headers: Arc<HeaderMap>,
+}
+
+impl<S, B> Service<Request<Incoming>> for ResponseHeaders<S>
+where
+    S: Service<Request<Incoming>, Response = Response<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let response = self.inner.call(req);
+        let headers = Arc::clone(&self.headers);
+        Box::pin(async move {
+            let mut response = response.await?;
+            for (name, value) in headers.iter() {
+                response.headers_mut().insert(name, value.clone());
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Answers GET and HEAD requests from `cache` where it holds a fresh entry,
+/// and stores the inner service's responses as `[cache]` allows: cache
+/// rules, key settings, cacheable statuses and content types, and
+/// `max_object_size`. Unlike [`RelayService`] it doesn't serve stale
+/// entries, revalidate, or collapse concurrent misses; responses of unknown
+/// length are passed through uncached.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Cache,
+    config: Arc<CacheConfig>,
+}
+
+impl CacheLayer {
+    pub fn new(cache: Cache, config: CacheConfig) -> Self {
+        Self {
+            cache,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            cache: Arc::clone(&self.cache),
+            config: Arc::clone(&self.config),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheService<S> {
+    inner: S,
+    cache: Cache,
+    config: Arc<CacheConfig>,
+}
+
+impl<S> Service<Request<Incoming>> for CacheService<S>
+where
+    S: Service<Request<Incoming>, Response = Response<Body>, Error = Box<dyn Error + Send + Sync>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        // The clone may not be ready; the one that was polled is used instead
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let rule = self
+            .config
+            .find_rule_with_pattern(req.uri().path())
+            .map(|matched| matched.rule.clone());
+        let cacheable_request = matches!(*req.method(), Method::GET | Method::HEAD)
+            && !req.headers().contains_key(AUTHORIZATION)
+            && rule.as_ref().is_none_or(|rule| rule.bypass != Some(true));
+        if !cacheable_request {
+            return Box::pin(inner.call(req));
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let config = Arc::clone(&self.config);
+        Box::pin(async move {
+            let key = generate_cache_key(req.uri(), req.headers(), &config.key, rule.as_ref());
+            let head = req.method() == Method::HEAD;
+            if let Some(cached) = cache.get(&key).await {
+                if !cached.is_stale(0.0) {
+                    let body = if head {
+                        Bytes::new()
+                    } else {
+                        cached.body.clone()
+                    };
+                    let mut response = cached.response_builder().body(full(body))?;
+                    // Replaces the inner service's own, stored with the entry
+                    response
+                        .headers_mut()
+                        .insert("X-Cache", HeaderValue::from_static("HIT"));
+                    return Ok(response);
+                }
+            }
+
+            let response = inner.call(req).await?;
+            let length = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .or_else(|| response.body().size_hint().exact());
+            let mut response = match length {
+                Some(length) if !head && length <= config.max_object_size => {
+                    let (parts, body) = response.into_parts();
+                    let body = body.collect().await?.to_bytes();
+                    let ttl = rule
+                        .as_ref()
+                        .and_then(|rule| rule.ttl)
+                        .unwrap_or(config.default_ttl);
+                    let (entry, cacheable) = cache_entry(
+                        &config,
+                        rule.as_ref(),
+                        parts.status,
+                        parts.headers.clone(),
+                        body.clone(),
+                        ttl,
+                    )
+                    .await;
+                    if cacheable {
+                        cache.set(key, entry, ttl).await;
+                    }
+                    Response::from_parts(parts, full(body))
+                }
+                _ => response,
+            };
+            response
+                .headers_mut()
+                .insert("X-Cache", HeaderValue::from_static("MISS"));
+            Ok(response)
+        })
+    }
+}
+
+/// Limits each client IP to the `[rate_limit]` rates, answering requests
+/// over the limit with 429 and a Retry-After header. Requests need a
+/// [`ClientAddr`] extension; those without one aren't limited.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(mut config: RateLimitConfig) -> Result<Self, RelayError> {
+        if config.compiled_routes.is_none() {
+            config.compile_routes().map_err(RelayError::config)?;
+        }
+        Ok(Self {
+            limiter: Arc::new(RateLimiter::new(config)),
+        })
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<Request<Incoming>> for RateLimit<S>
+where
+    S: Service<Request<Incoming>, Response = Response<Body>, Error = Box<dyn Error + Send + Sync>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        if let Some(ClientAddr(addr)) = req.extensions().get::<ClientAddr>() {
+            let bot = self.limiter.limits_bots()
+                && DeviceClass::from_headers(req.headers()) == DeviceClass::Bot;
+            if let Err(retry_after) = self.limiter.check(addr.ip(), req.uri().path(), bot) {
+                let response = Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                    .body(full("Too Many Requests"));
+                return Box::pin(async move { Ok(response?) });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Writes an access log line for each request as `[logging]` configures
+/// it. The cache status is read from the response's `X-Cache` header,
+/// defaulting to PASS, and bytes sent from its Content-Length.
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    log: Arc<AccessLog>,
+}
+
+impl AccessLogLayer {
+    pub fn new(config: &LoggingConfig) -> Self {
+        Self {
+            log: Arc::new(AccessLog::new(config)),
+        }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            log: Arc::clone(&self.log),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    log: Arc<AccessLog>,
+}
+
+impl<S, B> Service<Request<Incoming>> for AccessLogService<S>
+where
+    S: Service<Request<Incoming>, Response = Response<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let remote_addr = req
+            .extensions()
+            .get::<ClientAddr>()
+            .map(|ClientAddr(addr)| *addr)
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let request_headers = self.log.request_headers(req.headers());
+        let response = self.inner.call(req);
+        let log = Arc::clone(&self.log);
+        Box::pin(async move {
+            let response = response.await?;
+            let headers = response.headers();
+            log.log(AccessLogEntry {
+                method,
+                path,
+                status: response.status().as_u16(),
+                duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+                cache_status: headers
+                    .get("X-Cache")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(CacheStatus::from_header)
+                    .unwrap_or(CacheStatus::Pass),
+                remote_addr,
+                bytes_sent: headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0),
+                country: None,
+                request_headers,
+                response_headers: log.response_headers(headers),
+            });
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,40 @@
+use hyper::{Request, Uri};
+use std::time::Duration;
+
+use crate::config::CacheRule;
+
+/// Set on a request rewritten to a rule's `spa_fallback`, carrying the
+/// rule's `spa_fallback_ttl` to use in place of the TTL its new path gets.
+#[derive(Clone, Copy, Debug)]
+pub struct ShellTtl(pub Duration);
+
+/// Whether a rule rewrites every path without a file extension up front,
+/// rather than only requests the upstream or directory answers with 404.
+pub fn rewrites_non_files(rule: &CacheRule) -> bool {
+    rule.spa_fallback_on.as_deref() == Some("non_file")
+}
+
+/// Whether the last segment of `path` has a file extension, as asset paths
+/// do and client-side routes usually don't.
+pub fn is_file_path(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .and_then(|segment| segment.rsplit_once('.'))
+        .is_some_and(|(name, extension)| !name.is_empty() && !extension.is_empty())
+}
+
+/// Points a request at the rule's `spa_fallback`. The query is dropped, so
+/// every route of the app shares one cached copy of the shell.
+pub fn rewrite<B>(req: &mut Request<B>, rule: &CacheRule) {
+    let Some(fallback) = rule
+        .spa_fallback
+        .as_deref()
+        .and_then(|fallback| fallback.parse::<Uri>().ok())
+    else {
+        return;
+    };
+    *req.uri_mut() = fallback;
+    if let Some(ttl) = rule.spa_fallback_ttl {
+        req.extensions_mut().insert(ShellTtl(ttl));
+    }
+}
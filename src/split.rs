@@ -0,0 +1,110 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hyper::Request;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::cache_key::cookies;
+use crate::config::SplitConfig;
+use crate::plugin::UpstreamOverride;
+use crate::upstream::parse_url;
+
+struct Split {
+    name: String,
+    routes: GlobSet,
+    url: String,
+    weight: f64,
+    sticky_cookie: Option<String>,
+    sticky_client_ip: bool,
+}
+
+/// Which side of a split a request was routed to.
+pub struct Variant<'a> {
+    pub split: &'a str,
+    pub secondary: bool,
+}
+
+impl Variant<'_> {
+    pub fn label(&self) -> &'static str {
+        if self.secondary {
+            "secondary"
+        } else {
+            "primary"
+        }
+    }
+}
+
+/// Weighted routing of requests between the configured upstream and
+/// secondary ones, for canary deployments. Requests routed to a secondary
+/// upstream carry an [`UpstreamOverride`], so they are cached apart.
+pub struct Splits {
+    splits: Vec<Split>,
+}
+
+impl Splits {
+    pub fn new(configs: &[SplitConfig]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let splits = configs
+            .iter()
+            .map(|config| {
+                let mut routes = GlobSetBuilder::new();
+                for pattern in &config.routes {
+                    routes.add(Glob::new(pattern)?);
+                }
+                if parse_url(&config.url).is_none() {
+                    return Err(format!("Invalid split URL: {}", config.url).into());
+                }
+                Ok(Split {
+                    name: config.name.clone().unwrap_or_else(|| config.url.clone()),
+                    routes: routes.build()?,
+                    url: config.url.clone(),
+                    weight: config.weight,
+                    sticky_cookie: config.sticky_cookie.clone(),
+                    sticky_client_ip: config.sticky_client_ip,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error + Send + Sync>>>()?;
+        Ok(Self { splits })
+    }
+
+    /// Picks a variant for a request on a split route, pointing it at the
+    /// secondary upstream when that wins. The first matching split applies;
+    /// requests a plugin already sent elsewhere are left alone.
+    pub fn route<B>(&self, req: &mut Request<B>, client_ip: IpAddr) -> Option<Variant<'_>> {
+        if req.extensions().get::<UpstreamOverride>().is_some() {
+            return None;
+        }
+        let split = self
+            .splits
+            .iter()
+            .find(|split| split.routes.is_match(req.uri().path()))?;
+
+        let cookie = split.sticky_cookie.as_deref().and_then(|name| {
+            cookies(req.headers())
+                .find(|(cookie, _)| *cookie == name)
+                .map(|(_, value)| value.to_string())
+        });
+        let sticky_key = cookie.or_else(|| split.sticky_client_ip.then(|| client_ip.to_string()));
+        let roll = match sticky_key {
+            Some(key) => bucket(&split.url, &key),
+            None => rand::random::<f64>(),
+        };
+
+        let secondary = roll < split.weight;
+        if secondary {
+            req.extensions_mut()
+                .insert(UpstreamOverride(split.url.clone()));
+        }
+        Some(Variant {
+            split: &split.name,
+            secondary,
+        })
+    }
+}
+
+/// A stable position in [0, 1) for `key`, the same on every instance, so a
+/// client stays on its variant wherever its requests land.
+fn bucket(url: &str, key: &str) -> f64 {
+    let digest = Sha256::digest(format!("{url}\n{key}").as_bytes());
+    let value = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
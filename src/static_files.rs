@@ -0,0 +1,246 @@
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, VARY,
+};
+use hyper::StatusCode;
+use percent_encoding::percent_decode_str;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::cache::CachedResponse;
+use crate::compression::{self, Encoding};
+use crate::handlers::Body;
+
+/// Precompressed variants looked for next to a file, in order of preference.
+const PRECOMPRESSED: [(Encoding, &str); 3] = [
+    (Encoding::Brotli, "br"),
+    (Encoding::Zstd, "zst"),
+    (Encoding::Gzip, "gz"),
+];
+
+/// Files up to this size are read into memory, where ranges, compression
+/// and transforms apply as they do to cache entries; larger ones are
+/// streamed from disk.
+const BUFFERED_MAX: u64 = 1024 * 1024;
+
+/// A file found for a request.
+pub enum StaticFile {
+    Buffered(CachedResponse),
+    Streamed {
+        path: PathBuf,
+        headers: HeaderMap,
+        len: u64,
+    },
+}
+
+impl StaticFile {
+    pub fn headers(&self) -> &HeaderMap {
+        match self {
+            Self::Buffered(file) => &file.headers,
+            Self::Streamed { headers, .. } => headers,
+        }
+    }
+}
+
+/// Maps a request path to a file under `root`. The directory part of the
+/// rule's pattern before any wildcard is dropped, so under "/assets/*" the
+/// path "/assets/app.js" is `root/app.js`. None for paths that would leave
+/// `root` or name hidden files.
+pub fn resolve(root: &Path, pattern: &str, path: &str) -> Option<PathBuf> {
+    let literal = &pattern[..pattern.find(['*', '?', '[', '{']).unwrap_or(pattern.len())];
+    let prefix = &literal[..literal.rfind('/').map_or(0, |slash| slash + 1)];
+    let relative = path.strip_prefix(prefix).unwrap_or(path);
+    let relative = percent_decode_str(relative).decode_utf8().ok()?;
+
+    let mut file = root.to_path_buf();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".well-known" => file.push(segment),
+            _ if segment.starts_with('.') || segment.contains(['\\', '\0']) => return None,
+            _ => file.push(segment),
+        }
+    }
+    Some(file)
+}
+
+/// Reads a file as a response, or `index.html` for a directory. A brotli,
+/// zstd or gzip copy alongside it, such as `app.js.br`, is sent instead to
+/// clients that accept it, except for range requests, whose offsets refer
+/// to the plain file. Only files up to `BUFFERED_MAX` are read here. None
+/// when there's no such file.
+pub async fn open(
+    file: PathBuf,
+    accept_encoding: Option<&str>,
+    ranged: bool,
+) -> std::io::Result<Option<StaticFile>> {
+    let (file, metadata) = match fs::metadata(&file).await {
+        Ok(metadata) if metadata.is_dir() => {
+            let index = file.join("index.html");
+            match fs::metadata(&index).await {
+                Ok(metadata) => (index, metadata),
+                Err(_) => return Ok(None),
+            }
+        }
+        Ok(metadata) => (file, metadata),
+        Err(_) => return Ok(None),
+    };
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+
+    let mut variants = Vec::new();
+    for (encoding, extension) in PRECOMPRESSED {
+        let mut variant = file.clone().into_os_string();
+        variant.push(".");
+        variant.push(extension);
+        let variant = PathBuf::from(variant);
+        if let Ok(metadata) = fs::metadata(&variant).await {
+            if metadata.is_file() {
+                variants.push((encoding, variant, metadata));
+            }
+        }
+    }
+    let offered: Vec<Encoding> = variants.iter().map(|(encoding, ..)| *encoding).collect();
+    let chosen = accept_encoding
+        .filter(|_| !ranged)
+        .and_then(|accept_encoding| compression::negotiate(accept_encoding, &offered))
+        .and_then(|chosen| variants.iter().find(|(encoding, ..)| *encoding == chosen));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(content_type_for_file(&file)),
+    );
+    if !variants.is_empty() {
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    let (path, metadata) = match chosen {
+        Some((encoding, variant, metadata)) => {
+            headers.insert(
+                CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            (variant, metadata)
+        }
+        None => (&file, &metadata),
+    };
+
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let seconds = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let etag = match chosen {
+        Some((encoding, ..)) => {
+            format!("\"{seconds:x}-{:x}-{}\"", metadata.len(), encoding.as_str())
+        }
+        None => format!("\"{seconds:x}-{:x}\"", metadata.len()),
+    };
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, etag);
+    }
+    if let Ok(last_modified) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+        headers.insert(LAST_MODIFIED, last_modified);
+    }
+
+    if metadata.len() > BUFFERED_MAX {
+        return Ok(Some(StaticFile::Streamed {
+            path: path.clone(),
+            headers,
+            len: metadata.len(),
+        }));
+    }
+    let body = fs::read(path).await?;
+    Ok(Some(StaticFile::Buffered(CachedResponse {
+        status: StatusCode::OK,
+        headers,
+        body: Bytes::from(body),
+        // Keeps on-the-fly compressed copies tied to this version of the file
+        cached_at: modified,
+        ttl: Duration::ZERO,
+        jitter_seed: 0.0,
+        fetch_duration: Duration::ZERO,
+    })))
+}
+
+/// A body reading `len` bytes of the file at `path` from `start`, a chunk at
+/// a time.
+pub async fn stream(path: &Path, start: u64, len: u64) -> std::io::Result<Body> {
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let frames = ReaderStream::new(file.take(len))
+        .map_ok(Frame::data)
+        .map_err(Into::into);
+    Ok(StreamBody::new(frames).boxed())
+}
+
+/// Whether the client's copy is current: its `If-None-Match` names the
+/// file's ETag or, without one, its `If-Modified-Since` is no earlier than
+/// the file's modification time.
+pub fn is_not_modified(request: &HeaderMap, response: &HeaderMap) -> bool {
+    if let Some(if_none_match) = header_str(request, IF_NONE_MATCH) {
+        let Some(etag) = header_str(response, ETAG) else {
+            return false;
+        };
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    let date = |headers, name| {
+        header_str(headers, name).and_then(|value| httpdate::parse_http_date(value).ok())
+    };
+    match (
+        date(request, IF_MODIFIED_SINCE),
+        date(response, LAST_MODIFIED),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn content_type_for_file(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("webmanifest") => "application/manifest+json",
+        _ => "application/octet-stream",
+    }
+}
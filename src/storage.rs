@@ -1,44 +1,268 @@
 use async_trait::async_trait;
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::error::Error;
+use std::hash::{BuildHasher, RandomState};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
+use tracing::warn;
 
 use crate::cache::CachedResponse;
-use hyper::body::Bytes;
+use crate::cache_key::sha256_hex;
+use crate::metrics::CACHE_EVICTIONS;
 
 #[async_trait]
 pub trait Storage: Send + Sync {
     async fn get(&self, key: &str) -> Option<CachedResponse>;
-    async fn set(&self, key: String, value: CachedResponse);
+    /// Stores `value` under `key`. `ttl` is how long the entry remains useful
+    /// (freshness plus any stale window); backends may evict it afterwards.
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration);
+    /// Removes `key`, returning whether an entry was present.
+    async fn delete(&self, key: &str) -> bool;
+    /// Number of entries stored. Polled periodically for the
+    /// `relay_cache_entries` gauge rather than on every request.
     async fn size(&self) -> usize;
+    /// Adds one to the counter at `key`, which starts at zero and expires
+    /// `ttl` after it is created, and returns the new count. Counters are
+    /// kept apart from cached responses.
+    async fn increment(&self, key: &str, ttl: Duration) -> u64;
+}
+
+/// Counters for backends that keep them in process memory.
+struct Counters {
+    counters: std::sync::Mutex<CounterMap>,
+}
+
+struct CounterMap {
+    counters: HashMap<String, (u64, SystemTime)>,
+    /// The size the map has to pass before it is next pruned.
+    prune_at: usize,
 }
 
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            counters: std::sync::Mutex::new(CounterMap {
+                counters: HashMap::new(),
+                prune_at: Self::PRUNE_THRESHOLD,
+            }),
+        }
+    }
+}
+
+impl Counters {
+    /// Expired counters are pruned once the map grows past this size, and
+    /// after that whenever it has doubled since the last prune.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        let now = SystemTime::now();
+        let mut counters = self.counters.lock().unwrap();
+        let CounterMap { counters, prune_at } = &mut *counters;
+        if counters.len() > *prune_at {
+            counters.retain(|_, (_, expires_at)| *expires_at > now);
+            *prune_at = (counters.len() * 2).max(Self::PRUNE_THRESHOLD);
+        }
+        let counter = counters.entry(key.to_string()).or_insert((0, now + ttl));
+        if counter.1 <= now {
+            *counter = (0, now + ttl);
+        }
+        counter.0 += 1;
+        counter.0
+    }
+}
+
+type MemoryShard = std::sync::RwLock<HashMap<String, (CachedResponse, SystemTime)>>;
+
+/// In-process cache, split into shards by key hash so that concurrent fills
+/// of different keys rarely wait on the same lock.
 pub struct MemoryStorage {
-    cache: RwLock<HashMap<String, CachedResponse>>,
+    shards: Box<[MemoryShard]>,
+    hasher: RandomState,
+    /// Entries across all shards, so `size` takes no locks
+    entries: AtomicUsize,
+    counters: Counters,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One entry of a memory snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    expires_at_millis: u64,
+    response: Vec<u8>,
 }
 
 impl MemoryStorage {
     pub fn new() -> Self {
+        // Several shards per thread keep the odds of two threads wanting the
+        // same one low
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let count = (threads * 4).next_power_of_two();
         Self {
-            cache: RwLock::new(HashMap::new()),
+            shards: (0..count).map(|_| MemoryShard::default()).collect(),
+            hasher: RandomState::new(),
+            entries: AtomicUsize::new(0),
+            counters: Counters::default(),
         }
     }
+
+    fn shard(&self, key: &str) -> &MemoryShard {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    fn insert(&self, key: String, value: CachedResponse, expires_at: SystemTime) {
+        let replaced = self
+            .shard(&key)
+            .write()
+            .unwrap()
+            .insert(key, (value, expires_at));
+        if replaced.is_none() {
+            self.entries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes every unexpired entry to `path`, returning how many were saved.
+    pub async fn save_snapshot(&self, path: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        // One shard at a time, so writers are only held up briefly
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            for (key, (response, expires_at)) in shard.iter() {
+                if *expires_at <= now {
+                    continue;
+                }
+                entries.push(SnapshotEntry {
+                    key: key.clone(),
+                    expires_at_millis: expires_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    response: response.to_bytes()?,
+                });
+            }
+        }
+        write_atomic(path, &bincode::serialize(&entries)?).await?;
+        Ok(entries.len())
+    }
+
+    /// Loads entries from a snapshot written by `save_snapshot`, skipping any
+    /// that expired while relay was down. A missing file loads nothing.
+    pub async fn load_snapshot(&self, path: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let entries: Vec<SnapshotEntry> = bincode::deserialize(&bytes)?;
+
+        let now = SystemTime::now();
+        let mut loaded = 0;
+        for entry in entries {
+            let expires_at = UNIX_EPOCH + Duration::from_millis(entry.expires_at_millis);
+            if expires_at <= now {
+                continue;
+            }
+            if let Ok(response) = CachedResponse::from_bytes(&entry.response) {
+                self.insert(entry.key, response, expires_at);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Saves a snapshot to `path` every `interval` for the life of the process.
+    pub fn spawn_snapshots(self: &Arc<Self>, path: PathBuf, interval: Duration) {
+        let storage = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick completes immediately; there is nothing new to save yet.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = storage.save_snapshot(&path).await {
+                    warn!("Failed to write cache snapshot {}: {e}", path.display());
+                }
+            }
+        });
+    }
 }
 
 #[async_trait]
 impl Storage for MemoryStorage {
     async fn get(&self, key: &str) -> Option<CachedResponse> {
-        self.cache.read().await.get(key).cloned()
+        let shard = self.shard(key);
+        {
+            let shard = shard.read().unwrap();
+            let (response, expires_at) = shard.get(key)?;
+            if *expires_at > SystemTime::now() {
+                return Some(response.clone());
+            }
+        }
+        let mut shard = shard.write().unwrap();
+        // Another request may have refreshed the entry since the read
+        if shard
+            .get(key)
+            .is_some_and(|(_, expires_at)| *expires_at <= SystemTime::now())
+        {
+            shard.remove(key);
+            self.entries.fetch_sub(1, Ordering::Relaxed);
+        }
+        None
     }
 
-    async fn set(&self, key: String, value: CachedResponse) {
-        self.cache.write().await.insert(key, value);
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration) {
+        self.insert(key, value, SystemTime::now() + ttl);
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        let removed = self.shard(key).write().unwrap().remove(key).is_some();
+        if removed {
+            self.entries.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
     }
 
     async fn size(&self) -> usize {
-        self.cache.read().await.len()
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        self.counters.increment(key, ttl)
+    }
+}
+
+/// Writes to a temporary file first so readers never observe a partial file.
+/// Each write gets its own temporary file, so concurrent writes of the same
+/// path can't interleave.
+async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = match tokio::fs::write(&tmp, contents).await {
+        Ok(()) => tokio::fs::rename(&tmp, path).await,
+        Err(e) => Err(e),
+    };
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
     }
+    written
 }
 
 pub struct RedisStorage {
@@ -60,38 +284,516 @@ impl Storage for RedisStorage {
     async fn get(&self, key: &str) -> Option<CachedResponse> {
         let mut conn = self.client.clone();
 
-        let result: Result<(Vec<u8>, u64), redis::RedisError> = redis::pipe()
-            .get(format!("{key}:body"))
-            .get(format!("{key}:cached_at"))
-            .query_async(&mut conn)
-            .await;
+        let result: Result<Option<Vec<u8>>, redis::RedisError> =
+            redis::cmd("GET").arg(key).query_async(&mut conn).await;
 
         match result {
-            Ok((body, cached_at_nanos)) => {
-                let elapsed = Duration::from_nanos(cached_at_nanos);
-                let cached_at = Instant::now() - elapsed;
-                Some(CachedResponse {
-                    body: Bytes::from(body),
-                    cached_at,
-                })
-            }
-            Err(_) => None,
+            Ok(Some(bytes)) => CachedResponse::from_bytes(&bytes).ok(),
+            _ => None,
         }
     }
 
-    async fn set(&self, key: String, value: CachedResponse) {
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration) {
         let mut conn = self.client.clone();
-        let elapsed = value.cached_at.elapsed().as_nanos() as u64;
+        let ttl_ms = ttl.as_millis().max(1) as u64;
 
-        let _: Result<(), redis::RedisError> = redis::pipe()
-            .set(format!("{key}:body"), value.body.to_vec())
-            .set(format!("{key}:cached_at"), elapsed)
+        let Ok(bytes) = value.to_bytes() else {
+            return;
+        };
+
+        let _: Result<(), redis::RedisError> = redis::cmd("SET")
+            .arg(key)
+            .arg(bytes)
+            .arg("PX")
+            .arg(ttl_ms)
             .query_async(&mut conn)
             .await;
     }
 
+    async fn delete(&self, key: &str) -> bool {
+        let mut conn = self.client.clone();
+        let removed: Result<usize, redis::RedisError> =
+            redis::cmd("DEL").arg(key).query_async(&mut conn).await;
+        removed.is_ok_and(|n| n > 0)
+    }
+
     async fn size(&self) -> usize {
-        0
+        let mut conn = self.client.clone();
+        let keys: Result<usize, redis::RedisError> =
+            redis::cmd("DBSIZE").query_async(&mut conn).await;
+        keys.unwrap_or(0)
+    }
+
+    /// Shared by every instance using the same Redis. If Redis can't be
+    /// reached the count is 0, so quotas fail open.
+    async fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        let mut conn = self.client.clone();
+        let ttl_ms = ttl.as_millis().max(1) as u64;
+        let count: Result<(u64,), redis::RedisError> = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(key)
+            .arg(0)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl_ms)
+            .ignore()
+            .cmd("INCR")
+            .arg(key)
+            .query_async(&mut conn)
+            .await;
+        count.map_or(0, |(count,)| count)
+    }
+}
+
+/// Metadata sidecar written next to each body file. The key is kept here
+/// because file names are derived from a hash of it.
+#[derive(Serialize, Deserialize)]
+struct DiskMetadata {
+    key: String,
+    expires_at_millis: u64,
+    response: Vec<u8>,
+}
+
+struct DiskEntry {
+    file_stem: String,
+    expires_at: SystemTime,
+    /// Bytes the body and metadata files take up
+    size: u64,
+    /// Status, headers and timestamp of the entry; the body stays on disk.
+    metadata: CachedResponse,
+}
+
+pub struct DiskStorage {
+    dir: PathBuf,
+    index: RwLock<HashMap<String, DiskEntry>>,
+    /// Entries in the index, so `size` takes no lock
+    entries: AtomicUsize,
+    /// Bytes taken up by the entries in the index
+    bytes: AtomicU64,
+    max_size: Option<u64>,
+    counters: Counters,
+}
+
+impl DiskStorage {
+    /// Opens (or creates) the cache directory and rebuilds the index from the
+    /// metadata files found there, discarding expired or unreadable entries
+    /// and files left behind by interrupted writes. Past `max_size` bytes,
+    /// the entries closest to expiring are evicted.
+    pub async fn new(path: &str, max_size: Option<u64>) -> std::io::Result<Self> {
+        let dir = PathBuf::from(path);
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut index = HashMap::new();
+        let mut bytes = 0;
+        let now = SystemTime::now();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("meta") => {}
+                Some("tmp") => {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    continue;
+                }
+                _ => continue,
+            }
+            let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let file_stem = file_stem.to_string();
+
+            let body_len = tokio::fs::metadata(dir.join(format!("{file_stem}.body")))
+                .await
+                .map(|body| body.len());
+            match (Self::read_metadata(&path).await, body_len) {
+                (Some((key, expires_at, metadata)), Ok(body_len)) if expires_at > now => {
+                    let size = body_len + entry.metadata().await.map_or(0, |meta| meta.len());
+                    bytes += size;
+                    index.insert(
+                        key,
+                        DiskEntry {
+                            file_stem,
+                            expires_at,
+                            size,
+                            metadata,
+                        },
+                    );
+                }
+                _ => Self::remove_files(&dir, &file_stem).await,
+            }
+        }
+
+        let storage = Self {
+            dir,
+            entries: AtomicUsize::new(index.len()),
+            bytes: AtomicU64::new(bytes),
+            index: RwLock::new(index),
+            max_size,
+            counters: Counters::default(),
+        };
+        storage.evict_over_limit().await;
+        Ok(storage)
+    }
+
+    /// Deletes expired entries every `interval` for the life of the process,
+    /// so entries nobody reads again don't stay on disk.
+    pub fn spawn_sweeper(self: &Arc<Self>, interval: Duration) {
+        let storage = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick completes immediately; startup already swept.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                storage.sweep().await;
+            }
+        });
+    }
+
+    /// Removes every expired entry, returning how many there were.
+    pub async fn sweep(&self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<DiskEntry> = {
+            let mut index = self.index.write().await;
+            let keys: Vec<String> = index
+                .iter()
+                .filter(|(_, entry)| entry.expires_at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter().filter_map(|key| index.remove(key)).collect()
+        };
+        for entry in &expired {
+            self.forget(entry);
+            Self::remove_files(&self.dir, &entry.file_stem).await;
+        }
+        expired.len()
+    }
+
+    /// Evicts the entries closest to expiring until the total size is back
+    /// under `max_size`.
+    async fn evict_over_limit(&self) {
+        let Some(max_size) = self.max_size else {
+            return;
+        };
+        if self.bytes.load(Ordering::Relaxed) <= max_size {
+            return;
+        }
+        let evicted: Vec<DiskEntry> = {
+            let mut index = self.index.write().await;
+            let mut by_expiry: Vec<(SystemTime, String)> = index
+                .iter()
+                .map(|(key, entry)| (entry.expires_at, key.clone()))
+                .collect();
+            by_expiry.sort_unstable();
+            let mut remaining = self.bytes.load(Ordering::Relaxed);
+            let mut evicted = Vec::new();
+            for (_, key) in by_expiry {
+                if remaining <= max_size {
+                    break;
+                }
+                if let Some(entry) = index.remove(&key) {
+                    remaining = remaining.saturating_sub(entry.size);
+                    evicted.push(entry);
+                }
+            }
+            evicted
+        };
+        for entry in &evicted {
+            self.forget(entry);
+            CACHE_EVICTIONS.inc();
+            Self::remove_files(&self.dir, &entry.file_stem).await;
+        }
+    }
+
+    /// Updates the counters for an entry taken out of the index.
+    fn forget(&self, entry: &DiskEntry) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(entry.size, Ordering::Relaxed);
+    }
+
+    async fn read_metadata(path: &Path) -> Option<(String, SystemTime, CachedResponse)> {
+        let bytes = tokio::fs::read(path).await.ok()?;
+        let metadata: DiskMetadata = bincode::deserialize(&bytes).ok()?;
+        let response = CachedResponse::from_bytes(&metadata.response).ok()?;
+        let expires_at = UNIX_EPOCH + Duration::from_millis(metadata.expires_at_millis);
+        Some((metadata.key, expires_at, response))
+    }
+
+    async fn remove_files(dir: &Path, file_stem: &str) {
+        let _ = tokio::fs::remove_file(dir.join(format!("{file_stem}.meta"))).await;
+        let _ = tokio::fs::remove_file(dir.join(format!("{file_stem}.body"))).await;
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let (file_stem, metadata) = {
+            let index = self.index.read().await;
+            let entry = index.get(key)?;
+            if entry.expires_at > SystemTime::now() {
+                (entry.file_stem.clone(), Some(entry.metadata.clone()))
+            } else {
+                (entry.file_stem.clone(), None)
+            }
+        };
+
+        let Some(mut response) = metadata else {
+            if let Some(entry) = self.index.write().await.remove(key) {
+                self.forget(&entry);
+            }
+            Self::remove_files(&self.dir, &file_stem).await;
+            return None;
+        };
+
+        let body = tokio::fs::read(self.dir.join(format!("{file_stem}.body")))
+            .await
+            .ok()?;
+        response.body = Bytes::from(body);
+        Some(response)
+    }
+
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration) {
+        let file_stem = sha256_hex(&key);
+        let expires_at = SystemTime::now() + ttl;
+
+        let metadata = CachedResponse {
+            body: Bytes::new(),
+            ..value.clone()
+        };
+        let Ok(response) = metadata.to_bytes() else {
+            return;
+        };
+        let Ok(meta_bytes) = bincode::serialize(&DiskMetadata {
+            key: key.clone(),
+            expires_at_millis: expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            response,
+        }) else {
+            return;
+        };
+
+        let body_path = self.dir.join(format!("{file_stem}.body"));
+        let meta_path = self.dir.join(format!("{file_stem}.meta"));
+        if write_atomic(&body_path, &value.body).await.is_err()
+            || write_atomic(&meta_path, &meta_bytes).await.is_err()
+        {
+            return;
+        }
+
+        let size = value.body.len() as u64 + meta_bytes.len() as u64;
+        let replaced = self.index.write().await.insert(
+            key,
+            DiskEntry {
+                file_stem,
+                expires_at,
+                size,
+                metadata,
+            },
+        );
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(replaced) = replaced {
+            self.forget(&replaced);
+        }
+        self.evict_over_limit().await;
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        let Some(entry) = self.index.write().await.remove(key) else {
+            return false;
+        };
+        self.forget(&entry);
+        Self::remove_files(&self.dir, &entry.file_stem).await;
+        true
+    }
+
+    async fn size(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        self.counters.increment(key, ttl)
+    }
+}
+
+/// Two-level cache: a small in-memory L1 in front of a shared or persistent L2.
+/// Reads promote L2 hits into L1; writes go through to both tiers.
+pub struct TieredStorage {
+    l1: RwLock<HashMap<String, (CachedResponse, SystemTime)>>,
+    l2: Cache,
+    l1_max_entries: usize,
+    l1_ttl: Duration,
+}
+
+impl TieredStorage {
+    pub fn new(l2: Cache, l1_max_entries: usize, l1_ttl: Duration) -> Self {
+        Self {
+            l1: RwLock::new(HashMap::new()),
+            l2,
+            l1_max_entries,
+            l1_ttl,
+        }
+    }
+
+    async fn insert_l1(&self, key: String, value: CachedResponse, ttl: Duration) {
+        if self.l1_max_entries == 0 {
+            return;
+        }
+        let now = SystemTime::now();
+        let expires_at = now + ttl.min(self.l1_ttl);
+
+        let mut l1 = self.l1.write().await;
+        if l1.len() >= self.l1_max_entries && !l1.contains_key(&key) {
+            l1.retain(|_, (_, expires_at)| *expires_at > now);
+            // Still full: evict the oldest entry. L1 is small, so a scan is cheap.
+            if l1.len() >= self.l1_max_entries {
+                let oldest = l1
+                    .iter()
+                    .min_by_key(|(_, (response, _))| response.cached_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    l1.remove(&oldest);
+                }
+            }
+        }
+        l1.insert(key, (value, expires_at));
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        if let Some((response, expires_at)) = self.l1.read().await.get(key) {
+            if *expires_at > SystemTime::now() {
+                return Some(response.clone());
+            }
+        }
+
+        let response = self.l2.get(key).await?;
+        self.insert_l1(key.to_string(), response.clone(), self.l1_ttl)
+            .await;
+        Some(response)
+    }
+
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration) {
+        self.insert_l1(key.clone(), value.clone(), ttl).await;
+        self.l2.set(key, value, ttl).await;
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        let in_l1 = self.l1.write().await.remove(key).is_some();
+        let in_l2 = self.l2.delete(key).await;
+        in_l1 || in_l2
+    }
+
+    async fn size(&self) -> usize {
+        self.l2.size().await
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        self.l2.increment(key, ttl).await
+    }
+}
+
+#[derive(Clone)]
+struct MokaEntry {
+    response: CachedResponse,
+    ttl: Duration,
+}
+
+/// Expires each moka entry after the TTL it was stored with.
+struct MokaExpiry;
+
+impl moka::Expiry<String, MokaEntry> for MokaExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &MokaEntry,
+        _created_at: std::time::Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &MokaEntry,
+        _updated_at: std::time::Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// In-memory backend built on moka's concurrent cache, bounded by the total
+/// size of stored responses rather than a single lock around a map.
+pub struct MokaStorage {
+    cache: moka::future::Cache<String, MokaEntry>,
+    counters: Counters,
+}
+
+impl MokaStorage {
+    pub fn new(max_size: u64) -> Self {
+        let cache = moka::future::Cache::builder()
+            .max_capacity(max_size)
+            .weigher(|key: &String, entry: &MokaEntry| {
+                let headers: usize = entry
+                    .response
+                    .headers
+                    .iter()
+                    .map(|(name, value)| name.as_str().len() + value.len())
+                    .sum();
+                (key.len() + headers + entry.response.body.len())
+                    .try_into()
+                    .unwrap_or(u32::MAX)
+            })
+            .expire_after(MokaExpiry)
+            .eviction_listener(|_key, _entry, cause| {
+                if cause == moka::notification::RemovalCause::Size {
+                    CACHE_EVICTIONS.inc();
+                }
+            })
+            .build();
+        Self {
+            cache,
+            counters: Counters::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MokaStorage {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.cache.get(key).await.map(|entry| entry.response)
+    }
+
+    async fn set(&self, key: String, value: CachedResponse, ttl: Duration) {
+        self.cache
+            .insert(
+                key,
+                MokaEntry {
+                    response: value,
+                    ttl,
+                },
+            )
+            .await;
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        self.cache.remove(key).await.is_some()
+    }
+
+    async fn size(&self) -> usize {
+        self.cache.entry_count() as usize
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> u64 {
+        self.counters.increment(key, ttl)
     }
 }
 
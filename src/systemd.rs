@@ -0,0 +1,125 @@
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{info, warn};
+
+/// Listening sockets handed over by systemd socket activation
+/// (`LISTEN_FDS`). systemd keeps them open while relay restarts, so
+/// connections queue up instead of being refused.
+pub struct InheritedSockets {
+    tcp: Vec<std::net::TcpListener>,
+    unix: Vec<std::os::unix::net::UnixListener>,
+}
+
+impl InheritedSockets {
+    pub fn from_env() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut fds = ListenFd::from_env();
+        let mut sockets = Self {
+            tcp: Vec::new(),
+            unix: Vec::new(),
+        };
+        for idx in 0..fds.len() {
+            if let Ok(Some(listener)) = fds.take_tcp_listener(idx) {
+                sockets.tcp.push(listener);
+            } else if let Ok(Some(listener)) = fds.take_unix_listener(idx) {
+                sockets.unix.push(listener);
+            } else {
+                return Err(format!(
+                    "Inherited file descriptor {} is not a TCP or Unix stream listener",
+                    idx + 3
+                )
+                .into());
+            }
+        }
+        Ok(sockets)
+    }
+
+    /// Takes the inherited socket bound to `addr`, if any.
+    pub fn take_tcp(
+        &mut self,
+        addr: SocketAddr,
+    ) -> Result<Option<TcpListener>, Box<dyn Error + Send + Sync>> {
+        let Some(idx) = self
+            .tcp
+            .iter()
+            .position(|listener| listener.local_addr().is_ok_and(|local| local == addr))
+        else {
+            return Ok(None);
+        };
+        let listener = self.tcp.swap_remove(idx);
+        listener.set_nonblocking(true)?;
+        Ok(Some(TcpListener::from_std(listener)?))
+    }
+
+    /// Takes the inherited socket bound to `path`, if any.
+    pub fn take_unix(
+        &mut self,
+        path: &str,
+    ) -> Result<Option<UnixListener>, Box<dyn Error + Send + Sync>> {
+        let Some(idx) = self.unix.iter().position(|listener| {
+            listener
+                .local_addr()
+                .is_ok_and(|local| local.as_pathname() == Some(Path::new(path)))
+        }) else {
+            return Ok(None);
+        };
+        let listener = self.unix.swap_remove(idx);
+        listener.set_nonblocking(true)?;
+        Ok(Some(UnixListener::from_std(listener)?))
+    }
+
+    /// Fails when a socket systemd passed matches no configured listener,
+    /// since its connections would never be accepted.
+    pub fn check_all_taken(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let unclaimed: Vec<String> = self
+            .tcp
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(|addr| addr.to_string())
+            .chain(self.unix.iter().filter_map(|listener| {
+                let addr = listener.local_addr().ok()?;
+                Some(format!("unix:{}", addr.as_pathname()?.display()))
+            }))
+            .collect();
+        if unclaimed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Inherited sockets match no server listener: {}",
+                unclaimed.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+/// Tells systemd relay is ready to serve (`Type=notify` units), and starts
+/// pinging its watchdog when `WatchdogSec` is set. Does nothing when relay
+/// wasn't started by systemd.
+pub fn notify_ready() {
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd of readiness: {err}");
+    }
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        // Pinging at half the timeout leaves room for a late wakeup
+        let interval = Duration::from_micros(usec / 2);
+        info!("systemd watchdog enabled: pinging every {interval:?}");
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            }
+        });
+    }
+}
+
+/// Tells systemd relay is shutting down.
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+}
@@ -0,0 +1,362 @@
+//! Support for end-to-end tests, enabled by the `test-support` feature: a
+//! scripted origin and a relay proxying to it, both on ephemeral ports.
+//!
+//! ```no_run
+//! use relay::testing::{MockOrigin, MockResponse, TestRelay};
+//!
+//! # async fn example() {
+//! let origin = MockOrigin::start().await;
+//! origin.respond("/hello", MockResponse::ok("world"));
+//! let relay = TestRelay::start(&origin, r#"
+//!     [cache]
+//!     default_ttl = "1s"
+//! "#).await;
+//!
+//! let res = relay.get("/hello").await;
+//! assert_eq!(res.header("x-cache"), Some("MISS"));
+//! assert_eq!(res.body, "world");
+//! # }
+//! ```
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::config::parse_config;
+use crate::server::RelayBuilder;
+
+/// A response the mock origin gives for a path.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    delay: Duration,
+    chunked: bool,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Bytes>) -> Self {
+        Self {
+            status: StatusCode::from_u16(status).expect("invalid status code"),
+            headers: Vec::new(),
+            body: body.into(),
+            delay: Duration::ZERO,
+            chunked: false,
+        }
+    }
+
+    /// A `200 OK` with `body`.
+    pub fn ok(body: impl Into<Bytes>) -> Self {
+        Self::new(200, body)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((
+            HeaderName::from_bytes(name.as_bytes()).expect("invalid header name"),
+            HeaderValue::from_str(value).expect("invalid header value"),
+        ));
+        self
+    }
+
+    /// Waits this long before responding.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Sends the body chunked, without a `Content-Length`.
+    pub fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+}
+
+/// A request the mock origin received.
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest {
+    pub method: String,
+    /// Path and query, as relay sent them
+    pub uri: String,
+    pub headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct OriginState {
+    routes: HashMap<String, MockResponse>,
+    received: Vec<ReceivedRequest>,
+}
+
+/// An HTTP/1.1 origin answering each path with the response scripted for
+/// it, and `404 Not Found` for any other path. Every request is recorded.
+pub struct MockOrigin {
+    addr: SocketAddr,
+    state: Arc<Mutex<OriginState>>,
+    task: JoinHandle<()>,
+}
+
+impl MockOrigin {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(OriginState::default()));
+        let task = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        let service = service_fn(move |req: Request<Incoming>| {
+                            let state = Arc::clone(&state);
+                            async move { Ok::<_, Infallible>(respond(&state, req).await) }
+                        });
+                        let _ = hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            }
+        });
+        Self { addr, state, task }
+    }
+
+    /// The URL to configure as relay's upstream.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answers requests for `path`, ignoring any query, with `response`
+    /// from now on.
+    pub fn respond(&self, path: &str, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .routes
+            .insert(path.to_string(), response);
+    }
+
+    /// Requests received for `path`, ignoring any query, so far.
+    pub fn requests(&self, path: &str) -> Vec<ReceivedRequest> {
+        self.state
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .filter(|req| path_of(&req.uri) == path)
+            .cloned()
+            .collect()
+    }
+
+    /// How many requests for `path` the origin received.
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests(path).len()
+    }
+}
+
+impl Drop for MockOrigin {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn path_of(uri: &str) -> &str {
+    uri.split('?').next().unwrap_or(uri)
+}
+
+async fn respond(
+    state: &Mutex<OriginState>,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, Infallible>> {
+    // Relay sends requests in absolute form
+    let uri = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |pq| pq.as_str())
+        .to_string();
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.received.push(ReceivedRequest {
+            method: req.method().to_string(),
+            uri: uri.clone(),
+            headers: req.headers().clone(),
+        });
+        state.routes.get(path_of(&uri)).cloned()
+    };
+    let response = response.unwrap_or_else(|| MockResponse::new(404, "not found"));
+    if !response.delay.is_zero() {
+        tokio::time::sleep(response.delay).await;
+    }
+    let body = Full::new(response.body);
+    // Without an exact size hint hyper has to fall back to chunked encoding
+    let body = if response.chunked {
+        body.map_frame(|frame| frame).boxed()
+    } else {
+        body.boxed()
+    };
+    let mut res = Response::new(body);
+    *res.status_mut() = response.status;
+    for (name, value) in response.headers {
+        res.headers_mut().append(name, value);
+    }
+    res
+}
+
+/// A response relay sent to the test client, with the body read in full.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl TestResponse {
+    /// The first value of header `name`, if present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+}
+
+/// Relay running in this process in front of a [`MockOrigin`], stopped
+/// when dropped.
+pub struct TestRelay {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl TestRelay {
+    /// Starts relay with the given TOML configuration, which may leave out
+    /// the server address and upstream URL: relay listens on a free local
+    /// port and proxies to `origin`.
+    pub async fn start(origin: &MockOrigin, config: &str) -> Self {
+        Self::start_with(origin, config, |builder| builder).await
+    }
+
+    /// Like [`TestRelay::start`], with `configure` adding layers or plugins
+    /// to the builder.
+    pub async fn start_with(
+        origin: &MockOrigin,
+        config: &str,
+        configure: impl Fn(RelayBuilder) -> RelayBuilder + Send + Sync + 'static,
+    ) -> Self {
+        let configure = std::sync::Arc::new(configure);
+        let mut table: toml::Table = toml::from_str(config).expect("invalid test config");
+        let upstream = table
+            .entry("upstream")
+            .or_insert_with(|| toml::Table::new().into());
+        upstream
+            .as_table_mut()
+            .expect("[upstream] is not a table")
+            .insert("url".to_string(), origin.url().into());
+
+        // The port can be taken between finding it free and binding it, so
+        // failed starts are retried on another port
+        for _ in 0..5 {
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let server = table
+                .entry("server")
+                .or_insert_with(|| toml::Table::new().into())
+                .as_table_mut()
+                .expect("[server] is not a table");
+            server.insert("host".to_string(), "127.0.0.1".into());
+            server.insert("port".to_string(), i64::from(port).into());
+            let config = parse_config(&toml::to_string(&table).unwrap()).expect("invalid config");
+
+            let (shutdown, signal) = oneshot::channel::<()>();
+            let configure = std::sync::Arc::clone(&configure);
+            let task = tokio::spawn(async move {
+                let result = configure(RelayBuilder::new(config))
+                    .shutdown(async {
+                        let _ = signal.await;
+                    })
+                    .run()
+                    .await;
+                if let Err(err) = result {
+                    eprintln!("Test relay failed: {err}");
+                }
+            });
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !task.is_finished() && Instant::now() < deadline {
+                if TcpStream::connect(addr).await.is_ok() {
+                    return Self {
+                        addr,
+                        shutdown: Some(shutdown),
+                        task,
+                    };
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            task.abort();
+        }
+        panic!("relay did not start");
+    }
+
+    /// The URL of `path` on this relay.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Request::get(path).body(Bytes::new()).unwrap())
+            .await
+    }
+
+    /// Sends `req` on a new connection, adding a `Host` header when it has
+    /// none.
+    pub async fn request(&self, mut req: Request<Bytes>) -> TestResponse {
+        if !req.headers().contains_key(hyper::header::HOST) {
+            req.headers_mut().insert(
+                hyper::header::HOST,
+                HeaderValue::from_str(&self.addr.to_string()).unwrap(),
+            );
+        }
+        let stream = TcpStream::connect(self.addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let (parts, body) = req.into_parts();
+        let req = Request::from_parts(parts, Full::new(body));
+        let res = sender.send_request(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        TestResponse {
+            status: parts.status.as_u16(),
+            headers: parts.headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+
+    /// Stops relay and waits for it to finish shutting down.
+    pub async fn stop(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{TlsConfig, UpstreamTlsConfig};
+
+/// Builds a TLS acceptor from PEM files. ALPN offers `h2` when HTTP/2 is
+/// enabled, falling back to `http/1.1`.
+pub fn load_acceptor(
+    config: &TlsConfig,
+    http2: bool,
+) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let mut server_config = load_server_config(config)?;
+    server_config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// A connector for outgoing TLS connections that trusts the bundled Mozilla
+/// root certificates.
+pub fn client_connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(client_config))
+}
+
+/// Client config for `https://` upstreams. Servers are verified against the
+/// bundled roots, or only against `ca` when set, and the certificate in
+/// `cert` and `key` is presented for mutual TLS when set.
+pub fn upstream_client_config(
+    config: Option<&UpstreamTlsConfig>,
+) -> Result<ClientConfig, Box<dyn Error + Send + Sync>> {
+    let roots = match config.and_then(|config| config.ca.as_ref()) {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots.add(cert)?;
+            }
+            roots
+        }
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    match config.map(|config| (&config.cert, &config.key)) {
+        Some((Some(cert), Some(key))) => {
+            Ok(builder.with_client_auth_cert(read_certs(cert)?, read_key(key)?)?)
+        }
+        Some((None, None)) | None => Ok(builder.with_no_client_auth()),
+        Some(_) => Err("upstream.tls needs both cert and key for a client certificate".into()),
+    }
+}
+
+/// Reads the certificate chain and private key into a rustls server config
+/// with no ALPN protocols set. With `client_ca`, clients must present a
+/// certificate issued by one of its CAs.
+pub fn load_server_config(
+    config: &TlsConfig,
+) -> Result<ServerConfig, Box<dyn Error + Send + Sync>> {
+    let certs = read_certs(&config.cert)?;
+    let key = read_key(&config.key)?;
+
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>> {
+    Ok(
+        rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read certificate {path}: {e}"))?,
+    )
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error + Send + Sync>> {
+    Ok(
+        rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))
+            .map_err(|e| format!("Failed to read private key {path}: {e}"))?
+            .ok_or_else(|| format!("No private key found in {path}"))?,
+    )
+}
+
+/// The subject of a verified client's end-entity certificate, e.g.
+/// `CN=billing, O=Example`.
+pub fn client_subject(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(certs?.first()?).ok()?;
+    Some(cert.subject().to_string())
+}
@@ -0,0 +1,150 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use regex::{NoExpand, Regex};
+use std::error::Error;
+
+use crate::cache::CachedResponse;
+use crate::compression::content_type_matches;
+use crate::config::TransformConfig;
+
+struct Replacement {
+    pattern: Regex,
+    to: String,
+    /// Whether `$1`-style group references in `to` are expanded
+    expand: bool,
+}
+
+struct TransformRule {
+    routes: GlobSet,
+    content_types: Vec<String>,
+    replacements: Vec<Replacement>,
+    inject_before_body_end: Option<String>,
+}
+
+/// Config-driven rewrites of text bodies, applied to cache entries as they
+/// are served so the stored copy stays exactly what the upstream sent.
+pub struct Transforms {
+    rules: Vec<TransformRule>,
+}
+
+impl Transforms {
+    pub fn new(configs: &[TransformConfig]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let rules = configs
+            .iter()
+            .map(TransformRule::new)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The rewritten body for an entry served at `path`, or `None` when no
+    /// rule applies or the rules leave it unchanged.
+    pub fn apply(&self, path: &str, cached: &CachedResponse) -> Option<Bytes> {
+        if self.rules.is_empty() || cached.headers.contains_key(CONTENT_ENCODING) {
+            return None;
+        }
+        let content_type = cached
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())?;
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|rule| {
+                rule.routes.is_match(path)
+                    && content_type_matches(&rule.content_types, content_type)
+            })
+            .peekable();
+        rules.peek()?;
+
+        let original = std::str::from_utf8(&cached.body).ok()?;
+        let mut body = original.to_string();
+        for rule in rules {
+            body = rule.apply(body);
+        }
+        (body != original).then(|| Bytes::from(body))
+    }
+}
+
+impl TransformRule {
+    fn new(config: &TransformConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut routes = GlobSetBuilder::new();
+        for pattern in &config.routes {
+            routes.add(Glob::new(pattern)?);
+        }
+
+        let mut replacements = config
+            .replace
+            .iter()
+            .map(|replace| {
+                let pattern = if replace.regex {
+                    Regex::new(&replace.from)?
+                } else {
+                    Regex::new(&regex::escape(&replace.from))?
+                };
+                Ok(Replacement {
+                    pattern,
+                    to: replace.to.clone(),
+                    expand: replace.regex,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+
+        if !config.relative_hosts.is_empty() {
+            let hosts = config
+                .relative_hosts
+                .iter()
+                .map(|host| regex::escape(host))
+                .collect::<Vec<_>>()
+                .join("|");
+            // `https://host/a` becomes `/a`, and a bare `"https://host"` becomes
+            // `"/"`; the host must end there, so `host.evil.com` is left alone
+            replacements.push(Replacement {
+                pattern: Regex::new(&format!(r"(?i)(?:https?:)?//(?:{hosts})(?::\d+)?/"))?,
+                to: "/".to_string(),
+                expand: false,
+            });
+            replacements.push(Replacement {
+                pattern: Regex::new(&format!(
+                    r#"(?i)(["'])(?:https?:)?//(?:{hosts})(?::\d+)?(["'])"#
+                ))?,
+                to: "${1}/${2}".to_string(),
+                expand: true,
+            });
+        }
+
+        Ok(Self {
+            routes: routes.build()?,
+            content_types: config
+                .content_types
+                .iter()
+                .map(|content_type| content_type.to_ascii_lowercase())
+                .collect(),
+            replacements,
+            inject_before_body_end: config.inject_before_body_end.clone(),
+        })
+    }
+
+    fn apply(&self, mut body: String) -> String {
+        for replacement in &self.replacements {
+            let replaced = if replacement.expand {
+                replacement
+                    .pattern
+                    .replace_all(&body, replacement.to.as_str())
+            } else {
+                replacement
+                    .pattern
+                    .replace_all(&body, NoExpand(&replacement.to))
+            };
+            body = replaced.into_owned();
+        }
+
+        if let Some(snippet) = &self.inject_before_body_end {
+            // Tags are ASCII, so lowercasing keeps byte offsets intact
+            if let Some(index) = body.to_ascii_lowercase().rfind("</body>") {
+                body.insert_str(index, snippet);
+            }
+        }
+        body
+    }
+}
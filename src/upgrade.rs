@@ -0,0 +1,128 @@
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONNECTION, HOST, UPGRADE};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{debug, warn};
+
+use crate::cache::is_hop_by_hop;
+use crate::geoip::client_country;
+use crate::handlers::{full, AppState, Body};
+use crate::logger::{AccessLogEntry, CacheStatus};
+use crate::metrics::observe_upstream_response;
+use crate::upstream::connect;
+
+/// True for requests asking to switch protocols, e.g. to WebSocket.
+pub fn is_upgrade_request(req: &Request<Incoming>) -> bool {
+    req.headers().contains_key(UPGRADE)
+        && req
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Forwards an upgrade request to the upstream and, once it agrees to switch
+/// protocols, tunnels bytes in both directions until either side closes.
+pub async fn proxy_upgrade(
+    mut req: Request<Incoming>,
+    state: &AppState,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let base_url = state.upstream_base(state.upstream_for(&req))?;
+    let host = HeaderValue::from_str(base_url.host().ok_or("upstream url has no host")?)?;
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+
+    let stream = connect(&base_url, &state.upstream_tls).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            debug!("Upgrade connection failed: {err:?}");
+        }
+    });
+
+    // Upgrade and Connection are hop-by-hop, but the upstream needs them to
+    // agree to the switch, so every header except Host is passed along
+    let mut builder = Request::builder()
+        .method(req.method().clone())
+        .uri(path_and_query.clone())
+        .header(HOST, host);
+    for (name, value) in req.headers().iter().filter(|(name, _)| *name != HOST) {
+        builder = builder.header(name, value);
+    }
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(state.upstream_request_headers.clone());
+    }
+    let sent = Instant::now();
+    let mut upstream_res = sender
+        .send_request(builder.body(Empty::<Bytes>::new())?)
+        .await?;
+    observe_upstream_response(sent, upstream_res.status());
+
+    let status = upstream_res.status();
+    let response_headers = state.access_log.response_headers(upstream_res.headers());
+    let mut response = Response::builder().status(status);
+    let body = if status == StatusCode::SWITCHING_PROTOCOLS {
+        for (name, value) in upstream_res.headers() {
+            response = response.header(name, value);
+        }
+
+        let client = hyper::upgrade::on(&mut req);
+        let upstream = hyper::upgrade::on(&mut upstream_res);
+        let path = path_and_query.clone();
+        tokio::task::spawn(async move {
+            match tokio::try_join!(client, upstream) {
+                Ok((client, upstream)) => {
+                    let mut client = TokioIo::new(client);
+                    let mut upstream = TokioIo::new(upstream);
+                    if let Err(err) =
+                        tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+                    {
+                        debug!("Upgrade tunnel closed with error: {path} - {err}");
+                    }
+                }
+                Err(err) => warn!("Upgrade failed: {path} - {err}"),
+            }
+        });
+        Bytes::new()
+    } else {
+        // The upstream declined; relay its answer like any other response
+        for (name, value) in upstream_res
+            .headers()
+            .iter()
+            .filter(|(name, _)| !is_hop_by_hop(name))
+        {
+            response = response.header(name, value);
+        }
+        upstream_res.into_body().collect().await?.to_bytes()
+    };
+
+    debug!("Upgrade {status}: {path_and_query}");
+    if state.access_log.enabled() {
+        state.access_log.log(AccessLogEntry {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            status: status.as_u16(),
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            cache_status: CacheStatus::Bypass,
+            remote_addr,
+            bytes_sent: body.len(),
+            country: client_country(req.extensions()),
+            request_headers: state.access_log.request_headers(req.headers()),
+            response_headers,
+        });
+    }
+
+    Ok(response.body(full(body))?)
+}
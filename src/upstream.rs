@@ -0,0 +1,281 @@
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::body::Incoming;
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+use crate::auth::secret;
+use crate::config::UpstreamHeaderConfig;
+use crate::dns;
+use crate::error::RelayError;
+use crate::handlers::Body;
+use crate::metrics::{observe_upstream_response, UPSTREAM_CONNECT_DURATION};
+use crate::proxy_protocol;
+
+/// A connection to the upstream, plain or TLS.
+pub trait UpstreamIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpstreamIo for T {}
+
+/// Parses an upstream URL, returning `None` unless it is an `http://` or
+/// `https://` URL with a host, or `unix:///path/to/app.sock` for a server on
+/// a Unix domain socket. The latter becomes `unix://localhost/path/to/app.sock`,
+/// so requests to the socket carry `Host: localhost`.
+pub fn parse_url(url: &str) -> Option<Uri> {
+    if let Some(path) = url.strip_prefix("unix://") {
+        if !path.starts_with('/') || path.len() < 2 {
+            return None;
+        }
+        return Uri::builder()
+            .scheme("unix")
+            .authority("localhost")
+            .path_and_query(path)
+            .build()
+            .ok();
+    }
+    let uri = url.parse::<Uri>().ok()?;
+    (matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()).then_some(uri)
+}
+
+/// Resolves `upstream.headers`, reading values from the environment or
+/// files, so a missing secret stops relay at startup.
+pub fn load_headers(
+    config: &HashMap<String, UpstreamHeaderConfig>,
+) -> Result<HeaderMap, Box<dyn Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    for (name, header) in config {
+        let value = match (&header.value, &header.value_env, &header.value_file) {
+            (Some(_), None, None) | (None, Some(_), None) => {
+                secret(&header.value, &header.value_env)?.unwrap_or_default()
+            }
+            (None, None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {path} for upstream header {name}: {e}"))?
+                .trim_end()
+                .to_string(),
+            _ => {
+                return Err(format!(
+                    "upstream header {name} needs exactly one of value, value_env or value_file"
+                )
+                .into())
+            }
+        };
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid upstream header name: {name}"))?;
+        let mut value = HeaderValue::from_str(&format!("{}{value}", header.prefix))
+            .map_err(|_| format!("Invalid value for upstream header {name}"))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// The scheme of requests sent to `url`: plain HTTP over a Unix socket.
+pub fn request_scheme(url: &Uri) -> &str {
+    match url.scheme_str() {
+        Some("https") => "https",
+        _ => "http",
+    }
+}
+
+tokio::task_local! {
+    /// Address of the client whose request is being handled, announced to
+    /// origins that expect the PROXY protocol
+    pub static CLIENT_ADDR: SocketAddr;
+}
+
+/// PROXY protocol header version sent on upstream connections, if any
+static PROXY_PROTOCOL: OnceLock<proxy_protocol::Version> = OnceLock::new();
+
+/// Starts every TCP connection to the upstream with a PROXY protocol header.
+/// Only the first call has an effect.
+pub fn send_proxy_protocol(version: proxy_protocol::Version) {
+    let _ = PROXY_PROTOCOL.set(version);
+}
+
+/// Connects to the host and port of `url`, over TLS for `https://` URLs, or
+/// to the socket at its path for `unix://` ones.
+pub async fn connect(
+    url: &Uri,
+    tls: &Arc<ClientConfig>,
+) -> Result<Box<dyn UpstreamIo>, RelayError> {
+    connect_for(url, tls, CLIENT_ADDR.try_with(|addr| *addr).ok()).await
+}
+
+/// Like [`connect`], for a connection made on behalf of `client`, or of no
+/// client in particular.
+async fn connect_for(
+    url: &Uri,
+    tls: &Arc<ClientConfig>,
+    client: Option<SocketAddr>,
+) -> Result<Box<dyn UpstreamIo>, RelayError> {
+    let start = Instant::now();
+    let stream = open(url, tls, client).await?;
+    UPSTREAM_CONNECT_DURATION.observe(start.elapsed().as_secs_f64());
+    Ok(stream)
+}
+
+async fn open(
+    url: &Uri,
+    tls: &Arc<ClientConfig>,
+    client: Option<SocketAddr>,
+) -> Result<Box<dyn UpstreamIo>, RelayError> {
+    if url.scheme_str() == Some("unix") {
+        let stream = UnixStream::connect(url.path())
+            .await
+            .map_err(RelayError::connect)?;
+        return Ok(Box::new(stream));
+    }
+    let host = url
+        .host()
+        .ok_or_else(|| RelayError::connect("upstream url has no host"))?;
+    let https = url.scheme_str() == Some("https");
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let mut stream = connect_tcp(host, port).await.map_err(RelayError::connect)?;
+    if let Some(version) = PROXY_PROTOCOL.get() {
+        let header = proxy_protocol::header(*version, client, stream.peer_addr()?);
+        stream
+            .write_all(&header)
+            .await
+            .map_err(RelayError::connect)?;
+    }
+    if !https {
+        return Ok(Box::new(stream));
+    }
+    let server_name =
+        ServerName::try_from(host.trim_matches(['[', ']']).to_string()).map_err(RelayError::tls)?;
+    let stream = TlsConnector::from(Arc::clone(tls))
+        .connect(server_name, stream)
+        .await
+        .map_err(RelayError::tls)?;
+    Ok(Box::new(stream))
+}
+
+/// How long a connection attempt gets before the next address is tried
+/// alongside it (RFC 8305's Connection Attempt Delay)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens a TCP connection to `host` using Happy Eyeballs (RFC 8305): its
+/// addresses are tried alternating between IPv6 and IPv4, each attempt
+/// starting when the previous one fails or has gone unanswered for
+/// [`CONNECTION_ATTEMPT_DELAY`]. The first connection established wins and
+/// the others are dropped, so a broken address family only costs the delay.
+async fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let mut addrs = interleave_families(dns::resolve(host, port).await?).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        let more = match addrs.next() {
+            Some(addr) => {
+                attempts.push(TcpStream::connect(addr));
+                addrs.len() > 0
+            }
+            None if attempts.is_empty() => break,
+            None => false,
+        };
+        let result = if more {
+            tokio::select! {
+                Some(result) = attempts.next() => result,
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => continue,
+            }
+        } else {
+            match attempts.next().await {
+                Some(result) => result,
+                None => break,
+            }
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.map_or_else(
+        || format!("no addresses found for {host}").into(),
+        Into::into,
+    ))
+}
+
+/// Reorders `addrs` to alternate between address families, IPv6 first,
+/// keeping the order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// A single HTTP/2 connection to the upstream shared by all requests, which
+/// are multiplexed over it as separate streams: h2c (prior knowledge) for
+/// `http://` URLs, or negotiated through ALPN for `https://`. The connection
+/// is opened on first use and reopened if it closes.
+pub struct Http2Upstream {
+    url: Uri,
+    tls: Arc<ClientConfig>,
+    sender: Mutex<Option<SendRequest<Body>>>,
+}
+
+impl Http2Upstream {
+    pub fn new(
+        upstream_url: &str,
+        tls: &ClientConfig,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let url = parse_url(upstream_url)
+            .ok_or_else(|| format!("Invalid upstream URL: {upstream_url}"))?;
+        let mut tls = tls.clone();
+        tls.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(Self {
+            url,
+            tls: Arc::new(tls),
+            sender: Mutex::new(None),
+        })
+    }
+
+    async fn sender(&self) -> Result<SendRequest<Body>, RelayError> {
+        let mut sender = self.sender.lock().await;
+        if let Some(existing) = sender.as_ref().filter(|s| !s.is_closed()) {
+            return Ok(existing.clone());
+        }
+
+        // Requests from every client share the connection
+        let stream = connect_for(&self.url, &self.tls, None).await?;
+        let (new_sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                warn!("HTTP/2 upstream connection failed: {err:?}");
+            }
+        });
+        *sender = Some(new_sender.clone());
+        Ok(new_sender)
+    }
+
+    /// Sends `req`, whose URI must be absolute, as a new stream on the shared
+    /// connection.
+    pub async fn send(&self, req: Request<Body>) -> Result<Response<Incoming>, RelayError> {
+        let mut sender = self.sender().await?;
+        sender.ready().await?;
+        let sent = Instant::now();
+        let res = sender.send_request(req).await?;
+        observe_upstream_response(sent, res.status());
+        Ok(res)
+    }
+}
@@ -0,0 +1,129 @@
+use futures_util::stream::{self, StreamExt};
+use http_body_util::BodyExt;
+use hyper::header::HeaderMap;
+use hyper::{Method, Uri};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::cache_key::generate_cache_key;
+use crate::handlers::{fetch_and_store, send_upstream, send_upstream_with_method, AppState};
+
+/// Runs the configured warmup once at startup and then on its interval,
+/// if one is set.
+pub fn spawn_warmup(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let Some(warmup) = &state.cache_config.warmup else {
+            return;
+        };
+        loop {
+            run_warmup(&state).await;
+            match warmup.interval {
+                Some(interval) => tokio::time::sleep(interval).await,
+                None => break,
+            }
+        }
+    });
+}
+
+async fn run_warmup(state: &AppState) {
+    let Some(warmup) = &state.cache_config.warmup else {
+        return;
+    };
+
+    let mut paths = warmup.paths.clone();
+    if let Some(sitemap) = &warmup.sitemap {
+        match fetch_sitemap(state, sitemap).await {
+            Ok(locations) => paths.extend(locations),
+            Err(e) => warn!("Cache WARMUP failed to fetch sitemap {sitemap}: {e}"),
+        }
+    }
+
+    let total = paths.len();
+    let warmed = AtomicUsize::new(0);
+    stream::iter(paths)
+        .for_each_concurrent(warmup.concurrency.max(1), |path| {
+            let warmed = &warmed;
+            async move {
+                if warm_path(state, &path).await {
+                    warmed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })
+        .await;
+    info!(
+        "Cache WARMUP: {}/{total} paths cached",
+        warmed.load(Ordering::Relaxed)
+    );
+}
+
+async fn warm_path(state: &AppState, path: &str) -> bool {
+    let uri = match path.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => {
+            warn!("Cache WARMUP skipped invalid path {path}: {e}");
+            return false;
+        }
+    };
+
+    let matched_rule = state.cache_config.find_rule_with_pattern(uri.path());
+    let rule = matched_rule.as_ref().map(|matched| &matched.rule);
+    if rule.is_some_and(|r| r.bypass == Some(true)) {
+        return false;
+    }
+
+    let cache_key = state.storage_key(generate_cache_key(
+        &uri,
+        &HeaderMap::new(),
+        &state.cache_config.key,
+        rule,
+    ));
+    match fetch_and_store(state, &cache_key, &uri, &HeaderMap::new(), None).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Cache WARMUP failed: {cache_key} - error: {e}");
+            false
+        }
+    }
+}
+
+/// Fetches a sitemap and returns the path and query of each `<loc>` entry.
+async fn fetch_sitemap(
+    state: &AppState,
+    sitemap: &str,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let uri = sitemap.parse::<Uri>()?;
+    // A bare path is fetched from the upstream, with its headers; an
+    // absolute URL from its own host
+    let res = if uri.authority().is_some() {
+        send_upstream(&uri, &state.upstream_tls, &uri).await?
+    } else {
+        let base_url = state.upstream_base(state.balancer.pick(&uri))?;
+        send_upstream_with_method(
+            &base_url,
+            &state.upstream_tls,
+            &uri,
+            Method::GET,
+            &state.upstream_request_headers,
+        )
+        .await?
+    };
+    if !res.status().is_success() {
+        return Err(format!("sitemap returned {}", res.status()).into());
+    }
+    let body = res.into_body().collect().await?.to_bytes();
+    Ok(sitemap_locations(&String::from_utf8_lossy(&body)))
+}
+
+fn sitemap_locations(xml: &str) -> Vec<String> {
+    xml.split("<loc>")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("</loc>"))
+        .filter_map(|(loc, _)| {
+            let loc = loc.trim().replace("&amp;", "&");
+            let uri = loc.parse::<Uri>().ok()?;
+            uri.path_and_query().map(|pq| pq.as_str().to_string())
+        })
+        .collect()
+}
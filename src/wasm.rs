@@ -0,0 +1,248 @@
+use async_trait::async_trait;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{Request, Response, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use tracing::warn;
+use wasmtime::{Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::config::WasmFilterConfig;
+use crate::handlers::{full, Body};
+use crate::plugin::Plugin;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A request/response filter compiled to WebAssembly.
+///
+/// Modules export their `memory`, an `alloc(len: i32) -> i32` function, and
+/// one or both hooks, `on_request` and `on_response`, each taking
+/// `(ptr: i32, len: i32)` and returning an `i64`. The input is JSON written
+/// to memory obtained from `alloc`; a hook returns `(ptr << 32) | len` of a
+/// JSON reply in its memory, or 0 to leave the message unchanged. Every call
+/// gets a fresh instance, so modules keep no state between requests.
+pub struct WasmFilter {
+    module_path: String,
+    routes: GlobSet,
+    guest: Guest,
+    on_request: bool,
+    on_response: bool,
+}
+
+/// A compiled module with its limits, cheap to clone onto another thread.
+#[derive(Clone)]
+struct Guest {
+    engine: Engine,
+    instance: InstancePre<StoreLimits>,
+    fuel: u64,
+    max_memory: usize,
+}
+
+/// What a hook is given.
+#[derive(Serialize)]
+struct FilterInput<'a> {
+    method: Option<&'a str>,
+    path: &'a str,
+    status: Option<u16>,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+/// What a hook asks for. From `on_request`, a `status` answers the request
+/// without forwarding it.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FilterOutput {
+    status: Option<u16>,
+    body: Option<String>,
+    /// New path and query for the request
+    path: Option<String>,
+    set_headers: Vec<(String, String)>,
+    remove_headers: Vec<String>,
+}
+
+impl WasmFilter {
+    pub fn new(config: &WasmFilterConfig) -> Result<Self, BoxError> {
+        let mut routes = GlobSetBuilder::new();
+        for pattern in &config.routes {
+            routes.add(Glob::new(pattern)?);
+        }
+
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::from_file(&engine, &config.module)
+            .map_err(|e| format!("Failed to load WASM filter {}: {e}", config.module))?;
+        let exports = |name: &str| module.exports().any(|export| export.name() == name);
+        if !exports("memory") || !exports("alloc") {
+            return Err(
+                format!("WASM filter {} must export memory and alloc", config.module).into(),
+            );
+        }
+        let (on_request, on_response) = (exports("on_request"), exports("on_response"));
+        if !on_request && !on_response {
+            return Err(format!(
+                "WASM filter {} exports neither on_request nor on_response",
+                config.module
+            )
+            .into());
+        }
+        // Modules get no imports, so they can't reach anything outside
+        // their own memory
+        let instance = Linker::new(&engine).instantiate_pre(&module)?;
+
+        Ok(Self {
+            module_path: config.module.clone(),
+            routes: routes.build()?,
+            guest: Guest {
+                engine,
+                instance,
+                fuel: config.fuel,
+                max_memory: config.max_memory,
+            },
+            on_request,
+            on_response,
+        })
+    }
+
+    /// Runs a hook on the blocking thread pool: a guest may burn through
+    /// its whole fuel allowance, which would otherwise stall every
+    /// connection on the calling worker thread.
+    async fn call(
+        &self,
+        hook: &'static str,
+        input: &FilterInput<'_>,
+    ) -> Result<FilterOutput, BoxError> {
+        let input = serde_json::to_vec(input)?;
+        let guest = self.guest.clone();
+        tokio::task::spawn_blocking(move || guest.call(hook, &input)).await?
+    }
+}
+
+impl Guest {
+    fn call(&self, hook: &str, input: &[u8]) -> Result<FilterOutput, BoxError> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.instance.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or("memory is not a memory export")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let hook = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, usize::try_from(ptr)?, input)?;
+
+        let reply = hook.call(&mut store, (ptr, len))? as u64;
+        if reply == 0 {
+            return Ok(FilterOutput::default());
+        }
+        let (ptr, len) = ((reply >> 32) as usize, (reply & 0xffff_ffff) as usize);
+        if len > memory.data_size(&store) {
+            return Err("reply is larger than the module's memory".into());
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output)?;
+        Ok(serde_json::from_slice(&output)?)
+    }
+}
+
+#[async_trait]
+impl Plugin for WasmFilter {
+    async fn on_request(&self, req: &mut Request<Incoming>) -> Option<Response<Body>> {
+        if !self.on_request || !self.routes.is_match(req.uri().path()) {
+            return None;
+        }
+        let input = FilterInput {
+            method: Some(req.method().as_str()),
+            path: req.uri().path_and_query().map_or("/", |pq| pq.as_str()),
+            status: None,
+            headers: text_headers(req.headers()),
+        };
+        // A filter that fails can't vouch for the request, so it isn't
+        // forwarded
+        let output = match self.call("on_request", &input).await {
+            Ok(output) => output,
+            Err(err) => {
+                warn!("WASM filter failed: {} - {err}", self.module_path);
+                return Some(error_response());
+            }
+        };
+
+        if let Some(status) = output.status {
+            let mut response =
+                Response::new(full(Bytes::from(output.body.clone().unwrap_or_default())));
+            *response.status_mut() =
+                StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            apply_headers(response.headers_mut(), &output);
+            return Some(response);
+        }
+        if let Some(path) = &output.path {
+            match path.parse::<Uri>() {
+                Ok(uri) if uri.authority().is_none() => *req.uri_mut() = uri,
+                _ => warn!("WASM filter returned an invalid path: {path}"),
+            }
+        }
+        apply_headers(req.headers_mut(), &output);
+        None
+    }
+
+    async fn on_upstream_response(&self, uri: &Uri, res: &mut Parts) {
+        if !self.on_response || !self.routes.is_match(uri.path()) {
+            return;
+        }
+        let input = FilterInput {
+            method: None,
+            path: uri.path_and_query().map_or("/", |pq| pq.as_str()),
+            status: Some(res.status.as_u16()),
+            headers: text_headers(&res.headers),
+        };
+        match self.call("on_response", &input).await {
+            Ok(output) => {
+                if let Some(status) = output.status.and_then(|s| StatusCode::from_u16(s).ok()) {
+                    res.status = status;
+                }
+                apply_headers(&mut res.headers, &output);
+            }
+            Err(err) => warn!("WASM filter failed: {} - {err}", self.module_path),
+        }
+    }
+}
+
+/// Headers whose values are valid text; others are not shown to filters but
+/// are left in place.
+fn text_headers(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect()
+}
+
+fn apply_headers(headers: &mut HeaderMap, output: &FilterOutput) {
+    for name in &output.remove_headers {
+        headers.remove(name.as_str());
+    }
+    for (name, value) in &output.set_headers {
+        match (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => warn!("WASM filter returned an invalid header: {name}"),
+        }
+    }
+}
+
+fn error_response() -> Response<Body> {
+    let mut response = Response::new(full(Bytes::from("Internal Server Error")));
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response
+}
@@ -0,0 +1,76 @@
+use hyper::body::Bytes;
+use hyper::Request;
+use relay::testing::{MockOrigin, MockResponse, TestRelay};
+
+const ADMIN: &str = r#"
+    [admin]
+    enabled = true
+"#;
+
+fn rule_request(method: &str, pattern: &str, rule: &str) -> Request<Bytes> {
+    Request::builder()
+        .method(method)
+        .uri(format!("/_relay/rules?pattern={pattern}"))
+        .body(Bytes::from(rule.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn rules_added_at_runtime_apply_to_the_next_request() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api/users", MockResponse::ok("users"));
+    let relay = TestRelay::start(&origin, ADMIN).await;
+
+    let res = relay
+        .request(rule_request("POST", "/api/*", r#"{"bypass": true}"#))
+        .await;
+    assert_eq!(res.status, 201);
+    relay.get("/api/users").await;
+    let res = relay.get("/api/users").await;
+    assert_eq!(res.header("x-cache"), Some("BYPASS"));
+    assert_eq!(origin.hits("/api/users"), 2);
+
+    let res = relay
+        .request(rule_request("PUT", "/api/*", r#"{"ttl": "90s"}"#))
+        .await;
+    assert_eq!(res.status, 200);
+    relay.get("/api/users").await;
+    let res = relay.get("/api/users").await;
+    assert_eq!(res.header("x-cache"), Some("HIT"));
+
+    let res = relay.get("/_relay/rules").await;
+    assert_eq!(res.body, r#"{"rules":[{"pattern":"/api/*","ttl":"90s"}]}"#);
+}
+
+#[tokio::test]
+async fn rule_changes_are_checked() {
+    let origin = MockOrigin::start().await;
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [admin]
+        enabled = true
+
+        [cache.rules]
+        "/static/*" = { ttl = "1h" }
+        "#,
+    )
+    .await;
+
+    let res = relay
+        .request(rule_request("POST", "/static/*", r#"{"ttl": "2h"}"#))
+        .await;
+    assert_eq!(res.status, 409);
+    let res = relay
+        .request(rule_request("PUT", "/missing", r#"{"ttl": "2h"}"#))
+        .await;
+    assert_eq!(res.status, 404);
+    let res = relay
+        .request(rule_request("POST", "/api/*", r#"{"ttl": "soon"}"#))
+        .await;
+    assert_eq!(res.status, 400);
+
+    let res = relay.request(rule_request("DELETE", "/static/*", "")).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(relay.get("/_relay/rules").await.body, r#"{"rules":[]}"#);
+}
@@ -0,0 +1,790 @@
+use hyper::body::Bytes;
+use hyper::Request;
+use relay::storage::MemoryStorage;
+use relay::testing::{MockOrigin, MockResponse, TestRelay};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[tokio::test]
+async fn repeat_requests_are_served_from_cache() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello"));
+    let relay = TestRelay::start(&origin, "").await;
+
+    let first = relay.get("/page").await;
+    assert_eq!(first.status, 200);
+    assert_eq!(first.header("x-cache"), Some("MISS"));
+    assert_eq!(first.body, "hello");
+
+    let second = relay.get("/page").await;
+    assert_eq!(second.header("x-cache"), Some("HIT"));
+    assert_eq!(second.body, "hello");
+    assert_eq!(origin.hits("/page"), 1);
+}
+
+#[tokio::test]
+async fn query_strings_are_cached_separately() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/search", MockResponse::ok("results"));
+    let relay = TestRelay::start(&origin, "").await;
+
+    relay.get("/search?q=a").await;
+    relay.get("/search?q=b").await;
+    let repeat = relay.get("/search?q=a").await;
+
+    assert_eq!(repeat.header("x-cache"), Some("HIT"));
+    let uris: Vec<String> = origin
+        .requests("/search")
+        .into_iter()
+        .map(|req| req.uri)
+        .collect();
+    assert_eq!(uris, ["/search?q=a", "/search?q=b"]);
+}
+
+#[tokio::test]
+async fn entries_expire_after_their_ttl() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/short", MockResponse::ok("v1"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/short"]
+        ttl = "300ms"
+        "#,
+    )
+    .await;
+
+    relay.get("/short").await;
+    assert_eq!(relay.get("/short").await.header("x-cache"), Some("HIT"));
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    origin.respond("/short", MockResponse::ok("v2"));
+    let expired = relay.get("/short").await;
+    assert_eq!(expired.header("x-cache"), Some("MISS"));
+    assert_eq!(expired.body, "v2");
+    assert_eq!(origin.hits("/short"), 2);
+}
+
+#[tokio::test]
+async fn stale_entries_are_served_when_the_origin_fails() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/flaky", MockResponse::ok("good"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        default_ttl = "200ms"
+        stale_if_error = "1h"
+        "#,
+    )
+    .await;
+
+    relay.get("/flaky").await;
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    origin.respond("/flaky", MockResponse::new(503, "down"));
+
+    let stale = relay.get("/flaky").await;
+    assert_eq!(stale.status, 200);
+    assert_eq!(stale.header("x-cache"), Some("STALE"));
+    assert_eq!(stale.header("x-cache-reason"), Some("upstream-error"));
+    assert_eq!(stale.body, "good");
+    assert_eq!(origin.hits("/flaky"), 2);
+}
+
+#[tokio::test]
+async fn origin_errors_pass_through_once_stale_if_error_runs_out() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/flaky", MockResponse::ok("good"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        default_ttl = "200ms"
+        stale_if_error = "200ms"
+        "#,
+    )
+    .await;
+
+    relay.get("/flaky").await;
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    origin.respond("/flaky", MockResponse::new(503, "down"));
+
+    let res = relay.get("/flaky").await;
+    assert_eq!(res.status, 503);
+    assert_eq!(res.body, "down");
+}
+
+#[tokio::test]
+async fn bypass_rules_skip_the_cache() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api/user", MockResponse::ok("alice"));
+    origin.respond("/static/app.js", MockResponse::ok("js"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/api/*"]
+        bypass = true
+        "#,
+    )
+    .await;
+
+    for _ in 0..3 {
+        let res = relay.get("/api/user").await;
+        assert_eq!(res.body, "alice");
+        assert_ne!(res.header("x-cache"), Some("HIT"));
+        relay.get("/static/app.js").await;
+    }
+    assert_eq!(origin.hits("/api/user"), 3);
+    assert_eq!(origin.hits("/static/app.js"), 1);
+}
+
+#[tokio::test]
+async fn uncacheable_statuses_are_not_stored() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/private", MockResponse::new(403, "forbidden"));
+    let relay = TestRelay::start(&origin, "").await;
+
+    assert_eq!(relay.get("/private").await.status, 403);
+    assert_eq!(relay.get("/private").await.status, 403);
+    assert_eq!(origin.hits("/private"), 2);
+}
+
+#[tokio::test]
+async fn origins_control_caching_through_response_headers() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/short",
+        MockResponse::ok("v1").header("X-Relay-TTL", "300ms"),
+    );
+    origin.respond(
+        "/private",
+        MockResponse::ok("mine").header("X-Relay-No-Cache", "1"),
+    );
+    let relay = TestRelay::start(&origin, "").await;
+
+    let first = relay.get("/short").await;
+    assert_eq!(first.header("x-relay-ttl"), None);
+    assert_eq!(relay.get("/short").await.header("x-cache"), Some("HIT"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(relay.get("/short").await.header("x-cache"), Some("MISS"));
+
+    assert_eq!(relay.get("/private").await.header("x-relay-no-cache"), None);
+    relay.get("/private").await;
+    assert_eq!(origin.hits("/private"), 2);
+}
+
+#[tokio::test]
+async fn clients_can_ask_for_fresher_responses() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello").header("age", "30"));
+    let relay = TestRelay::start(&origin, "").await;
+    let get = |cache_control: &str| {
+        Request::get("/page")
+            .header("cache-control", cache_control)
+            .body(Bytes::new())
+            .unwrap()
+    };
+
+    relay.get("/page").await;
+    let res = relay.request(get("max-age=60")).await;
+    assert_eq!(res.header("x-cache"), Some("HIT"));
+    let res = relay.request(get("max-age=10")).await;
+    assert_eq!(res.header("x-cache"), Some("MISS"));
+    let res = relay.request(get("no-cache")).await;
+    assert_eq!(res.header("x-cache"), Some("MISS"));
+    assert_eq!(origin.hits("/page"), 3);
+
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        ignore_client_cache_control = true
+        "#,
+    )
+    .await;
+    relay.get("/page").await;
+    let res = relay.request(get("no-cache")).await;
+    assert_eq!(res.header("x-cache"), Some("HIT"));
+}
+
+#[tokio::test]
+async fn heuristic_freshness_follows_last_modified() {
+    let origin = MockOrigin::start().await;
+    let last_modified = SystemTime::now() - Duration::from_secs(3);
+    origin.respond(
+        "/page",
+        MockResponse::ok("hello").header("last-modified", &httpdate::fmt_http_date(last_modified)),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        heuristic_freshness = true
+        "#,
+    )
+    .await;
+
+    relay.get("/page").await;
+    assert_eq!(relay.get("/page").await.header("x-cache"), Some("HIT"));
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(relay.get("/page").await.header("x-cache"), Some("MISS"));
+}
+
+#[tokio::test]
+async fn uncacheable_keys_are_passed_to_the_origin() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/private", MockResponse::new(403, "forbidden"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        hit_for_pass_ttl = "300ms"
+        "#,
+    )
+    .await;
+
+    assert_eq!(relay.get("/private").await.header("x-cache"), Some("MISS"));
+    let res = relay.get("/private").await;
+    assert_eq!(res.header("x-cache"), Some("PASS"));
+    assert_eq!(res.body, "forbidden");
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    origin.respond("/private", MockResponse::ok("public"));
+    assert_eq!(relay.get("/private").await.header("x-cache"), Some("MISS"));
+    assert_eq!(relay.get("/private").await.header("x-cache"), Some("HIT"));
+}
+
+#[tokio::test]
+async fn rules_can_vary_the_key_on_request_headers() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api/users", MockResponse::ok("users"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/api/*"]
+        vary_headers = ["X-Tenant-Id"]
+        "#,
+    )
+    .await;
+    let get = |tenant: &str| {
+        Request::get("/api/users")
+            .header("x-tenant-id", tenant)
+            .body(Bytes::new())
+            .unwrap()
+    };
+
+    assert_eq!(
+        relay.request(get("a")).await.header("x-cache"),
+        Some("MISS")
+    );
+    assert_eq!(
+        relay.request(get("b")).await.header("x-cache"),
+        Some("MISS")
+    );
+    assert_eq!(relay.request(get("a")).await.header("x-cache"), Some("HIT"));
+    let tenants: Vec<_> = origin
+        .requests("/api/users")
+        .iter()
+        .map(|received| received.headers["x-tenant-id"].clone())
+        .collect();
+    assert_eq!(tenants, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn rules_can_vary_the_key_on_cookies() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/products", MockResponse::ok("products"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/products"]
+        vary_cookies = ["currency"]
+        "#,
+    )
+    .await;
+    let get = |cookie: &str| {
+        Request::get("/products")
+            .header("cookie", cookie)
+            .body(Bytes::new())
+            .unwrap()
+    };
+
+    relay.request(get("currency=EUR; visitor=1")).await;
+    relay.request(get("currency=USD; visitor=1")).await;
+    let res = relay.request(get("visitor=2; currency=EUR")).await;
+    assert_eq!(res.header("x-cache"), Some("HIT"));
+    let sent: Vec<_> = origin
+        .requests("/products")
+        .iter()
+        .map(|received| received.headers["cookie"].clone())
+        .collect();
+    assert_eq!(sent, ["currency=EUR", "currency=USD"]);
+}
+
+#[tokio::test]
+async fn accept_language_is_narrowed_to_supported_locales() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/home", MockResponse::ok("home"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/home"]
+        languages = ["en", "fr", "de"]
+        "#,
+    )
+    .await;
+    let get = |accept_language: &str| {
+        Request::get("/home")
+            .header("accept-language", accept_language)
+            .body(Bytes::new())
+            .unwrap()
+    };
+
+    relay.request(get("fr-CA,fr;q=0.9,en;q=0.8")).await;
+    relay.request(get("ja")).await;
+    let hits = [get("fr"), get("en-GB"), get("es;q=0.9, de;q=0.5")];
+    relay.request(get("de")).await;
+    for req in hits {
+        assert_eq!(relay.request(req).await.header("x-cache"), Some("HIT"));
+    }
+    let sent: Vec<_> = origin
+        .requests("/home")
+        .iter()
+        .map(|received| received.headers["accept-language"].clone())
+        .collect();
+    assert_eq!(sent, ["fr", "en", "de"]);
+}
+
+#[tokio::test]
+async fn device_classes_get_their_own_entries() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/home", MockResponse::ok("home"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.key]
+        device_class = true
+        "#,
+    )
+    .await;
+    let get = |user_agent: &str| {
+        Request::get("/home")
+            .header("user-agent", user_agent)
+            .body(Bytes::new())
+            .unwrap()
+    };
+    let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148";
+    let pixel = "Mozilla/5.0 (Linux; Android 14; Pixel 8) Chrome/120.0 Mobile Safari/537.36";
+    let ipad = "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X) Mobile/15E148";
+    let mac = "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_0) Safari/605.1.15";
+    let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    for user_agent in [iphone, ipad, mac, googlebot] {
+        relay.request(get(user_agent)).await;
+    }
+    assert_eq!(
+        relay.request(get(pixel)).await.header("x-cache"),
+        Some("HIT")
+    );
+    let sent: Vec<_> = origin
+        .requests("/home")
+        .iter()
+        .map(|received| received.headers["x-device-class"].clone())
+        .collect();
+    assert_eq!(sent, ["mobile", "tablet", "desktop", "bot"]);
+}
+
+#[tokio::test]
+async fn crawlers_are_served_older_entries() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/article", MockResponse::ok("article"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/article"]
+        ttl = "200ms"
+        bot_ttl = "1h"
+        "#,
+    )
+    .await;
+    let get = |user_agent: &str| {
+        Request::get("/article")
+            .header("user-agent", user_agent)
+            .body(Bytes::new())
+            .unwrap()
+    };
+    let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+    let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+
+    relay.request(get(firefox)).await;
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let res = relay.request(get(googlebot)).await;
+    assert_eq!(res.header("x-cache"), Some("HIT"));
+    let res = relay.request(get(firefox)).await;
+    assert_eq!(res.header("x-cache"), Some("MISS"));
+}
+
+#[tokio::test]
+async fn spa_routes_fall_back_to_the_shell_with_its_own_ttl() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/app/index.html", MockResponse::ok("<div id=app>"));
+    origin.respond("/app/main.js", MockResponse::ok("boot()"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/app/*"]
+        ttl = "1d"
+        spa_fallback = "/app/index.html"
+        spa_fallback_ttl = "1m"
+        "#,
+    )
+    .await;
+
+    let settings = relay.get("/app/settings").await;
+    assert_eq!(settings.status, 200);
+    assert_eq!(settings.body, "<div id=app>");
+
+    let profile = relay
+        .request(
+            Request::get("/app/profile?tab=2")
+                .header("x-relay-debug", "1")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(profile.body, "<div id=app>");
+    assert_eq!(profile.header("x-cache"), Some("HIT"));
+    assert_eq!(profile.header("x-relay-debug-ttl"), Some("60"));
+    assert_eq!(origin.hits("/app/index.html"), 1);
+
+    assert_eq!(relay.get("/app/main.js").await.body, "boot()");
+}
+
+#[tokio::test]
+async fn refresh_ahead_drops_paths_nobody_requests_and_caps_how_many_it_tracks() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/reports/a", MockResponse::ok("a"));
+    origin.respond("/reports/b", MockResponse::ok("b"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        refresh_ahead_max_keys = 1
+
+        [cache.rules."/reports/*"]
+        ttl = "1s"
+        refresh_interval = "100ms"
+        "#,
+    )
+    .await;
+
+    relay.get("/reports/a").await;
+    relay.get("/reports/b").await;
+    tokio::time::sleep(Duration::from_millis(450)).await;
+    assert!(origin.hits("/reports/a") > 1);
+    assert_eq!(origin.hits("/reports/b"), 1);
+
+    // Nobody asked for it again within its TTL
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    let settled = origin.hits("/reports/a");
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(origin.hits("/reports/a"), settled);
+}
+
+#[tokio::test]
+async fn refresh_ahead_fetches_each_variant_with_its_own_headers() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/feed/latest", MockResponse::ok("feed"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/feed/*"]
+        ttl = "1m"
+        refresh_interval = "100ms"
+        vary_headers = ["X-Tenant"]
+        "#,
+    )
+    .await;
+
+    relay
+        .request(
+            Request::get("/feed/latest")
+                .header("x-tenant", "acme")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    tokio::time::sleep(Duration::from_millis(350)).await;
+
+    let requests = origin.requests("/feed/latest");
+    assert!(requests.len() > 1);
+    assert!(requests.iter().all(|req| req
+        .headers
+        .get("x-tenant")
+        .is_some_and(|value| value == "acme")));
+}
+
+#[tokio::test]
+async fn bodies_that_decode_past_max_object_size_are_not_stored() {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![0; 2 * 1024 * 1024]).unwrap();
+    let bomb = encoder.finish().unwrap();
+
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/bomb",
+        MockResponse::ok(bomb).header("content-encoding", "gzip"),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        max_object_size = "1MB"
+        "#,
+    )
+    .await;
+
+    let first = relay.get("/bomb").await;
+    assert_eq!(first.status, 200);
+    assert_eq!(first.header("content-encoding"), Some("gzip"));
+    relay.get("/bomb").await;
+    assert_eq!(origin.hits("/bomb"), 2);
+}
+
+#[tokio::test]
+async fn disk_storage_evicts_entries_closest_to_expiring_past_max_size() {
+    let dir = std::env::temp_dir().join(format!("relay-disk-evict-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let origin = MockOrigin::start().await;
+    origin.respond("/first", MockResponse::ok("a".repeat(400)));
+    origin.respond("/second", MockResponse::ok("b".repeat(400)));
+    let relay = TestRelay::start(
+        &origin,
+        &format!(
+            r#"
+            [storage]
+            backend = "disk"
+
+            [storage.disk]
+            path = "{}"
+            max_size = "1KB"
+            "#,
+            dir.display()
+        ),
+    )
+    .await;
+
+    relay.get("/first").await;
+    relay.get("/second").await;
+    assert_eq!(relay.get("/second").await.header("x-cache"), Some("HIT"));
+    assert_eq!(relay.get("/first").await.header("x-cache"), Some("MISS"));
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn chunked_responses_are_served_like_hits_when_small_enough() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/small", MockResponse::ok("small").chunked());
+    origin.respond("/large", MockResponse::ok("x".repeat(2048)).chunked());
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        max_object_size = "1KB"
+        "#,
+    )
+    .await;
+
+    // The first miss already gets the range handling a hit would
+    let first = relay
+        .request(
+            Request::get("/small")
+                .header("range", "bytes=1-3")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(first.header("x-cache"), Some("MISS"));
+    assert_eq!(first.status, 206);
+    assert_eq!(first.body, "mal");
+    let second = relay.get("/small").await;
+    assert_eq!(second.header("x-cache"), Some("HIT"));
+    assert_eq!(second.header("content-length"), Some("5"));
+    assert_eq!(second.body, "small");
+
+    assert_eq!(relay.get("/large").await.body.len(), 2048);
+    assert_eq!(relay.get("/large").await.body.len(), 2048);
+    assert_eq!(origin.hits("/large"), 2);
+}
+
+#[tokio::test]
+async fn bodies_declared_larger_than_max_object_size_are_streamed_uncached() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/video", MockResponse::ok("v".repeat(2048)));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache]
+        max_object_size = "1KB"
+        "#,
+    )
+    .await;
+
+    let first = relay.get("/video").await;
+    assert_eq!(first.header("content-length"), Some("2048"));
+    assert_eq!(first.body.len(), 2048);
+    relay.get("/video").await;
+    assert_eq!(origin.hits("/video"), 2);
+}
+
+#[tokio::test]
+async fn cache_layer_answers_hits_in_front_of_the_pipeline() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api/user", MockResponse::ok("alice"));
+    let relay = TestRelay::start_with(
+        &origin,
+        r#"
+        [cache.rules."/api/*"]
+        bypass = true
+        "#,
+        |builder| {
+            let config = relay::parse_config(
+                r#"
+                [server]
+                host = "127.0.0.1"
+                port = 8080
+                [upstream]
+                url = "http://localhost"
+                "#,
+            )
+            .unwrap();
+            builder.layer(relay::CacheLayer::new(
+                Arc::new(MemoryStorage::new()),
+                config.cache,
+            ))
+        },
+    )
+    .await;
+
+    let first = relay.get("/api/user").await;
+    assert_eq!(first.header("x-cache"), Some("MISS"));
+    let second = relay.get("/api/user").await;
+    assert_eq!(second.body, "alice");
+    assert_eq!(second.header("x-cache"), Some("HIT"));
+    assert_eq!(origin.hits("/api/user"), 1);
+}
+
+#[tokio::test]
+async fn signature_params_are_only_left_out_of_keys_on_signed_routes() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/search", MockResponse::ok("results"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [signed_urls]
+        routes = ["/downloads/*"]
+        secret = "test-secret"
+        "#,
+    )
+    .await;
+
+    relay.get("/search?expires=1").await;
+    relay.get("/search?expires=2").await;
+    assert_eq!(origin.hits("/search"), 2);
+}
+
+#[tokio::test]
+async fn detached_misses_keep_their_concurrency_permit() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/slow",
+        MockResponse::ok("slow").delay(Duration::from_millis(500)),
+    );
+    origin.respond("/other", MockResponse::ok("other"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [server]
+        max_concurrent_requests = 1
+        "#,
+    )
+    .await;
+
+    // The client gives up, but the fetch carries on holding the permit
+    let _ = tokio::time::timeout(Duration::from_millis(100), relay.get("/slow")).await;
+    assert_eq!(relay.get("/other").await.status, 503);
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(relay.get("/other").await.status, 200);
+    assert_eq!(relay.get("/slow").await.header("x-cache"), Some("HIT"));
+    assert_eq!(origin.hits("/slow"), 1);
+}
+
+#[tokio::test]
+async fn slow_fetches_are_not_hedged_to_the_same_server() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/slow",
+        MockResponse::ok("slow").delay(Duration::from_millis(300)),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [upstream.hedging]
+        percentile = "50%"
+        min_delay = "10ms"
+        "#,
+    )
+    .await;
+
+    // Enough responses for hedging to start
+    for i in 0..100 {
+        relay.get(&format!("/warmup/{i}")).await;
+    }
+    assert_eq!(relay.get("/slow").await.body, "slow");
+    assert_eq!(origin.hits("/slow"), 1);
+}
+
+#[tokio::test]
+async fn large_bodies_are_decoded_and_compressed() {
+    use std::io::Write;
+
+    let text = "relay ".repeat(20_000);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/big.txt",
+        MockResponse::ok(encoder.finish().unwrap())
+            .header("content-type", "text/plain")
+            .header("content-encoding", "gzip"),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [compression]
+        enabled = true
+        "#,
+    )
+    .await;
+
+    let plain = relay.get("/big.txt").await;
+    assert_eq!(plain.header("content-encoding"), None);
+    assert_eq!(plain.body, text);
+
+    let compressed = relay
+        .request(
+            Request::get("/big.txt")
+                .header("accept-encoding", "gzip")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(compressed.header("x-cache"), Some("HIT"));
+    assert_eq!(compressed.header("content-encoding"), Some("gzip"));
+    assert!(compressed.body.len() < text.len());
+}
@@ -0,0 +1,202 @@
+use relay::testing::{MockOrigin, MockResponse, TestRelay};
+use std::time::{Duration, Instant};
+
+/// Stops `origin` and waits until its port refuses connections.
+async fn stop(origin: MockOrigin) {
+    let url = origin.url();
+    let addr = url.trim_start_matches("http://").to_string();
+    drop(origin);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while tokio::net::TcpStream::connect(&addr).await.is_ok() {
+        assert!(
+            Instant::now() < deadline,
+            "origin still accepting connections"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// The value of the sample named `series` on the relay's `/metrics`, or 0
+/// before it has been recorded.
+async fn metric(relay: &TestRelay, series: &str) -> f64 {
+    let metrics = relay.get("/metrics").await.body;
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.trim().parse().ok())
+        .unwrap_or(0.0)
+}
+
+/// Waits for `series` to grow past `before`. Counters are shared by every
+/// relay in the test process, so only the change is meaningful.
+async fn wait_for_increase(relay: &TestRelay, series: &str, before: f64) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while metric(relay, series).await <= before {
+        assert!(Instant::now() < deadline, "{series} did not increase");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn unreachable_upstream_is_a_bad_gateway() {
+    let origin = MockOrigin::start().await;
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [prometheus]
+        enabled = true
+        "#,
+    )
+    .await;
+    stop(origin).await;
+
+    let errors = r#"relay_errors_total{kind="upstream_connect"}"#;
+    let upstream_errors = r#"relay_upstream_errors_total{kind="upstream_connect"}"#;
+    let errors_before = metric(&relay, errors).await;
+    let upstream_errors_before = metric(&relay, upstream_errors).await;
+
+    let res = relay.get("/page").await;
+    assert_eq!(res.status, 502);
+
+    wait_for_increase(&relay, errors, errors_before).await;
+    wait_for_increase(&relay, upstream_errors, upstream_errors_before).await;
+}
+
+#[tokio::test]
+async fn custom_error_body_replaces_the_default() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [upstream]
+        error_body = "Back soon"
+        "#,
+    )
+    .await;
+    stop(origin).await;
+
+    let res = relay.get("/other").await;
+    assert_eq!(res.status, 502);
+    assert_eq!(res.body, "Back soon");
+}
+
+#[tokio::test]
+async fn rate_limit_layer_rejects_requests_over_the_limit() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello"));
+    let relay = TestRelay::start_with(&origin, "", |builder| {
+        let config = relay::parse_config(
+            r#"
+            [server]
+            host = "127.0.0.1"
+            port = 8080
+            [upstream]
+            url = "http://localhost"
+            [rate_limit]
+            enabled = true
+            rate = 1
+            burst = 1
+            "#,
+        )
+        .unwrap();
+        builder.layer(relay::RateLimitLayer::new(config.rate_limit).unwrap())
+    })
+    .await;
+
+    assert_eq!(relay.get("/page").await.status, 200);
+    let limited = relay.get("/page").await;
+    assert_eq!(limited.status, 429);
+    assert!(limited.header("retry-after").is_some());
+}
+
+#[tokio::test]
+async fn the_most_specific_rate_limit_route_applies() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api/login", MockResponse::ok("login"));
+    origin.respond("/api/search", MockResponse::ok("search"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [rate_limit]
+        enabled = true
+        [rate_limit.routes]
+        "/api/*" = { rate = 1, burst = 1 }
+        "/api/login" = { rate = 1, burst = 3 }
+        "#,
+    )
+    .await;
+
+    for _ in 0..3 {
+        assert_eq!(relay.get("/api/login").await.status, 200);
+    }
+    assert_eq!(relay.get("/api/login").await.status, 429);
+    assert_eq!(relay.get("/api/search").await.status, 200);
+    assert_eq!(relay.get("/api/search").await.status, 429);
+}
+
+#[tokio::test]
+async fn the_most_specific_access_route_applies() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/public/page", MockResponse::ok("hello"));
+    origin.respond("/private/page", MockResponse::ok("secret"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [access.routes]
+        "/*" = { deny = ["127.0.0.0/8"] }
+        "/public/*" = { deny = [] }
+        "/public/**/*" = { deny = [] }
+        "#,
+    )
+    .await;
+
+    assert_eq!(relay.get("/public/page").await.status, 200);
+    assert_eq!(relay.get("/private/page").await.status, 403);
+}
+
+#[tokio::test]
+async fn requests_without_a_known_key_are_metered_by_client() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/api", MockResponse::ok("ok"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [quotas]
+        anonymous_limit = 2
+        [quotas.keys]
+        "known" = 100
+        "#,
+    )
+    .await;
+
+    let with_key = |key: &str| {
+        hyper::Request::get("/api")
+            .header("x-api-key", key)
+            .body(hyper::body::Bytes::new())
+            .unwrap()
+    };
+    assert_eq!(relay.get("/api").await.status, 200);
+    assert_eq!(relay.request(with_key("made-up")).await.status, 200);
+    let limited = relay.get("/api").await;
+    assert_eq!(limited.status, 429);
+    assert_eq!(limited.header("x-ratelimit-limit"), Some("2"));
+    assert_eq!(relay.request(with_key("known")).await.status, 200);
+}
+
+#[tokio::test]
+async fn invalid_startup_settings_are_config_errors() {
+    let config = relay::parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        [upstream]
+        url = "http://localhost"
+        http_version = "3"
+        "#,
+    )
+    .unwrap();
+
+    let error = relay::run(config).await.unwrap_err();
+    assert_eq!(error.kind(), "config");
+}
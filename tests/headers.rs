@@ -0,0 +1,319 @@
+use hyper::body::Bytes;
+use hyper::Request;
+use relay::testing::{MockOrigin, MockResponse, TestRelay};
+use std::time::Duration;
+
+#[tokio::test]
+async fn origin_headers_are_passed_through_and_cached() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/page",
+        MockResponse::ok("hello")
+            .header("content-type", "text/plain")
+            .header("x-origin", "yes"),
+    );
+    let relay = TestRelay::start(&origin, "").await;
+
+    for _ in 0..2 {
+        let res = relay.get("/page").await;
+        assert_eq!(res.header("content-type"), Some("text/plain"));
+        assert_eq!(res.header("x-origin"), Some("yes"));
+    }
+}
+
+#[tokio::test]
+async fn hits_report_their_age() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello").header("age", "30"));
+    let relay = TestRelay::start(&origin, "").await;
+
+    relay.get("/page").await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let hit = relay.get("/page").await;
+
+    assert_eq!(hit.header("x-cache"), Some("HIT"));
+    let age: u64 = hit.header("age").unwrap().parse().unwrap();
+    assert!((31..=32).contains(&age), "unexpected age {age}");
+}
+
+#[tokio::test]
+async fn hop_by_hop_headers_are_not_passed_on() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/page",
+        MockResponse::ok("hello")
+            .header("proxy-authenticate", "Basic")
+            .header("keep-alive", "timeout=5"),
+    );
+    let relay = TestRelay::start(&origin, "").await;
+
+    let res = relay.get("/page").await;
+    assert!(res.header("proxy-authenticate").is_none());
+    assert!(res.header("keep-alive").is_none());
+    let hit = relay.get("/page").await;
+    assert_eq!(hit.header("x-cache"), Some("HIT"));
+    assert!(hit.header("proxy-authenticate").is_none());
+}
+
+#[tokio::test]
+async fn requests_carry_the_upstream_host() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello"));
+    let relay = TestRelay::start(&origin, "").await;
+
+    relay
+        .request(
+            Request::get("/page")
+                .header("host", "example.com")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+
+    let received = &origin.requests("/page")[0];
+    assert_eq!(received.method, "GET");
+    let host = received.headers["host"].to_str().unwrap();
+    assert!(host.starts_with("127.0.0.1"), "unexpected host {host}");
+}
+
+#[tokio::test]
+async fn debug_headers_are_added_on_request() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/docs/intro", MockResponse::ok("hello"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/docs/*"]
+        ttl = "10m"
+        "#,
+    )
+    .await;
+
+    let plain = relay.get("/docs/intro").await;
+    assert!(plain.header("x-relay-rule").is_none());
+
+    let debug = relay
+        .request(
+            Request::get("/docs/intro")
+                .header("x-relay-debug", "1")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(debug.header("x-cache"), Some("HIT"));
+    assert_eq!(debug.header("x-relay-rule"), Some("/docs/*"));
+    assert_eq!(debug.header("x-relay-debug-ttl"), Some("600"));
+    assert_eq!(debug.header("x-relay-ttl"), None);
+    assert_eq!(
+        debug.header("x-relay-upstream"),
+        Some(origin.url().as_str())
+    );
+}
+
+#[tokio::test]
+async fn responses_setting_cookies_are_not_cached() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/account",
+        MockResponse::ok("mine").header("set-cookie", "session=abc"),
+    );
+    origin.respond(
+        "/static/app.js",
+        MockResponse::ok("app").header("set-cookie", "lb=1"),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [cache.rules."/static/*"]
+        cache_set_cookie = true
+        "#,
+    )
+    .await;
+
+    for _ in 0..2 {
+        let res = relay.get("/account").await;
+        assert_eq!(res.header("set-cookie"), Some("session=abc"));
+    }
+    assert_eq!(origin.hits("/account"), 2);
+
+    let first = relay.get("/static/app.js").await;
+    assert_eq!(first.header("set-cookie"), Some("lb=1"));
+    let second = relay.get("/static/app.js").await;
+    assert_eq!(second.header("x-cache"), Some("HIT"));
+    assert_eq!(second.header("set-cookie"), None);
+}
+
+#[tokio::test]
+async fn cors_preflights_are_answered_and_headers_added_to_cached_responses() {
+    let origin = MockOrigin::start().await;
+    origin.respond(
+        "/api/items",
+        MockResponse::ok("[]").header("access-control-allow-origin", "*"),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [[cors]]
+        routes = ["/api/*"]
+        allow_origins = ["https://app.example.com"]
+        allow_methods = ["GET", "PUT"]
+        allow_headers = ["Content-Type"]
+        max_age = "10m"
+        "#,
+    )
+    .await;
+
+    let preflight = |origin: &str| {
+        Request::options("/api/items")
+            .header("origin", origin)
+            .header("access-control-request-method", "PUT")
+            .body(Bytes::new())
+            .unwrap()
+    };
+    let res = relay.request(preflight("https://app.example.com")).await;
+    assert_eq!(res.status, 204);
+    assert_eq!(
+        res.header("access-control-allow-origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(res.header("access-control-allow-methods"), Some("GET, PUT"));
+    assert_eq!(
+        res.header("access-control-allow-headers"),
+        Some("Content-Type")
+    );
+    assert_eq!(res.header("access-control-max-age"), Some("600"));
+    let refused = relay.request(preflight("https://evil.example.com")).await;
+    assert_eq!(refused.status, 403);
+    assert_eq!(origin.hits("/api/items"), 0);
+
+    for _ in 0..2 {
+        let res = relay
+            .request(
+                Request::get("/api/items")
+                    .header("origin", "https://app.example.com")
+                    .body(Bytes::new())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(
+            res.header("access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(res.header("vary"), Some("Origin"));
+    }
+    assert_eq!(origin.hits("/api/items"), 1);
+
+    let other = relay
+        .request(
+            Request::get("/api/items")
+                .header("origin", "https://evil.example.com")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(other.header("x-cache"), Some("HIT"));
+    assert_eq!(other.header("access-control-allow-origin"), None);
+}
+
+#[tokio::test]
+async fn security_headers_are_added_unless_the_origin_sets_them() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/page", MockResponse::ok("hello"));
+    origin.respond(
+        "/embed",
+        MockResponse::ok("widget").header("x-frame-options", "ALLOWALL"),
+    );
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [headers.security]
+        content_security_policy = "default-src 'self'"
+        referrer_policy = ""
+        "#,
+    )
+    .await;
+
+    for _ in 0..2 {
+        let res = relay.get("/page").await;
+        assert_eq!(
+            res.header("strict-transport-security"),
+            Some("max-age=31536000; includeSubDomains")
+        );
+        assert_eq!(res.header("x-content-type-options"), Some("nosniff"));
+        assert_eq!(res.header("x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(
+            res.header("content-security-policy"),
+            Some("default-src 'self'")
+        );
+        assert_eq!(res.header("referrer-policy"), None);
+    }
+
+    let res = relay.get("/embed").await;
+    assert_eq!(res.header("x-frame-options"), Some("ALLOWALL"));
+}
+
+#[tokio::test]
+async fn configured_headers_are_sent_to_the_upstream() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/forecast", MockResponse::ok("sunny"));
+    let key_file = std::env::temp_dir().join(format!("relay-api-key-{}", std::process::id()));
+    std::fs::write(&key_file, "s3cret\n").unwrap();
+    let relay = TestRelay::start(
+        &origin,
+        &format!(
+            r#"
+            [upstream.headers]
+            Authorization = {{ value_file = "{}", prefix = "Bearer " }}
+            X-Client = {{ value = "relay" }}
+            "#,
+            key_file.display()
+        ),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let res = relay.get("/forecast").await;
+        assert_eq!(res.body, "sunny");
+    }
+    let requests = origin.requests("/forecast");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].headers.get("authorization").unwrap(),
+        "Bearer s3cret"
+    );
+    assert_eq!(requests[0].headers.get("x-client").unwrap(), "relay");
+
+    let _ = std::fs::remove_file(key_file);
+}
+
+#[tokio::test]
+async fn debug_headers_need_an_allowed_client_or_the_token() {
+    let origin = MockOrigin::start().await;
+    origin.respond("/docs/intro", MockResponse::ok("hello"));
+    let relay = TestRelay::start(
+        &origin,
+        r#"
+        [debug]
+        enabled = true
+        allow = ["10.0.0.0/8"]
+        token = "let-me-see"
+        "#,
+    )
+    .await;
+
+    let debug_request = |value: &str| {
+        Request::get("/docs/intro")
+            .header("x-relay-debug", value)
+            .body(Bytes::new())
+            .unwrap()
+    };
+    assert!(relay
+        .get("/docs/intro")
+        .await
+        .header("x-relay-rule")
+        .is_none());
+    let guessed = relay.request(debug_request("1")).await;
+    assert!(guessed.header("x-relay-rule").is_none());
+    let trusted = relay.request(debug_request("let-me-see")).await;
+    assert_eq!(trusted.header("x-relay-rule"), Some("default"));
+}
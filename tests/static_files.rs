@@ -0,0 +1,164 @@
+use hyper::body::Bytes;
+use hyper::Request;
+use relay::testing::{MockOrigin, TestRelay};
+use std::fs;
+use std::path::PathBuf;
+
+fn asset_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("relay-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("css")).unwrap();
+    fs::write(dir.join("css/site.css"), "body { color: red }").unwrap();
+    fs::write(dir.join("app.js"), "console.log('plain')").unwrap();
+    fs::write(dir.join("app.js.gz"), "pretend-gzip").unwrap();
+    fs::write(dir.join(".env"), "SECRET=1").unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn files_are_served_from_the_directory_without_the_origin() {
+    let origin = MockOrigin::start().await;
+    let dir = asset_dir("static");
+    let relay = TestRelay::start(
+        &origin,
+        &format!(
+            r#"
+            [cache.rules."/assets/*"]
+            serve_static = "{}"
+            ttl = "1h"
+            "#,
+            dir.display()
+        ),
+    )
+    .await;
+
+    let res = relay.get("/assets/css/site.css").await;
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "body { color: red }");
+    assert_eq!(res.header("content-type"), Some("text/css; charset=utf-8"));
+    assert_eq!(res.header("cache-control"), Some("max-age=3600"));
+    let etag = res.header("etag").unwrap().to_string();
+    assert!(res.header("last-modified").is_some());
+
+    let revalidated = relay
+        .request(
+            Request::get("/assets/css/site.css")
+                .header("if-none-match", &etag)
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(revalidated.status, 304);
+    assert_eq!(revalidated.body, "");
+
+    let ranged = relay
+        .request(
+            Request::get("/assets/css/site.css")
+                .header("range", "bytes=0-3")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(ranged.status, 206);
+    assert_eq!(ranged.body, "body");
+
+    let gzipped = relay
+        .request(
+            Request::get("/assets/app.js")
+                .header("accept-encoding", "gzip")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(gzipped.header("content-encoding"), Some("gzip"));
+    assert_eq!(gzipped.header("vary"), Some("Accept-Encoding"));
+    assert_eq!(gzipped.body, "pretend-gzip");
+    let plain = relay.get("/assets/app.js").await;
+    assert_eq!(plain.header("content-encoding"), None);
+    assert_eq!(plain.body, "console.log('plain')");
+
+    assert_eq!(relay.get("/assets/missing.css").await.status, 404);
+    assert_eq!(relay.get("/assets/.env").await.status, 404);
+    assert_eq!(relay.get("/assets/%2e%2e/etc/passwd").await.status, 404);
+    assert_eq!(origin.hits("/assets/css/site.css"), 0);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn paths_without_an_extension_are_served_the_spa_shell() {
+    let origin = MockOrigin::start().await;
+    let dir = asset_dir("spa");
+    fs::write(dir.join("index.html"), "<div id=app>").unwrap();
+    let relay = TestRelay::start(
+        &origin,
+        &format!(
+            r#"
+            [cache.rules."/*"]
+            serve_static = "{}"
+            ttl = "1d"
+            spa_fallback = "/index.html"
+            spa_fallback_on = "non_file"
+            spa_fallback_ttl = "1m"
+            "#,
+            dir.display()
+        ),
+    )
+    .await;
+
+    let route = relay.get("/orders/42").await;
+    assert_eq!(route.status, 200);
+    assert_eq!(route.body, "<div id=app>");
+    assert_eq!(route.header("cache-control"), Some("max-age=60"));
+
+    let asset = relay.get("/app.js").await;
+    assert_eq!(asset.body, "console.log('plain')");
+    assert_eq!(asset.header("cache-control"), Some("max-age=86400"));
+    assert_eq!(relay.get("/missing.js").await.status, 404);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn large_files_are_streamed_from_disk_with_ranges() {
+    let origin = MockOrigin::start().await;
+    let dir = asset_dir("large");
+    let video: Vec<u8> = (0..3 * 1024 * 1024)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect();
+    fs::write(dir.join("clip.mp4"), &video).unwrap();
+    let relay = TestRelay::start(
+        &origin,
+        &format!(
+            r#"
+            [cache.rules."/media/*"]
+            serve_static = "{}"
+            "#,
+            dir.display()
+        ),
+    )
+    .await;
+
+    let full = relay.get("/media/clip.mp4").await;
+    assert_eq!(full.status, 200);
+    assert_eq!(full.header("content-length"), Some("3145728"));
+    assert_eq!(full.header("accept-ranges"), Some("bytes"));
+    assert_eq!(full.body.as_bytes(), &video[..]);
+
+    let ranged = relay
+        .request(
+            Request::get("/media/clip.mp4")
+                .header("range", "bytes=2097152-2097161")
+                .body(Bytes::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(ranged.status, 206);
+    assert_eq!(
+        ranged.header("content-range"),
+        Some("bytes 2097152-2097161/3145728")
+    );
+    assert_eq!(ranged.body.as_bytes(), &video[2097152..2097162]);
+
+    let _ = fs::remove_dir_all(dir);
+}